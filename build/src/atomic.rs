@@ -0,0 +1,99 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Replacing a file whole. The new contents are written next to the old ones and renamed over
+//! them, so a build killed halfway, or a full disk, leaves either the old file or the new one,
+//! never a mix.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// Replaces `path` with what `write` writes. With `sync`, the contents and then the rename are
+/// flushed to disk before returning, so the new file also survives a power loss.
+pub(crate) fn write_atomic<P, F>(path: P, sync: bool, write: F) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let result = File::create(&tmp_path).and_then(|file| {
+        let mut file = BufWriter::new(file);
+        write(&mut file)?;
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        if sync {
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, path)
+    });
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    if sync {
+        sync_dir(path)?;
+    }
+    Ok(())
+}
+
+/// Flushes the directory entry of `path`, which is where a rename is recorded.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories can't be opened as files here, and renames are durable once they return.
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn replaces_the_file() {
+        let dir = TempDir::new("atomic-replace");
+        let path = dir.join("file");
+        std::fs::write(&path, "old contents that are longer").unwrap();
+        write_atomic(&path, true, |w| w.write_all(b"new")).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn failed_write_keeps_the_old_file() {
+        let dir = TempDir::new("atomic-failed");
+        let path = dir.join("file");
+        std::fs::write(&path, "old").unwrap();
+        let result = write_atomic(&path, false, |w| {
+            w.write_all(b"half of the new")?;
+            Err(io::Error::from(io::ErrorKind::Other))
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 1);
+    }
+}
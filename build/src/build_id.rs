@@ -0,0 +1,79 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Display,
+    hash::{BuildHasher, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Environment variable through which commands can see the id of the build that spawned them.
+pub const BUILD_ID_ENV: &str = "NINJA_BUILD_ID";
+
+/// Identifies a single invocation, so that artifacts, logs and remote execution requests can be
+/// correlated with the build that produced them.
+///
+/// The id is the invocation time in milliseconds followed by a random component, both in hex. It
+/// sorts by time, and two invocations in the same millisecond are still distinct.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BuildId(String);
+
+impl BuildId {
+    pub fn generate() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // RandomState is randomly keyed per instance, which is plenty for avoiding collisions
+        // without pulling in a random number generator.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        hasher.write_u128(now.as_nanos());
+        BuildId(format!("{:x}-{:016x}", now.as_millis(), hasher.finish()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for BuildId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format() {
+        let id = BuildId::generate();
+        let parts: Vec<&str> = id.as_str().split('-').collect();
+        assert_eq!(parts.len(), 2);
+        assert!(u64::from_str_radix(parts[0], 16).is_ok());
+        assert_eq!(parts[1].len(), 16);
+        assert!(u64::from_str_radix(parts[1], 16).is_ok());
+    }
+
+    #[test]
+    fn unique() {
+        let ids: std::collections::HashSet<BuildId> =
+            (0..100).map(|_| BuildId::generate()).collect();
+        assert_eq!(ids.len(), 100);
+    }
+}
@@ -0,0 +1,816 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The build log records, for every output, the hash of the command that last produced it and
+//! when it ran.
+//!
+//! Besides its own format, the log reads the v5 and v6 `.ninja_log` files written by ninja, so an
+//! existing build directory can be adopted without a clean build. Both share a line layout:
+//! `start_ms\tend_ms\tmtime\toutput\tcommand_hash`, where the hash is MurmurHash64A in hex. The
+//! first write after loading one of them rewrites the whole log in the native format, keeping
+//! every entry.
+//!
+//! Lines starting with `#` after the header are comments. Each invocation that appends to the log
+//! first writes `# build <id>`, with the id commands see in `NINJA_BUILD_ID`, so entries can be
+//! traced back to the build that wrote them. Ninja skips the line too, since it has no tabs.
+//!
+//! During a build, edges finish on many tasks at once. They hand entries to a `LogSender`, and a
+//! single `LogWriter` task appends them, so lines are never interleaved. Schedulers, which see
+//! every edge finish on one thread, append through a `SharedBuildLog` instead, which the rebuilder
+//! also reads.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Instant, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::{
+    atomic::write_atomic, build_id::BuildId, diagnostics, task::Key, workspace::Workspace,
+};
+
+const NATIVE_HEADER: &str = "# ninjars log v1";
+/// The version in `NATIVE_HEADER`, recorded in the state file.
+pub const VERSION: u32 = 1;
+const NINJA_HEADER_PREFIX: &[u8] = b"# ninja log v";
+/// Starts the comment before the entries of each invocation.
+const BUILD_COMMENT: &str = "# build ";
+/// Like ninja, only bother compacting logs with at least this many lines...
+const MIN_COMPACTION_LINES: usize = 100;
+/// ... of which at most one in this many is live.
+const COMPACTION_RATIO: usize = 3;
+
+#[derive(Error, Debug)]
+pub enum BuildLogError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("unsupported build log version '{0}'")]
+    UnsupportedVersion(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Ninja5,
+    Ninja6,
+    Native,
+}
+
+impl LogFormat {
+    fn from_header(header: &[u8]) -> Result<Self, BuildLogError> {
+        if header == NATIVE_HEADER.as_bytes() {
+            return Ok(LogFormat::Native);
+        }
+        match header.strip_prefix(NINJA_HEADER_PREFIX) {
+            Some(b"5") => Ok(LogFormat::Ninja5),
+            Some(b"6") => Ok(LogFormat::Ninja6),
+            _ => Err(BuildLogError::UnsupportedVersion(
+                String::from_utf8_lossy(header).into_owned(),
+            )),
+        }
+    }
+}
+
+/// When the writer asks the OS to put the log on disk, trading speed for what survives a power
+/// loss. A crash of ninja itself loses nothing already written under any policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave it to the OS.
+    Never,
+    /// Once, after the last entry.
+    OnClose,
+    /// After every entry.
+    EveryEntry,
+}
+
+#[derive(Error, Debug)]
+#[error("unknown sync policy '{0}', expected 'never', 'close' or 'entry'")]
+pub struct SyncPolicyError(String);
+
+impl std::str::FromStr for SyncPolicy {
+    type Err = SyncPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "close" => Ok(SyncPolicy::OnClose),
+            "entry" => Ok(SyncPolicy::EveryEntry),
+            e => Err(SyncPolicyError(e.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub output: Vec<u8>,
+    /// `hash_command` of the command that produced `output`.
+    pub command_hash: u64,
+    /// Milliseconds since the start of the build.
+    pub start_ms: u32,
+    pub end_ms: u32,
+    /// The mtime of `output` after the command ran, as recorded by whichever ninja wrote it.
+    pub mtime: u64,
+}
+
+fn number<T: std::str::FromStr>(field: Option<&[u8]>) -> Option<T> {
+    std::str::from_utf8(field?).ok()?.parse().ok()
+}
+
+impl LogEntry {
+    fn parse(line: &[u8], format: LogFormat) -> Option<Self> {
+        let mut fields = line.splitn(5, |c| *c == b'\t');
+        let mut next = || fields.next();
+        let start_ms = number(next())?;
+        let end_ms = number(next())?;
+        let mtime = number(next())?;
+        let (output, hash) = match format {
+            LogFormat::Ninja5 | LogFormat::Ninja6 => {
+                let output = next()?;
+                (output, next()?)
+            }
+            LogFormat::Native => {
+                let hash = next()?;
+                (next()?, hash)
+            }
+        };
+        if output.is_empty() {
+            return None;
+        }
+        let command_hash = u64::from_str_radix(std::str::from_utf8(hash).ok()?, 16).ok()?;
+        Some(LogEntry {
+            output: output.to_vec(),
+            command_hash,
+            start_ms,
+            end_ms,
+            mtime,
+        })
+    }
+
+    /// The native layout puts the output last, so it is the only field that may contain tabs.
+    fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        write!(
+            w,
+            "{}\t{}\t{}\t{:x}\t",
+            self.start_ms, self.end_ms, self.mtime, self.command_hash
+        )?;
+        w.write_all(&self.output)?;
+        w.write_all(b"\n")
+    }
+}
+
+/// MurmurHash64A, as used by ninja, so that hashes read from its logs still match our commands.
+pub fn hash_command(command: &[u8]) -> u64 {
+    const SEED: u64 = 0xDECA_FBAD_DECA_FBAD;
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut h = SEED ^ (command.len() as u64).wrapping_mul(M);
+    let mut chunks = command.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes([
+            chunk[0], chunk[1], chunk[2], chunk[3], chunk[4], chunk[5], chunk[6], chunk[7],
+        ]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+#[derive(Debug, Default)]
+pub struct BuildLog {
+    entries: HashMap<Vec<u8>, LogEntry>,
+    /// Entries recorded since the log was loaded, in order.
+    pending: Vec<Vec<u8>>,
+    /// None if there was no log on disk.
+    format: Option<LogFormat>,
+    lines: usize,
+    /// Set when the log does not end with a newline, because a build was killed halfway through
+    /// writing a line. Appending is only safe after truncating to this length.
+    complete_len: Option<u64>,
+}
+
+impl BuildLog {
+    /// Reads the log at `path`. A missing log is an empty one.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BuildLogError> {
+        match std::fs::read(path) {
+            Ok(contents) => Self::parse(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Later entries for an output replace earlier ones. Lines that cannot be parsed, usually
+    /// because a build was killed halfway through writing them, are skipped. So is a log whose
+    /// header was never finished, which is started over.
+    pub fn parse(contents: &[u8]) -> Result<Self, BuildLogError> {
+        let mut log = Self::default();
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            let complete = contents
+                .iter()
+                .rposition(|c| *c == b'\n')
+                .map_or(0, |i| i + 1);
+            log.complete_len = Some(complete as u64);
+        }
+        let mut lines = contents.split(|c| *c == b'\n');
+        let format = match lines.next() {
+            None | Some(b"") => return Ok(log),
+            Some(_) if log.complete_len == Some(0) => return Ok(log),
+            Some(header) => LogFormat::from_header(header)?,
+        };
+        log.format = Some(format);
+        for line in lines.filter(|line| !line.is_empty()) {
+            log.lines += 1;
+            if line.starts_with(b"#") {
+                continue;
+            }
+            if let Some(entry) = LogEntry::parse(line, format) {
+                log.entries.insert(entry.output.clone(), entry);
+            }
+        }
+        Ok(log)
+    }
+
+    pub fn format(&self) -> Option<LogFormat> {
+        self.format
+    }
+
+    pub fn entry(&self, output: &[u8]) -> Option<&LogEntry> {
+        self.entries.get(output)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn record(&mut self, entry: LogEntry) {
+        self.pending.push(entry.output.clone());
+        self.entries.insert(entry.output.clone(), entry);
+    }
+
+    /// Whether the next write replaces the file instead of appending to it. This happens for logs
+    /// written by ninja, which are migrated, and for logs mostly made of superseded entries.
+    pub fn needs_rewrite(&self) -> bool {
+        match self.format {
+            None => true,
+            Some(LogFormat::Native) => {
+                self.lines > MIN_COMPACTION_LINES
+                    && self.lines > self.entries.len() * COMPACTION_RATIO
+            }
+            Some(LogFormat::Ninja5) | Some(LogFormat::Ninja6) => true,
+        }
+    }
+
+    /// Writes out the entries recorded since the last write.
+    pub fn write<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if self.needs_rewrite() {
+            self.rewrite(path)?;
+        } else if !self.pending.is_empty() {
+            let mut file = io::BufWriter::new(self.open_for_append(path)?);
+            for output in &self.pending {
+                self.entries[output].write_to(&mut file)?;
+            }
+            self.lines += self.pending.len();
+            file.flush()?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Opens the log for appending, first dropping any partial last line so the next entry
+    /// starts on a line of its own.
+    fn open_for_append(&mut self, path: &Path) -> io::Result<File> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        if let Some(len) = self.complete_len.take() {
+            file.set_len(len)?;
+        }
+        Ok(file)
+    }
+
+    /// Migrates or compacts the log if needed, then opens it for appending. Doing this before
+    /// the first entry means a build killed halfway leaves a log that can be appended to.
+    fn start_appending(&mut self, path: &Path) -> io::Result<File> {
+        if self.needs_rewrite() {
+            self.rewrite(path)?;
+        }
+        self.open_for_append(path)
+    }
+
+    /// Marks where the entries of the build `id` start.
+    fn append_build_id(&mut self, file: &mut File, id: &BuildId) -> io::Result<()> {
+        file.write_all(format!("{}{}\n", BUILD_COMMENT, id).as_bytes())?;
+        self.lines += 1;
+        Ok(())
+    }
+
+    /// One write per line, so a crash can only ever tear the last one.
+    fn append(&mut self, file: &mut File, entry: LogEntry) -> io::Result<()> {
+        let mut line = Vec::new();
+        entry.write_to(&mut line)?;
+        file.write_all(&line)?;
+        self.lines += 1;
+        self.entries.insert(entry.output.clone(), entry);
+        Ok(())
+    }
+
+    /// Moves the log into a writer, to be run as a task for the duration of a build, that appends
+    /// every entry sent to the returned sender. The writer finishes, returning the log, once every
+    /// sender is dropped.
+    pub fn into_writer<P: Into<PathBuf>>(
+        self,
+        path: P,
+        sync: SyncPolicy,
+    ) -> (LogSender, LogWriter) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            LogSender(sender),
+            LogWriter {
+                log: self,
+                path: path.into(),
+                sync,
+                receiver,
+            },
+        )
+    }
+
+    /// Replaces the log with one line per output, atomically so an interrupted rewrite loses
+    /// nothing. It is synced regardless of the policy, since it replaces every entry at once.
+    fn rewrite(&mut self, path: &Path) -> io::Result<()> {
+        let mut entries: Vec<&LogEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| a.output.cmp(&b.output));
+        write_atomic(path, true, |file| {
+            writeln!(file, "{}", NATIVE_HEADER)?;
+            for entry in &entries {
+                entry.write_to(file)?;
+            }
+            Ok(())
+        })?;
+        self.format = Some(LogFormat::Native);
+        self.lines = entries.len();
+        self.complete_len = None;
+        Ok(())
+    }
+}
+
+/// Hands entries to a `LogWriter`. Cheap to clone, and never blocks.
+#[derive(Debug, Clone)]
+pub struct LogSender(mpsc::UnboundedSender<LogEntry>);
+
+impl LogSender {
+    /// Queues `entry` for writing. Entries sent after the writer failed are dropped, since the
+    /// writer already reported why.
+    pub fn record(&self, entry: LogEntry) {
+        let _ = self.0.send(entry);
+    }
+}
+
+#[derive(Debug)]
+pub struct LogWriter {
+    log: BuildLog,
+    path: PathBuf,
+    sync: SyncPolicy,
+    receiver: mpsc::UnboundedReceiver<LogEntry>,
+}
+
+impl LogWriter {
+    pub async fn run(mut self) -> io::Result<BuildLog> {
+        let mut file = self.log.start_appending(&self.path)?;
+        while let Some(entry) = self.receiver.recv().await {
+            self.log.append(&mut file, entry)?;
+            if self.sync == SyncPolicy::EveryEntry {
+                file.sync_data()?;
+            }
+        }
+        if self.sync != SyncPolicy::Never {
+            file.sync_all()?;
+        }
+        Ok(self.log)
+    }
+}
+
+/// The mtime of `output` in nanoseconds since the Unix epoch, as both logs record it. 0 if it
+/// does not exist.
+pub(crate) fn output_mtime(output: &Path) -> u64 {
+    std::fs::metadata(output)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+}
+
+struct OpenLog {
+    log: BuildLog,
+    path: PathBuf,
+    /// Opened for the first entry. None again once writing failed, after which entries are only
+    /// kept in memory.
+    file: Option<File>,
+    failed: bool,
+    /// What `start_ms` and `end_ms` count from.
+    opened: Instant,
+    /// Written before the first entry, if set.
+    build_id: Option<BuildId>,
+}
+
+/// The log for the length of an invocation. The rebuilder looks up the commands of earlier builds
+/// in it, and schedulers append an entry as each command succeeds, so a build that is killed
+/// halfway keeps what it finished. Cloning shares the log.
+#[derive(Clone)]
+pub struct SharedBuildLog(Rc<RefCell<OpenLog>>);
+
+impl fmt::Debug for SharedBuildLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedBuildLog")
+            .field(&self.0.borrow().path)
+            .finish()
+    }
+}
+
+impl SharedBuildLog {
+    /// Reads the log at `path`, which is where entries will be appended. A missing log is an
+    /// empty one.
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, BuildLogError> {
+        let path = path.into();
+        let log = BuildLog::load(&path)?;
+        Ok(SharedBuildLog(Rc::new(RefCell::new(OpenLog {
+            log,
+            path,
+            file: None,
+            failed: false,
+            opened: Instant::now(),
+            build_id: None,
+        }))))
+    }
+
+    /// Marks the entries this invocation appends as coming from the build `id`.
+    pub fn for_build(self, id: BuildId) -> Self {
+        self.0.borrow_mut().build_id = Some(id);
+        self
+    }
+
+    /// The hash of the command that last produced `output`.
+    pub fn command_hash(&self, output: &[u8]) -> Option<u64> {
+        self.0
+            .borrow()
+            .log
+            .entry(output)
+            .map(|entry| entry.command_hash)
+    }
+
+    /// Records that `command` produced every output of `key`, having started at `started`. Like
+    /// the build itself, logging is best effort. The first write error is reported and later
+    /// entries are only remembered for the rest of this invocation.
+    pub fn record_command(
+        &self,
+        workspace: &Workspace,
+        key: &Key,
+        command: &str,
+        started: Instant,
+    ) {
+        let mut open = self.0.borrow_mut();
+        let open = &mut *open;
+        let since_opened =
+            |instant: Instant| instant.saturating_duration_since(open.opened).as_millis() as u32;
+        let start_ms = since_opened(started);
+        let end_ms = since_opened(Instant::now());
+        let command_hash = hash_command(command.as_bytes());
+        for output in key.iter() {
+            let mtime = output_mtime(&workspace.path(output.as_bytes()));
+            let entry = LogEntry {
+                output: output.as_bytes().to_vec(),
+                command_hash,
+                start_ms,
+                end_ms,
+                mtime,
+            };
+            if open.failed {
+                open.log.entries.insert(entry.output.clone(), entry);
+                continue;
+            }
+            let result = match open.file.as_mut() {
+                Some(file) => open.log.append(file, entry),
+                None => open.log.start_appending(&open.path).and_then(|mut file| {
+                    if let Some(id) = &open.build_id {
+                        open.log.append_build_id(&mut file, id)?;
+                    }
+                    let result = open.log.append(&mut file, entry);
+                    open.file = Some(file);
+                    result
+                }),
+            };
+            if let Err(e) = result {
+                diagnostics::warn(
+                    "build log",
+                    format_args!("writing {}: {}", open.path.display(), e),
+                );
+                open.failed = true;
+                open.file = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn entry(output: &str, command: &str) -> LogEntry {
+        LogEntry {
+            output: output.as_bytes().to_vec(),
+            command_hash: hash_command(command.as_bytes()),
+            start_ms: 1,
+            end_ms: 2,
+            mtime: 3,
+        }
+    }
+
+    /// The log's path, in a directory that is removed once the returned `TempDir` is dropped.
+    fn temp_log(name: &str) -> (TempDir, std::path::PathBuf) {
+        let dir = TempDir::new(&format!("log-{}", name));
+        let path = dir.join(".ninja_log");
+        (dir, path)
+    }
+
+    #[test]
+    fn reads_ninja_formats() {
+        for header in &["# ninja log v5", "# ninja log v6"] {
+            let log = BuildLog::parse(
+                format!(
+                    "{}\n10\t20\t123\tfoo.o\t1f2e\n30\t40\t456\tfoo.o\tabc\n5\t6\t7\tbar.o\t9\n",
+                    header
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            assert_eq!(log.len(), 2);
+            assert!(log.needs_rewrite());
+            assert_eq!(
+                log.entry(b"foo.o"),
+                Some(&LogEntry {
+                    output: b"foo.o".to_vec(),
+                    command_hash: 0xabc,
+                    start_ms: 30,
+                    end_ms: 40,
+                    mtime: 456,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn header_matches_version() {
+        assert_eq!(NATIVE_HEADER, format!("# ninjars log v{}", VERSION));
+    }
+
+    #[test]
+    fn rejects_old_versions() {
+        assert!(matches!(
+            BuildLog::parse(b"# ninja log v4\n"),
+            Err(BuildLogError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn skips_truncated_lines() {
+        let log = BuildLog::parse(b"# ninja log v5\n1\t2\t3\ta\tff\n4\t5\t6\tb").unwrap();
+        assert_eq!(log.len(), 1);
+        assert!(log.entry(b"b").is_none());
+    }
+
+    #[test]
+    fn starts_over_after_torn_header() {
+        let (_dir, path) = temp_log("torn-header");
+        std::fs::write(&path, &NATIVE_HEADER[..8]).unwrap();
+        let mut log = BuildLog::load(&path).unwrap();
+        assert_eq!(log.format(), None);
+        log.record(entry("a.o", "cc"));
+        log.write(&path).unwrap();
+
+        let contents = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(contents.lines().next(), Some(NATIVE_HEADER));
+        assert_eq!(
+            BuildLog::load(&path).unwrap().entry(b"a.o"),
+            Some(&entry("a.o", "cc"))
+        );
+    }
+
+    #[test]
+    fn skips_corrupt_lines() {
+        let mut contents = format!("{}\n1\t2\t3\tff\ta.o\n", NATIVE_HEADER).into_bytes();
+        contents.extend_from_slice(b"\0\0\x7fgarbage\n4\tx\t6\tff\tb.o\n7\t8\t9\tzz\tc.o\n");
+        let log = BuildLog::parse(&contents).unwrap();
+        assert_eq!(log.len(), 1);
+        assert!(log.entry(b"a.o").is_some());
+    }
+
+    #[test]
+    fn hash_matches_ninja() {
+        // Same values as ninja's MurmurHash64A.
+        assert_eq!(hash_command(b"cc -c foo.c"), 0x72ac_2a38_13d2_8da1);
+        assert_eq!(hash_command(b""), 0x87c2_bc0b_eaf1_d91d);
+        // Exercise every tail length.
+        let hashes: std::collections::HashSet<u64> = (0..17)
+            .map(|n| hash_command(&b"0123456789abcdefg"[..n]))
+            .collect();
+        assert_eq!(hashes.len(), 17);
+    }
+
+    #[test]
+    fn migrates_on_first_write() {
+        let (_dir, path) = temp_log("migrate");
+        std::fs::write(&path, "# ninja log v5\n10\t20\t123\told.o\tff\n").unwrap();
+        let mut log = BuildLog::load(&path).unwrap();
+        log.record(entry("new.o", "cc new.c"));
+        log.write(&path).unwrap();
+
+        let contents = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        assert!(contents.starts_with("# ninjars log v1\n"));
+        let reloaded = BuildLog::load(&path).unwrap();
+        assert_eq!(reloaded.format(), Some(LogFormat::Native));
+        assert_eq!(reloaded.entry(b"old.o"), log.entry(b"old.o"));
+        assert_eq!(reloaded.entry(b"new.o"), Some(&entry("new.o", "cc new.c")));
+        assert!(!reloaded.needs_rewrite());
+
+        // Native logs are appended to.
+        let mut log = reloaded;
+        log.record(entry("new.o", "cc -O2 new.c"));
+        log.write(&path).unwrap();
+        let contents = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(contents.lines().count(), 4);
+        assert_eq!(
+            BuildLog::load(&path).unwrap().entry(b"new.o"),
+            Some(&entry("new.o", "cc -O2 new.c"))
+        );
+    }
+
+    #[test]
+    fn compacts_superseded_entries() {
+        let mut contents = format!("{}\n", NATIVE_HEADER);
+        for i in 0..(MIN_COMPACTION_LINES + 1) {
+            contents.push_str(&format!("{}\t{}\t0\tff\tsame.o\n", i, i + 1));
+        }
+        let log = BuildLog::parse(contents.as_bytes()).unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log.entry(b"same.o").unwrap().start_ms, 100);
+        assert!(log.needs_rewrite());
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn writer_appends_from_many_tasks() {
+        let (_dir, path) = temp_log("writer");
+        std::fs::write(&path, "# ninja log v5\n10\t20\t123\told.o\tff\n").unwrap();
+        let log = BuildLog::load(&path).unwrap();
+        let (sender, writer) = log.into_writer(&path, SyncPolicy::EveryEntry);
+        let log = runtime()
+            .block_on(async {
+                let senders: Vec<_> = (0..10)
+                    .map(|i| {
+                        let sender = sender.clone();
+                        async move {
+                            sender.record(entry(&format!("{}.o", i), "cc"));
+                        }
+                    })
+                    .collect();
+                drop(sender);
+                let (log, _) = futures::join!(writer.run(), futures::future::join_all(senders));
+                log
+            })
+            .unwrap();
+        assert_eq!(log.len(), 11);
+
+        let reloaded = BuildLog::load(&path).unwrap();
+        assert_eq!(reloaded.format(), Some(LogFormat::Native));
+        assert_eq!(reloaded.len(), 11);
+        for i in 0..10 {
+            let output = format!("{}.o", i);
+            assert_eq!(
+                reloaded.entry(output.as_bytes()),
+                Some(&entry(&output, "cc"))
+            );
+        }
+    }
+
+    #[test]
+    fn recovers_from_torn_last_line() {
+        let (_dir, path) = temp_log("torn");
+        std::fs::write(
+            &path,
+            format!("{}\n1\t2\t3\tff\ta.o\n4\t5\t6\tf", NATIVE_HEADER),
+        )
+        .unwrap();
+        let log = BuildLog::load(&path).unwrap();
+        assert_eq!(log.len(), 1);
+        let (sender, writer) = log.into_writer(&path, SyncPolicy::Never);
+        sender.record(entry("b.o", "cc"));
+        drop(sender);
+        runtime().block_on(writer.run()).unwrap();
+
+        let contents = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(contents.lines().count(), 3);
+        let reloaded = BuildLog::load(&path).unwrap();
+        assert_eq!(reloaded.entry(b"b.o"), Some(&entry("b.o", "cc")));
+        assert_eq!(reloaded.len(), 2);
+    }
+
+    #[test]
+    fn shared_log_appends_commands() {
+        let (_dir, path) = temp_log("shared");
+        std::fs::write(&path, "# ninja log v5\n10\t20\t123\told.o\tff\n").unwrap();
+        let output = path.with_file_name("out.o");
+        std::fs::write(&output, "").unwrap();
+        let log = SharedBuildLog::load(&path).unwrap();
+        assert_eq!(log.command_hash(b"old.o"), Some(0xff));
+        let key = Key::Path(output.to_str().unwrap().as_bytes().to_vec().into());
+        log.clone()
+            .record_command(&Workspace::default(), &key, "cc out.c", Instant::now());
+        assert_eq!(
+            log.command_hash(output.to_str().unwrap().as_bytes()),
+            Some(hash_command(b"cc out.c"))
+        );
+
+        let reloaded = BuildLog::load(&path).unwrap();
+        assert_eq!(reloaded.format(), Some(LogFormat::Native));
+        assert_eq!(reloaded.len(), 2);
+        let entry = reloaded.entry(output.to_str().unwrap().as_bytes()).unwrap();
+        assert_eq!(entry.command_hash, hash_command(b"cc out.c"));
+        assert_ne!(entry.mtime, 0);
+    }
+
+    #[test]
+    fn shared_log_marks_each_build() {
+        let (_dir, path) = temp_log("build-id");
+        let output = path.with_file_name("out.o");
+        let key = Key::Path(output.to_str().unwrap().as_bytes().to_vec().into());
+        let ids = [BuildId::generate(), BuildId::generate()];
+        for id in &ids {
+            SharedBuildLog::load(&path)
+                .unwrap()
+                .for_build(id.clone())
+                .record_command(&Workspace::default(), &key, "cc out.c", Instant::now());
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let comments: Vec<&str> = contents
+            .lines()
+            .filter(|l| l.starts_with("# build "))
+            .collect();
+        assert_eq!(
+            comments,
+            vec![format!("# build {}", ids[0]), format!("# build {}", ids[1])]
+        );
+        let reloaded = BuildLog::load(&path).unwrap();
+        assert_eq!(reloaded.len(), 1);
+        assert!(!reloaded.needs_rewrite());
+    }
+
+    #[test]
+    fn missing_log_is_empty() {
+        let log = BuildLog::load("/nonexistent/.ninja_log").unwrap();
+        assert!(log.is_empty());
+        assert_eq!(log.format(), None);
+    }
+}
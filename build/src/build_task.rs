@@ -1,17 +1,188 @@
-use std::{os::unix::ffi::OsStrExt, process::Output};
+use std::{
+    fmt, io,
+    os::unix::{fs::PermissionsExt, process::ExitStatusExt},
+    path::Path,
+    process::{ExitStatus, Output, Stdio},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use thiserror::Error;
 use tokio::process::Command;
 
-use crate::{interface::BuildTask, task::Key};
+use ninja_parse::repr::Deps;
+
+use crate::{
+    depfile,
+    interface::BuildTask,
+    process_group::ProcessGroup,
+    task::{Key, ResponseFile, Task},
+    workspace::Workspace,
+};
+
+/// Why a command could not be run, with a guess at the cause when the error alone is cryptic,
+/// like `No such file or directory` for a missing shell.
+#[derive(Debug)]
+pub struct SpawnError {
+    pub error: io::Error,
+    hint: Option<String>,
+}
+
+impl SpawnError {
+    /// Explains `error` from spawning `shell` to run `script`.
+    pub(crate) fn diagnose(error: io::Error, shell: &Path, script: &str) -> Self {
+        let hint = if error.raw_os_error() == Some(libc::E2BIG) {
+            Some(format!(
+                "the command is {} bytes, more than the system allows; an rspfile would shorten it",
+                script.len()
+            ))
+        } else if error.raw_os_error() == Some(libc::EAGAIN) {
+            Some("the system is out of processes; try fewer jobs with -j".to_owned())
+        } else if error.kind() == io::ErrorKind::NotFound {
+            if shell.components().count() == 1 && !shell.has_root() {
+                let path = std::env::var_os("PATH").unwrap_or_default();
+                if std::env::split_paths(&path).any(|dir| dir.join(shell).is_file()) {
+                    None
+                } else {
+                    Some(format!(
+                        "the shell '{}' is not in PATH={}",
+                        shell.display(),
+                        Path::new(&path).display()
+                    ))
+                }
+            } else if !shell.exists() {
+                Some(format!("the shell '{}' does not exist", shell.display()))
+            } else {
+                Some("the shell exists, so the command's directory may not".to_owned())
+            }
+        } else if error.kind() == io::ErrorKind::PermissionDenied {
+            match std::fs::metadata(shell) {
+                Ok(metadata) if metadata.permissions().mode() & 0o111 == 0 => {
+                    Some(format!("the shell '{}' is not executable", shell.display()))
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+        SpawnError { error, hint }
+    }
+
+    pub fn hint(&self) -> Option<&str> {
+        self.hint.as_deref()
+    }
+}
+
+impl From<io::Error> for SpawnError {
+    fn from(error: io::Error) -> Self {
+        SpawnError { error, hint: None }
+    }
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        if let Some(hint) = &self.hint {
+            write!(f, " ({})", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SpawnError {}
 
 #[derive(Error, Debug)]
 pub enum CommandTaskError {
     #[error("{0}")]
-    SpawnFailed(#[from] std::io::Error),
+    SpawnFailed(#[from] SpawnError),
     #[error("failed with {}", .0.status)]
     CommandFailed(Output),
+    #[error("interrupted by signal {signal}")]
+    Signaled { output: Output, signal: i32 },
+    #[error("timed out after {}s", .0.as_secs_f64())]
+    TimedOut(Duration),
+    #[error("succeeded, but did not create {}", .missing.join(", "))]
+    OutputMissing {
+        output: Output,
+        missing: Vec<String>,
+    },
+    #[error("succeeded, but reading the inputs it discovered failed: {error}")]
+    BadDeps { output: Output, error: String },
+}
+
+/// What kind of failure a command had, so CI can triage failures without parsing messages.
+///
+/// Roughly, `SpawnFailed`, `Signaled` and `TimedOut` point at the machine or a crashing tool,
+/// while `NonZeroExit` is the build itself failing and `OutputMissing` a rule that doesn't
+/// produce what the manifest says it does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FailureKind {
+    SpawnFailed,
+    NonZeroExit,
+    Signaled,
+    TimedOut,
+    OutputMissing,
+    BadDeps,
+}
+
+impl FailureKind {
+    /// Stable name for machine-readable output.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::SpawnFailed => "spawn-failed",
+            FailureKind::NonZeroExit => "exit",
+            FailureKind::Signaled => "signal",
+            FailureKind::TimedOut => "timeout",
+            FailureKind::OutputMissing => "output-missing",
+            FailureKind::BadDeps => "bad-deps",
+        }
+    }
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl From<io::Error> for CommandTaskError {
+    fn from(error: io::Error) -> Self {
+        CommandTaskError::SpawnFailed(error.into())
+    }
+}
+
+impl CommandTaskError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            CommandTaskError::SpawnFailed(_) => FailureKind::SpawnFailed,
+            CommandTaskError::CommandFailed(_) => FailureKind::NonZeroExit,
+            CommandTaskError::Signaled { .. } => FailureKind::Signaled,
+            CommandTaskError::TimedOut(_) => FailureKind::TimedOut,
+            CommandTaskError::OutputMissing { .. } => FailureKind::OutputMissing,
+            CommandTaskError::BadDeps { .. } => FailureKind::BadDeps,
+        }
+    }
+
+    /// What the command printed, if it got as far as running.
+    pub fn output(&self) -> Option<&Output> {
+        match self {
+            CommandTaskError::CommandFailed(output)
+            | CommandTaskError::Signaled { output, .. }
+            | CommandTaskError::OutputMissing { output, .. }
+            | CommandTaskError::BadDeps { output, .. } => Some(output),
+            CommandTaskError::SpawnFailed(_) | CommandTaskError::TimedOut(_) => None,
+        }
+    }
+
+    /// Whether the command was stopped by a signal that usually means the user wants the whole
+    /// build to stop, rather than by a crash.
+    pub fn is_interrupt(&self) -> bool {
+        matches!(
+            self,
+            CommandTaskError::Signaled { signal, .. }
+                if [libc::SIGINT, libc::SIGTERM, libc::SIGHUP].contains(signal)
+        )
+    }
 }
 
 pub type CommandTaskResult = Result<Output, CommandTaskError>;
@@ -21,35 +192,350 @@ pub trait NinjaTask: BuildTask<CommandTaskResult> + std::fmt::Debug {}
 pub struct CommandTask {
     key: Key,
     command: String,
+    console: bool,
+    rspfile: Option<ResponseFile>,
+    workspace: Workspace,
 }
 
 impl CommandTask {
     pub fn new(key: Key, command: String) -> CommandTask {
-        CommandTask { key, command }
+        CommandTask {
+            key,
+            command,
+            console: false,
+            rspfile: None,
+            workspace: Workspace::default(),
+        }
+    }
+
+    /// Run the command in `workspace` instead of the current directory.
+    pub fn workspace(mut self, workspace: Workspace) -> CommandTask {
+        self.workspace = workspace;
+        self
+    }
+
+    /// Let the command write to the terminal directly instead of capturing its output, for
+    /// commands in the `console` pool.
+    pub fn console(mut self, console: bool) -> CommandTask {
+        self.console = console;
+        self
+    }
+
+    /// Write `rspfile` before running the command.
+    pub fn rspfile(mut self, rspfile: Option<ResponseFile>) -> CommandTask {
+        self.rspfile = rspfile;
+        self
     }
 
     pub async fn run_command(&self) -> CommandTaskResult {
-        // Create directories for all outputs.
-        // TODO: Somehow hide this behind a disk interface or something so we can mock it.
-        for output in self.key.iter() {
-            if let Some(dir) =
-                std::path::Path::new(std::ffi::OsStr::from_bytes(output.as_bytes())).parent()
-            {
-                if !dir.exists() {
-                    std::fs::create_dir_all(dir)?;
+        create_output_dirs(&self.workspace, &self.key)?;
+        write_rspfile(&self.workspace, self.rspfile.as_ref())?;
+        let output = run_script(
+            &self.workspace,
+            &self.command,
+            self.key.namespace(),
+            self.console || self.workspace.streams(),
+        )
+        .await?;
+        let result = check_outputs_exist(&self.workspace, &self.key, output);
+        if result.is_ok() {
+            remove_rspfile(&self.workspace, self.rspfile.as_ref());
+        }
+        result
+    }
+}
+
+/// A command that exits successfully without creating its outputs would otherwise be run again
+/// on every build.
+pub(crate) fn check_outputs_exist(
+    workspace: &Workspace,
+    key: &Key,
+    output: Output,
+) -> CommandTaskResult {
+    let missing: Vec<String> = key
+        .iter()
+        .filter(|path| !workspace.path(path.as_bytes()).exists())
+        .map(|path| path.display().to_string())
+        .collect();
+    if missing.is_empty() {
+        Ok(output)
+    } else {
+        Err(CommandTaskError::OutputMissing { output, missing })
+    }
+}
+
+/// Takes the inputs a command discovered out of its depfile or output, to be recorded in the deps
+/// log. Like ninja, the depfile is removed and `/showIncludes` lines are dropped from the output.
+/// A missing depfile means nothing was discovered.
+pub(crate) fn extract_deps(
+    workspace: &Workspace,
+    task: &Task,
+    mut output: Output,
+) -> Result<(Output, Vec<Vec<u8>>), CommandTaskError> {
+    match (&task.deps, &task.depfile) {
+        (Some(Deps::Gcc), Some(depfile)) => {
+            let path = workspace.path(depfile.as_bytes());
+            let contents = match std::fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((output, vec![])),
+                Err(e) => {
+                    let error = format!("reading '{}': {}", depfile.display(), e);
+                    return Err(CommandTaskError::BadDeps { output, error });
+                }
+            };
+            match depfile::parse(&contents) {
+                Ok(parsed) => {
+                    // Once recorded, the depfile is only clutter.
+                    let _ = std::fs::remove_file(&path);
+                    Ok((output, parsed.inputs))
+                }
+                Err(e) => {
+                    let error = format!("'{}': {}", depfile.display(), e);
+                    Err(CommandTaskError::BadDeps { output, error })
                 }
             }
         }
+        (Some(Deps::Msvc { prefix }), _) => {
+            let mut inputs = Vec::new();
+            let mut kept = Vec::with_capacity(output.stdout.len());
+            let mut rest = output.stdout.as_slice();
+            while !rest.is_empty() {
+                let end = rest
+                    .iter()
+                    .position(|c| *c == b'\n')
+                    .map_or(rest.len(), |i| i + 1);
+                let (line, next) = rest.split_at(end);
+                rest = next;
+                if line.starts_with(prefix) {
+                    let include = &line[prefix.len()..];
+                    let start = include
+                        .iter()
+                        .position(|c| !c.is_ascii_whitespace())
+                        .unwrap_or(include.len());
+                    let end = include
+                        .iter()
+                        .rposition(|c| !c.is_ascii_whitespace())
+                        .map_or(start, |i| i + 1);
+                    if start < end {
+                        inputs.push(include[start..end].to_vec());
+                    }
+                } else {
+                    kept.extend_from_slice(line);
+                }
+            }
+            output.stdout = kept;
+            Ok((output, inputs))
+        }
+        _ => Ok((output, vec![])),
+    }
+}
 
-        let output = Command::new("/bin/sh")
-            .arg("-c")
-            .arg(&self.command)
-            .output()
-            .await?;
-        if !output.status.success() {
-            return Err(CommandTaskError::CommandFailed(output));
+pub(crate) fn create_output_dirs(workspace: &Workspace, key: &Key) -> std::io::Result<()> {
+    // Create directories for all outputs.
+    // TODO: Somehow hide this behind a disk interface or something so we can mock it.
+    for output in key.iter() {
+        if let Some(dir) = workspace.path(output.as_bytes()).parent() {
+            if !dir.exists() {
+                std::fs::create_dir_all(dir)?;
+            }
         }
-        Ok(output)
+    }
+    Ok(())
+}
+
+/// Writes the response file of a command about to run, creating its directory if needed.
+pub(crate) fn write_rspfile(
+    workspace: &Workspace,
+    rspfile: Option<&ResponseFile>,
+) -> std::io::Result<()> {
+    if let Some(rspfile) = rspfile {
+        let path = workspace.path(rspfile.path.as_bytes());
+        if let Some(dir) = path.parent() {
+            if !dir.exists() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        std::fs::write(&path, &rspfile.content)?;
+    }
+    Ok(())
+}
+
+/// Removes the response file of a command that succeeded. Like ninja, it is left behind after a
+/// failure, for running the command again by hand.
+pub(crate) fn remove_rspfile(workspace: &Workspace, rspfile: Option<&ResponseFile>) {
+    if let Some(rspfile) = rspfile {
+        if !workspace.keeps_rspfiles() {
+            let _ = std::fs::remove_file(workspace.path(rspfile.path.as_bytes()));
+        }
+    }
+}
+
+/// Runs `script` with the shell, capturing its output unless `console` is set. Even then, the
+/// command gets no stdin, since it runs in its own process group.
+///
+/// Like ninja, stderr goes to the same pipe as stdout, so `stdout` has both in the order they
+/// were written and `stderr` is empty.
+pub(crate) async fn run_script(
+    workspace: &Workspace,
+    script: &str,
+    namespace: Option<&[u8]>,
+    console: bool,
+) -> CommandTaskResult {
+    let shell = workspace.shell_path();
+    let mut command = Command::new(shell);
+    command
+        .arg("-c")
+        .arg(script)
+        // Reading the terminal from a background process group would stop the command.
+        .stdin(Stdio::null());
+    if console {
+        command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+    } else {
+        command.stdout(Stdio::piped()).stderr(Stdio::null());
+        unsafe {
+            command.pre_exec(|| {
+                if libc::dup2(libc::STDOUT_FILENO, libc::STDERR_FILENO) == -1 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+        }
+    }
+    // Paths in a namespaced command are relative to the namespace directory. Creating the
+    // output directories made sure it exists.
+    workspace.configure(&mut command, namespace);
+    // If this future is dropped before the command finishes, the group is killed.
+    let (child, group) = ProcessGroup::spawn(&mut command, workspace.scheduling())
+        .map_err(|e| SpawnError::diagnose(e, shell, script))?;
+    let output = child.wait_with_output().await?;
+    group.finished();
+    if let Some(signal) = output.status.signal() {
+        return Err(CommandTaskError::Signaled { output, signal });
+    }
+    if !output.status.success() {
+        return Err(CommandTaskError::CommandFailed(output));
+    }
+    Ok(output)
+}
+
+/// Printed after each command in a batch succeeds, so output can be attributed to the edge that
+/// produced it.
+const BATCH_MARKER: &str = "__ninja_batch_edge_finished__";
+
+/// Splits `output` at each marker line. The last part belongs to the command that was running
+/// when the batch ended.
+fn split_at_markers(output: &[u8]) -> Vec<Vec<u8>> {
+    let marker = format!("{}\n", BATCH_MARKER);
+    let marker = marker.as_bytes();
+    let mut parts = Vec::new();
+    let mut rest = output;
+    while let Some(at) = rest.windows(marker.len()).position(|w| w == marker) {
+        parts.push(rest[..at].to_vec());
+        rest = &rest[at + marker.len()..];
+    }
+    parts.push(rest.to_vec());
+    parts
+}
+
+/// Commands of several edges of a rule with `batch` set, run in a single shell invocation
+/// joined with `&&`, to save spawning a process per edge when the commands are trivial.
+///
+/// Each edge still gets its own result. Edges are all in the same namespace and workspace, so they
+/// share a working directory.
+#[derive(Debug)]
+pub(crate) struct CommandBatch<'a> {
+    edges: Vec<&'a CommandTask>,
+}
+
+impl<'a> CommandBatch<'a> {
+    pub(crate) fn new(edges: Vec<&'a CommandTask>) -> Self {
+        CommandBatch { edges }
+    }
+
+    fn script(edges: &[&CommandTask]) -> String {
+        edges
+            .iter()
+            .map(|edge| {
+                // A subshell each, so one command changing directory or options doesn't affect
+                // the next, just like separate invocations.
+                format!(
+                    "(\n{}\n) && printf '%s\\n' {marker}",
+                    edge.command,
+                    marker = BATCH_MARKER
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" && ")
+    }
+
+    /// Results are in the order of the edges. An edge whose command never ran, because an earlier
+    /// one failed and `keep_going` is false, gets None.
+    pub(crate) async fn run(&self, keep_going: bool) -> Vec<Option<CommandTaskResult>> {
+        let mut results: Vec<Option<CommandTaskResult>> = Vec::with_capacity(self.edges.len());
+        while results.len() < self.edges.len() {
+            let remaining = &self.edges[results.len()..];
+            if !results.is_empty() && !keep_going {
+                results.extend(remaining.iter().map(|_| None));
+                break;
+            }
+            if let Err(e) = remaining.iter().try_for_each(|edge| {
+                create_output_dirs(&edge.workspace, &edge.key)?;
+                write_rspfile(&edge.workspace, edge.rspfile.as_ref())
+            }) {
+                results.push(Some(Err(e.into())));
+                continue;
+            }
+            let workspace = &remaining[0].workspace;
+            let namespace = remaining[0].key.namespace();
+            let (status, stdout, failure) =
+                match run_script(workspace, &Self::script(remaining), namespace, false).await {
+                    Ok(output) => (output.status, output.stdout, None),
+                    Err(CommandTaskError::CommandFailed(output)) => {
+                        (output.status, output.stdout, Some(None))
+                    }
+                    Err(CommandTaskError::Signaled { output, signal }) => {
+                        (output.status, output.stdout, Some(Some(signal)))
+                    }
+                    // Only spawning can fail otherwise.
+                    Err(e) => {
+                        results.push(Some(Err(e)));
+                        continue;
+                    }
+                };
+            let stdout = split_at_markers(&stdout);
+            let part = |parts: &[Vec<u8>], i: usize| parts.get(i).cloned().unwrap_or_default();
+            // Commands before the last marker succeeded. A command printing the marker itself
+            // can't claim the one that actually failed.
+            let succeeded = match failure {
+                None => remaining.len(),
+                Some(_) => std::cmp::min(stdout.len() - 1, remaining.len() - 1),
+            };
+            for (i, edge) in remaining.iter().enumerate().take(succeeded) {
+                let output = Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: part(&stdout, i),
+                    stderr: vec![],
+                };
+                let result = check_outputs_exist(&edge.workspace, &edge.key, output);
+                if result.is_ok() {
+                    remove_rspfile(&edge.workspace, edge.rspfile.as_ref());
+                }
+                results.push(Some(result));
+            }
+            if let Some(signal) = failure {
+                let output = Output {
+                    status,
+                    stdout: part(&stdout, succeeded),
+                    stderr: vec![],
+                };
+                results.push(Some(Err(match signal {
+                    Some(signal) => CommandTaskError::Signaled { output, signal },
+                    None => CommandTaskError::CommandFailed(output),
+                })));
+            }
+        }
+        results
     }
 }
 
@@ -58,6 +544,316 @@ impl BuildTask<CommandTaskResult> for CommandTask {
     async fn run(&self) -> CommandTaskResult {
         self.run_command().await
     }
+
+    fn as_command(&self) -> Option<&CommandTask> {
+        Some(self)
+    }
 }
 
 impl NinjaTask for CommandTask {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// Each command creates its output, once it has done what it was given.
+    fn run_batch(
+        dir: &TempDir,
+        commands: &[&str],
+        keep_going: bool,
+    ) -> Vec<Option<CommandTaskResult>> {
+        let commands: Vec<CommandTask> = commands
+            .iter()
+            .enumerate()
+            .map(|(i, command)| {
+                let out = dir.path(&format!("out{}", i));
+                CommandTask::new(
+                    Key::Path(out.clone().into_bytes().into()),
+                    format!("{} && touch {}", command, out),
+                )
+            })
+            .collect();
+        runtime().block_on(CommandBatch::new(commands.iter().collect()).run(keep_going))
+    }
+
+    fn stdout(result: &Option<CommandTaskResult>) -> &[u8] {
+        match result {
+            Some(Ok(output)) => &output.stdout,
+            Some(Err(CommandTaskError::CommandFailed(output))) => &output.stdout,
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_outputs() {
+        let dir = TempDir::new("missing");
+        let task = CommandTask::new(
+            Key::Path(dir.path("never").into_bytes().into()),
+            "true".to_owned(),
+        );
+        let err = runtime().block_on(task.run_command()).unwrap_err();
+        assert_eq!(err.kind(), FailureKind::OutputMissing);
+        assert!(err.to_string().ends_with("never"));
+
+        let task = CommandTask::new(
+            Key::Path(dir.path("batched").into_bytes().into()),
+            "echo hi".to_owned(),
+        );
+        let results = runtime().block_on(CommandBatch::new(vec![&task]).run(false));
+        assert!(matches!(
+            &results[0],
+            Some(Err(CommandTaskError::OutputMissing { output, .. })) if output.stdout == b"hi\n"
+        ));
+    }
+
+    #[test]
+    fn streams_keep_the_order_they_were_written_in() {
+        let output = runtime()
+            .block_on(run_script(
+                &Workspace::default(),
+                "echo one; echo two >&2; echo three",
+                None,
+                false,
+            ))
+            .unwrap();
+        assert_eq!(output.stdout, b"one\ntwo\nthree\n");
+        assert!(output.stderr.is_empty());
+    }
+
+    #[test]
+    fn rspfiles_are_removed_once_their_command_succeeds() {
+        let dir = TempDir::new("rspfile");
+        let rspfile = |name: &str| ResponseFile {
+            path: dir.path(name).into_bytes().into(),
+            content: b"a.o\nb.o".to_vec(),
+        };
+        let out = dir.path("out");
+        let task = CommandTask::new(
+            Key::Path(out.clone().into_bytes().into()),
+            format!("cp {} {}", dir.path("ok.rsp"), out),
+        )
+        .rspfile(Some(rspfile("ok.rsp")));
+        runtime().block_on(task.run_command()).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), b"a.o\nb.o");
+        assert!(!Path::new(&dir.path("ok.rsp")).exists());
+
+        let task = CommandTask::new(
+            Key::Path(dir.path("never").into_bytes().into()),
+            "false".to_owned(),
+        )
+        .rspfile(Some(rspfile("failed.rsp")));
+        assert!(runtime().block_on(task.run_command()).is_err());
+        // Kept for running the command again by hand.
+        assert!(Path::new(&dir.path("failed.rsp")).exists());
+    }
+
+    #[test]
+    fn streamed_output_is_not_captured() {
+        let dir = TempDir::new("streamed");
+        let out = dir.path("out");
+        let task = CommandTask::new(
+            Key::Path(out.clone().into_bytes().into()),
+            format!("echo streamed && touch {}", out),
+        )
+        .workspace(Workspace::default().stream(true));
+        let output = runtime().block_on(task.run_command()).unwrap();
+        assert!(output.stdout.is_empty());
+    }
+
+    #[test]
+    fn classification() {
+        let output = Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: vec![],
+            stderr: vec![],
+        };
+        assert_eq!(
+            CommandTaskError::CommandFailed(output.clone()).kind(),
+            FailureKind::NonZeroExit
+        );
+        assert_eq!(
+            CommandTaskError::Signaled {
+                output,
+                signal: libc::SIGSEGV
+            }
+            .kind(),
+            FailureKind::Signaled
+        );
+        assert_eq!(
+            CommandTaskError::TimedOut(Duration::from_secs(1))
+                .kind()
+                .as_str(),
+            "timeout"
+        );
+    }
+
+    #[test]
+    fn markers() {
+        let output = format!("a\n{m}\nb{m}\nc", m = BATCH_MARKER);
+        assert_eq!(
+            split_at_markers(output.as_bytes()),
+            vec![b"a\n".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+    }
+
+    #[test]
+    fn output_is_attributed_per_edge() {
+        let results = {
+            let dir = TempDir::new("attributed");
+            run_batch(&dir, &["echo one", "echo two >&2", "true"], false)
+        };
+        assert_eq!(results.len(), 3);
+        assert_eq!(stdout(&results[0]), b"one\n");
+        assert_eq!(stdout(&results[1]), b"two\n");
+        assert!(matches!(&results[2], Some(Ok(output)) if output.stdout.is_empty()));
+    }
+
+    #[test]
+    fn failure_stops_the_batch() {
+        let results = {
+            let dir = TempDir::new("stops");
+            run_batch(&dir, &["echo one", "echo two; exit 3", "echo three"], false)
+        };
+        assert_eq!(stdout(&results[0]), b"one\n");
+        assert_eq!(stdout(&results[1]), b"two\n");
+        match &results[1] {
+            Some(Err(CommandTaskError::CommandFailed(output))) => {
+                assert_eq!(output.status.code(), Some(3))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(results[2].is_none());
+    }
+
+    #[test]
+    fn keep_going_runs_the_rest() {
+        let results = {
+            let dir = TempDir::new("keep-going");
+            run_batch(&dir, &["false", "echo two", "echo three"], true)
+        };
+        assert!(matches!(
+            &results[0],
+            Some(Err(CommandTaskError::CommandFailed(_)))
+        ));
+        assert_eq!(stdout(&results[1]), b"two\n");
+        assert_eq!(stdout(&results[2]), b"three\n");
+    }
+
+    fn deps_task(deps: Deps, depfile: Option<String>) -> Task {
+        Task {
+            dependencies: vec![],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: crate::task::TaskVariant::Command("cc".to_owned()),
+            rule: None,
+            depfile: depfile.map(|depfile| depfile.into_bytes().into()),
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: Some(deps),
+            pool: None,
+            description: None,
+        }
+    }
+
+    fn output(stdout: &[u8]) -> Output {
+        Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.to_vec(),
+            stderr: vec![],
+        }
+    }
+
+    #[test]
+    fn extracts_msvc_includes() {
+        let task = deps_task(
+            Deps::Msvc {
+                prefix: b"Note: including file:".to_vec(),
+            },
+            None,
+        );
+        let (output, inputs) = extract_deps(
+            &Workspace::default(),
+            &task,
+            output(
+                b"foo.c\nNote: including file:  C:\\a b.h\r\nwarning\nNote: including file: c.h",
+            ),
+        )
+        .unwrap();
+        assert_eq!(output.stdout, b"foo.c\nwarning\n");
+        assert_eq!(inputs, vec![b"C:\\a b.h".to_vec(), b"c.h".to_vec()]);
+    }
+
+    #[test]
+    fn extracts_gcc_depfile() {
+        let dir = TempDir::new("gcc-deps");
+        let depfile = dir.path("foo.o.d");
+        let task = deps_task(Deps::Gcc, Some(depfile.clone()));
+        let (_, inputs) = extract_deps(&Workspace::default(), &task, output(b"")).unwrap();
+        assert!(inputs.is_empty(), "a missing depfile lists nothing");
+
+        std::fs::write(&depfile, "foo.o: foo.c foo.h\n").unwrap();
+        let (_, inputs) = extract_deps(&Workspace::default(), &task, output(b"")).unwrap();
+        assert_eq!(inputs, vec![b"foo.c".to_vec(), b"foo.h".to_vec()]);
+        assert!(!std::path::Path::new(&depfile).exists());
+
+        std::fs::write(&depfile, "foo.o foo.c\n").unwrap();
+        let err = extract_deps(&Workspace::default(), &task, output(b"")).unwrap_err();
+        assert_eq!(err.kind(), FailureKind::BadDeps);
+    }
+
+    #[test]
+    fn missing_shell_is_explained() {
+        let dir = TempDir::new("missing-shell");
+        let workspace = Workspace::new(&*dir).shell(dir.path("sh"));
+        let err = runtime()
+            .block_on(run_script(&workspace, "true", None, false))
+            .unwrap_err();
+        match err {
+            CommandTaskError::SpawnFailed(e) => {
+                assert_eq!(
+                    e.hint(),
+                    Some(format!("the shell '{}' does not exist", dir.path("sh")).as_str())
+                );
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+
+        std::fs::write(dir.path("sh"), "#!/bin/sh\n").unwrap();
+        let err = runtime()
+            .block_on(run_script(&workspace, "true", None, false))
+            .unwrap_err();
+        assert!(err.to_string().ends_with("is not executable)"), "{}", err);
+    }
+
+    #[test]
+    fn spawn_hints() {
+        let e2big = io::Error::from_raw_os_error(libc::E2BIG);
+        let e = SpawnError::diagnose(e2big, Path::new("/bin/sh"), "echo hi");
+        assert_eq!(
+            e.hint(),
+            Some(
+                "the command is 7 bytes, more than the system allows; an rspfile would shorten it"
+            )
+        );
+
+        let not_found = io::Error::from(io::ErrorKind::NotFound);
+        let e = SpawnError::diagnose(not_found, Path::new("no-such-shell-here"), "");
+        assert!(e
+            .hint()
+            .unwrap()
+            .starts_with("the shell 'no-such-shell-here' is not in PATH"));
+
+        let e: SpawnError = io::Error::from(io::ErrorKind::Other).into();
+        assert_eq!(e.hint(), None);
+    }
+}
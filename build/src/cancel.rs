@@ -0,0 +1,93 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Cancelling builds from elsewhere in the program, for programs that embed the builder and
+//! don't want it to handle signals.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Stops builds of the schedulers it was given to, from any thread. No more commands start, and
+/// the ones running are killed, along with everything they spawned.
+///
+/// Cancelling is for good: builds started with the handle afterwards stop right away.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<Inner>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the handle is cancelled.
+    pub(crate) async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            self.0.notify.notified().await;
+        }
+        // Only one waiter is woken, so pass it on to builds running on other threads.
+        self.0.notify.notify();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn wakes_every_waiter() {
+        let cancel = CancelHandle::new();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let cancel = cancel.clone();
+                std::thread::spawn(move || {
+                    let mut runtime = tokio::runtime::Builder::new()
+                        .basic_scheduler()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+                    runtime.block_on(cancel.cancelled());
+                })
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!cancel.is_cancelled());
+        cancel.cancel();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    }
+}
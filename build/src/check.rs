@@ -0,0 +1,324 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Static checks over a set of Tasks that do not require running anything.
+//!
+//! The parser already rejects duplicate rules, duplicate outputs and unknown rules. What remains
+//! are properties of the graph as a whole, which only make sense after conversion to Tasks.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use petgraph::{algo::tarjan_scc, graph::NodeIndex};
+use thiserror::Error;
+
+use crate::{
+    task::{Key, KeyPath, Task, Tasks},
+    GraphTooLarge, ParallelTopoScheduler, SchedulerGraph,
+};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CheckError {
+    #[error("dependency cycle: {}", .0.join(" -> "))]
+    DependencyCycle(Vec<String>),
+    #[error("unknown target '{0}'")]
+    UnknownTarget(String),
+    #[error(transparent)]
+    GraphTooLarge(#[from] GraphTooLarge),
+}
+
+/// Finds a path from `start` back to itself that only passes through nodes in `component`.
+fn cycle_through(
+    graph: &SchedulerGraph,
+    component: &HashSet<NodeIndex>,
+    start: NodeIndex,
+) -> Vec<NodeIndex> {
+    let mut parents: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(start);
+    while let Some(node) = queue.pop_front() {
+        for next in graph.neighbors(node) {
+            if next == start {
+                let mut path = vec![node];
+                let mut current = node;
+                while let Some(parent) = parents.get(&current) {
+                    path.push(*parent);
+                    current = *parent;
+                }
+                // The walk above went from the end of the cycle back to the start.
+                path.reverse();
+                path.push(start);
+                return path;
+            }
+            if component.contains(&next) && !parents.contains_key(&next) {
+                parents.insert(next, node);
+                queue.push_back(next);
+            }
+        }
+    }
+    unreachable!("strongly connected component without a cycle");
+}
+
+/// Reports every dependency cycle in the graph, one per strongly connected component.
+///
+/// Multi keys are an artifact of how edges with several outputs are represented, so they are
+/// left out of the reported cycle.
+pub fn find_cycles(tasks: &Tasks) -> Vec<CheckError> {
+    let graph = match ParallelTopoScheduler::build_graph(tasks, None) {
+        Ok(graph) => graph,
+        Err(e) => return vec![e.into()],
+    };
+    let mut cycles = Vec::new();
+    for component in tarjan_scc(&graph) {
+        let is_cycle = component.len() > 1 || graph.contains_edge(component[0], component[0]);
+        if !is_cycle {
+            continue;
+        }
+        // Start from the smallest key so the report is stable across runs.
+        let start = *component
+            .iter()
+            .filter(|node| graph[**node].is_path())
+            .min_by_key(|node| graph[**node])
+            .unwrap_or(&component[0]);
+        let members: HashSet<NodeIndex> = component.into_iter().collect();
+        let names = cycle_through(&graph, &members, start)
+            .into_iter()
+            .filter_map(|node| match graph[node] {
+                Key::Path(p) => Some(p.display().to_string()),
+                Key::Multi(_) => None,
+            })
+            .collect();
+        cycles.push(CheckError::DependencyCycle(names));
+    }
+    cycles
+}
+
+/// Reports requested targets that no edge mentions, either as an output or an input.
+pub fn find_unknown_targets(tasks: &Tasks, requested: &[KeyPath]) -> Vec<CheckError> {
+    let mut known: HashSet<&Key> = HashSet::new();
+    for (key, task) in tasks.all_tasks() {
+        known.insert(key);
+        known.extend(task.dependencies());
+        known.extend(task.order_dependencies());
+    }
+    requested
+        .iter()
+        .filter(|target| !known.contains(&Key::Path((*target).clone())))
+        .map(|target| CheckError::UnknownTarget(target.display().to_string()))
+        .collect()
+}
+
+/// Runs all checks, returning every problem found instead of stopping at the first.
+pub fn check_tasks(tasks: &Tasks, requested: Option<&[KeyPath]>) -> Vec<CheckError> {
+    let mut errors = find_cycles(tasks);
+    if let Some(requested) = requested {
+        errors.extend(find_unknown_targets(tasks, requested));
+    }
+    errors
+}
+
+/// Properties of the graph that are not errors, but often point at bugs in the generator.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GraphAnalysis {
+    /// How many edges the manifest has, phony ones included.
+    pub edges: usize,
+    /// Inputs that no edge produces. Usually these are source files, but a misspelled path or a
+    /// forgotten edge looks the same.
+    pub orphan_inputs: Vec<KeyPath>,
+    /// Edges, named by their outputs, that none of the requested targets depend on. Always empty
+    /// when nothing was requested, since then every edge is built.
+    pub unreachable_edges: Vec<Key>,
+}
+
+/// Each output of an edge with several outputs gets a task that only points at the edge. Those
+/// are an artifact of the representation, not edges in the manifest.
+fn is_edge(task: &Task) -> bool {
+    !(task.is_retrieve() && task.dependencies().len() == 1 && task.dependencies()[0].is_multi())
+}
+
+pub fn analyze_graph(tasks: &Tasks, requested: Option<&[KeyPath]>) -> GraphAnalysis {
+    let mut edges = 0;
+    let mut orphan_inputs: HashSet<&KeyPath> = HashSet::new();
+    for task in tasks.all_tasks().values() {
+        if is_edge(task) {
+            edges += 1;
+        }
+        for dep in task.dependencies().iter().chain(task.order_dependencies()) {
+            if let Key::Path(path) = dep {
+                if tasks.task(dep).is_none() {
+                    orphan_inputs.insert(path);
+                }
+            }
+        }
+    }
+    let mut orphan_inputs: Vec<KeyPath> = orphan_inputs.into_iter().cloned().collect();
+    orphan_inputs.sort();
+
+    let mut unreachable_edges = Vec::new();
+    if let Some(requested) = requested {
+        let mut reached: HashSet<&Key> = HashSet::new();
+        let requested: Vec<Key> = requested.iter().cloned().map(Key::Path).collect();
+        let mut pending: Vec<&Key> = requested.iter().collect();
+        while let Some(key) = pending.pop() {
+            if !reached.insert(key) {
+                continue;
+            }
+            if let Some(task) = tasks.task(key) {
+                pending.extend(task.dependencies().iter().chain(task.order_dependencies()));
+            }
+        }
+        unreachable_edges = tasks
+            .all_tasks()
+            .iter()
+            .filter(|(key, task)| is_edge(task) && !reached.contains(key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        unreachable_edges.sort();
+    }
+
+    GraphAnalysis {
+        edges,
+        orphan_inputs,
+        unreachable_edges,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ninja_parse::repr::{Action, Build, Description};
+
+    use super::*;
+    use crate::task::description_to_tasks;
+
+    fn edge(outputs: &[&str], inputs: &[&str]) -> Build {
+        Build {
+            action: Action::Command("touch".to_owned()),
+            rule: b"touch".to_vec(),
+            rule_index: None,
+            description: None,
+            depfile: None,
+            rspfile: None,
+            deps: None,
+            pool: None,
+            namespace: None,
+            inputs: inputs.iter().map(|i| i.as_bytes().to_vec()).collect(),
+            implicit_inputs: vec![],
+            order_inputs: vec![],
+            outputs: outputs.iter().map(|o| o.as_bytes().to_vec()).collect(),
+            implicit_outputs: vec![],
+        }
+    }
+
+    fn tasks(builds: Vec<Build>) -> Tasks {
+        description_to_tasks(Description {
+            builds,
+            defaults: None,
+            rules: vec![],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
+        })
+        .0
+    }
+
+    #[test]
+    fn no_cycles() {
+        let tasks = tasks(vec![edge(&["b"], &["a"]), edge(&["c"], &["b"])]);
+        assert!(check_tasks(&tasks, None).is_empty());
+    }
+
+    #[test]
+    fn simple_cycle() {
+        let tasks = tasks(vec![
+            edge(&["a"], &["c"]),
+            edge(&["b"], &["a"]),
+            edge(&["c"], &["b"]),
+        ]);
+        let errors = find_cycles(&tasks);
+        assert_eq!(
+            errors,
+            vec![CheckError::DependencyCycle(vec![
+                "a".to_owned(),
+                "c".to_owned(),
+                "b".to_owned(),
+                "a".to_owned()
+            ])]
+        );
+        assert_eq!(errors[0].to_string(), "dependency cycle: a -> c -> b -> a");
+    }
+
+    #[test]
+    fn self_cycle() {
+        let tasks = tasks(vec![edge(&["a"], &["a"])]);
+        assert_eq!(
+            find_cycles(&tasks),
+            vec![CheckError::DependencyCycle(vec![
+                "a".to_owned(),
+                "a".to_owned()
+            ])]
+        );
+    }
+
+    #[test]
+    fn cycle_through_multiple_outputs() {
+        let tasks = tasks(vec![edge(&["a", "b"], &["c"]), edge(&["c"], &["b"])]);
+        let errors = find_cycles(&tasks);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "dependency cycle: b -> c -> b");
+    }
+
+    #[test]
+    fn unknown_targets() {
+        let tasks = tasks(vec![edge(&["b"], &["a"])]);
+        let requested: Vec<KeyPath> = vec![
+            b"a".to_vec().into(),
+            b"b".to_vec().into(),
+            b"nope".to_vec().into(),
+        ];
+        assert_eq!(
+            check_tasks(&tasks, Some(&requested)),
+            vec![CheckError::UnknownTarget("nope".to_owned())]
+        );
+    }
+
+    #[test]
+    fn analysis() {
+        let tasks = tasks(vec![
+            edge(&["b"], &["a"]),
+            edge(&["c", "d"], &["b", "typo"]),
+            edge(&["unused"], &["a"]),
+            edge(&["also_unused", "and_this"], &[]),
+        ]);
+        let requested: Vec<KeyPath> = vec![b"c".to_vec().into()];
+        assert_eq!(
+            analyze_graph(&tasks, Some(&requested)),
+            GraphAnalysis {
+                edges: 4,
+                orphan_inputs: vec![b"a".to_vec().into(), b"typo".to_vec().into()],
+                unreachable_edges: vec![
+                    Key::Path(b"unused".to_vec().into()),
+                    Key::Multi(
+                        vec![b"also_unused".to_vec().into(), b"and_this".to_vec().into()].into()
+                    ),
+                ],
+            }
+        );
+        assert!(analyze_graph(&tasks, None).unreachable_edges.is_empty());
+    }
+}
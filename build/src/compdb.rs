@@ -0,0 +1,191 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Writes a JSON compilation database, `compile_commands.json`, so that clangd and other tools
+//! know how each file is compiled.
+
+use std::{
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use crate::{
+    failures::json_string,
+    task::{Key, KeyPath, Tasks},
+};
+
+fn path_string(path: &Path) -> String {
+    String::from_utf8_lossy(path.as_os_str().as_bytes()).into_owned()
+}
+
+/// Writes an entry for each command edge of `rules`, or of every rule if it is empty, in the
+/// format ninja's `-t compdb` uses. Edges without inputs are left out, since an entry is about
+/// its first input. `base` is the directory ninja runs in, which commands of namespaced edges run
+/// below.
+pub fn write_compdb(
+    tasks: &Tasks,
+    rules: &[&[u8]],
+    base: &Path,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut edges: Vec<(&Key, &str, &KeyPath)> = tasks
+        .all_tasks()
+        .iter()
+        .filter(|(key, _)| {
+            rules.is_empty()
+                || matches!(tasks.rule_for(key), Some(rule) if rules.contains(&rule.name.as_slice()))
+        })
+        .filter_map(|(key, task)| {
+            let input = match task.explicit_dependencies().first()? {
+                Key::Path(input) => input,
+                Key::Multi(_) => return None,
+            };
+            Some((key, task.command()?.as_str(), input))
+        })
+        .collect();
+    edges.sort();
+    write!(out, "[")?;
+    for (i, (key, command, input)) in edges.into_iter().enumerate() {
+        let output = key.iter().next().expect("keys have a path");
+        let directory = match output.namespace() {
+            Some(namespace) => base.join(std::ffi::OsStr::from_bytes(namespace)),
+            None => base.to_owned(),
+        };
+        // Inputs from another namespace are outside the directory the command runs in.
+        let file = if input.namespace() == output.namespace() {
+            String::from_utf8_lossy(input.relative()).into_owned()
+        } else {
+            path_string(&base.join(std::ffi::OsStr::from_bytes(input.as_bytes())))
+        };
+        write!(
+            out,
+            "{}\n  {{\n    \"directory\": {},\n    \"command\": {},\n    \"file\": {},\n    \
+             \"output\": {}\n  }}",
+            if i > 0 { "," } else { "" },
+            json_string(&path_string(&directory)),
+            json_string(command),
+            json_string(&file),
+            json_string(&String::from_utf8_lossy(output.relative())),
+        )?;
+    }
+    writeln!(out, "\n]")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::TasksBuilder;
+
+    #[test]
+    fn entries_for_rules() {
+        let mut builder = TasksBuilder::new();
+        let no_inputs: &[&str] = &[];
+        builder
+            .source("a.c")
+            .source("b.c")
+            .source("b.h")
+            .command(&["b.o"], &["b.c", "b.h"], "cc -c \"b.c\"")
+            .unwrap()
+            .command(&["a.o"], &["a.c"], "cc -c a.c")
+            .unwrap()
+            .command(&["gen.h"], no_inputs, "gen")
+            .unwrap()
+            .phony(&["all"], &["a.o", "b.o"])
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let mut out = Vec::new();
+        write_compdb(&tasks, &[], Path::new("/src"), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"[
+  {
+    "directory": "/src",
+    "command": "cc -c a.c",
+    "file": "a.c",
+    "output": "a.o"
+  },
+  {
+    "directory": "/src",
+    "command": "cc -c \"b.c\"",
+    "file": "b.c",
+    "output": "b.o"
+  }
+]
+"#
+        );
+
+        // Tasks not from a manifest have no rule.
+        let mut out = Vec::new();
+        write_compdb(&tasks, &[b"cc"], Path::new("/src"), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[\n]\n");
+    }
+
+    #[test]
+    fn namespaced_edges_of_a_rule() {
+        use ninja_parse::repr::{Action, Build, Description, RuleDescriptor};
+
+        let build = |rule: &str, namespace: Option<&str>, input: &str| Build {
+            action: Action::Command(format!("{} {}", rule, input)),
+            rule: rule.as_bytes().to_vec(),
+            rule_index: Some(if rule == "cc" { 0 } else { 1 }),
+            description: None,
+            depfile: None,
+            rspfile: None,
+            deps: None,
+            pool: None,
+            namespace: namespace.map(|n| n.as_bytes().to_vec()),
+            inputs: vec![input.as_bytes().to_vec()],
+            implicit_inputs: vec![],
+            order_inputs: vec![],
+            outputs: vec![b"x.o".to_vec()],
+            implicit_outputs: vec![],
+        };
+        let rule = |name: &str| RuleDescriptor {
+            name: name.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        let (tasks, _) = crate::task::description_to_tasks(Description {
+            builds: vec![
+                build("cc", Some("host"), "x.c"),
+                build("cc", Some("target"), ":shared.c"),
+                build("asm", None, "x.s"),
+            ],
+            rules: vec![rule("cc"), rule("asm")],
+            ..Default::default()
+        });
+        let mut out = Vec::new();
+        write_compdb(&tasks, &[b"cc"], Path::new("/src"), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"[
+  {
+    "directory": "/src/host",
+    "command": "cc x.c",
+    "file": "x.c",
+    "output": "x.o"
+  },
+  {
+    "directory": "/src/target",
+    "command": "cc :shared.c",
+    "file": "/src/shared.c",
+    "output": "x.o"
+  }
+]
+"#
+        );
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reads the Makefile fragments that compilers write with `-MD`/`-MMD`, listing the headers a
+//! source file included, so that changing one rebuilds its objects.
+//!
+//! Only what compilers emit is understood: `outputs: inputs` rules, with `\` continuing a line.
+//! A backslash before a space or `#` escapes it, doubled backslashes before those stand for one
+//! backslash each, and `$$` is `$`. Rules with no inputs, as written by `-MP`, are allowed.
+
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+/// The paths listed by one depfile.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Depfile {
+    pub outputs: Vec<Vec<u8>>,
+    /// In the order first listed, without duplicates.
+    pub inputs: Vec<Vec<u8>>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DepfileError {
+    #[error("line {0}: expected ':' after the outputs")]
+    MissingColon(usize),
+    #[error("line {0}: expected outputs before ':'")]
+    MissingOutputs(usize),
+}
+
+fn is_newline(contents: &[u8], i: usize) -> bool {
+    match contents.get(i) {
+        Some(b'\n') => true,
+        Some(b'\r') => contents.get(i + 1) == Some(&b'\n'),
+        _ => false,
+    }
+}
+
+fn newline_len(contents: &[u8], i: usize) -> usize {
+    if contents[i] == b'\r' {
+        2
+    } else {
+        1
+    }
+}
+
+fn push_backslashes(path: &mut Vec<u8>, count: usize) {
+    path.resize(path.len() + count, b'\\');
+}
+
+/// Reads one path starting at `i`, returning it and where it ended.
+fn read_path(contents: &[u8], mut i: usize) -> (Vec<u8>, usize) {
+    let mut path = Vec::new();
+    while i < contents.len() {
+        match contents[i] {
+            b' ' | b'\t' => break,
+            _ if is_newline(contents, i) => break,
+            b'\\' => {
+                let run = contents[i..].iter().take_while(|c| **c == b'\\').count();
+                let next = i + run;
+                match contents.get(next) {
+                    Some(b' ') | Some(b'#') => {
+                        push_backslashes(&mut path, run / 2);
+                        if run % 2 == 1 {
+                            path.push(contents[next]);
+                            i = next + 1;
+                        } else {
+                            i = next;
+                        }
+                    }
+                    // Leave the last backslash to continue the line.
+                    _ if is_newline(contents, next) => {
+                        push_backslashes(&mut path, run - 1);
+                        i = next - 1;
+                        break;
+                    }
+                    _ => {
+                        push_backslashes(&mut path, run);
+                        i = next;
+                    }
+                }
+            }
+            b'$' if contents.get(i + 1) == Some(&b'$') => {
+                path.push(b'$');
+                i += 2;
+            }
+            c => {
+                path.push(c);
+                i += 1;
+            }
+        }
+    }
+    (path, i)
+}
+
+pub fn parse(contents: &[u8]) -> Result<Depfile, DepfileError> {
+    let mut depfile = Depfile::default();
+    let mut seen_inputs = HashSet::new();
+    let mut line = 1;
+    // Whether the current rule has reached its ':', and whether it has any paths at all.
+    let mut after_colon = false;
+    let mut rule_started = false;
+    let mut i = 0;
+    loop {
+        while i < contents.len() && (contents[i] == b' ' || contents[i] == b'\t') {
+            i += 1;
+        }
+        if contents.get(i) == Some(&b'\\') && is_newline(contents, i + 1) {
+            i += 1 + newline_len(contents, i + 1);
+            line += 1;
+            continue;
+        }
+        let end_of_rule = i == contents.len() || is_newline(contents, i);
+        if end_of_rule {
+            if rule_started && !after_colon {
+                return Err(DepfileError::MissingColon(line));
+            }
+            after_colon = false;
+            rule_started = false;
+            if i == contents.len() {
+                break;
+            }
+            i += newline_len(contents, i);
+            line += 1;
+            continue;
+        }
+
+        let (mut path, next) = read_path(contents, i);
+        i = next;
+        let is_output = !after_colon && path.last() == Some(&b':');
+        if is_output {
+            path.pop();
+            if path.is_empty() && !rule_started {
+                return Err(DepfileError::MissingOutputs(line));
+            }
+            after_colon = true;
+        }
+        rule_started = true;
+        if path.is_empty() {
+            continue;
+        }
+        if is_output || !after_colon {
+            depfile.outputs.push(path);
+        } else if seen_inputs.insert(path.clone()) {
+            depfile.inputs.push(path);
+        }
+    }
+    Ok(depfile)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<Vec<u8>> {
+        paths.iter().map(|p| p.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn compiler_output() {
+        let depfile =
+            parse(b"foo.o: foo.c \\\n  include/foo.h \\\r\n  /usr/include/stdio.h\n").unwrap();
+        assert_eq!(depfile.outputs, paths(&["foo.o"]));
+        assert_eq!(
+            depfile.inputs,
+            paths(&["foo.c", "include/foo.h", "/usr/include/stdio.h"])
+        );
+    }
+
+    #[test]
+    fn phony_rules_and_duplicates() {
+        // As written by -MP.
+        let depfile = parse(b"foo.o: foo.c foo.h\n\nfoo.h:\nfoo.o: foo.h bar.h\n").unwrap();
+        assert_eq!(depfile.outputs, paths(&["foo.o", "foo.h", "foo.o"]));
+        assert_eq!(depfile.inputs, paths(&["foo.c", "foo.h", "bar.h"]));
+    }
+
+    #[test]
+    fn escapes() {
+        let depfile =
+            parse(br"out\ file.o: a\ b.h c\#d.h e$$f.h g\\\ h.h back\slash.h".as_ref()).unwrap();
+        assert_eq!(depfile.outputs, paths(&["out file.o"]));
+        assert_eq!(
+            depfile.inputs,
+            paths(&["a b.h", "c#d.h", "e$f.h", r"g\ h.h", r"back\slash.h"])
+        );
+    }
+
+    #[test]
+    fn several_outputs_and_no_trailing_newline() {
+        let depfile = parse(b"a.o b.o : x.h").unwrap();
+        assert_eq!(depfile.outputs, paths(&["a.o", "b.o"]));
+        assert_eq!(depfile.inputs, paths(&["x.h"]));
+        assert_eq!(parse(b"").unwrap(), Depfile::default());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            parse(b"foo.o: foo.c\nfoo.o foo.c\n"),
+            Err(DepfileError::MissingColon(2))
+        );
+        assert_eq!(parse(b": foo.c\n"), Err(DepfileError::MissingOutputs(1)));
+    }
+}
@@ -0,0 +1,465 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The deps log records, for outputs of rules with `deps`, the inputs their command discovered,
+//! so that depfiles and compiler output only need to be read once.
+//!
+//! The format is ninja's `.ninja_deps` version 4, so an existing build directory can be adopted
+//! without a clean build. After the header, each record is a little-endian `u32` size, with the
+//! top bit set for deps records, followed by that many bytes:
+//!
+//! - A path record is the path, padded with NULs to a multiple of four bytes, then the bitwise
+//!   not of its id. Ids count path records from zero.
+//! - A deps record is the output's id, its mtime in nanoseconds as two `u32`s, low half first,
+//!   then the ids of its inputs.
+//!
+//! Later deps records for an output replace earlier ones. A log that was cut short, or that
+//! refers to paths it never defined, is used up to the last good record, which is where the next
+//! record is written.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    convert::TryInto,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use thiserror::Error;
+
+use crate::{atomic::write_atomic, diagnostics};
+
+const HEADER: &[u8] = b"# ninjadeps\n";
+pub const VERSION: u32 = 4;
+const DEPS_RECORD: u32 = 0x8000_0000;
+/// Like ninja, records larger than this are treated as corruption.
+const MAX_RECORD_SIZE: usize = (1 << 19) - 1;
+
+#[derive(Error, Debug)]
+pub enum DepsLogError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("deps for '{0}' are too many to record")]
+    TooLarge(String),
+}
+
+/// The inputs recorded for an output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedDeps {
+    /// The output's mtime, in nanoseconds since the Unix epoch, when the inputs were recorded.
+    pub mtime: u64,
+    /// As the command reported them, so relative to the directory it ran in.
+    pub inputs: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    mtime: u64,
+    inputs: Vec<u32>,
+}
+
+#[derive(Debug, Default)]
+pub struct DepsLog {
+    paths: Vec<Vec<u8>>,
+    ids: HashMap<Vec<u8>, u32>,
+    deps: HashMap<u32, Record>,
+    /// Set when the end of the file has to go before the next record is appended: a torn or
+    /// corrupt tail, or everything if the file is not a log this version can read.
+    valid_len: Option<u64>,
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl DepsLog {
+    /// Reads the log at `path`. A missing log is an empty one.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(Self::parse(&contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Never fails, since a log that cannot be read only means commands run again.
+    pub fn parse(contents: &[u8]) -> Self {
+        let mut log = Self::default();
+        let start = HEADER.len() + 4;
+        if contents.is_empty() {
+            return log;
+        }
+        if contents.len() < start
+            || !contents.starts_with(HEADER)
+            || u32_at(contents, HEADER.len()) != VERSION
+        {
+            log.valid_len = Some(0);
+            return log;
+        }
+        let mut offset = start;
+        while offset < contents.len() {
+            if log.read_record(contents, offset).is_none() {
+                log.valid_len = Some(offset as u64);
+                break;
+            }
+            let size = (u32_at(contents, offset) & !DEPS_RECORD) as usize;
+            offset += 4 + size;
+        }
+        log
+    }
+
+    /// Adds the record at `offset`, or returns None if it is torn or corrupt.
+    fn read_record(&mut self, contents: &[u8], offset: usize) -> Option<()> {
+        let header = u32_at(contents.get(offset..offset + 4)?, 0);
+        let size = (header & !DEPS_RECORD) as usize;
+        if size > MAX_RECORD_SIZE || size & 3 != 0 {
+            return None;
+        }
+        let record = contents.get(offset + 4..offset + 4 + size)?;
+        if header & DEPS_RECORD != 0 {
+            if size < 12 {
+                return None;
+            }
+            let output = u32_at(record, 0);
+            let mtime = u32_at(record, 4) as u64 | (u32_at(record, 8) as u64) << 32;
+            let inputs: Vec<u32> = (12..size).step_by(4).map(|i| u32_at(record, i)).collect();
+            let known = self.paths.len() as u32;
+            if output >= known || inputs.iter().any(|input| *input >= known) {
+                return None;
+            }
+            self.deps.insert(output, Record { mtime, inputs });
+        } else {
+            if size < 4 {
+                return None;
+            }
+            let id = self.paths.len() as u32;
+            if !u32_at(record, size - 4) != id {
+                return None;
+            }
+            let mut path = &record[..size - 4];
+            while let [rest @ .., 0] = path {
+                path = rest;
+            }
+            self.ids.insert(path.to_vec(), id);
+            self.paths.push(path.to_vec());
+        }
+        Some(())
+    }
+
+    pub fn deps(&self, output: &[u8]) -> Option<RecordedDeps> {
+        let record = self.deps.get(self.ids.get(output)?)?;
+        Some(RecordedDeps {
+            mtime: record.mtime,
+            inputs: record
+                .inputs
+                .iter()
+                .map(|id| self.paths[*id as usize].clone())
+                .collect(),
+        })
+    }
+
+    /// Opens the log for appending, dropping anything after the last good record. A new log, or
+    /// one that is being started over, is first replaced by just the header, so that a build
+    /// killed right then leaves a log that can still be read.
+    fn start_appending(&mut self, path: &Path) -> io::Result<File> {
+        let empty = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len() == 0,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        if empty || self.valid_len == Some(0) {
+            write_atomic(path, false, |file| {
+                file.write_all(HEADER)?;
+                file.write_all(&VERSION.to_le_bytes())
+            })?;
+            self.valid_len = None;
+        }
+        let file = OpenOptions::new().append(true).open(path)?;
+        if let Some(len) = self.valid_len.take() {
+            file.set_len(len)?;
+        }
+        Ok(file)
+    }
+
+    /// Appends `path` to `buf` as a path record, if it does not have an id yet.
+    fn id(&mut self, path: &[u8], buf: &mut Vec<u8>) -> u32 {
+        if let Some(id) = self.ids.get(path) {
+            return *id;
+        }
+        let id = self.paths.len() as u32;
+        let padding = (4 - path.len() % 4) % 4;
+        buf.extend_from_slice(&((path.len() + padding + 4) as u32).to_le_bytes());
+        buf.extend_from_slice(path);
+        buf.resize(buf.len() + padding, 0);
+        buf.extend_from_slice(&(!id).to_le_bytes());
+        self.ids.insert(path.to_vec(), id);
+        self.paths.push(path.to_vec());
+        id
+    }
+
+    /// Appends the records for `output` in one write, so a crash can only ever tear the last
+    /// ones. Nothing is written if the same inputs were recorded at the same mtime already.
+    fn append(
+        &mut self,
+        file: &mut File,
+        output: &[u8],
+        mtime: u64,
+        inputs: &[Vec<u8>],
+    ) -> Result<(), DepsLogError> {
+        let size = 12 + 4 * inputs.len();
+        if size > MAX_RECORD_SIZE {
+            return Err(DepsLogError::TooLarge(
+                String::from_utf8_lossy(output).into_owned(),
+            ));
+        }
+        let unchanged = matches!(self.deps(output),
+            Some(recorded) if recorded.mtime == mtime && recorded.inputs.as_slice() == inputs);
+        if unchanged {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        let output_id = self.id(output, &mut buf);
+        let input_ids: Vec<u32> = inputs
+            .iter()
+            .map(|input| self.id(input, &mut buf))
+            .collect();
+        buf.extend_from_slice(&(size as u32 | DEPS_RECORD).to_le_bytes());
+        buf.extend_from_slice(&output_id.to_le_bytes());
+        buf.extend_from_slice(&(mtime as u32).to_le_bytes());
+        buf.extend_from_slice(&((mtime >> 32) as u32).to_le_bytes());
+        for id in &input_ids {
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        file.write_all(&buf)?;
+        self.deps.insert(
+            output_id,
+            Record {
+                mtime,
+                inputs: input_ids,
+            },
+        );
+        Ok(())
+    }
+}
+
+struct OpenLog {
+    log: DepsLog,
+    path: PathBuf,
+    /// Opened for the first record. None again once writing failed, after which nothing more is
+    /// recorded.
+    file: Option<File>,
+    failed: bool,
+}
+
+/// The deps log for the length of an invocation, read by the rebuilder and appended to by
+/// schedulers as commands succeed. Cloning shares the log.
+#[derive(Clone)]
+pub struct SharedDepsLog(Rc<RefCell<OpenLog>>);
+
+impl fmt::Debug for SharedDepsLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedDepsLog")
+            .field(&self.0.borrow().path)
+            .finish()
+    }
+}
+
+impl SharedDepsLog {
+    /// Reads the log at `path`, which is where records will be appended.
+    pub fn load<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let log = DepsLog::load(&path)?;
+        Ok(SharedDepsLog(Rc::new(RefCell::new(OpenLog {
+            log,
+            path,
+            file: None,
+            failed: false,
+        }))))
+    }
+
+    pub fn deps(&self, output: &[u8]) -> Option<RecordedDeps> {
+        self.0.borrow().log.deps(output)
+    }
+
+    /// Records that `output`, as of `mtime`, depends on `inputs`. Like the build log, this is best
+    /// effort. The first error is reported and nothing more is recorded, so the affected edges
+    /// run again next time.
+    pub fn record(&self, output: &[u8], mtime: u64, inputs: &[Vec<u8>]) {
+        let mut open = self.0.borrow_mut();
+        let open = &mut *open;
+        if open.failed {
+            return;
+        }
+        if open.file.is_none() {
+            match open.log.start_appending(&open.path) {
+                Ok(file) => open.file = Some(file),
+                Err(e) => {
+                    diagnostics::warn(
+                        "deps log",
+                        format_args!("writing {}: {}", open.path.display(), e),
+                    );
+                    open.failed = true;
+                    return;
+                }
+            }
+        }
+        let file = open.file.as_mut().unwrap();
+        if let Err(e) = open.log.append(file, output, mtime, inputs) {
+            diagnostics::warn(
+                "deps log",
+                format_args!("writing {}: {}", open.path.display(), e),
+            );
+            open.failed = true;
+            open.file = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// The log's path, in a directory that is removed once the returned `TempDir` is dropped.
+    fn temp_log(name: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new(&format!("deps-{}", name));
+        let path = dir.join(".ninja_deps");
+        (dir, path)
+    }
+
+    fn paths(names: &[&str]) -> Vec<Vec<u8>> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_dir, path) = temp_log("round-trip");
+        let log = SharedDepsLog::load(&path).unwrap();
+        log.record(b"foo.o", 1 << 40 | 7, &paths(&["foo.c", "foo.h"]));
+        log.record(b"bar.o", 5, &paths(&["bar.c", "foo.h"]));
+        log.record(b"foo.o", 9, &paths(&["foo.c", "other.h"]));
+        drop(log);
+
+        let reloaded = DepsLog::load(&path).unwrap();
+        assert_eq!(
+            reloaded.deps(b"foo.o"),
+            Some(RecordedDeps {
+                mtime: 9,
+                inputs: paths(&["foo.c", "other.h"]),
+            })
+        );
+        assert_eq!(
+            reloaded.deps(b"bar.o").unwrap().inputs,
+            paths(&["bar.c", "foo.h"])
+        );
+        assert_eq!(reloaded.deps(b"foo.c"), None);
+        assert_eq!(reloaded.valid_len, None);
+    }
+
+    #[test]
+    fn unchanged_deps_are_not_rewritten() {
+        let (_dir, path) = temp_log("unchanged");
+        let log = SharedDepsLog::load(&path).unwrap();
+        log.record(b"foo.o", 1, &paths(&["foo.c"]));
+        let len = std::fs::metadata(&path).unwrap().len();
+        log.record(b"foo.o", 1, &paths(&["foo.c"]));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn recovers_from_torn_record() {
+        let (_dir, path) = temp_log("torn");
+        let log = SharedDepsLog::load(&path).unwrap();
+        log.record(b"a.o", 1, &paths(&["a.c"]));
+        drop(log);
+        let good = std::fs::metadata(&path).unwrap().len();
+        let mut contents = std::fs::read(&path).unwrap();
+        contents.extend_from_slice(&[8, 0, 0, 0, b'b']);
+        std::fs::write(&path, &contents).unwrap();
+
+        let log = SharedDepsLog::load(&path).unwrap();
+        assert_eq!(log.0.borrow().log.valid_len, Some(good));
+        log.record(b"b.o", 2, &paths(&["b.c"]));
+        drop(log);
+        let reloaded = DepsLog::load(&path).unwrap();
+        assert_eq!(reloaded.valid_len, None);
+        assert_eq!(reloaded.deps(b"a.o").unwrap().inputs, paths(&["a.c"]));
+        assert_eq!(reloaded.deps(b"b.o").unwrap().inputs, paths(&["b.c"]));
+    }
+
+    #[test]
+    fn reads_ninja_log() {
+        // As written by ninja for `build foo.o: cc foo.c` with a depfile listing foo.h.
+        let mut contents = b"# ninjadeps\n\x04\x00\x00\x00".to_vec();
+        for (id, path) in [&b"foo.o"[..], b"foo.c", b"foo.h"].iter().enumerate() {
+            contents.extend_from_slice(&[12, 0, 0, 0]);
+            contents.extend_from_slice(path);
+            contents.extend_from_slice(&[0, 0, 0]);
+            contents.extend_from_slice(&(!(id as u32)).to_le_bytes());
+        }
+        contents.extend_from_slice(&[20, 0, 0, 0x80, 0, 0, 0, 0, 42, 0, 0, 0, 0, 0, 0, 0]);
+        contents.extend_from_slice(&[1, 0, 0, 0, 2, 0, 0, 0]);
+        let log = DepsLog::parse(&contents);
+        assert_eq!(
+            log.deps(b"foo.o"),
+            Some(RecordedDeps {
+                mtime: 42,
+                inputs: paths(&["foo.c", "foo.h"]),
+            })
+        );
+    }
+
+    #[test]
+    fn starts_over_after_torn_header() {
+        let (_dir, path) = temp_log("torn-header");
+        std::fs::write(&path, &HEADER[..5]).unwrap();
+        let log = SharedDepsLog::load(&path).unwrap();
+        assert_eq!(log.0.borrow().log.valid_len, Some(0));
+        log.record(b"a.o", 1, &paths(&["a.c"]));
+        drop(log);
+        let reloaded = DepsLog::load(&path).unwrap();
+        assert_eq!(reloaded.valid_len, None);
+        assert_eq!(reloaded.deps(b"a.o").unwrap().inputs, paths(&["a.c"]));
+    }
+
+    #[test]
+    fn drops_records_after_corrupt_one() {
+        let (_dir, path) = temp_log("corrupt");
+        let log = SharedDepsLog::load(&path).unwrap();
+        log.record(b"a.o", 1, &paths(&["a.c"]));
+        drop(log);
+        let good = std::fs::read(&path).unwrap();
+        // A deps record naming path ids that were never written, then a valid looking path.
+        let mut contents = good.clone();
+        contents.extend_from_slice(&[12, 0, 0, 0x80, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        contents.extend_from_slice(&[8, 0, 0, 0, b'b', b'.', b'o', 0, !2, !0, !0, !0]);
+        std::fs::write(&path, &contents).unwrap();
+
+        let log = DepsLog::load(&path).unwrap();
+        assert_eq!(log.valid_len, Some(good.len() as u64));
+        assert_eq!(log.paths.len(), 2);
+    }
+
+    #[test]
+    fn other_versions_start_over() {
+        let log = DepsLog::parse(b"# ninjadeps\n\x03\x00\x00\x00");
+        assert_eq!(log.valid_len, Some(0));
+        assert!(log.deps.is_empty());
+    }
+}
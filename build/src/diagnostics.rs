@@ -0,0 +1,174 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Warnings shared by every part of the build. The same problem tends to be hit by thousands of
+//! edges at once, so only the first few warnings of each kind are printed, and the rest are
+//! counted and summarized by `flush`.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+/// Warnings of one kind printed before the rest are only counted.
+pub const MAX_SIMILAR_WARNINGS: usize = 5;
+
+#[derive(Debug)]
+struct Coalescer {
+    limit: usize,
+    // Kinds in the order they were first seen, so the summary is stable.
+    kinds: Vec<&'static str>,
+    counts: HashMap<&'static str, usize>,
+    // Kinds whose total is always part of the summary.
+    summarized: HashSet<&'static str>,
+}
+
+impl Coalescer {
+    fn new(limit: usize) -> Self {
+        Coalescer {
+            limit,
+            kinds: Vec::new(),
+            counts: HashMap::new(),
+            summarized: HashSet::new(),
+        }
+    }
+
+    /// Whether this warning should be printed.
+    fn record(&mut self, kind: &'static str) -> bool {
+        let kinds = &mut self.kinds;
+        let count = self.counts.entry(kind).or_insert_with(|| {
+            kinds.push(kind);
+            0
+        });
+        *count += 1;
+        *count <= self.limit
+    }
+
+    /// Like `record`, and the summary will say how many warnings of this kind there were.
+    fn record_summarized(&mut self, kind: &'static str) -> bool {
+        self.summarized.insert(kind);
+        self.record(kind)
+    }
+
+    /// One line per kind that had warnings suppressed, or is summarized. Resets the counts.
+    fn summary(&mut self) -> Vec<String> {
+        let lines = self
+            .kinds
+            .iter()
+            .filter_map(|kind| {
+                let count = self.counts[kind];
+                let suppressed = count.saturating_sub(self.limit);
+                if self.summarized.contains(kind) {
+                    let not_shown = if suppressed > 0 {
+                        format!(", {} not shown", suppressed)
+                    } else {
+                        String::new()
+                    };
+                    Some(format!(
+                        "ninja: warning: {} warning(s){}: {}",
+                        count, not_shown, kind
+                    ))
+                } else if suppressed > 0 {
+                    Some(format!(
+                        "ninja: warning: suppressed {} similar warning(s): {}",
+                        suppressed, kind
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.kinds.clear();
+        self.counts.clear();
+        self.summarized.clear();
+        lines
+    }
+}
+
+thread_local! {
+    static WARNINGS: RefCell<Coalescer> = RefCell::new(Coalescer::new(MAX_SIMILAR_WARNINGS));
+}
+
+/// Prints `message` as a warning, unless too many warnings of the same `kind` were printed
+/// already. `kind` is shown in the summary, so it should describe the problem in a few words.
+pub fn warn(kind: &'static str, message: impl Display) {
+    if WARNINGS.with(|w| w.borrow_mut().record(kind)) {
+        eprintln!("ninja: warning: {}", message);
+    }
+}
+
+/// Like `warn`, for problems that are easy to miss among the output of a build, so `flush` says
+/// how many there were even if all of them were printed.
+pub fn warn_summarized(kind: &'static str, message: impl Display) {
+    if WARNINGS.with(|w| w.borrow_mut().record_summarized(kind)) {
+        eprintln!("ninja: warning: {}", message);
+    }
+}
+
+/// Reports how many warnings were suppressed, and totals of summarized ones. Call once everything that may warn is done.
+pub fn flush() {
+    for line in WARNINGS.with(|w| w.borrow_mut().summary()) {
+        eprintln!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prints_up_to_the_limit_per_kind() {
+        let mut c = Coalescer::new(2);
+        let printed: Vec<bool> = (0..4).map(|_| c.record("directory input")).collect();
+        assert_eq!(printed, vec![true, true, false, false]);
+        assert!(c.record("missing depfile"));
+        assert_eq!(
+            c.summary(),
+            vec!["ninja: warning: suppressed 2 similar warning(s): directory input"]
+        );
+    }
+
+    #[test]
+    fn summarized_kinds_always_have_a_total() {
+        let mut c = Coalescer::new(2);
+        c.record("directory input");
+        c.record_summarized("outputs not updated");
+        assert_eq!(
+            c.summary(),
+            vec!["ninja: warning: 1 warning(s): outputs not updated"]
+        );
+        for _ in 0..3 {
+            c.record_summarized("outputs not updated");
+        }
+        assert_eq!(
+            c.summary(),
+            vec!["ninja: warning: 3 warning(s), 1 not shown: outputs not updated"]
+        );
+        c.record("outputs not updated");
+        assert!(c.summary().is_empty(), "only until the summary");
+    }
+
+    #[test]
+    fn summary_resets() {
+        let mut c = Coalescer::new(1);
+        c.record("a");
+        c.record("a");
+        assert_eq!(c.summary().len(), 1);
+        assert!(c.summary().is_empty());
+        assert!(c.record("a"));
+    }
+}
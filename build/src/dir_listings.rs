@@ -0,0 +1,220 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The listing of each directory input as the last build saw it, for `--directory-inputs=contents`.
+//! Comparing a directory's listing with the one recorded here is what notices files that were
+//! removed or renamed, which leave no newer mtime behind.
+//!
+//! After the header, each line is the hash of a directory's listing in hex, when that listing was
+//! first seen, in nanoseconds since the Unix epoch, and the directory, separated by tabs. Lines
+//! that can't be read are dropped, which only makes the directories they were for look changed.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{atomic::write_atomic, diagnostics};
+
+const HEADER: &str = "# ninja_rs dirs v";
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Listing {
+    hash: u64,
+    /// Nanoseconds since the Unix epoch.
+    since: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct DirListings {
+    listings: HashMap<Vec<u8>, Listing>,
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+}
+
+impl DirListings {
+    /// Reads the listings at `path`. Missing listings are empty ones.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(Self::parse(&contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Never fails, since forgetting a listing only means its dependents are built again.
+    pub fn parse(contents: &[u8]) -> Self {
+        let mut listings = Self::default();
+        let mut lines = contents.split(|b| *b == b'\n');
+        let header = format!("{}{}", HEADER, VERSION);
+        if lines.next() != Some(header.as_bytes()) {
+            return listings;
+        }
+        for line in lines {
+            let mut fields = line.splitn(3, |b| *b == b'\t');
+            let number = |field: Option<&[u8]>, radix| {
+                std::str::from_utf8(field?)
+                    .ok()
+                    .and_then(|s| u64::from_str_radix(s, radix).ok())
+            };
+            if let (Some(hash), Some(since), Some(dir)) = (
+                number(fields.next(), 16),
+                number(fields.next(), 10),
+                fields.next(),
+            ) {
+                listings
+                    .listings
+                    .insert(dir.to_vec(), Listing { hash, since });
+            }
+        }
+        listings
+    }
+
+    fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}{}", HEADER, VERSION)?;
+        let mut dirs: Vec<_> = self.listings.iter().collect();
+        dirs.sort_by(|a, b| a.0.cmp(b.0));
+        for (dir, listing) in dirs {
+            write!(w, "{:x}\t{}\t", listing.hash, listing.since)?;
+            w.write_all(dir)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// When `dir`, whose listing hashes to `hash`, last changed, recording its listing if it is
+    /// new. A listing that isn't the recorded one changed just now, even if every mtime in it is
+    /// older, since files may have been removed. One seen for the first time is only as new as
+    /// `newest`, so that starting to record listings doesn't rebuild everything.
+    fn changed(&mut self, dir: &[u8], hash: u64, newest: SystemTime) -> (SystemTime, bool) {
+        let since = match self.listings.get(dir) {
+            Some(listing) if listing.hash == hash => {
+                return (UNIX_EPOCH + Duration::from_nanos(listing.since), false)
+            }
+            Some(_) => SystemTime::now(),
+            None => newest,
+        };
+        self.listings.insert(
+            dir.to_vec(),
+            Listing {
+                hash,
+                since: nanos(since),
+            },
+        );
+        (since, true)
+    }
+}
+
+struct OpenListings {
+    listings: DirListings,
+    path: PathBuf,
+    failed: bool,
+}
+
+/// The listings for the length of an invocation. Each change is written out as the rebuilder
+/// finds it, so a build that is stopped halfway still rebuilds what depends on the directory the
+/// next time. Cloning shares the listings.
+#[derive(Clone)]
+pub struct SharedDirListings(Rc<RefCell<OpenListings>>);
+
+impl fmt::Debug for SharedDirListings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedDirListings")
+            .field(&self.0.borrow().path)
+            .finish()
+    }
+}
+
+impl SharedDirListings {
+    /// Reads the listings at `path`, which is where they will be written.
+    pub fn load<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let listings = DirListings::load(&path)?;
+        Ok(SharedDirListings(Rc::new(RefCell::new(OpenListings {
+            listings,
+            path,
+            failed: false,
+        }))))
+    }
+
+    /// Like `DirListings::changed`. Like the build log, writing is best effort. The first error is
+    /// reported and later listings are only remembered for the rest of this invocation.
+    pub fn changed(&self, dir: &[u8], hash: u64, newest: SystemTime) -> SystemTime {
+        let mut open = self.0.borrow_mut();
+        let open = &mut *open;
+        let (since, recorded) = open.listings.changed(dir, hash, newest);
+        if recorded && !open.failed {
+            let listings = &open.listings;
+            if let Err(e) = write_atomic(&open.path, false, |w| listings.write_to(w)) {
+                diagnostics::warn(
+                    "directory listings",
+                    format_args!("writing {}: {}", open.path.display(), e),
+                );
+                open.failed = true;
+            }
+        }
+        since
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn new_listings_are_as_old_as_their_files() {
+        let mut listings = DirListings::default();
+        assert_eq!(listings.changed(b"src", 1, at(100)), (at(100), true));
+        assert_eq!(listings.changed(b"src", 1, at(200)), (at(100), false));
+        let (since, recorded) = listings.changed(b"src", 2, at(100));
+        assert!(recorded);
+        assert!(since > at(200));
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("dir-listings-round-trip");
+        let path = dir.join("dirs");
+        let listings = SharedDirListings::load(&path).unwrap();
+        listings.changed(b"src", 0xabc, at(100));
+        listings.changed(b"with\ttab", 1, at(200));
+
+        let mut read = DirListings::load(&path).unwrap();
+        assert_eq!(read.changed(b"src", 0xabc, at(300)), (at(100), false));
+        assert_eq!(read.changed(b"with\ttab", 1, at(300)), (at(200), false));
+
+        std::fs::write(&path, b"# ninja_rs dirs v0\nabc\t100\tsrc\n").unwrap();
+        let mut other_version = DirListings::load(&path).unwrap();
+        assert_eq!(
+            other_version.changed(b"src", 0xabc, at(300)),
+            (at(300), true)
+        );
+    }
+}
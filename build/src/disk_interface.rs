@@ -15,10 +15,40 @@
  */
 
 use ninja_metrics::scoped_metric;
-use std::{io::Result, path::Path, time::SystemTime};
+use std::{
+    io::Result,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// An entry of a directory, as it was listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    /// False for a symlink, even one to a directory, so that walks don't follow links back up
+    /// the tree.
+    pub is_dir: bool,
+}
 
 pub trait DiskInterface {
     fn modified<P: AsRef<Path>>(&self, p: P) -> Result<SystemTime>;
+
+    /// Only consulted for build inputs. Interfaces that never deal with directories can rely on
+    /// the default.
+    fn is_dir<P: AsRef<Path>>(&self, _p: P) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Entries directly inside the directory `p`.
+    fn read_dir<P: AsRef<Path>>(&self, _p: P) -> Result<Vec<DirEntry>> {
+        Ok(Vec::new())
+    }
+
+    /// Only used to read depfiles. Interfaces for builds without them can rely on the default,
+    /// which finds nothing.
+    fn read<P: AsRef<Path>>(&self, _p: P) -> Result<Vec<u8>> {
+        Err(std::io::ErrorKind::NotFound.into())
+    }
 }
 
 pub struct SystemDiskInterface;
@@ -27,4 +57,27 @@ impl DiskInterface for SystemDiskInterface {
         scoped_metric!("stat");
         std::fs::metadata(p)?.modified()
     }
+
+    fn is_dir<P: AsRef<Path>>(&self, p: P) -> Result<bool> {
+        scoped_metric!("stat");
+        Ok(std::fs::metadata(p)?.is_dir())
+    }
+
+    fn read_dir<P: AsRef<Path>>(&self, p: P) -> Result<Vec<DirEntry>> {
+        scoped_metric!("read_dir");
+        std::fs::read_dir(p)?
+            .map(|entry| {
+                let entry = entry?;
+                Ok(DirEntry {
+                    is_dir: entry.file_type()?.is_dir(),
+                    path: entry.path(),
+                })
+            })
+            .collect()
+    }
+
+    fn read<P: AsRef<Path>>(&self, p: P) -> Result<Vec<u8>> {
+        scoped_metric!("read_depfile");
+        std::fs::read(p)
+    }
 }
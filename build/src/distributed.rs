@@ -0,0 +1,1199 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Experimental distributed builds, for a shared filesystem.
+//!
+//! A coordinator parses the manifest, decides what is dirty and hands ready edges to workers on
+//! other machines, which only run commands. Workers must be started in the directory the
+//! coordinator builds in, as it appears on their machine, since commands and their namespaces are
+//! sent as relative paths. The coordinator checks outputs itself, so they must be visible to it
+//! once a worker reports success.
+//!
+//! Anyone who can talk to a worker can run any command as the user it runs as, so a coordinator
+//! first has to send the token the worker was given, and a worker closes connections that don't
+//! before reading anything else. Nothing is encrypted, so the token and the commands can be read
+//! by anyone on the network in between.
+//!
+//! The protocol is a stream of length-prefixed frames over TCP. Once the coordinator is let in, a
+//! worker greets it with the number of commands it runs at once (its slots). The coordinator keeps the ready edges
+//! and sends each worker up to twice its slots, so workers never sit waiting for the next
+//! command. When the ready edges run out, an idle worker steals: the coordinator asks a worker
+//! holding more commands than slots to return its newest one, and sends it to the idle worker if
+//! it had not started yet.
+//!
+//! When a worker goes away, the edges it had not reported on are handed to the remaining workers.
+//! When the coordinator goes away, workers kill the commands it was waiting for.
+//!
+//! Batching, `--command-timeout` and the frontend are only implemented for local builds.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    os::unix::process::ExitStatusExt,
+    process::{ExitStatus, Output},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use petgraph::graph::NodeIndex;
+use tokio::task::LocalSet;
+
+use crate::task::{Key, Tasks};
+use crate::{
+    build_log::SharedBuildLog,
+    build_task::{
+        check_outputs_exist, create_output_dirs, remove_rspfile, run_script, write_rspfile,
+    },
+    deps_log::SharedDepsLog,
+    interface, record_command,
+    status_format::StatusFormat,
+    BuildError, BuildReport, BuildState, CommandFailure, CommandTaskError, CommandTaskResult,
+    Outcome, ParallelTopoScheduler, Printer, SchedulerGraph, Workspace,
+};
+
+/// Bumped whenever a message changes, since coordinator and workers may be different builds.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Larger frames are treated as corruption rather than allocated.
+const MAX_FRAME: usize = 1 << 30;
+
+/// The largest frame a worker reads before the coordinator has sent the token, so strangers can't
+/// make it allocate much.
+const MAX_AUTH_FRAME: usize = 4096;
+
+/// How long a worker waits for the token, so a connection that never sends one doesn't keep
+/// coordinators out.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Commands a worker is sent per slot. Anything beyond the first is queued on the worker, and can
+/// be stolen.
+const COMMANDS_PER_SLOT: usize = 2;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    /// Coordinator to worker, once, as soon as it connects.
+    Auth { token: Vec<u8> },
+    /// Worker to coordinator, once, after the coordinator's token was accepted.
+    Hello { version: u32, slots: u32 },
+    Run {
+        id: u64,
+        namespace: Option<Vec<u8>>,
+        command: String,
+    },
+    /// Asks for a command back, if it has not started.
+    Steal { id: u64 },
+    /// The command was not started, and won't be.
+    Returned { id: u64 },
+    Done {
+        id: u64,
+        /// As returned by `waitpid`.
+        status: i32,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
+    SpawnFailed {
+        id: u64,
+        /// The errno, so the coordinator can report it as if it had failed locally.
+        errno: i32,
+    },
+}
+
+fn put_bytes(frame: &mut Vec<u8>, bytes: &[u8]) {
+    frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    frame.extend_from_slice(bytes);
+}
+
+fn write_message(writer: &mut impl Write, message: &Message) -> io::Result<()> {
+    let mut frame = Vec::new();
+    match message {
+        Message::Auth { token } => {
+            frame.push(7);
+            put_bytes(&mut frame, token);
+        }
+        Message::Hello { version, slots } => {
+            frame.push(1);
+            frame.extend_from_slice(&version.to_be_bytes());
+            frame.extend_from_slice(&slots.to_be_bytes());
+        }
+        Message::Run {
+            id,
+            namespace,
+            command,
+        } => {
+            frame.push(2);
+            frame.extend_from_slice(&id.to_be_bytes());
+            match namespace {
+                Some(namespace) => {
+                    frame.push(1);
+                    put_bytes(&mut frame, namespace);
+                }
+                None => frame.push(0),
+            }
+            put_bytes(&mut frame, command.as_bytes());
+        }
+        Message::Steal { id } => {
+            frame.push(3);
+            frame.extend_from_slice(&id.to_be_bytes());
+        }
+        Message::Returned { id } => {
+            frame.push(4);
+            frame.extend_from_slice(&id.to_be_bytes());
+        }
+        Message::Done {
+            id,
+            status,
+            stdout,
+            stderr,
+        } => {
+            frame.push(5);
+            frame.extend_from_slice(&id.to_be_bytes());
+            frame.extend_from_slice(&status.to_be_bytes());
+            put_bytes(&mut frame, stdout);
+            put_bytes(&mut frame, stderr);
+        }
+        Message::SpawnFailed { id, errno } => {
+            frame.push(6);
+            frame.extend_from_slice(&id.to_be_bytes());
+            frame.extend_from_slice(&errno.to_be_bytes());
+        }
+    }
+    writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// Reads the fields of one frame in order.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("truncated message"));
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid("message is not UTF-8"))
+    }
+}
+
+/// None if the stream ended cleanly between messages.
+fn read_message(reader: &mut impl Read) -> io::Result<Option<Message>> {
+    read_message_within(reader, MAX_FRAME)
+}
+
+fn read_message_within(reader: &mut impl Read, max_frame: usize) -> io::Result<Option<Message>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_frame {
+        return Err(invalid("message too large"));
+    }
+    let mut frame = vec![0u8; len];
+    reader.read_exact(&mut frame)?;
+    let mut fields = Fields(&frame);
+    let message = match fields.u8()? {
+        1 => Message::Hello {
+            version: fields.u32()?,
+            slots: fields.u32()?,
+        },
+        2 => Message::Run {
+            id: fields.u64()?,
+            namespace: match fields.u8()? {
+                0 => None,
+                _ => Some(fields.bytes()?),
+            },
+            command: fields.string()?,
+        },
+        3 => Message::Steal { id: fields.u64()? },
+        4 => Message::Returned { id: fields.u64()? },
+        5 => Message::Done {
+            id: fields.u64()?,
+            status: fields.i32()?,
+            stdout: fields.bytes()?,
+            stderr: fields.bytes()?,
+        },
+        6 => Message::SpawnFailed {
+            id: fields.u64()?,
+            errno: fields.i32()?,
+        },
+        7 => Message::Auth {
+            token: fields.bytes()?,
+        },
+        _ => return Err(invalid("unknown message")),
+    };
+    if !fields.0.is_empty() {
+        return Err(invalid("trailing bytes in message"));
+    }
+    Ok(Some(message))
+}
+
+/// Runs commands for coordinators that connect to `listener` and send `token`, one coordinator at
+/// a time and up to `slots` commands at once, in `workspace`. Only returns if accepting fails, or
+/// right away if `token` is empty.
+pub fn serve(
+    listener: TcpListener,
+    slots: usize,
+    workspace: &Workspace,
+    token: &[u8],
+) -> io::Result<()> {
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "an empty token would let anyone run commands",
+        ));
+    }
+    for stream in listener.incoming() {
+        let stream = stream?;
+        match authenticate(&stream, token) {
+            Ok(true) => {}
+            Ok(false) => {
+                let peer = stream.peer_addr().map(|peer| peer.to_string());
+                eprintln!(
+                    "ninja: worker: refused {}, which did not send the token",
+                    peer.as_deref().unwrap_or("a connection")
+                );
+                continue;
+            }
+            Err(e) => {
+                eprintln!("ninja: worker: refused a connection: {}", e);
+                continue;
+            }
+        }
+        if let Err(e) = serve_coordinator(stream, std::cmp::max(slots, 1), workspace) {
+            eprintln!("ninja: worker: lost coordinator: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Whether the first message on `stream` is `token`.
+fn authenticate(stream: &TcpStream, token: &[u8]) -> io::Result<bool> {
+    stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+    let authenticated = match read_message_within(&mut &*stream, MAX_AUTH_FRAME)? {
+        Some(Message::Auth { token: sent }) => same_secret(&sent, token),
+        _ => false,
+    };
+    stream.set_read_timeout(None)?;
+    Ok(authenticated)
+}
+
+/// Looks at every byte even after a difference, so how long it takes doesn't tell how much of a
+/// guess was right.
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+enum WorkerEvent {
+    Received(Message),
+    Disconnected,
+    Finished(u64, CommandTaskResult),
+}
+
+fn result_message(id: u64, result: CommandTaskResult) -> Message {
+    match result {
+        Ok(output)
+        | Err(CommandTaskError::CommandFailed(output))
+        | Err(CommandTaskError::Signaled { output, .. }) => Message::Done {
+            id,
+            status: output.status.into_raw(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        },
+        // Running a script locally can only fail otherwise by failing to spawn or wait for it.
+        Err(CommandTaskError::SpawnFailed(e)) => Message::SpawnFailed {
+            id,
+            errno: e.error.raw_os_error().unwrap_or(libc::EIO),
+        },
+        Err(_) => unreachable!("not produced by run_script"),
+    }
+}
+
+fn serve_coordinator(stream: TcpStream, slots: usize, workspace: &Workspace) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    write_message(
+        &mut writer,
+        &Message::Hello {
+            version: PROTOCOL_VERSION,
+            slots: slots as u32,
+        },
+    )?;
+    let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let reader_sender = sender.clone();
+    let mut reader = stream;
+    std::thread::spawn(move || loop {
+        let event = match read_message(&mut reader) {
+            Ok(Some(message)) => WorkerEvent::Received(message),
+            _ => WorkerEvent::Disconnected,
+        };
+        let disconnected = matches!(event, WorkerEvent::Disconnected);
+        if reader_sender.send(event).is_err() || disconnected {
+            break;
+        }
+    });
+
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    let local_set = LocalSet::new();
+    let result = local_set.block_on(&mut runtime, async {
+        let mut queued = std::collections::VecDeque::new();
+        let mut running = 0;
+        while let Some(event) = events.recv().await {
+            match event {
+                WorkerEvent::Received(Message::Run {
+                    id,
+                    namespace,
+                    command,
+                }) => queued.push_back((id, namespace, command)),
+                WorkerEvent::Received(Message::Steal { id }) => {
+                    // Otherwise it has started, and its result will follow.
+                    if let Some(position) = queued.iter().position(|(queued, _, _)| *queued == id) {
+                        queued.remove(position);
+                        write_message(&mut writer, &Message::Returned { id })?;
+                    }
+                }
+                WorkerEvent::Received(message) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected {:?}", message),
+                    ));
+                }
+                WorkerEvent::Disconnected => return Ok(()),
+                WorkerEvent::Finished(id, result) => {
+                    running -= 1;
+                    write_message(&mut writer, &result_message(id, result))?;
+                }
+            }
+            while running < slots {
+                let (id, namespace, command) = match queued.pop_front() {
+                    Some(run) => run,
+                    None => break,
+                };
+                running += 1;
+                let sender = sender.clone();
+                let workspace = workspace.clone();
+                tokio::task::spawn_local(async move {
+                    let result =
+                        run_script(&workspace, &command, namespace.as_deref(), false).await;
+                    let _ = sender.send(WorkerEvent::Finished(id, result));
+                });
+            }
+        }
+        Ok(())
+    });
+    // Dropping the local set drops the commands still running, which kills them.
+    drop(local_set);
+    let _ = writer.shutdown(Shutdown::Both);
+    result
+}
+
+enum CoordinatorEvent {
+    Received(usize, Message),
+    Disconnected(usize),
+}
+
+#[derive(Debug)]
+struct Worker {
+    address: String,
+    /// None once the worker has gone away.
+    writer: Option<TcpStream>,
+    slots: usize,
+    /// Ids of the commands sent and not yet reported on, oldest first.
+    sent: Vec<u64>,
+    /// A command this worker was asked to give back.
+    stealing: Option<u64>,
+}
+
+impl Worker {
+    fn connect(
+        address: &str,
+        index: usize,
+        events: mpsc::Sender<CoordinatorEvent>,
+        token: &[u8],
+    ) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        write_message(
+            &mut stream,
+            &Message::Auth {
+                token: token.to_vec(),
+            },
+        )?;
+        let slots = match read_message(&mut stream)? {
+            Some(Message::Hello { version, slots }) if version == PROTOCOL_VERSION => slots,
+            Some(Message::Hello { version, .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "worker speaks protocol version {}, not {}",
+                        version, PROTOCOL_VERSION
+                    ),
+                ))
+            }
+            // Workers close the connection without a word if the token is wrong.
+            None => return Err(invalid("worker refused the token")),
+            _ => return Err(invalid("worker did not say hello")),
+        };
+        let mut reader = stream.try_clone()?;
+        std::thread::spawn(move || loop {
+            match read_message(&mut reader) {
+                Ok(Some(message)) => {
+                    if events
+                        .send(CoordinatorEvent::Received(index, message))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                _ => {
+                    let _ = events.send(CoordinatorEvent::Disconnected(index));
+                    break;
+                }
+            }
+        });
+        Ok(Worker {
+            address: address.to_owned(),
+            writer: Some(stream),
+            slots: std::cmp::max(slots as usize, 1),
+            sent: Vec::new(),
+            stealing: None,
+        })
+    }
+
+    fn has_room(&self, per_slot: usize) -> bool {
+        self.writer.is_some() && self.sent.len() < self.slots * per_slot
+    }
+
+    fn gone(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Workers with free slots are filled first, least loaded first, then up to `COMMANDS_PER_SLOT`.
+fn pick_worker(workers: &[Worker]) -> Option<usize> {
+    [1, COMMANDS_PER_SLOT].iter().find_map(|&per_slot| {
+        workers
+            .iter()
+            .enumerate()
+            .filter(|(_, worker)| worker.has_room(per_slot))
+            .min_by_key(|(_, worker)| worker.sent.len() * 1000 / worker.slots)
+            .map(|(index, _)| index)
+    })
+}
+
+/// Runs commands on worker processes instead of locally. See the module documentation.
+#[derive(Debug)]
+pub struct DistributedScheduler {
+    workers: Vec<String>,
+    failures_allowed: usize,
+    verbose: bool,
+    status_format: StatusFormat,
+    build_log: Option<SharedBuildLog>,
+    deps_log: Option<SharedDepsLog>,
+    workspace: Workspace,
+    token: Vec<u8>,
+}
+
+impl DistributedScheduler {
+    /// `workers` are `host:port` addresses of processes running `serve` with `token`.
+    pub fn new(workers: Vec<String>, token: Vec<u8>) -> Self {
+        DistributedScheduler {
+            workers,
+            token,
+            failures_allowed: 1,
+            verbose: false,
+            status_format: StatusFormat::default(),
+            build_log: None,
+            deps_log: None,
+            workspace: Workspace::default(),
+        }
+    }
+
+    /// Like `ParallelTopoScheduler::keep_going`.
+    pub fn keep_going(mut self, failures_allowed: usize) -> Self {
+        self.failures_allowed = std::cmp::max(failures_allowed, 1);
+        self
+    }
+
+    /// Like `ParallelTopoScheduler::verbose`.
+    pub fn verbose(mut self) -> Self {
+        self.verbose = true;
+        self
+    }
+
+    /// Like `ParallelTopoScheduler::status_format`.
+    pub fn status_format(mut self, format: StatusFormat) -> Self {
+        self.status_format = format;
+        self
+    }
+
+    /// Like `ParallelTopoScheduler::build_log`. Commands are timed from when they are sent.
+    pub fn build_log(mut self, log: SharedBuildLog) -> Self {
+        self.build_log = Some(log);
+        self
+    }
+
+    /// Like `ParallelTopoScheduler::deps_log`. Depfiles are read from the shared filesystem.
+    pub fn deps_log(mut self, log: SharedDepsLog) -> Self {
+        self.deps_log = Some(log);
+        self
+    }
+
+    /// Where outputs are looked for, and their directories created, before and after commands run
+    /// remotely. Workers run commands in their own workspace.
+    pub fn workspace(mut self, workspace: Workspace) -> Self {
+        self.workspace = workspace;
+        self
+    }
+
+    fn schedule_internal(
+        &self,
+        rebuilder: &impl interface::Rebuilder<Key, CommandTaskResult>,
+        tasks: &Tasks,
+        start: Option<&[Key]>,
+    ) -> Result<BuildReport, BuildError> {
+        let graph = ParallelTopoScheduler::build_graph(tasks, start)?;
+        let mut build_state = BuildState::for_start(&graph, start, false);
+        let (sender, events) = mpsc::channel();
+        let mut workers = Vec::new();
+        for address in &self.workers {
+            match Worker::connect(address, workers.len(), sender.clone(), &self.token) {
+                Ok(worker) => workers.push(worker),
+                Err(e) => eprintln!("ninja: warning: not using worker {}: {}", address, e),
+            }
+        }
+        // Only the workers' readers may keep the channel open, so it closes when all are gone.
+        drop(sender);
+
+        let mut next_id = 0u64;
+        let mut assigned: HashMap<u64, (usize, NodeIndex)> = HashMap::new();
+        // Edges known to be dirty. Dirtiness is only decided once, since a command that was
+        // interrupted on a worker may have left newer, but broken, outputs behind.
+        let mut started: HashSet<NodeIndex> = HashSet::new();
+        let mut printer = Printer::default();
+        printer.verbose(self.verbose);
+        printer.status_format(self.status_format.clone());
+        printer.expect(build_state.commands(&graph, tasks));
+        let mut progress = Progress {
+            printer,
+            report: BuildReport::default(),
+            failures_allowed: self.failures_allowed,
+            stopped: false,
+            build_log: self.build_log.clone(),
+            deps_log: self.deps_log.clone(),
+            workspace: self.workspace.clone(),
+            sent_at: HashMap::new(),
+        };
+
+        while !build_state.done() {
+            while !progress.stopped && !build_state.done() {
+                let index = match pick_worker(&workers) {
+                    Some(index) => index,
+                    None => break,
+                };
+                let node = match build_state.ready.pop_front() {
+                    Some(node) => node,
+                    None => break,
+                };
+                let key = graph[node];
+                let task = match tasks.task(key) {
+                    Some(task) if task.is_command() => task,
+                    // Sources and phony edges have nothing to run.
+                    phony => {
+                        if phony.is_some() {
+                            progress.report.record(key, Outcome::Succeeded);
+                        }
+                        build_state.finish_node(&graph, node, true);
+                        continue;
+                    }
+                };
+                if !started.contains(&node) {
+                    if rebuilder
+                        .build(key.clone(), None, task)
+                        .map_err(|e| BuildError::RebuilderError(Box::new(e)))?
+                        .is_none()
+                    {
+                        progress.report.record(key, Outcome::UpToDate);
+                        progress.printer.up_to_date(task);
+                        build_state.finish_node(&graph, node, true);
+                        continue;
+                    }
+                    started.insert(node);
+                    progress.printer.started(task);
+                    // Workers share the filesystem, so the response file can be written here.
+                    if let Err(e) = create_output_dirs(&self.workspace, key)
+                        .and_then(|()| write_rspfile(&self.workspace, task.rspfile.as_ref()))
+                    {
+                        progress.finished(
+                            rebuilder,
+                            &graph,
+                            tasks,
+                            &mut build_state,
+                            node,
+                            Err(e.into()),
+                        )?;
+                        continue;
+                    }
+                }
+                let id = next_id;
+                next_id += 1;
+                let run = Message::Run {
+                    id,
+                    namespace: key.namespace().map(<[u8]>::to_vec),
+                    command: task.command().unwrap().clone(),
+                };
+                let worker = &mut workers[index];
+                match write_message(worker.writer.as_mut().unwrap(), &run) {
+                    Ok(()) => {
+                        worker.sent.push(id);
+                        assigned.insert(id, (index, node));
+                        progress.sent_at.insert(node, Instant::now());
+                    }
+                    Err(_) => {
+                        // Its reader will report it gone, and hand off what it had.
+                        worker.gone();
+                        build_state.ready.push_front(node);
+                    }
+                }
+            }
+            if build_state.done() {
+                break;
+            }
+            if assigned.is_empty() {
+                if progress.stopped {
+                    break;
+                }
+                if workers.iter().all(|worker| worker.writer.is_none()) {
+                    return Err(BuildError::Distributed(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "no workers left to run commands",
+                    )));
+                }
+            }
+
+            if build_state.ready.is_empty() && !progress.stopped {
+                steal(&mut workers);
+            }
+
+            let event = match events.recv() {
+                Ok(event) => event,
+                Err(_) => {
+                    for index in 0..workers.len() {
+                        hand_off(index, &mut workers, &mut assigned, &mut build_state);
+                    }
+                    continue;
+                }
+            };
+            match event {
+                CoordinatorEvent::Received(index, Message::Returned { id }) => {
+                    if let Some((_, node)) = forget(&mut workers[index], &mut assigned, id) {
+                        build_state.ready.push_front(node);
+                    }
+                }
+                CoordinatorEvent::Received(
+                    index,
+                    Message::Done {
+                        id,
+                        status,
+                        stdout,
+                        stderr,
+                    },
+                ) => {
+                    if let Some((_, node)) = forget(&mut workers[index], &mut assigned, id) {
+                        let output = Output {
+                            status: ExitStatus::from_raw(status),
+                            stdout,
+                            stderr,
+                        };
+                        let result = remote_result(&self.workspace, graph[node], output);
+                        progress.finished(
+                            rebuilder,
+                            &graph,
+                            tasks,
+                            &mut build_state,
+                            node,
+                            result,
+                        )?;
+                    }
+                }
+                CoordinatorEvent::Received(index, Message::SpawnFailed { id, errno }) => {
+                    if let Some((_, node)) = forget(&mut workers[index], &mut assigned, id) {
+                        let error = io::Error::from_raw_os_error(errno);
+                        progress.finished(
+                            rebuilder,
+                            &graph,
+                            tasks,
+                            &mut build_state,
+                            node,
+                            Err(error.into()),
+                        )?;
+                    }
+                }
+                CoordinatorEvent::Received(index, message) => {
+                    eprintln!(
+                        "ninja: warning: dropping worker {}: unexpected {:?}",
+                        workers[index].address, message
+                    );
+                    hand_off(index, &mut workers, &mut assigned, &mut build_state);
+                }
+                CoordinatorEvent::Disconnected(index) => {
+                    hand_off(index, &mut workers, &mut assigned, &mut build_state);
+                }
+            }
+        }
+        for worker in &mut workers {
+            worker.gone();
+        }
+
+        progress.report.finish(&graph, tasks)
+    }
+}
+
+/// What finished edges are reported to, like the local scheduler's printer and report.
+struct Progress {
+    printer: Printer,
+    report: BuildReport,
+    failures_allowed: usize,
+    /// No new commands are sent once this is set.
+    stopped: bool,
+    build_log: Option<SharedBuildLog>,
+    deps_log: Option<SharedDepsLog>,
+    workspace: Workspace,
+    /// When each running edge was last sent to a worker.
+    sent_at: HashMap<NodeIndex, Instant>,
+}
+
+impl Progress {
+    /// Fails only if the rebuilder can't take the result into account.
+    fn finished(
+        &mut self,
+        rebuilder: &impl interface::Rebuilder<Key, CommandTaskResult>,
+        graph: &SchedulerGraph,
+        tasks: &Tasks,
+        build_state: &mut BuildState,
+        node: NodeIndex,
+        result: CommandTaskResult,
+    ) -> Result<(), BuildError> {
+        let key = graph[node];
+        let task = tasks.task(key).unwrap();
+        let sent_at = self.sent_at.remove(&node).unwrap_or_else(Instant::now);
+        let result = record_command(
+            &self.workspace,
+            key,
+            task,
+            sent_at,
+            result,
+            self.build_log.as_ref(),
+            self.deps_log.as_ref(),
+        );
+        if result.is_ok() {
+            remove_rspfile(&self.workspace, task.rspfile.as_ref());
+            rebuilder
+                .finished(key.clone(), task)
+                .map_err(|e| BuildError::RebuilderError(Box::new(e)))?;
+        }
+        build_state.finish_node(graph, node, result.is_ok());
+        match &result {
+            Ok(_) => self.report.record(key, Outcome::Succeeded),
+            Err(err) => {
+                self.report.record_failure(
+                    CommandFailure::new(
+                        key.clone(),
+                        task.command().cloned().unwrap_or_default(),
+                        err,
+                    )
+                    .with_rule(tasks.rule_for(key)),
+                );
+                self.stopped |=
+                    err.is_interrupt() || self.report.failures().len() >= self.failures_allowed;
+            }
+        }
+        self.printer.finished(key, task, result);
+        Ok(())
+    }
+}
+
+fn forget(
+    worker: &mut Worker,
+    assigned: &mut HashMap<u64, (usize, NodeIndex)>,
+    id: u64,
+) -> Option<(usize, NodeIndex)> {
+    worker.sent.retain(|sent| *sent != id);
+    if worker.stealing == Some(id) {
+        worker.stealing = None;
+    }
+    // Results for edges that were handed off already are stale.
+    assigned.remove(&id)
+}
+
+/// Puts everything `index` had not reported on back at the front of the ready queue.
+fn hand_off(
+    index: usize,
+    workers: &mut [Worker],
+    assigned: &mut HashMap<u64, (usize, NodeIndex)>,
+    build_state: &mut BuildState,
+) {
+    let worker = &mut workers[index];
+    worker.gone();
+    let sent = std::mem::take(&mut worker.sent);
+    worker.stealing = None;
+    let mut handed_off = 0;
+    for id in sent.into_iter().rev() {
+        if let Some((_, node)) = assigned.remove(&id) {
+            build_state.ready.push_front(node);
+            handed_off += 1;
+        }
+    }
+    if handed_off > 0 {
+        eprintln!(
+            "ninja: warning: lost worker {}, handing off {} command(s)",
+            worker.address, handed_off
+        );
+    }
+}
+
+/// When some worker has a free slot and nothing is ready, ask the busiest worker with queued
+/// commands for its newest one back.
+fn steal(workers: &mut [Worker]) {
+    if !workers.iter().any(|worker| worker.has_room(1)) {
+        return;
+    }
+    let victim = workers
+        .iter_mut()
+        .filter(|worker| {
+            worker.writer.is_some() && worker.stealing.is_none() && worker.sent.len() > worker.slots
+        })
+        .max_by_key(|worker| worker.sent.len() * 1000 / worker.slots);
+    if let Some(victim) = victim {
+        let id = *victim.sent.last().unwrap();
+        if write_message(victim.writer.as_mut().unwrap(), &Message::Steal { id }).is_ok() {
+            victim.stealing = Some(id);
+        } else {
+            victim.gone();
+        }
+    }
+}
+
+fn remote_result(workspace: &Workspace, key: &Key, output: Output) -> CommandTaskResult {
+    if let Some(signal) = output.status.signal() {
+        return Err(CommandTaskError::Signaled { output, signal });
+    }
+    if !output.status.success() {
+        return Err(CommandTaskError::CommandFailed(output));
+    }
+    check_outputs_exist(workspace, key, output)
+}
+
+impl interface::Scheduler<Key, CommandTaskResult> for DistributedScheduler {
+    type Report = BuildReport;
+    type Error = BuildError;
+
+    fn schedule(
+        &self,
+        rebuilder: &impl interface::Rebuilder<Key, CommandTaskResult>,
+        tasks: &Tasks,
+        start: Vec<Key>,
+    ) -> Result<BuildReport, Self::Error> {
+        self.schedule_internal(rebuilder, tasks, Some(&start))
+    }
+
+    fn schedule_externals(
+        &self,
+        rebuilder: &impl interface::Rebuilder<Key, CommandTaskResult>,
+        tasks: &Tasks,
+    ) -> Result<BuildReport, Self::Error> {
+        self.schedule_internal(rebuilder, tasks, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        build_externals, caching_mtime_rebuilder, task::TasksBuilder, testing::TempDir,
+        DirectoryInputs,
+    };
+
+    const TOKEN: &[u8] = b"sesame";
+
+    fn start_worker(slots: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || serve(listener, slots, &Workspace::default(), TOKEN));
+        address
+    }
+
+    fn scheduler(workers: Vec<String>) -> DistributedScheduler {
+        DistributedScheduler::new(workers, TOKEN.to_vec())
+    }
+
+    /// `c` depends on `a` and `b`, and every command appends its name to `log`.
+    fn diamond(dir: &TempDir) -> Tasks {
+        let touch = |p: &str| format!("echo {} >> {} && touch {}", p, dir.path("log"), dir.path(p));
+        let no_inputs: &[&str] = &[];
+        let mut builder = TasksBuilder::new();
+        builder
+            .command(&[dir.path("a")], no_inputs, touch("a"))
+            .unwrap()
+            .command(&[dir.path("b")], no_inputs, touch("b"))
+            .unwrap()
+            .command(
+                &[dir.path("c")],
+                &[dir.path("a"), dir.path("b")],
+                touch("c"),
+            )
+            .unwrap();
+        builder.build().unwrap()
+    }
+
+    fn ran(dir: &TempDir) -> Vec<String> {
+        let log = dir.path("log");
+        let ran = std::fs::read_to_string(&log).unwrap_or_default();
+        let _ = std::fs::remove_file(&log);
+        let mut ran: Vec<String> = ran.lines().map(str::to_owned).collect();
+        ran.sort();
+        ran
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = vec![
+            Message::Auth {
+                token: TOKEN.to_vec(),
+            },
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                slots: 4,
+            },
+            Message::Run {
+                id: 1,
+                namespace: Some(b"sub".to_vec()),
+                command: "cc -c a.c".to_owned(),
+            },
+            Message::Run {
+                id: 2,
+                namespace: None,
+                command: String::new(),
+            },
+            Message::Steal { id: 3 },
+            Message::Returned { id: 3 },
+            Message::Done {
+                id: 1,
+                status: 256,
+                stdout: b"out".to_vec(),
+                stderr: vec![],
+            },
+            Message::SpawnFailed {
+                id: 2,
+                errno: libc::ENOENT,
+            },
+        ];
+        let mut stream = Vec::new();
+        for message in &messages {
+            write_message(&mut stream, message).unwrap();
+        }
+        let mut reader = &stream[..];
+        for message in &messages {
+            assert_eq!(read_message(&mut reader).unwrap().as_ref(), Some(message));
+        }
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+
+        let mut reader = &stream[..stream.len() - 1];
+        for _ in 0..messages.len() - 1 {
+            read_message(&mut reader).unwrap();
+        }
+        assert!(read_message(&mut reader).is_err());
+    }
+
+    #[test]
+    fn builds_on_workers() {
+        let dir = TempDir::new("distributed-build");
+        let tasks = diamond(&dir);
+        let scheduler = scheduler(vec![start_worker(1), start_worker(2)]);
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        build_externals(&scheduler, &rebuilder, &tasks).unwrap();
+        assert_eq!(ran(&dir), vec!["a", "b", "c"]);
+
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        build_externals(&scheduler, &rebuilder, &tasks).unwrap();
+        assert!(ran(&dir).is_empty(), "everything should be up to date");
+    }
+
+    #[test]
+    fn reports_failures_from_workers() {
+        let mut builder = TasksBuilder::new();
+        let no_inputs: &[&str] = &[];
+        builder
+            .command(&["ninja-test-remote-fail"], no_inputs, "exit 3")
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let scheduler = scheduler(vec![start_worker(1)]);
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        match build_externals(&scheduler, &rebuilder, &tasks) {
+            Err(BuildError::CommandsFailed(report)) => {
+                let failures = &report.failures().0;
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].kind, crate::FailureKind::NonZeroExit);
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hands_off_from_lost_worker() {
+        // Takes one command and goes away without running it.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let flaky = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_message(&mut stream).unwrap();
+            let hello = Message::Hello {
+                version: PROTOCOL_VERSION,
+                slots: 8,
+            };
+            write_message(&mut stream, &hello).unwrap();
+            read_message(&mut stream).unwrap();
+        });
+
+        let dir = TempDir::new("distributed-handoff");
+        let tasks = diamond(&dir);
+        let scheduler = scheduler(vec![flaky, start_worker(1)]);
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        build_externals(&scheduler, &rebuilder, &tasks).unwrap();
+        assert_eq!(ran(&dir), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn fails_without_workers() {
+        // Nothing listens on a port that was just released.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let dir = TempDir::new("distributed-no-workers");
+        let tasks = diamond(&dir);
+        let scheduler = scheduler(vec![address]);
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        match build_externals(&scheduler, &rebuilder, &tasks) {
+            Err(BuildError::Distributed(_)) => {}
+            other => panic!("expected no workers, got {:?}", other),
+        }
+        assert!(ran(&dir).is_empty());
+    }
+
+    #[test]
+    fn refuses_coordinators_without_the_token() {
+        let dir = TempDir::new("distributed-token");
+        let tasks = diamond(&dir);
+        let scheduler = DistributedScheduler::new(vec![start_worker(1)], b"sesam".to_vec());
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        match build_externals(&scheduler, &rebuilder, &tasks) {
+            Err(BuildError::Distributed(_)) => {}
+            other => panic!("expected no workers, got {:?}", other),
+        }
+        assert!(ran(&dir).is_empty());
+
+        // Commands sent straight away are never read.
+        let mut stream = TcpStream::connect(start_worker(1)).unwrap();
+        let run = Message::Run {
+            id: 1,
+            namespace: None,
+            command: format!("touch {}", dir.path("a")),
+        };
+        write_message(&mut stream, &run).unwrap();
+        assert_eq!(read_message(&mut stream).unwrap(), None);
+        assert!(!std::path::Path::new(&dir.path("a")).exists());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(serve(listener, 1, &Workspace::default(), b"").is_err());
+    }
+
+    #[test]
+    fn queued_commands_can_be_stolen() {
+        let mut worker = TcpStream::connect(start_worker(1)).unwrap();
+        let auth = Message::Auth {
+            token: TOKEN.to_vec(),
+        };
+        write_message(&mut worker, &auth).unwrap();
+        assert!(matches!(
+            read_message(&mut worker).unwrap(),
+            Some(Message::Hello { slots: 1, .. })
+        ));
+        for (id, command) in [(1, "sleep 0.2"), (2, "true")].iter() {
+            let run = Message::Run {
+                id: *id,
+                namespace: None,
+                command: command.to_string(),
+            };
+            write_message(&mut worker, &run).unwrap();
+        }
+        write_message(&mut worker, &Message::Steal { id: 2 }).unwrap();
+        // Already running, so it can't be stolen.
+        write_message(&mut worker, &Message::Steal { id: 1 }).unwrap();
+        assert_eq!(
+            read_message(&mut worker).unwrap(),
+            Some(Message::Returned { id: 2 })
+        );
+        assert!(matches!(
+            read_message(&mut worker).unwrap(),
+            Some(Message::Done {
+                id: 1,
+                status: 0,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,246 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Records why the rebuilder decided each key was dirty or clean, for `-d explain-file`.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, Write},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{diagnostics, task::Key};
+
+/// Why a key was, or was not, considered dirty.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Reason {
+    /// An output does not exist, or an edge it depends on was already dirty.
+    OutputDirty,
+    /// The newest input is newer than the oldest output.
+    InputNewer,
+    /// An input was produced by an edge that is being rebuilt.
+    InputDirty,
+    /// Every input is older than the oldest output.
+    UpToDate,
+    /// The edge has no inputs and its outputs exist.
+    NoInputs,
+    /// The rule has a depfile, but the command has not written it yet.
+    DepfileMissing,
+    /// An input listed in the depfile is newer than the oldest output, missing, or being rebuilt.
+    DepfileInput,
+    /// The build log has no record of the command that produced an output.
+    NotInLog,
+    /// The command differs from the one the build log recorded for an output.
+    CommandChanged,
+    /// The rule has `deps`, but the deps log has nothing recorded for the output.
+    DepsMissing,
+    /// The output was written after the deps log recorded its inputs.
+    DepsOutOfDate,
+    /// An input recorded in the deps log is newer than the oldest output, missing, or being
+    /// rebuilt.
+    DepsInput,
+}
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::OutputDirty => "output-dirty-or-missing",
+            Reason::InputNewer => "input-newer",
+            Reason::InputDirty => "input-dirty",
+            Reason::UpToDate => "up-to-date",
+            Reason::NoInputs => "no-inputs",
+            Reason::DepfileMissing => "depfile-missing",
+            Reason::DepfileInput => "depfile-input",
+            Reason::NotInLog => "not-in-log",
+            Reason::CommandChanged => "command-changed",
+            Reason::DepsMissing => "deps-missing",
+            Reason::DepsOutOfDate => "deps-out-of-date",
+            Reason::DepsInput => "deps-input",
+        }
+    }
+}
+
+/// One dirtiness decision.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Explanation {
+    pub key: Key,
+    pub dirty: bool,
+    pub reason: Reason,
+    /// The oldest output's mtime, if every output exists.
+    pub output_mtime: Option<SystemTime>,
+    /// The newest input's mtime, if it is known from disk.
+    pub input_mtime: Option<SystemTime>,
+}
+
+fn write_mtime(f: &mut fmt::Formatter<'_>, mtime: Option<SystemTime>) -> fmt::Result {
+    match mtime.and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => write!(f, "{}", since_epoch.as_nanos()),
+        None => write!(f, "-"),
+    }
+}
+
+/// One tab separated line, in the order of `HEADER`. Timestamps are nanoseconds since the Unix
+/// epoch, or `-` when unknown.
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t",
+            self.key.display().exact(),
+            if self.dirty { "dirty" } else { "clean" },
+            self.reason.as_str()
+        )?;
+        write_mtime(f, self.output_mtime)?;
+        write!(f, "\t")?;
+        write_mtime(f, self.input_mtime)
+    }
+}
+
+pub const HEADER: &str = "# outputs\tdecision\treason\toutput_mtime_ns\tinput_mtime_ns";
+
+enum Sink {
+    Writer(Box<dyn Write>),
+    Memory(Vec<Explanation>),
+}
+
+/// Where explanations are written. Cloning shares the destination, so rebuilders for the manifest
+/// and for the build append to the same file.
+#[derive(Clone)]
+pub struct ExplainLog(Rc<RefCell<Option<Sink>>>);
+
+impl fmt::Debug for ExplainLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ExplainLog").finish()
+    }
+}
+
+impl ExplainLog {
+    pub fn new(mut out: Box<dyn Write>) -> io::Result<Self> {
+        writeln!(out, "{}", HEADER)?;
+        Ok(ExplainLog(Rc::new(RefCell::new(Some(Sink::Writer(out))))))
+    }
+
+    /// Keeps explanations for `take_recorded`, instead of writing them.
+    pub fn in_memory() -> Self {
+        ExplainLog(Rc::new(RefCell::new(Some(Sink::Memory(Vec::new())))))
+    }
+
+    /// The explanations recorded so far by an `in_memory` log. Always empty for other logs.
+    pub fn take_recorded(&self) -> Vec<Explanation> {
+        match self.0.borrow_mut().as_mut() {
+            Some(Sink::Memory(recorded)) => std::mem::take(recorded),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Like the build itself, explaining is best effort. The first write error is reported and
+    /// later explanations are dropped.
+    pub fn record(&self, explanation: &Explanation) {
+        let mut out = self.0.borrow_mut();
+        match out.as_mut() {
+            Some(Sink::Writer(writer)) => {
+                if let Err(e) = writeln!(writer, "{}", explanation) {
+                    diagnostics::warn("explain", format_args!("writing explanations: {}", e));
+                    *out = None;
+                }
+            }
+            Some(Sink::Memory(recorded)) => recorded.push(explanation.clone()),
+            None => {}
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match self.0.borrow_mut().as_mut() {
+            Some(Sink::Writer(writer)) => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Collects everything written, for inspection after the log is done with it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let shared = Shared::default();
+        let log = ExplainLog::new(Box::new(shared.clone())).unwrap();
+        log.record(&Explanation {
+            key: Key::Path(b"out".to_vec().into()),
+            dirty: true,
+            reason: Reason::InputNewer,
+            output_mtime: Some(UNIX_EPOCH + Duration::from_secs(1)),
+            input_mtime: Some(UNIX_EPOCH + Duration::from_secs(2)),
+        });
+        log.record(&Explanation {
+            key: Key::Multi(vec![b"a".to_vec().into(), b"b".to_vec().into()].into()),
+            dirty: true,
+            reason: Reason::OutputDirty,
+            output_mtime: None,
+            input_mtime: None,
+        });
+        assert_eq!(
+            String::from_utf8(shared.0.borrow().clone()).unwrap(),
+            format!(
+                "{}\nout\tdirty\tinput-newer\t1000000000\t2000000000\na b\tdirty\toutput-dirty-or-missing\t-\t-\n",
+                HEADER
+            )
+        );
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WriteZero.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_errors_disable_the_log() {
+        let log = ExplainLog(Rc::new(RefCell::new(Some(Sink::Writer(Box::new(Broken))))));
+        log.record(&Explanation {
+            key: Key::Path(b"out".to_vec().into()),
+            dirty: false,
+            reason: Reason::UpToDate,
+            output_mtime: None,
+            input_mtime: None,
+        });
+        assert!(log.0.borrow().is_none());
+    }
+}
@@ -0,0 +1,454 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    fmt,
+    io::{self, Write},
+    process::ExitStatus,
+};
+
+use console::Style;
+use ninja_parse::repr::RuleDescriptor;
+
+use crate::{
+    build_id::BuildId,
+    build_task::{CommandTaskError, FailureKind},
+    task::Key,
+};
+
+/// Lines of output kept per failure. The full output was already printed when the command
+/// finished, so the summary only needs enough to jog the user's memory.
+const MAX_OUTPUT_LINES: usize = 10;
+
+/// Keeps the last `MAX_OUTPUT_LINES` lines, since compilers tend to put the error that stopped
+/// them at the end.
+fn truncate_output(output: &str) -> (String, usize) {
+    let lines: Vec<&str> = output.lines().collect();
+    let skipped = lines.len().saturating_sub(MAX_OUTPUT_LINES);
+    (lines[skipped..].join("\n"), skipped)
+}
+
+/// The line most likely to say what went wrong: the first that looks like an error, or else the
+/// last one, which is usually where a tool explains why it is giving up.
+fn error_line(output: &str) -> Option<String> {
+    let looks_like_error = |line: &&str| {
+        let line = line.to_ascii_lowercase();
+        ["error", "fatal", "undefined reference"]
+            .iter()
+            .any(|word| line.contains(word))
+    };
+    let mut lines = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    lines
+        .clone()
+        .find(looks_like_error)
+        .or_else(|| lines.next_back())
+        .map(str::to_owned)
+}
+
+#[derive(Debug)]
+pub struct CommandFailure {
+    pub key: Key,
+    pub command: String,
+    pub kind: FailureKind,
+    /// None if the command did not run to completion, because it could not be spawned or was
+    /// killed for taking too long.
+    pub status: Option<ExitStatus>,
+    /// What went wrong, in a few words.
+    pub reason: String,
+    /// The tail of stdout followed by stderr, or the spawn error.
+    pub output: String,
+    /// How many lines were dropped from the start of `output`.
+    pub skipped_lines: usize,
+    /// The rule of the failed edge, if it came from one.
+    pub rule: Option<String>,
+    /// The line of the full output most likely to explain the failure. See `error_line`.
+    pub error_line: Option<String>,
+}
+
+impl CommandFailure {
+    pub(crate) fn new(key: Key, command: String, err: &CommandTaskError) -> Self {
+        let (reason, output) = match err {
+            CommandTaskError::SpawnFailed(e) => ("failed to spawn".to_owned(), e.to_string()),
+            CommandTaskError::CommandFailed(out) => (out.status.to_string(), String::new()),
+            // The exit status says the same thing, less clearly.
+            err => (err.to_string(), String::new()),
+        };
+        let (status, output) = match err.output() {
+            Some(out) => {
+                let mut combined = String::from_utf8_lossy(&out.stdout).into_owned();
+                combined.push_str(&String::from_utf8_lossy(&out.stderr));
+                (Some(out.status), combined)
+            }
+            None => (None, output),
+        };
+        let error_line = error_line(&output);
+        let (output, skipped_lines) = truncate_output(&output);
+        CommandFailure {
+            key,
+            command,
+            kind: err.kind(),
+            status,
+            reason,
+            output,
+            skipped_lines,
+            rule: None,
+            error_line,
+        }
+    }
+
+    pub(crate) fn with_rule(mut self, rule: Option<&RuleDescriptor>) -> Self {
+        self.rule = rule.map(|rule| String::from_utf8_lossy(&rule.name).into_owned());
+        self
+    }
+
+    /// The exit code or signal, or what kept the command from finishing.
+    fn short_status(&self) -> String {
+        use std::os::unix::process::ExitStatusExt;
+        match (
+            self.status.and_then(|s| s.code()),
+            self.status.and_then(|s| s.signal()),
+        ) {
+            (Some(code), _) if self.kind == FailureKind::NonZeroExit => format!("exit {}", code),
+            (_, Some(signal)) => format!("signal {}", signal),
+            _ => self.kind.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CommandFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {} [{}]", self.key.display(), self.reason, self.kind)?;
+        writeln!(f, "  {}", self.command)?;
+        if self.skipped_lines > 0 {
+            writeln!(f, "    ... {} line(s) omitted", self.skipped_lines)?;
+        }
+        for line in self.output.lines() {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every command that failed during a build, in the order they finished.
+#[derive(Debug, Default)]
+pub struct Failures(pub Vec<CommandFailure>);
+
+impl fmt::Display for Failures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} command(s) failed:", self.0.len())?;
+        for failure in &self.0 {
+            write!(f, "{}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl Failures {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// One aligned line per failure, with its first output, rule, status and the line of output
+    /// most likely to explain it, for the end of the build. The full output was already shown when
+    /// each command failed.
+    pub fn summary(&self, colored: bool) -> String {
+        let rows: Vec<[String; 3]> = self
+            .0
+            .iter()
+            .map(|failure| {
+                let output = failure.key.iter().next().expect("keys have a path");
+                [
+                    output.display().to_string(),
+                    failure.rule.clone().unwrap_or_default(),
+                    failure.short_status(),
+                ]
+            })
+            .collect();
+        let mut widths = [0; 3];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = std::cmp::max(*width, cell.chars().count());
+            }
+        }
+        // Padded before styling, so escape codes don't count towards the width.
+        let styles = [
+            Style::new().red().bold(),
+            Style::new().cyan(),
+            Style::new().yellow(),
+        ];
+        let mut summary = String::new();
+        for (failure, row) in self.0.iter().zip(rows) {
+            let mut line = String::from(" ");
+            for ((cell, width), style) in row.iter().zip(&widths).zip(&styles) {
+                let padded = format!("{:<width$}", cell, width = width);
+                line.push(' ');
+                line.push_str(&style.apply_to(padded).force_styling(colored).to_string());
+                line.push(' ');
+            }
+            if let Some(error_line) = &failure.error_line {
+                line.push(' ');
+                line.push_str(error_line);
+            }
+            summary.push_str(line.trim_end());
+            summary.push('\n');
+        }
+        summary
+    }
+
+    /// Prints `summary` to stderr, in color if stderr supports it, under the id of the build the
+    /// failures happened in.
+    pub fn print_summary(&self, build_id: &BuildId) {
+        eprint!(
+            "failed commands in build {}:\n{}",
+            build_id,
+            self.summary(console::colors_enabled_stderr())
+        );
+    }
+
+    /// Write the failures as a JSON array, one object per command, for CI systems to triage. Each
+    /// object names the build it failed in, so failures collected from several builds can still
+    /// be told apart.
+    pub fn write_json(&self, build_id: &BuildId, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "[")?;
+        for (i, failure) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(
+                out,
+                "{{\"build_id\":{},\"target\":{},\"kind\":{},\"exit_code\":",
+                json_string(build_id.as_str()),
+                json_string(&failure.key.display().exact().to_string()),
+                json_string(failure.kind.as_str()),
+            )?;
+            match failure.status.and_then(|status| status.code()) {
+                Some(code) => write!(out, "{}", code)?,
+                None => write!(out, "null")?,
+            }
+            write!(
+                out,
+                ",\"reason\":{},\"command\":{},\"output\":{}}}",
+                json_string(&failure.reason),
+                json_string(&failure.command),
+                json_string(&failure.output),
+            )?;
+        }
+        writeln!(out, "]")
+    }
+}
+
+/// Quotes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::process::ExitStatusExt, process::Output, time::Duration};
+
+    use super::*;
+
+    fn failure(key: &str, stdout: &str, stderr: &str) -> CommandFailure {
+        CommandFailure::new(
+            Key::Path(key.as_bytes().to_vec().into()),
+            format!("cc -c {}.c", key),
+            &CommandTaskError::CommandFailed(Output {
+                status: ExitStatus::from_raw(1 << 8),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: stderr.as_bytes().to_vec(),
+            }),
+        )
+    }
+
+    #[test]
+    fn report() {
+        let failures = Failures(vec![
+            failure("a", "", "a.c:1: error\n"),
+            failure("b", "warning\n", "b.c:2: error\n"),
+        ]);
+        assert_eq!(
+            failures.to_string(),
+            "2 command(s) failed:\n\
+             a: exit status: 1 [exit]\n  cc -c a.c\n    a.c:1: error\n\
+             b: exit status: 1 [exit]\n  cc -c b.c\n    warning\n    b.c:2: error\n"
+        );
+    }
+
+    #[test]
+    fn summary_is_aligned() {
+        let mut link = CommandFailure::new(
+            Key::Path(b"app".to_vec().into()),
+            "ld".to_owned(),
+            &CommandTaskError::Signaled {
+                output: Output {
+                    status: ExitStatus::from_raw(libc::SIGKILL),
+                    stdout: vec![],
+                    stderr: vec![],
+                },
+                signal: libc::SIGKILL,
+            },
+        );
+        link.rule = Some("link".to_owned());
+        let mut compile = failure(
+            "long.o",
+            "",
+            "In file included from long.c:1:\nlong.h:3: Error: oops\nlong.c:9: error: again\n",
+        );
+        compile.rule = Some("cc".to_owned());
+        let failures = Failures(vec![compile, link]);
+        assert_eq!(
+            failures.summary(false),
+            "  long.o  cc    exit 1    long.h:3: Error: oops\n  \
+             app     link  signal 9\n"
+        );
+        assert!(failures.summary(true).contains("\u{1b}["));
+    }
+
+    #[test]
+    fn error_line_falls_back_to_the_last_line() {
+        assert_eq!(
+            error_line("compiling\ngave up\n\n").as_deref(),
+            Some("gave up")
+        );
+        assert_eq!(error_line(""), None);
+    }
+
+    #[test]
+    fn output_is_truncated_from_the_start() {
+        let stderr: String = (0..15).map(|i| format!("line {}\n", i)).collect();
+        let failure = failure("a", "", &stderr);
+        assert_eq!(failure.skipped_lines, 5);
+        assert!(failure.output.starts_with("line 5\n"));
+        assert!(failure.output.ends_with("line 14"));
+    }
+
+    #[test]
+    fn signaled() {
+        let failure = CommandFailure::new(
+            Key::Path(b"a".to_vec().into()),
+            "crash".to_owned(),
+            &CommandTaskError::Signaled {
+                output: Output {
+                    status: ExitStatus::from_raw(libc::SIGKILL),
+                    stdout: vec![],
+                    stderr: vec![],
+                },
+                signal: libc::SIGKILL,
+            },
+        );
+        assert_eq!(
+            failure.to_string(),
+            "a: interrupted by signal 9 [signal]\n  crash\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let failures = Failures(vec![
+            failure("a", "", "a.c:1: \"quoted\"\n"),
+            CommandFailure::new(
+                Key::Path(b"b".to_vec().into()),
+                "sleep 100".to_owned(),
+                &CommandTaskError::TimedOut(Duration::from_secs(5)),
+            ),
+        ]);
+        let build_id = BuildId::generate();
+        let mut out = Vec::new();
+        failures.write_json(&build_id, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "[{{\"build_id\":\"{id}\",\"target\":\"a\",\"kind\":\"exit\",\"exit_code\":1,\
+                 \"reason\":\"exit status: 1\",\"command\":\"cc -c a.c\",\
+                 \"output\":\"a.c:1: \\\"quoted\\\"\"}},\
+                 {{\"build_id\":\"{id}\",\"target\":\"b\",\"kind\":\"timeout\",\"exit_code\":null,\
+                 \"reason\":\"timed out after 5s\",\"command\":\"sleep 100\",\"output\":\"\"}}]\n",
+                id = build_id
+            )
+        );
+    }
+
+    #[test]
+    fn timed_out() {
+        let failure = CommandFailure::new(
+            Key::Path(b"a".to_vec().into()),
+            "sleep 100".to_owned(),
+            &CommandTaskError::TimedOut(Duration::from_secs(5)),
+        );
+        assert_eq!(failure.status, None);
+        assert_eq!(
+            failure.to_string(),
+            "a: timed out after 5s [timeout]\n  sleep 100\n"
+        );
+    }
+
+    #[test]
+    fn output_missing() {
+        let failure = CommandFailure::new(
+            Key::Path(b"a".to_vec().into()),
+            "echo hi".to_owned(),
+            &CommandTaskError::OutputMissing {
+                output: Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: b"hi\n".to_vec(),
+                    stderr: vec![],
+                },
+                missing: vec!["a".to_owned()],
+            },
+        );
+        assert_eq!(failure.kind, FailureKind::OutputMissing);
+        assert_eq!(
+            failure.to_string(),
+            "a: succeeded, but did not create a [output-missing]\n  echo hi\n    hi\n"
+        );
+    }
+
+    #[test]
+    fn spawn_failure() {
+        let failure = CommandFailure::new(
+            Key::Path(b"a".to_vec().into()),
+            "nope".to_owned(),
+            &CommandTaskError::SpawnFailed(
+                std::io::Error::new(std::io::ErrorKind::NotFound, "no shell").into(),
+            ),
+        );
+        assert_eq!(
+            failure.to_string(),
+            "a: failed to spawn [spawn-failed]\n  nope\n    no shell\n"
+        );
+    }
+}
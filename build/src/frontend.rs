@@ -0,0 +1,395 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Streams build progress to an external UI, using the messages of ninja's frontend protocol.
+//!
+//! Each message is a `ninja.Status` protobuf, preceded by its length as a varint:
+//!
+//! ```text
+//! message Status {
+//!   message TotalEdges { optional uint32 total_edges = 1; }
+//!   message BuildStarted {
+//!     optional uint32 parallelism = 1; optional bool verbose = 2; optional string build_id = 3;
+//!   }
+//!   message BuildFinished {}
+//!   message EdgeStarted {
+//!     optional uint32 id = 1; optional uint32 start_time = 2;
+//!     repeated string inputs = 3; repeated string outputs = 4;
+//!     optional string desc = 5; optional string command = 6; optional bool console = 7;
+//!   }
+//!   message EdgeFinished {
+//!     optional uint32 id = 1; optional uint32 end_time = 2;
+//!     optional sint32 status = 3; optional string output = 4;
+//!   }
+//!   message Message {
+//!     enum Severity { INFO = 0; WARNING = 1; ERROR = 2; DEBUG = 3; }
+//!     optional Severity level = 1; optional string message = 2;
+//!   }
+//!   optional TotalEdges total_edges = 1;
+//!   optional BuildStarted build_started = 2;
+//!   optional BuildFinished build_finished = 3;
+//!   optional EdgeStarted edge_started = 4;
+//!   optional EdgeFinished edge_finished = 5;
+//!   optional Message message = 6;
+//! }
+//! ```
+//!
+//! `build_id` is not in ninja's protocol. It is the id commands see in `NINJA_BUILD_ID`, and
+//! frontends that don't know the field skip it, as protobuf decoders do.
+//!
+//! The messages are few and simple enough that they are encoded by hand, rather than pulling in a
+//! protobuf implementation.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::Path,
+    time::Instant,
+};
+
+use crate::{
+    build_id::BuildId,
+    build_task::{CommandTaskError, CommandTaskResult},
+    diagnostics,
+    task::{Key, Task},
+};
+
+const VARINT: u32 = 0;
+const LENGTH_DELIMITED: u32 = 2;
+
+/// Builds one protobuf message.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn tag(&mut self, field: u32, wire_type: u32) {
+        self.varint(u64::from(field << 3 | wire_type));
+    }
+
+    fn uint32(&mut self, field: u32, value: u32) -> &mut Self {
+        self.tag(field, VARINT);
+        self.varint(u64::from(value));
+        self
+    }
+
+    fn sint32(&mut self, field: u32, value: i32) -> &mut Self {
+        self.tag(field, VARINT);
+        // ZigZag encoding, so small negative numbers stay small.
+        self.varint(u64::from(((value << 1) ^ (value >> 31)) as u32));
+        self
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) -> &mut Self {
+        self.tag(field, LENGTH_DELIMITED);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn message(&mut self, field: u32, message: &Message) -> &mut Self {
+        self.bytes(field, &message.0)
+    }
+}
+
+fn key_names(key: &Key) -> Vec<Vec<u8>> {
+    key.iter()
+        .map(|path| path.name().into_owned().into_bytes())
+        .collect()
+}
+
+/// What happened while bringing the manifest up to date. Regenerating it means parsing it again
+/// before the build proper starts, so telling the frontend explains why a build seems to start
+/// twice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ManifestEvent {
+    /// The manifest is out of date, and its generator is about to run.
+    Dirty,
+    /// The regenerated manifest has been parsed again.
+    Reloaded,
+    /// The manifest was already up to date.
+    Unchanged,
+}
+
+/// Sends build progress to a frontend. Once the frontend goes away, the build carries on
+/// without it.
+pub struct Frontend {
+    out: Option<Box<dyn Write>>,
+    start: Instant,
+    total_edges: u32,
+    build_id: Option<BuildId>,
+}
+
+impl std::fmt::Debug for Frontend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Frontend")
+            .field("connected", &self.out.is_some())
+            .field("total_edges", &self.total_edges)
+            .finish()
+    }
+}
+
+impl Frontend {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Frontend {
+            out: Some(out),
+            start: Instant::now(),
+            total_edges: 0,
+            build_id: None,
+        }
+    }
+
+    /// Tells the frontend, as the build starts, that it is the build `id`.
+    pub fn build_id(mut self, id: BuildId) -> Self {
+        self.build_id = Some(id);
+        self
+    }
+
+    /// Connects to a Unix socket at `path`, or opens it for writing if it is anything else, like
+    /// a named pipe.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let out: Box<dyn Write> = if std::fs::metadata(path)?.file_type().is_socket() {
+            Box::new(UnixStream::connect(path)?)
+        } else {
+            Box::new(OpenOptions::new().write(true).open(path)?)
+        };
+        Ok(Frontend::new(out))
+    }
+
+    fn elapsed_ms(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+
+    /// Writes a `Status` with `message` in `field`.
+    fn send(&mut self, field: u32, message: &Message) {
+        let out = match self.out.as_mut() {
+            Some(out) => out,
+            None => return,
+        };
+        let mut status = Message::default();
+        status.message(field, message);
+        let mut framed = Message::default();
+        framed.varint(status.0.len() as u64);
+        framed.0.extend_from_slice(&status.0);
+        if let Err(e) = out.write_all(&framed.0).and_then(|_| out.flush()) {
+            diagnostics::warn("frontend", format_args!("frontend disconnected: {}", e));
+            self.out = None;
+        }
+    }
+
+    pub(crate) fn build_started(&mut self, parallelism: usize, verbose: bool) {
+        let mut started = Message::default();
+        started
+            .uint32(1, parallelism as u32)
+            .uint32(2, verbose as u32);
+        if let Some(id) = &self.build_id {
+            started.bytes(3, id.as_str().as_bytes());
+        }
+        self.send(2, &started);
+    }
+
+    pub(crate) fn edge_started(&mut self, id: usize, key: &Key, task: &Task) {
+        // Totals are only known as the rebuilder decides what needs to run.
+        self.total_edges += 1;
+        let total = self.total_edges;
+        self.send(1, Message::default().uint32(1, total));
+
+        let mut edge = Message::default();
+        edge.uint32(1, id as u32).uint32(2, self.elapsed_ms());
+        for input in task.dependencies() {
+            for name in key_names(input) {
+                edge.bytes(3, &name);
+            }
+        }
+        for output in key_names(key) {
+            edge.bytes(4, &output);
+        }
+        if let Some(description) = &task.description {
+            edge.bytes(5, description.as_bytes());
+        }
+        if let Some(command) = task.command() {
+            edge.bytes(6, command.as_bytes());
+        }
+        self.send(4, &edge);
+    }
+
+    pub(crate) fn edge_finished(&mut self, id: usize, result: &CommandTaskResult) {
+        let (status, output) = match result {
+            Ok(output) => (0, Some(output)),
+            Err(CommandTaskError::CommandFailed(output)) => {
+                (output.status.code().unwrap_or(1), Some(output))
+            }
+            // Like a shell reports it.
+            Err(CommandTaskError::Signaled { output, signal }) => (128 + signal, Some(output)),
+            Err(CommandTaskError::SpawnFailed(_)) => (127, None),
+            // Like timeout(1) reports it.
+            Err(CommandTaskError::TimedOut(_)) => (124, None),
+            Err(CommandTaskError::OutputMissing { output, .. })
+            | Err(CommandTaskError::BadDeps { output, .. }) => (1, Some(output)),
+        };
+        let mut edge = Message::default();
+        edge.uint32(1, id as u32)
+            .uint32(2, self.elapsed_ms())
+            .sint32(3, status);
+        match (output, result) {
+            (Some(output), _) => {
+                let mut combined = output.stdout.clone();
+                combined.extend_from_slice(&output.stderr);
+                edge.bytes(4, &combined);
+            }
+            (None, Err(e)) => {
+                edge.bytes(4, e.to_string().as_bytes());
+            }
+            (None, Ok(_)) => {}
+        }
+        self.send(5, &edge);
+    }
+
+    pub(crate) fn build_finished(&mut self) {
+        self.send(3, &Message::default());
+    }
+
+    /// Tells the frontend what happened to `manifest`, as an informational message.
+    pub fn manifest(&mut self, event: ManifestEvent, manifest: &str) {
+        let text = match event {
+            ManifestEvent::Dirty => format!("manifest '{}' is out of date, regenerating", manifest),
+            ManifestEvent::Reloaded => format!("reloaded regenerated manifest '{}'", manifest),
+            ManifestEvent::Unchanged => format!("manifest '{}' is up to date", manifest),
+        };
+        // Severity INFO is 0.
+        self.send(6, Message::default().uint32(1, 0).bytes(2, text.as_bytes()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, os::unix::process::ExitStatusExt, process::Output, rc::Rc};
+
+    use super::*;
+    use crate::task::TaskVariant;
+
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn varints_and_zigzag() {
+        let mut m = Message::default();
+        m.varint(300);
+        assert_eq!(m.0, vec![0xac, 0x02]);
+        let mut m = Message::default();
+        m.sint32(1, -1).sint32(1, 1);
+        assert_eq!(m.0, vec![0x08, 0x01, 0x08, 0x02]);
+    }
+
+    #[test]
+    fn framing() {
+        let capture = Capture::default();
+        let mut frontend = Frontend::new(Box::new(capture.clone()));
+        frontend.build_started(4, false);
+        frontend.build_finished();
+        assert_eq!(
+            *capture.0.borrow(),
+            vec![
+                // build_started { parallelism: 4, verbose: false }
+                0x06, 0x12, 0x04, 0x08, 0x04, 0x10, 0x00, // build_finished {}
+                0x02, 0x1a, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn build_started_names_the_build() {
+        let capture = Capture::default();
+        let id = BuildId::generate();
+        let mut frontend = Frontend::new(Box::new(capture.clone())).build_id(id.clone());
+        frontend.build_started(4, false);
+        let id = id.as_str().as_bytes();
+        let mut started = vec![0x08, 0x04, 0x10, 0x00, 0x1a, id.len() as u8];
+        started.extend_from_slice(id);
+        let mut expected = vec![started.len() as u8 + 2, 0x12, started.len() as u8];
+        expected.extend(started);
+        assert_eq!(*capture.0.borrow(), expected);
+    }
+
+    #[test]
+    fn manifest_events() {
+        let capture = Capture::default();
+        let mut frontend = Frontend::new(Box::new(capture.clone()));
+        frontend.manifest(ManifestEvent::Unchanged, "b");
+        let mut expected = vec![0x20, 0x32, 0x1e, 0x08, 0x00, 0x12, 0x1a];
+        expected.extend_from_slice(b"manifest 'b' is up to date");
+        assert_eq!(*capture.0.borrow(), expected);
+    }
+
+    #[test]
+    fn edges() {
+        let capture = Capture::default();
+        let mut frontend = Frontend::new(Box::new(capture.clone()));
+        let task = Task {
+            dependencies: vec![Key::Path(b"a.c".to_vec().into())],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command("cc a.c".to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: Some("CC a.o".to_owned()),
+        };
+        frontend.edge_started(7, &Key::Path(b"a.o".to_vec().into()), &task);
+        frontend.edge_finished(
+            7,
+            &Err(CommandTaskError::CommandFailed(Output {
+                status: std::process::ExitStatus::from_raw(2 << 8),
+                stdout: b"oops\n".to_vec(),
+                stderr: vec![],
+            })),
+        );
+        let bytes = capture.0.borrow();
+        // total_edges { total_edges: 1 }
+        assert_eq!(&bytes[..5], &[0x04, 0x0a, 0x02, 0x08, 0x01]);
+        let as_text = String::from_utf8_lossy(&bytes);
+        assert!(as_text.contains("a.c"));
+        assert!(as_text.contains("a.o"));
+        assert!(as_text.contains("CC a.o"));
+        assert!(as_text.contains("cc a.c"));
+        // edge_finished ends with status 2, zigzagged to 4, then the output.
+        assert!(bytes.ends_with(&[0x18, 0x04, 0x22, 0x05, b'o', b'o', b'p', b's', b'\n']));
+    }
+}
@@ -14,7 +14,10 @@
  * limitations under the License.
  */
 
-use crate::task::{Task, Tasks};
+use crate::{
+    build_task::CommandTask,
+    task::{Task, Tasks},
+};
 use async_trait::async_trait;
 use core::fmt::Debug;
 
@@ -22,6 +25,12 @@ use core::fmt::Debug;
 pub trait BuildTask<V> {
     // Cannot pass state until we have structured concurrency.
     async fn run(&self) -> V;
+
+    /// The command this task runs, if that is all it does, so the scheduler can run it in one
+    /// shell with others of the same `batch` rule. Other tasks always run on their own.
+    fn as_command(&self) -> Option<&CommandTask> {
+        None
+    }
 }
 
 impl<V> Debug for dyn BuildTask<V> {
@@ -30,6 +39,74 @@ impl<V> Debug for dyn BuildTask<V> {
     }
 }
 
+/// Decides whether `key` needs to be built, and if so, returns the task that builds it.
+///
+/// The schedulers ask the rebuilder about each key once its dependencies are done, so it can
+/// look at what they produced. Rebuilders that don't run commands, like this one that only
+/// records what it was asked about, make the scheduler a generic build engine.
+///
+/// ```
+/// use std::{
+///     cell::RefCell,
+///     os::unix::process::ExitStatusExt,
+///     process::{ExitStatus, Output},
+/// };
+///
+/// use async_trait::async_trait;
+/// use ninja_builder::{
+///     build,
+///     interface::{BuildTask, Rebuilder},
+///     task::{Key, Task, TasksBuilder},
+///     CommandTaskResult, ParallelTopoScheduler,
+/// };
+///
+/// struct Done;
+///
+/// #[async_trait(?Send)]
+/// impl BuildTask<CommandTaskResult> for Done {
+///     async fn run(&self) -> CommandTaskResult {
+///         Ok(Output {
+///             status: ExitStatus::from_raw(0),
+///             stdout: vec![],
+///             stderr: vec![],
+///         })
+///     }
+/// }
+///
+/// #[derive(Default)]
+/// struct Recorder(RefCell<Vec<String>>);
+///
+/// impl Rebuilder<Key, CommandTaskResult> for Recorder {
+///     type Task = Done;
+///     type Error = std::convert::Infallible;
+///
+///     fn build(
+///         &self,
+///         key: Key,
+///         _: Option<CommandTaskResult>,
+///         task: &Task,
+///     ) -> Result<Option<Box<Done>>, Self::Error> {
+///         self.0.borrow_mut().push(key.display().to_string());
+///         // Only edges with a command have anything to run.
+///         Ok(task.command().map(|_| Box::new(Done)))
+///     }
+/// }
+///
+/// let mut builder = TasksBuilder::new();
+/// builder.command(&["gen.h"], &[] as &[&str], "gen > gen.h")?;
+/// builder.command(&["main.o"], &["gen.h"], "cc -c main.c")?;
+/// let tasks = builder.build()?;
+/// let recorder = Recorder::default();
+/// build(
+///     &ParallelTopoScheduler::new(1),
+///     &recorder,
+///     &tasks,
+///     vec![Key::Path(b"main.o".to_vec().into())],
+/// )?;
+/// // Dependencies are asked about first.
+/// assert_eq!(*recorder.0.borrow(), vec!["gen.h", "main.o"]);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
 pub trait Rebuilder<K, V> {
     type Task: BuildTask<V> + ?Sized;
     type Error: std::error::Error + Send + Sync + 'static;
@@ -39,6 +116,12 @@ pub trait Rebuilder<K, V> {
         current_value: Option<V>,
         task: &Task,
     ) -> Result<Option<Box<Self::Task>>, Self::Error>;
+
+    /// Called once the task `build` returned for `key` has run successfully, before anything that
+    /// depends on `key` is built, so that what the task actually did can be taken into account.
+    fn finished(&self, _key: K, _task: &Task) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 /*impl<T> BuildTask<V> for Option<T> where T: BuildTask<V> {
@@ -46,18 +129,59 @@ pub trait Rebuilder<K, V> {
     }
 }*/
 
+/// Runs the tasks a rebuilder returns, in dependency order. Usually called through `build`,
+/// `build_externals` or `build_requested`, which are generic over the scheduler, so code can run
+/// builds without caring whether they are local or distributed.
+///
+/// ```
+/// # use std::{os::unix::process::ExitStatusExt, process::{ExitStatus, Output}};
+/// # use async_trait::async_trait;
+/// # use ninja_builder::{interface::{BuildTask, Rebuilder}, task::{Key, Task}, CommandTaskResult};
+/// # struct Done;
+/// # #[async_trait(?Send)]
+/// # impl BuildTask<CommandTaskResult> for Done {
+/// #     async fn run(&self) -> CommandTaskResult {
+/// #         Ok(Output { status: ExitStatus::from_raw(0), stdout: vec![], stderr: vec![] })
+/// #     }
+/// # }
+/// # struct AlwaysDirty;
+/// # impl Rebuilder<Key, CommandTaskResult> for AlwaysDirty {
+/// #     type Task = Done;
+/// #     type Error = std::convert::Infallible;
+/// #     fn build(&self, _: Key, _: Option<CommandTaskResult>, _: &Task)
+/// #         -> Result<Option<Box<Done>>, Self::Error> { Ok(Some(Box::new(Done))) }
+/// # }
+/// use ninja_builder::{
+///     build_externals, interface::Scheduler, task::{Tasks, TasksBuilder}, ParallelTopoScheduler,
+/// };
+///
+/// fn build_everything<S: Scheduler<Key, CommandTaskResult>>(
+///     scheduler: &S,
+///     tasks: &Tasks,
+/// ) -> Result<S::Report, S::Error> {
+///     build_externals(scheduler, &AlwaysDirty, tasks)
+/// }
+///
+/// let mut builder = TasksBuilder::new();
+/// builder.command(&["out"], &[] as &[&str], "make out")?;
+/// let report = build_everything(&ParallelTopoScheduler::new(1), &builder.build()?)?;
+/// assert!(report.failures().is_empty());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
 pub trait Scheduler<K, V> {
+    /// What the scheduler tells its caller about a build it completed.
+    type Report;
     type Error: std::error::Error + Send + Sync + 'static;
     fn schedule(
         &self,
         rebuilder: &impl Rebuilder<K, V>,
         tasks: &Tasks,
         start: Vec<K>,
-    ) -> Result<(), Self::Error>;
+    ) -> Result<Self::Report, Self::Error>;
 
     fn schedule_externals(
         &self,
         rebuilder: &impl Rebuilder<K, V>,
         tasks: &Tasks,
-    ) -> Result<(), Self::Error>;
+    ) -> Result<Self::Report, Self::Error>;
 }
@@ -14,146 +14,121 @@
  * limitations under the License.
  */
 
-#![feature(option_expect_none)]
-
 extern crate petgraph;
 
 use std::{
+    cell::{Cell, RefCell},
     collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
-    io::Write,
+    time::{Duration, Instant},
 };
 
-use petgraph::{graph::NodeIndex, visit::DfsPostOrder, Direction};
+use futures::{
+    future::{self, Either},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use petgraph::{
+    graph::{IndexType, NodeIndex},
+    visit::DfsPostOrder,
+    Direction,
+};
 use thiserror::Error;
 use tokio::{sync::Semaphore, task::LocalSet};
 
+mod atomic;
+pub mod build_id;
+pub mod build_log;
 mod build_task;
+mod cancel;
+pub mod check;
+pub mod compdb;
+pub mod depfile;
+pub mod deps_log;
+pub mod diagnostics;
+pub mod dir_listings;
 pub mod disk_interface;
+pub mod distributed;
+pub mod explain;
+mod failures;
+pub mod frontend;
 pub mod interface;
+pub mod lock;
+pub mod plan;
+pub mod platform;
+mod printer;
+mod process_group;
 #[cfg(test)]
 mod property_tests;
 mod rebuilder;
+mod report;
+pub mod selection;
+pub mod simulation;
+pub mod state;
+pub mod status_format;
 pub mod task;
+#[cfg(test)]
+mod testing;
 pub mod tracking_rebuilder;
+mod utilization;
+mod workspace;
 
-use build_task::{CommandTaskError, CommandTaskResult};
+use build_log::{output_mtime, SharedBuildLog};
+use build_task::{extract_deps, CommandBatch};
+pub use build_task::{CommandTaskError, CommandTaskResult, FailureKind, SpawnError};
+pub use cancel::CancelHandle;
+use deps_log::SharedDepsLog;
 use disk_interface::SystemDiskInterface;
+pub use failures::{json_string, CommandFailure, Failures};
+use frontend::Frontend;
 use interface::BuildTask;
-pub use rebuilder::{CachingMTimeRebuilder, DiskDirtyCache, RebuilderError};
+pub use printer::TerminalLike;
+use printer::{Capacity, Printer};
+use process_group::Interruptible;
+pub use process_group::{ChildScheduling, ForwardSignals};
+pub use rebuilder::{
+    CachingMTimeRebuilder, DirectoryInputs, DirectoryInputsError, DiskDirtyCache, RebuilderError,
+};
+pub use report::{BuildReport, Outcome, Unverified};
+use status_format::StatusFormat;
 use task::{Key, Task, Tasks};
+use utilization::Utilization;
+pub use workspace::Workspace;
 
-type SchedulerGraph<'a> = petgraph::Graph<&'a Key, ()>;
+// 32-bit indices keep the graph half the size, and no build comes near 4 billion keys.
+type SchedulerGraph<'a, Ix = u32> = petgraph::Graph<&'a Key, (), petgraph::Directed, Ix>;
+
+/// The tasks need more nodes or edges than the scheduler's graph can index.
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("the build graph needs more than {limit} {what}")]
+pub struct GraphTooLarge {
+    limit: usize,
+    what: &'static str,
+}
 
 #[derive(Error, Debug)]
 pub enum BuildError {
     #[error("command pool panic")]
     CommandPoolPanic,
-    #[error("command failed {0}")]
-    CommandFailed(#[from] CommandTaskError),
+    /// What happened to every other edge is in the report too.
+    #[error("{} command(s) failed", .0.failures().len())]
+    CommandsFailed(BuildReport),
     #[error(transparent)]
     RebuilderError(#[from] Box<dyn std::error::Error + Send + Sync>),
-}
-
-#[derive(Debug)]
-struct Printer {
-    finished: usize,
-    total: usize,
-    console: console::Term,
-}
-
-impl Default for Printer {
-    fn default() -> Self {
-        Printer {
-            finished: 0,
-            total: 0,
-            console: console::Term::stdout(),
-        }
-    }
-}
-
-// How this is called does need re-doing.
-// First, having NoopTask but not passing it the build task means it cannot tell whether a command
-// would actually be run or not.
-impl Printer {
-    fn print_status(&mut self, task: &Task) {
-        if !task.is_command() {
-            return;
-        }
-        let command = task.command().unwrap().trim();
-
-        if self.console.is_term() {
-            // TODO: Handle non-ASCII properly.
-            // TODO: ninja style elision.
-            let size = self
-                .console
-                .size_checked()
-                .map(|(_rows, columns)| columns)
-                .unwrap_or(80);
-            self.console.clear_line().expect("clear");
-            write!(
-                self.console,
-                "[{}/{}] {}",
-                // TODO: Properly calculate instead of just removing 10 chars.
-                self.finished,
-                self.total,
-                &command[..std::cmp::min(command.len(), (size as usize) - 10)]
-            )
-            .expect("write");
-        } else {
-            writeln!(
-                self.console,
-                "[{}/{}] {}",
-                self.finished, self.total, command
-            )
-            .expect("write");
-        }
-    }
-
-    fn started(&mut self, task: &Task) {
-        self.total += 1;
-        self.print_status(task);
-    }
-
-    fn finished(&mut self, task: &Task, result: CommandTaskResult) {
-        self.finished += 1;
-        self.print_status(task);
-        if let Ok(output) = result {
-            if !output.stdout.is_empty() {
-                write!(
-                    self.console,
-                    "\n{}", // TODO: Correct newline handling.
-                    std::str::from_utf8(&output.stdout).unwrap()
-                )
-                .unwrap();
-            }
-        } else {
-            // TODO: Print build edge.
-            writeln!(self.console, "\nFAILED\n{}", task.command().unwrap()).unwrap();
-            match result.unwrap_err() {
-                err @ CommandTaskError::SpawnFailed(_) => {
-                    writeln!(self.console, "Failed to spawn command: {}", err).unwrap();
-                }
-                CommandTaskError::CommandFailed(out) => {
-                    // ninja interleaves streams, but this will do for now.
-                    self.console.write(&out.stdout).unwrap();
-                    self.console.write(&out.stderr).unwrap();
-                }
-            }
-            panic!("FAILED");
-        }
-    }
-}
-
-impl Drop for Printer {
-    fn drop(&mut self) {
-        if self.console.is_term() {
-            if self.total > 0 {
-                self.console.write_line("").unwrap();
-            } else {
-                self.console.write_line("ninja: no work to do.").unwrap();
-            }
-        }
-    }
+    #[error("distributed build: {0}")]
+    Distributed(std::io::Error),
+    #[error(transparent)]
+    GraphTooLarge(#[from] GraphTooLarge),
+    /// The build stopped after ninja received the signal. Commands that were running finished
+    /// first.
+    #[error("interrupted by signal {0}")]
+    Interrupted(i32),
+    /// The build was stopped with its `CancelHandle`. Commands that were running were killed.
+    #[error("cancelled")]
+    Cancelled,
+    /// No edges were started after the deadline. Commands that were running finished first, and
+    /// the report has the edges left as skipped.
+    #[error("deadline reached with {} edge(s) not built", .0.count(Outcome::Skipped))]
+    DeadlineReached(BuildReport),
 }
 
 #[derive(Debug, Default)]
@@ -162,6 +137,10 @@ struct BuildState {
     finished: HashSet<NodeIndex>,
     ready: VecDeque<NodeIndex>,
     waiting_tasks: HashSet<NodeIndex>,
+    /// Nodes that run before the rest of `ready` as soon as they are ready. See
+    /// `ParallelTopoScheduler::prioritize_requested`.
+    prioritized: HashSet<NodeIndex>,
+    ready_prioritized: VecDeque<NodeIndex>,
 }
 
 impl BuildState {
@@ -172,14 +151,97 @@ impl BuildState {
 
     pub fn next_ready(&mut self) -> Option<NodeIndex> {
         assert!(!self.done());
-        self.ready.pop_front()
+        self.ready_prioritized
+            .pop_front()
+            .or_else(|| self.ready.pop_front())
+    }
+
+    fn push_ready(&mut self, node: NodeIndex) {
+        if self.prioritized.contains(&node) {
+            self.ready_prioritized.push_back(node);
+        } else {
+            self.ready.push_back(node);
+        }
+    }
+
+    /// Everything reachable from `start`, or from the roots of the graph if it is None, in
+    /// dependency order. With `prioritize`, the nodes in `start` and their direct dependencies
+    /// run before other ready nodes.
+    fn for_start(graph: &SchedulerGraph, start: Option<&[Key]>, prioritize: bool) -> Self {
+        let mut build_state = BuildState::default();
+        if let (Some(keys), true) = (start, prioritize) {
+            let keys: HashSet<&Key> = keys.iter().collect();
+            for node in graph
+                .node_indices()
+                .filter(|idx| keys.contains(graph[*idx]))
+            {
+                build_state.prioritized.insert(node);
+                build_state
+                    .prioritized
+                    .extend(graph.neighbors_directed(node, Direction::Outgoing));
+            }
+        }
+        // Cannot use depth_first_search which doesn't say if it is postorder.
+        // Cannot use Topo since it doesn't offer move_to and partial traversals.
+        // TODO: So we really need to enforce no cycles here.
+        let mut visitor = DfsPostOrder::empty(graph);
+        let requested: Box<dyn Iterator<Item = NodeIndex>> = match start {
+            Some(keys) => {
+                let keys: HashSet<&Key> = keys.iter().collect();
+                Box::new(
+                    graph
+                        .node_indices()
+                        .filter(move |idx| keys.contains(graph[*idx])),
+                )
+            }
+            None => Box::new(graph.externals(Direction::Incoming)),
+        };
+        for start in requested {
+            visitor.move_to(start);
+            while let Some(node) = visitor.next(graph) {
+                build_state.add_node(graph, node);
+            }
+        }
+        build_state
+    }
+
+    /// How many of the wanted nodes are edges with a command. That many commands run unless some
+    /// turn out to be up to date.
+    fn commands(&self, graph: &SchedulerGraph, tasks: &Tasks) -> usize {
+        self.ready
+            .iter()
+            .chain(&self.ready_prioritized)
+            .chain(&self.waiting_tasks)
+            .filter(|node| matches!(tasks.task(graph[**node]), Some(task) if task.is_command()))
+            .count()
+    }
+
+    /// Removes up to `limit` nodes that `matches` from the ready queue, keeping the rest in order.
+    fn take_ready(
+        &mut self,
+        limit: usize,
+        mut matches: impl FnMut(NodeIndex) -> bool,
+    ) -> Vec<NodeIndex> {
+        let mut taken = Vec::new();
+        for ready in [&mut self.ready_prioritized, &mut self.ready].iter_mut() {
+            let mut kept = VecDeque::with_capacity(ready.len());
+            for node in ready.drain(..) {
+                if taken.len() < limit && matches(node) {
+                    taken.push(node);
+                } else {
+                    kept.push_back(node);
+                }
+            }
+            **ready = kept;
+        }
+        taken
     }
 
     pub fn add_node(&mut self, graph: &SchedulerGraph, node: NodeIndex) {
         self.wanted += 1;
         if graph.edges_directed(node, Direction::Outgoing).count() == 0 {
             // No dependencies, we can start this immediately.
-            self.ready.push_back(node);
+            self.push_ready(node);
         } else {
             // Has dependencies, wait until they are done.
             self.waiting_tasks.insert(node);
@@ -201,7 +263,7 @@ impl BuildState {
                 .all(|dependency| self.finished.contains(&dependency))
             {
                 self.waiting_tasks.remove(&dependent);
-                self.ready.push_back(dependent);
+                self.push_ready(dependent);
             }
         }
     }
@@ -249,97 +311,239 @@ impl BuildState {
 #[derive(Debug)]
 pub struct ParallelTopoScheduler {
     parallelism: usize,
+    failures_allowed: usize,
+    report_no_work: bool,
+    verbose: bool,
+    status_format: StatusFormat,
+    prioritize_requested: bool,
+    command_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    cancel: CancelHandle,
+    handle_signals: bool,
+    frontend: RefCell<Option<Frontend>>,
+    build_log: Option<SharedBuildLog>,
+    deps_log: Option<SharedDepsLog>,
+    workspace: Workspace,
 }
 
 impl ParallelTopoScheduler {
     pub fn new(parallelism: usize) -> Self {
-        ParallelTopoScheduler { parallelism }
+        ParallelTopoScheduler {
+            parallelism,
+            failures_allowed: 1,
+            report_no_work: true,
+            verbose: false,
+            status_format: StatusFormat::default(),
+            prioritize_requested: false,
+            command_timeout: None,
+            deadline: None,
+            cancel: CancelHandle::new(),
+            handle_signals: false,
+            frontend: RefCell::new(None),
+            build_log: None,
+            deps_log: None,
+            workspace: Workspace::default(),
+        }
+    }
+
+    /// Also stream progress to `frontend`, for external UIs.
+    pub fn frontend(self, frontend: Frontend) -> Self {
+        self.frontend.replace(Some(frontend));
+        self
     }
 
-    fn build_graph(tasks: &Tasks, start: Option<Vec<Key>>) -> SchedulerGraph {
-        let mut keys_to_nodes: HashMap<&Key, NodeIndex> = HashMap::new();
-        let mut graph = SchedulerGraph::new();
-        fn add_or_get_node<'a>(
-            map: &mut HashMap<&'a Key, NodeIndex>,
-            graph: &mut SchedulerGraph<'a>,
+    /// Don't say "no work to do" when everything is up to date. For internal passes, like bringing
+    /// the manifest up to date, that precede the build the user asked for.
+    pub fn quiet_when_up_to_date(mut self) -> Self {
+        self.report_no_work = false;
+        self
+    }
+
+    /// Print the full command of every edge as it runs, like ninja's `-v`, instead of descriptions
+    /// on a single status line.
+    pub fn verbose(mut self) -> Self {
+        self.verbose = true;
+        self
+    }
+
+    /// Prefix status lines with `format`, like ninja's `NINJA_STATUS`.
+    pub fn status_format(mut self, format: StatusFormat) -> Self {
+        self.status_format = format;
+        self
+    }
+
+    /// Keep starting new commands until `failures_allowed` commands have failed, like ninja's
+    /// `-k`. Commands that are already running are always allowed to finish.
+    pub fn keep_going(mut self, failures_allowed: usize) -> Self {
+        self.failures_allowed = std::cmp::max(failures_allowed, 1);
+        self
+    }
+
+    /// Run the edges of the requested targets, and the edges producing their direct inputs, as
+    /// soon as they are ready, ahead of other ready edges. Asking for a binary then compiles its
+    /// own sources early, so their errors show up without waiting for the rest of the build.
+    pub fn prioritize_requested(mut self) -> Self {
+        self.prioritize_requested = true;
+        self
+    }
+
+    /// Kill commands, and fail their edges, if they run for longer than `timeout`. A batch of
+    /// commands gets `timeout` for the whole batch.
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    /// Start no edges after `deadline`, letting running commands finish, and fail with
+    /// `BuildError::DeadlineReached` if anything is left. What did finish is logged as usual, so
+    /// the next build carries on from there.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Stop when `cancel` is cancelled, so one handle can stop several schedulers, like a build
+    /// and the passes before it.
+    pub fn cancel_handle(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Stops builds of this scheduler from another thread.
+    pub fn canceller(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Forward SIGINT, SIGTERM and SIGHUP to running commands while building, and stop at the
+    /// first one like ninja does. The program's own handlers are put back afterwards. Without
+    /// this, signals are left to the program, which can cancel builds with `canceller`.
+    pub fn handle_signals(mut self) -> Self {
+        self.handle_signals = true;
+        self
+    }
+
+    /// Record every command that succeeds in `log`.
+    pub fn build_log(mut self, log: SharedBuildLog) -> Self {
+        self.build_log = Some(log);
+        self
+    }
+
+    /// Record the inputs discovered by commands of rules with `deps` in `log`.
+    pub fn deps_log(mut self, log: SharedDepsLog) -> Self {
+        self.deps_log = Some(log);
+        self
+    }
+
+    /// Run batched commands, and look for what commands produced, in `workspace`. The rebuilder
+    /// should use the same one.
+    pub fn workspace(mut self, workspace: Workspace) -> Self {
+        self.workspace = workspace;
+        self
+    }
+
+    /// Nodes borrow their keys from `tasks`, so building the graph copies no keys.
+    fn build_graph<'a>(
+        tasks: &'a Tasks,
+        start: Option<&[Key]>,
+    ) -> Result<SchedulerGraph<'a>, GraphTooLarge> {
+        Self::build_graph_with_indices(tasks, start)
+    }
+
+    fn build_graph_with_indices<'a, Ix: IndexType>(
+        tasks: &'a Tasks,
+        start: Option<&[Key]>,
+    ) -> Result<SchedulerGraph<'a, Ix>, GraphTooLarge> {
+        let mut keys_to_nodes: HashMap<&Key, NodeIndex<Ix>> = HashMap::new();
+        let mut graph = SchedulerGraph::default();
+        // petgraph reserves the largest index, and panics when asked for it.
+        let limit = <Ix as IndexType>::max().index();
+        fn add_or_get_node<'a, Ix: IndexType>(
+            map: &mut HashMap<&'a Key, NodeIndex<Ix>>,
+            graph: &mut SchedulerGraph<'a, Ix>,
             key: &'a Key,
-        ) -> NodeIndex {
+        ) -> Result<NodeIndex<Ix>, GraphTooLarge> {
             match map.entry(key) {
                 Entry::Vacant(e) => {
+                    let limit = <Ix as IndexType>::max().index();
+                    if graph.node_count() >= limit {
+                        return Err(GraphTooLarge {
+                            limit,
+                            what: "nodes",
+                        });
+                    }
                     let node = graph.add_node(key);
                     e.insert(node);
-                    node
+                    Ok(node)
                 }
-                Entry::Occupied(e) => *e.get(),
+                Entry::Occupied(e) => Ok(*e.get()),
             }
         }
+        let add_edge = |graph: &mut SchedulerGraph<'a, Ix>, source, dep| {
+            if graph.edge_count() >= limit {
+                return Err(GraphTooLarge {
+                    limit,
+                    what: "edges",
+                });
+            }
+            graph.add_edge(source, dep, ());
+            Ok(())
+        };
 
         let task_map = tasks.all_tasks();
 
         if let Some(start) = start {
             // The borrow checker has a problem with recursion, so bring out the BFS.
-            let mut queue = std::collections::VecDeque::from(start);
+            // Keys without a task have nothing to traverse, so they are left out.
+            let mut queue: VecDeque<(&Key, &Task)> = start
+                .iter()
+                .filter_map(|key| task_map.get_key_value(key))
+                .collect();
             let mut visited = HashSet::new();
-            while !queue.is_empty() {
-                let key = queue.pop_front().unwrap();
-                if let Some((key, task)) = task_map.get_key_value(&key) {
-                    let source = add_or_get_node(&mut keys_to_nodes, &mut graph, key);
-                    if !visited.contains(&source) {
-                        visited.insert(source);
-                        for dep in task.dependencies().iter().chain(task.order_dependencies()) {
-                            let dep_node = add_or_get_node(&mut keys_to_nodes, &mut graph, dep);
-                            graph.add_edge(source, dep_node, ());
-                            queue.push_back(dep.clone());
+            while let Some((key, task)) = queue.pop_front() {
+                let source = add_or_get_node(&mut keys_to_nodes, &mut graph, key)?;
+                if visited.insert(source) {
+                    for dep in task.dependencies().iter().chain(task.order_dependencies()) {
+                        let dep_node = add_or_get_node(&mut keys_to_nodes, &mut graph, dep)?;
+                        add_edge(&mut graph, source, dep_node)?;
+                        if let Some(dep_task) = task_map.get(dep) {
+                            queue.push_back((dep, dep_task));
                         }
                     }
                 }
             }
         } else {
             for (key, task) in task_map {
-                let source = add_or_get_node(&mut keys_to_nodes, &mut graph, key);
+                let source = add_or_get_node(&mut keys_to_nodes, &mut graph, key)?;
                 for dep in task.dependencies().iter().chain(task.order_dependencies()) {
-                    let dep_node = add_or_get_node(&mut keys_to_nodes, &mut graph, dep);
-                    graph.add_edge(source, dep_node, ());
+                    let dep_node = add_or_get_node(&mut keys_to_nodes, &mut graph, dep)?;
+                    add_edge(&mut graph, source, dep_node)?;
                 }
             }
         }
-        graph
+        Ok(graph)
     }
 
-    fn schedule_internal(
+    fn schedule_internal<R: interface::Rebuilder<Key, CommandTaskResult>>(
         &self,
-        rebuilder: &impl interface::Rebuilder<Key, CommandTaskResult>,
+        rebuilder: &R,
         tasks: &Tasks,
-        start: Option<Vec<Key>>,
-    ) -> Result<(), BuildError> {
+        start: Option<&[Key]>,
+    ) -> Result<BuildReport, BuildError> {
         // Umm.. OK So if the user did not request a particular start, and there are no defaults,
         // then we need to first build a graph and then find the externals.
         // But if there is a start, could we build a graph that has only reachable nodes, and also
         // get our topo sort at the same time?
-        let graph = Self::build_graph(&tasks, start.clone());
-        let mut build_state = BuildState::default();
+        let graph = Self::build_graph(tasks, start)?;
+        let mut build_state = BuildState::for_start(&graph, start, self.prioritize_requested);
         let mut printer = Printer::default();
-
-        // Cannot use depth_first_search which doesn't say if it is postorder.
-        // Cannot use Topo since it doesn't offer move_to and partial traversals.
-        // TODO: So we really need to enforce no cycles here.
-        let mut visitor = DfsPostOrder::empty(&graph);
-        let requested: Box<dyn Iterator<Item = NodeIndex>> = match start {
-            Some(keys) => {
-                let x = &graph;
-                Box::new(
-                    graph
-                        .node_indices()
-                        .filter(move |idx| keys.contains(x[*idx])),
-                )
-            }
-            None => Box::new(graph.externals(Direction::Incoming)),
-        };
-        for start in requested {
-            visitor.move_to(start);
-            while let Some(node) = visitor.next(&graph) {
-                build_state.add_node(&graph, node);
-            }
+        printer.report_no_work(self.report_no_work);
+        printer.verbose(self.verbose);
+        printer.stream(self.workspace.streams());
+        printer.status_format(self.status_format.clone());
+        printer.expect(build_state.commands(&graph, tasks));
+        let printer = RefCell::new(printer);
+        if let Some(frontend) = self.frontend.borrow_mut().as_mut() {
+            frontend.build_started(self.parallelism, self.verbose);
         }
 
         let local_set = LocalSet::new();
@@ -350,25 +554,232 @@ impl ParallelTopoScheduler {
             .build()
             .unwrap();
 
-        let mut pending = Vec::new();
         let sem = Semaphore::new(self.parallelism);
-        local_set.block_on(&mut runtime, async {
+        // Commands in a pool wait for room in it before taking one of the -j slots, so they don't
+        // hold slots that commands outside the pool could use.
+        let pools: HashMap<&[u8], Semaphore> = tasks
+            .pools()
+            .filter(|(_, depth)| *depth > 0)
+            .map(|(name, depth)| (name, Semaphore::new(depth)))
+            .collect();
+        // For the status line, which only shows it while pools have commands running. Sampled once
+        // a command holds its slots and once a finished one has given them back, so it counts
+        // running commands rather than queued ones.
+        let capacity = || {
+            let mut busy: Vec<(String, usize, usize)> = tasks
+                .pools()
+                .filter_map(|(name, depth)| {
+                    let used = depth - pools.get(name)?.available_permits();
+                    Some((String::from_utf8_lossy(name).into_owned(), used, depth))
+                })
+                .filter(|(_, used, _)| *used > 0)
+                .collect();
+            busy.sort();
+            Capacity {
+                jobs: self.parallelism - sem.available_permits(),
+                parallelism: self.parallelism,
+                pools: busy,
+            }
+        };
+        // Commands only count as started once they have their slots, so the status line's running
+        // count and the frontend don't include commands waiting for one.
+        let start = |node: NodeIndex| {
+            let key = graph[node];
+            let task = tasks.task(key).unwrap();
+            let mut printer = printer.borrow_mut();
+            printer.capacity(capacity());
+            printer.started(task);
+            if let (Some(frontend), true) = (self.frontend.borrow_mut().as_mut(), task.is_command())
+            {
+                frontend.edge_started(node.index(), key, task);
+            }
+        };
+        // Only sampled for -d stats.
+        let mut utilization = if ninja_metrics::is_enabled() {
+            Some(Utilization::new(self.parallelism))
+        } else {
+            None
+        };
+        let mut report = BuildReport::default();
+        let stopped = Cell::new(false);
+        let deadline_reached = Cell::new(false);
+        // Stops the build once the deadline passes, and says whether it is stopped for any reason.
+        let check_stopped = || {
+            if !stopped.get() && matches!(self.deadline, Some(d) if Instant::now() >= d) {
+                stopped.set(true);
+                deadline_reached.set(true);
+            }
+            stopped.get()
+        };
+        // Only the commands that made progress are polled, so thousands of them waiting doesn't
+        // make each wakeup slower.
+        let mut pending = FuturesUnordered::new();
+        let mut dispatched = 0;
+        let interruptible = if self.handle_signals {
+            Some(Interruptible::new())
+        } else {
+            None
+        };
+        let interrupted = || interruptible.as_ref().and_then(Interruptible::signal);
+        let result = local_set.block_on(&mut runtime, async {
             while !build_state.done() {
-                if let Some(node) = build_state.next_ready() {
+                // Nothing told running commands to stop, so they are killed rather than waited for.
+                if self.cancel.is_cancelled() {
+                    pending = FuturesUnordered::new();
+                    break;
+                }
+                // Running commands received the signal too, so waiting for them won't take long.
+                if interrupted().is_some() {
+                    stopped.set(true);
+                }
+                if check_stopped() && pending.is_empty() {
+                    break;
+                }
+                let next = if stopped.get() {
+                    None
+                } else {
+                    build_state.next_ready()
+                };
+                if let Some(node) = next {
                     let key = graph[node];
                     if let Some(task) = tasks.task(key) {
                         if let Some(build_task) = rebuilder
                             .build(key.clone(), None, task)
                             .map_err(|e| BuildError::RebuilderError(Box::new(e)))?
                         {
-                            printer.started(task);
                             let sem = &sem;
-                            pending.push(Box::pin(async move {
-                                let _p = sem.acquire().await;
-                                futures::future::ready((node, build_task.run().await)).await
-                            }));
+                            let start = &start;
+                            let pool = task.pool.as_deref().and_then(|pool| pools.get(pool));
+                            let check_stopped = &check_stopped;
+                            let command_timeout = self.command_timeout;
+                            let run_alone = move |node: NodeIndex, build_task: Box<R::Task>| {
+                                async move {
+                                    let _pool = match pool {
+                                        Some(pool) => Some(pool.acquire().await),
+                                        None => None,
+                                    };
+                                    let _p = sem.acquire().await;
+                                    // Commands still waiting for a slot when the build stops
+                                    // never run.
+                                    if check_stopped() {
+                                        return vec![(node, None)];
+                                    }
+                                    start(node);
+                                    let started = Instant::now();
+                                    let result = match command_timeout {
+                                        Some(limit) => {
+                                            tokio::time::timeout(limit, build_task.run())
+                                                .await
+                                                .unwrap_or(Err(CommandTaskError::TimedOut(limit)))
+                                        }
+                                        None => build_task.run().await,
+                                    };
+                                    vec![(node, Some((started, result)))]
+                                }
+                                .boxed_local()
+                            };
+                            // Other ready edges of the same rule may go along in one shell.
+                            let batch_size = task.rule.map_or(0, |id| tasks.rule(id).batch);
+                            let mut batch = vec![(node, build_task)];
+                            // A batch's output has to be captured to tell whose it is.
+                            if batch_size > 1
+                                && batch[0].1.as_command().is_some()
+                                && !task.is_console()
+                                && !self.workspace.streams()
+                            {
+                                let same_batch = |other: NodeIndex| {
+                                    let other_key = graph[other];
+                                    other_key.namespace() == key.namespace()
+                                        && matches!(tasks.task(other_key), Some(other)
+                                            if other.is_command() && other.rule == task.rule
+                                                && other.pool == task.pool)
+                                };
+                                for other in build_state.take_ready(batch_size - 1, same_batch) {
+                                    let other_key = graph[other];
+                                    let other_task = tasks.task(other_key).unwrap();
+                                    match rebuilder
+                                        .build(other_key.clone(), None, other_task)
+                                        .map_err(|e| BuildError::RebuilderError(Box::new(e)))?
+                                    {
+                                        Some(other_build_task)
+                                            if other_build_task.as_command().is_some() =>
+                                        {
+                                            batch.push((other, other_build_task))
+                                        }
+                                        Some(other_build_task) => {
+                                            pending.push(run_alone(other, other_build_task))
+                                        }
+                                        None => {
+                                            report.record(other_key, Outcome::UpToDate);
+                                            printer.borrow_mut().up_to_date(other_task);
+                                            build_state.finish_node(&graph, other, true);
+                                        }
+                                    }
+                                }
+                            }
+                            if batch.len() == 1 {
+                                let (node, build_task) = batch.pop().unwrap();
+                                pending.push(run_alone(node, build_task));
+                            } else {
+                                // The rebuilder's tasks for the edges run together in one shell.
+                                let (batch, build_tasks): (Vec<_>, Vec<_>) =
+                                    batch.into_iter().unzip();
+                                let keep_going = self.failures_allowed > 1;
+                                pending.push(
+                                    async move {
+                                        let _pool = match pool {
+                                            Some(pool) => Some(pool.acquire().await),
+                                            None => None,
+                                        };
+                                        let _p = sem.acquire().await;
+                                        if check_stopped() {
+                                            return batch.into_iter().map(|n| (n, None)).collect();
+                                        }
+                                        for &node in &batch {
+                                            start(node);
+                                        }
+                                        let commands = CommandBatch::new(
+                                            build_tasks
+                                                .iter()
+                                                .map(|task| task.as_command().unwrap())
+                                                .collect(),
+                                        );
+                                        let started = Instant::now();
+                                        let results = match command_timeout {
+                                            Some(limit) => {
+                                                match tokio::time::timeout(
+                                                    limit,
+                                                    commands.run(keep_going),
+                                                )
+                                                .await
+                                                {
+                                                    Ok(results) => results,
+                                                    Err(_) => batch
+                                                        .iter()
+                                                        .map(|_| {
+                                                            Some(Err(CommandTaskError::TimedOut(
+                                                                limit,
+                                                            )))
+                                                        })
+                                                        .collect(),
+                                                }
+                                            }
+                                            None => commands.run(keep_going).await,
+                                        };
+                                        batch
+                                            .into_iter()
+                                            .zip(results)
+                                            .map(|(node, result)| {
+                                                (node, result.map(|result| (started, result)))
+                                            })
+                                            .collect()
+                                    }
+                                    .boxed_local(),
+                                );
+                            }
                         } else {
-                            // No task, so this is a source and we are done.
+                            report.record(key, Outcome::UpToDate);
+                            printer.borrow_mut().up_to_date(task);
                             build_state.finish_node(&graph, node, true);
                         }
                     } else {
@@ -380,31 +791,199 @@ impl ParallelTopoScheduler {
                     // We clearly had capacity, and we were able to find a ready task.
                     // This means we "made progress", either enqueuing the task or
                     // immediately marking it as done. So try to do more queueing.
-                    continue;
+                    dispatched += 1;
+                    if dispatched < DISPATCHES_BETWEEN_YIELDS {
+                        continue;
+                    }
                 }
+                dispatched = 0;
 
-                let (finished, _, left) = futures::future::select_all(pending).await;
-                pending = left;
+                let mut finished = if next.is_some() {
+                    // A long run of ready edges, like many up to date ones, would otherwise keep
+                    // the runtime from reaping commands and the status line from moving.
+                    let _ = tokio::task::yield_now().await;
+                    Vec::new()
+                } else {
+                    if let Some(utilization) = utilization.as_mut() {
+                        // Everything ready has been dispatched at this point, so anything beyond
+                        // the available parallelism is waiting on the semaphore.
+                        utilization.sample(
+                            std::time::Instant::now(),
+                            pending.len().saturating_sub(self.parallelism),
+                            pending.len(),
+                        );
+                    }
+                    // A status line held back by the printer's throttle is drawn once it is due,
+                    // even if nothing finishes by then.
+                    loop {
+                        let due = printer.borrow().redraw_due();
+                        let cancelled = self.cancel.cancelled();
+                        futures::pin_mut!(cancelled);
+                        let next = future::select(pending.next(), cancelled);
+                        let next = match due {
+                            Some(due) => {
+                                let due = tokio::time::Instant::from_std(due);
+                                match tokio::time::timeout_at(due, next).await {
+                                    Ok(next) => next,
+                                    Err(_) => {
+                                        printer.borrow_mut().redraw_if_due();
+                                        continue;
+                                    }
+                                }
+                            }
+                            None => next.await,
+                        };
+                        break match next {
+                            Either::Left((finished, _)) => {
+                                finished.expect("waiting with nothing pending")
+                            }
+                            // Seen at the top of the loop.
+                            Either::Right(_) => Vec::new(),
+                        };
+                    }
+                };
+                let mut handled = 0;
+                loop {
+                    for finished in finished.drain(..) {
+                        let (node, started, result) = match finished {
+                            (node, Some((started, result))) => (node, started, result),
+                            // Stopped before it got its slots, so it was never reported as started
+                            // either.
+                            (node, None) => {
+                                report.record(graph[node], Outcome::Skipped);
+                                build_state.finish_node(&graph, node, false);
+                                continue;
+                            }
+                        };
+                        // If we executed something, that node must have a key and task.
+                        let key = graph[node];
+                        let task = tasks.task(key).unwrap();
+                        let result = record_command(
+                            &self.workspace,
+                            key,
+                            task,
+                            started,
+                            result,
+                            self.build_log.as_ref(),
+                            self.deps_log.as_ref(),
+                        );
+                        if result.is_ok() {
+                            rebuilder
+                                .finished(key.clone(), task)
+                                .map_err(|e| BuildError::RebuilderError(Box::new(e)))?;
+                        }
+                        // Hmm... need a way to convey result to the outside world later, but keep going
+                        // with other tasks. In addition, don't want to pretend something is wrong with
+                        // the queue itself.
+                        // This will update ready and finished, so we will have made progress.
+                        build_state.finish_node(&graph, node, result.is_ok());
+                        match &result {
+                            Ok(_) => report.record(key, Outcome::Succeeded),
+                            Err(err) => {
+                                report.record_failure(
+                                    CommandFailure::new(
+                                        key.clone(),
+                                        task.command().cloned().unwrap_or_default(),
+                                        err,
+                                    )
+                                    .with_rule(tasks.rule_for(key)),
+                                );
+                                // An interrupted command means the user is trying to stop the build,
+                                // so don't keep going regardless of -k.
+                                stopped.set(
+                                    err.is_interrupt()
+                                        || report.failures().len() >= self.failures_allowed,
+                                );
+                            }
+                        }
+                        if let (Some(frontend), true) =
+                            (self.frontend.borrow_mut().as_mut(), task.is_command())
+                        {
+                            frontend.edge_finished(node.index(), &result);
+                        }
+                        let mut printer = printer.borrow_mut();
+                        printer.capacity(capacity());
+                        printer.finished(key, task, result);
+                    }
+                    // Handle commands that finished meanwhile too, rather than going back to
+                    // dispatching between each. Once the build is stopping, polling the others
+                    // could start commands that are waiting for a slot.
+                    handled += 1;
+                    if stopped.get() || handled >= MAX_COMPLETIONS_PER_WAKEUP {
+                        break;
+                    }
+                    match pending.next().now_or_never() {
+                        Some(Some(more)) => finished = more,
+                        _ => break,
+                    }
+                }
+            }
+            assert!(pending.is_empty());
+            if self.cancel.is_cancelled() {
+                return Err(BuildError::Cancelled);
+            }
+            match interrupted() {
+                Some(signal) => Err(BuildError::Interrupted(signal)),
+                None if deadline_reached.get() => report.past_deadline(&graph, tasks),
+                None => report.finish(&graph, tasks),
+            }
+        });
+        if let Some(frontend) = self.frontend.borrow_mut().as_mut() {
+            frontend.build_finished();
+        }
+        if let Some(mut utilization) = utilization {
+            utilization.sample(std::time::Instant::now(), 0, 0);
+            eprintln!(
+                "scheduler utilization (-j {}):\n{}",
+                self.parallelism, utilization
+            );
+        }
+        result
+    }
+}
+
+/// How many ready edges the local scheduler dispatches before yielding to the runtime and handling
+/// finished commands.
+const DISPATCHES_BETWEEN_YIELDS: usize = 256;
 
-                let (node, result) = finished;
-                // Hmm... need a way to convey result to the outside world later, but keep going with
-                // other tasks. In addition, don't want to pretend something is wrong with the
-                // queue itself.
-                // This will update ready and finished, so we will have made progress.
-                build_state.finish_node(&graph, node, result.is_ok());
+/// How many finished commands the local scheduler handles before dispatching more edges.
+const MAX_COMPLETIONS_PER_WAKEUP: usize = 64;
 
-                // If we executed something, that node must have a key and task.
-                let key = graph[node];
-                let task = tasks.task(key);
-                printer.finished(task.unwrap(), result);
+/// Records a command that succeeded in the logs. Failing to read the inputs it discovered fails
+/// the edge, as in ninja, since it would otherwise not run again when they change.
+fn record_command(
+    workspace: &Workspace,
+    key: &Key,
+    task: &Task,
+    started: Instant,
+    result: CommandTaskResult,
+    build_log: Option<&SharedBuildLog>,
+    deps_log: Option<&SharedDepsLog>,
+) -> CommandTaskResult {
+    let output = result?;
+    let command = match task.command() {
+        Some(command) => command,
+        None => return Ok(output),
+    };
+    let output = match (deps_log, &task.deps) {
+        (Some(deps_log), Some(_)) => {
+            let (output, inputs) = extract_deps(workspace, task, output)?;
+            for path in key.iter() {
+                let mtime = output_mtime(&workspace.path(path.as_bytes()));
+                deps_log.record(path.as_bytes(), mtime, &inputs);
             }
-            assert!(pending.is_empty());
-            Ok(())
-        })
+            output
+        }
+        _ => output,
+    };
+    if let Some(build_log) = build_log {
+        build_log.record_command(workspace, key, command, started);
     }
+    Ok(output)
 }
 
 impl interface::Scheduler<Key, CommandTaskResult> for ParallelTopoScheduler {
+    type Report = BuildReport;
     type Error = BuildError;
 
     fn schedule(
@@ -412,42 +991,839 @@ impl interface::Scheduler<Key, CommandTaskResult> for ParallelTopoScheduler {
         rebuilder: &impl interface::Rebuilder<Key, CommandTaskResult>,
         tasks: &Tasks,
         start: Vec<Key>,
-    ) -> Result<(), Self::Error> {
-        self.schedule_internal(rebuilder, tasks, Some(start))
+    ) -> Result<BuildReport, Self::Error> {
+        self.schedule_internal(rebuilder, tasks, Some(&start))
     }
 
     fn schedule_externals(
         &self,
         rebuilder: &impl interface::Rebuilder<Key, CommandTaskResult>,
         tasks: &Tasks,
-    ) -> Result<(), Self::Error> {
+    ) -> Result<BuildReport, Self::Error> {
         self.schedule_internal(rebuilder, tasks, None)
     }
 }
 
+/// Builds every root of the graph, that is every key nothing else depends on.
+///
+/// ```
+/// # use std::{os::unix::process::ExitStatusExt, process::{ExitStatus, Output}};
+/// # use async_trait::async_trait;
+/// # use ninja_builder::{interface::{BuildTask, Rebuilder}, task::{Key, Task}, CommandTaskResult};
+/// # struct Done;
+/// # #[async_trait(?Send)]
+/// # impl BuildTask<CommandTaskResult> for Done {
+/// #     async fn run(&self) -> CommandTaskResult {
+/// #         Ok(Output { status: ExitStatus::from_raw(0), stdout: vec![], stderr: vec![] })
+/// #     }
+/// # }
+/// # struct AlwaysDirty;
+/// # impl Rebuilder<Key, CommandTaskResult> for AlwaysDirty {
+/// #     type Task = Done;
+/// #     type Error = std::convert::Infallible;
+/// #     fn build(&self, _: Key, _: Option<CommandTaskResult>, _: &Task)
+/// #         -> Result<Option<Box<Done>>, Self::Error> { Ok(Some(Box::new(Done))) }
+/// # }
+/// use ninja_builder::{build_externals, task::TasksBuilder, Outcome, ParallelTopoScheduler};
+///
+/// let mut builder = TasksBuilder::new();
+/// builder.command(&["a"], &[] as &[&str], "make a")?;
+/// builder.command(&["b"], &["a"], "make b")?;
+/// let tasks = builder.build()?;
+/// let report = build_externals(&ParallelTopoScheduler::new(1), &AlwaysDirty, &tasks)?;
+/// assert_eq!(report.count(Outcome::Succeeded), 2);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
 pub fn build_externals<K, V, Scheduler>(
     scheduler: &Scheduler,
     rebuilder: &impl interface::Rebuilder<K, V>,
     tasks: &Tasks,
-) -> Result<(), Scheduler::Error>
+) -> Result<Scheduler::Report, Scheduler::Error>
 where
     Scheduler: interface::Scheduler<K, V>,
 {
-    Ok(scheduler.schedule_externals(rebuilder, tasks)?)
+    scheduler.schedule_externals(rebuilder, tasks)
 }
 
+/// Builds `requested` keys, or every root of the graph if nothing was requested.
+///
+/// Use `task::requested_targets` to decide between command line targets and manifest defaults.
+///
+/// ```
+/// # use std::{os::unix::process::ExitStatusExt, process::{ExitStatus, Output}};
+/// # use async_trait::async_trait;
+/// # use ninja_builder::{interface::{BuildTask, Rebuilder}, task::{Key, Task}, CommandTaskResult};
+/// # struct Done;
+/// # #[async_trait(?Send)]
+/// # impl BuildTask<CommandTaskResult> for Done {
+/// #     async fn run(&self) -> CommandTaskResult {
+/// #         Ok(Output { status: ExitStatus::from_raw(0), stdout: vec![], stderr: vec![] })
+/// #     }
+/// # }
+/// # struct AlwaysDirty;
+/// # impl Rebuilder<Key, CommandTaskResult> for AlwaysDirty {
+/// #     type Task = Done;
+/// #     type Error = std::convert::Infallible;
+/// #     fn build(&self, _: Key, _: Option<CommandTaskResult>, _: &Task)
+/// #         -> Result<Option<Box<Done>>, Self::Error> { Ok(Some(Box::new(Done))) }
+/// # }
+/// use ninja_builder::{build_requested, task::TasksBuilder, Outcome, ParallelTopoScheduler};
+///
+/// let mut builder = TasksBuilder::new();
+/// builder.command(&["a"], &[] as &[&str], "make a")?;
+/// builder.command(&["b"], &[] as &[&str], "make b")?;
+/// let tasks = builder.build()?;
+/// let scheduler = ParallelTopoScheduler::new(2);
+/// let report = build_requested(&scheduler, &AlwaysDirty, &tasks, None)?;
+/// assert_eq!(report.count(Outcome::Succeeded), 2);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn build_requested<K, V, Scheduler>(
+    scheduler: &Scheduler,
+    rebuilder: &impl interface::Rebuilder<K, V>,
+    tasks: &Tasks,
+    requested: Option<Vec<K>>,
+) -> Result<Scheduler::Report, Scheduler::Error>
+where
+    Scheduler: interface::Scheduler<K, V>,
+{
+    match requested {
+        Some(start) => build(scheduler, rebuilder, tasks, start),
+        None => build_externals(scheduler, rebuilder, tasks),
+    }
+}
+
+/// Builds the keys in `start` and everything they depend on, as the rebuilder decides, and says
+/// what happened to each.
+///
+/// ```
+/// # use std::{os::unix::process::ExitStatusExt, process::{ExitStatus, Output}};
+/// # use async_trait::async_trait;
+/// # use ninja_builder::{interface::{BuildTask, Rebuilder}, task::{Key, Task}, CommandTaskResult};
+/// # struct Done;
+/// # #[async_trait(?Send)]
+/// # impl BuildTask<CommandTaskResult> for Done {
+/// #     async fn run(&self) -> CommandTaskResult {
+/// #         Ok(Output { status: ExitStatus::from_raw(0), stdout: vec![], stderr: vec![] })
+/// #     }
+/// # }
+/// # struct AlwaysDirty;
+/// # impl Rebuilder<Key, CommandTaskResult> for AlwaysDirty {
+/// #     type Task = Done;
+/// #     type Error = std::convert::Infallible;
+/// #     fn build(&self, _: Key, _: Option<CommandTaskResult>, _: &Task)
+/// #         -> Result<Option<Box<Done>>, Self::Error> { Ok(Some(Box::new(Done))) }
+/// # }
+/// use ninja_builder::{build, task::TasksBuilder, Outcome, ParallelTopoScheduler};
+///
+/// let mut builder = TasksBuilder::new();
+/// builder.source("foo.c");
+/// builder.command(&["foo.o"], &["foo.c"], "cc -c foo.c -o foo.o")?;
+/// builder.command(&["foo"], &["foo.o"], "cc foo.o -o foo")?;
+/// builder.command(&["bar"], &["foo.c"], "cc foo.c -o bar")?;
+/// let tasks = builder.build()?;
+/// let foo = Key::Path(b"foo".to_vec().into());
+/// let report = build(&ParallelTopoScheduler::new(2), &AlwaysDirty, &tasks, vec![foo.clone()])?;
+/// assert_eq!(report.outcome(&foo), Some(Outcome::Succeeded));
+/// // Nothing asked for bar.
+/// assert_eq!(report.outcome(&Key::Path(b"bar".to_vec().into())), None);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
 pub fn build<K, V, Scheduler>(
     scheduler: &Scheduler,
     rebuilder: &impl interface::Rebuilder<K, V>,
     tasks: &Tasks,
     start: Vec<K>,
-) -> Result<(), Scheduler::Error>
+) -> Result<Scheduler::Report, Scheduler::Error>
 where
     Scheduler: interface::Scheduler<K, V>,
 {
-    Ok(scheduler.schedule(rebuilder, tasks, start)?)
+    scheduler.schedule(rebuilder, tasks, start)
 }
 
-pub fn caching_mtime_rebuilder() -> CachingMTimeRebuilder<DiskDirtyCache<SystemDiskInterface>> {
-    CachingMTimeRebuilder::new(DiskDirtyCache::new(SystemDiskInterface {}))
+/// Whether building `target` would run no commands. Only the rebuilder runs, over everything
+/// `target` depends on in dependency order, so nothing is built and no logs are written. A key
+/// no edge produces is always up to date.
+pub fn is_up_to_date<R>(rebuilder: &R, tasks: &Tasks, target: &Key) -> Result<bool, BuildError>
+where
+    R: interface::Rebuilder<Key, CommandTaskResult>,
+{
+    let start = std::slice::from_ref(target);
+    let graph = ParallelTopoScheduler::build_graph(tasks, Some(start))?;
+    let root = match graph.node_indices().find(|node| graph[*node] == target) {
+        Some(root) => root,
+        None => return Ok(true),
+    };
+    let mut visitor = DfsPostOrder::new(&graph, root);
+    while let Some(node) = visitor.next(&graph) {
+        let key = graph[node];
+        if let Some(task) = tasks.task(key) {
+            if rebuilder
+                .build(key.clone(), None, task)
+                .map_err(|e| BuildError::RebuilderError(Box::new(e)))?
+                .is_some()
+            {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+pub fn caching_mtime_rebuilder(
+    directory_inputs: DirectoryInputs,
+) -> CachingMTimeRebuilder<DiskDirtyCache<SystemDiskInterface>> {
+    CachingMTimeRebuilder::new(DiskDirtyCache::with_directory_inputs(
+        SystemDiskInterface {},
+        directory_inputs,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{
+        interface::{Rebuilder, Scheduler},
+        task::{description_to_tasks, requested_targets, KeyPath, TasksBuilder},
+        testing::TempDir,
+    };
+
+    /// Records which entry point was used instead of building anything.
+    #[derive(Default)]
+    struct RecordingScheduler {
+        calls: RefCell<Vec<Option<Vec<Key>>>>,
+    }
+
+    impl Scheduler<Key, CommandTaskResult> for RecordingScheduler {
+        type Report = ();
+        type Error = BuildError;
+
+        fn schedule(
+            &self,
+            _rebuilder: &impl Rebuilder<Key, CommandTaskResult>,
+            _tasks: &Tasks,
+            start: Vec<Key>,
+        ) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push(Some(start));
+            Ok(())
+        }
+
+        fn schedule_externals(
+            &self,
+            _rebuilder: &impl Rebuilder<Key, CommandTaskResult>,
+            _tasks: &Tasks,
+        ) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push(None);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn build_requested_falls_back_to_externals() {
+        let scheduler = RecordingScheduler::default();
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        let (tasks, _) = description_to_tasks(Default::default());
+        let key = Key::Path(b"a".to_vec().into());
+
+        build_requested(&scheduler, &rebuilder, &tasks, Some(vec![key.clone()])).unwrap();
+        build_requested(&scheduler, &rebuilder, &tasks, None).unwrap();
+        assert_eq!(*scheduler.calls.borrow(), vec![Some(vec![key]), None]);
+    }
+
+    #[test]
+    fn graph_too_large_for_indices() {
+        // u8 indices stand in for u32, which would take billions of keys to run out.
+        let chain = |length: usize| {
+            let mut builder = TasksBuilder::new();
+            builder.source("0");
+            for i in 1..length {
+                builder
+                    .command(&[format!("{}", i)], &[format!("{}", i - 1)], "cp")
+                    .unwrap();
+            }
+            builder.build().unwrap()
+        };
+        let tasks = chain(255);
+        assert!(ParallelTopoScheduler::build_graph_with_indices::<u8>(&tasks, None).is_ok());
+        let tasks = chain(256);
+        assert_eq!(
+            ParallelTopoScheduler::build_graph_with_indices::<u8>(&tasks, None).unwrap_err(),
+            GraphTooLarge {
+                limit: 255,
+                what: "nodes"
+            }
+        );
+
+        // Few keys, but each of 20 edges depends on the same 15 inputs.
+        let inputs: Vec<String> = (0..15).map(|i| format!("in{}", i)).collect();
+        let mut builder = TasksBuilder::new();
+        for input in &inputs {
+            builder.source(input);
+        }
+        for i in 0..20 {
+            builder
+                .command(&[format!("out{}", i)], &inputs, "cc")
+                .unwrap();
+        }
+        let tasks = builder.build().unwrap();
+        let error =
+            ParallelTopoScheduler::build_graph_with_indices::<u8>(&tasks, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the build graph needs more than 255 edges"
+        );
+    }
+
+    #[test]
+    fn requested_edges_run_first() {
+        let mut builder = TasksBuilder::new();
+        let no_inputs: &[&str] = &[];
+        builder
+            .command(&["app"], &["a.o", "b.o", "lib.a"], "link")
+            .unwrap()
+            .command(&["lib.a"], &["x2"], "ar")
+            .unwrap()
+            .command(&["a.o"], no_inputs, "cc a")
+            .unwrap()
+            .command(&["b.o"], &["gen"], "cc b")
+            .unwrap()
+            .command(&["gen"], no_inputs, "gen")
+            .unwrap()
+            .command(&["x2"], &["x1"], "x2")
+            .unwrap()
+            .command(&["x1"], no_inputs, "x1")
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let key = |path: &str| Key::Path(path.as_bytes().to_vec().into());
+        let start = [key("app")];
+        let graph = ParallelTopoScheduler::build_graph(&tasks, Some(&start)).unwrap();
+        let mut state = BuildState::for_start(&graph, Some(&start), true);
+
+        assert_eq!(graph[state.next_ready().unwrap()], &key("a.o"));
+        let mut rest = vec![state.next_ready().unwrap(), state.next_ready().unwrap()];
+        rest.sort_by_key(|node| graph[*node]);
+        assert_eq!(state.next_ready(), None);
+        // x1 finishing first readies x2, but app needs b.o directly.
+        for node in rest.into_iter().rev() {
+            state.finish_node(&graph, node, true);
+        }
+        assert_eq!(graph[state.next_ready().unwrap()], &key("b.o"));
+        assert_eq!(graph[state.next_ready().unwrap()], &key("x2"));
+    }
+
+    fn failing_tasks() -> Tasks {
+        let mut builder = TasksBuilder::new();
+        let no_inputs: &[&str] = &[];
+        builder
+            .command(&["ninja-test-fail-1"], no_inputs, "exit 1")
+            .unwrap()
+            .command(&["ninja-test-fail-2"], no_inputs, "exit 2")
+            .unwrap()
+            .command(&["ninja-test-after"], &["ninja-test-fail-1"], "exit 0")
+            .unwrap();
+        builder.build().unwrap()
+    }
+
+    fn failed_keys(result: Result<BuildReport, BuildError>) -> Vec<Key> {
+        match result {
+            Err(BuildError::CommandsFailed(report)) => {
+                let mut keys: Vec<Key> =
+                    report.failures().0.iter().map(|f| f.key.clone()).collect();
+                keys.sort();
+                keys
+            }
+            other => panic!("expected failures, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stops_after_first_failure() {
+        let scheduler = ParallelTopoScheduler::new(1);
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        let keys = failed_keys(build_externals(&scheduler, &rebuilder, &failing_tasks()));
+        assert_eq!(keys.len(), 1);
+    }
+
+    #[test]
+    fn nothing_starts_after_the_deadline() {
+        let scheduler = ParallelTopoScheduler::new(1).deadline(Instant::now());
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        match build_externals(&scheduler, &rebuilder, &failing_tasks()) {
+            Err(BuildError::DeadlineReached(report)) => {
+                assert_eq!(report.count(Outcome::Skipped), 3);
+                assert!(report.failures().is_empty());
+            }
+            other => panic!("expected the deadline, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keep_going_aggregates_failures() {
+        let scheduler = ParallelTopoScheduler::new(1).keep_going(usize::MAX);
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        let keys = failed_keys(build_externals(&scheduler, &rebuilder, &failing_tasks()));
+        assert_eq!(
+            keys,
+            vec![
+                Key::Path(b"ninja-test-fail-1".to_vec().into()),
+                Key::Path(b"ninja-test-fail-2".to_vec().into()),
+            ]
+        );
+    }
+
+    /// The `Status` fields of the frontend messages sent while building `tasks`, in order.
+    fn frontend_events(
+        scheduler: ParallelTopoScheduler,
+        tasks: &Tasks,
+    ) -> (Result<BuildReport, BuildError>, Vec<u8>) {
+        #[derive(Clone, Default)]
+        struct Capture(std::rc::Rc<RefCell<Vec<u8>>>);
+
+        impl std::io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let capture = Capture::default();
+        let scheduler = scheduler.frontend(Frontend::new(Box::new(capture.clone())));
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        let result = build_externals(&scheduler, &rebuilder, tasks);
+        let bytes = capture.0.borrow();
+        let mut events = vec![];
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            // Every message here is shorter than 128 bytes, so its length is a single byte.
+            let len = rest[0] as usize;
+            events.push(rest[1] >> 3);
+            rest = &rest[1 + len..];
+        }
+        (result, events)
+    }
+
+    const EDGE_STARTED: u8 = 4;
+    const EDGE_FINISHED: u8 = 5;
+
+    #[test]
+    fn edges_start_once_they_have_a_slot() {
+        let mut builder = TasksBuilder::new();
+        let no_inputs: &[&str] = &[];
+        for i in 0..3 {
+            builder
+                .command(&[format!("ninja-test-slot-{}", i)], no_inputs, "true")
+                .unwrap();
+        }
+        // None of them creates its output, so keep going past that.
+        let scheduler = ParallelTopoScheduler::new(1).keep_going(usize::MAX);
+        let (_, events) = frontend_events(scheduler, &builder.build().unwrap());
+        let edges: Vec<u8> = events
+            .into_iter()
+            .filter(|event| *event == EDGE_STARTED || *event == EDGE_FINISHED)
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                EDGE_STARTED,
+                EDGE_FINISHED,
+                EDGE_STARTED,
+                EDGE_FINISHED,
+                EDGE_STARTED,
+                EDGE_FINISHED
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_going_stops_after_n_failures() {
+        let mut builder = TasksBuilder::new();
+        let no_inputs: &[&str] = &[];
+        for i in 0..4 {
+            builder
+                .command(&[format!("ninja-test-k-{}", i)], no_inputs, "exit 1")
+                .unwrap();
+        }
+        let tasks = builder.build().unwrap();
+        // One at a time, so the third failure can't already be running when the second ends.
+        let scheduler = ParallelTopoScheduler::new(1).keep_going(2);
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        match build_externals(&scheduler, &rebuilder, &tasks) {
+            Err(BuildError::CommandsFailed(report)) => {
+                assert_eq!(report.count(Outcome::Failed), 2);
+                assert_eq!(report.count(Outcome::Skipped), 2);
+            }
+            other => panic!("expected failures, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn edges_skipped_by_a_stop_are_never_started() {
+        let count = |events: &[u8], kind: u8| events.iter().filter(|e| **e == kind).count();
+
+        // Both failing edges are ready at once, but the second is still waiting for the only slot
+        // when the first fails.
+        let (result, events) = frontend_events(ParallelTopoScheduler::new(1), &failing_tasks());
+        assert_eq!(failed_keys(result).len(), 1);
+        assert_eq!(count(&events, EDGE_STARTED), 1);
+        assert_eq!(count(&events, EDGE_FINISHED), 1);
+
+        let scheduler = ParallelTopoScheduler::new(1).deadline(Instant::now());
+        let (_, events) = frontend_events(scheduler, &failing_tasks());
+        assert_eq!(count(&events, EDGE_STARTED), 0);
+        assert_eq!(count(&events, EDGE_FINISHED), 0);
+    }
+
+    #[test]
+    fn report_says_what_happened_to_each_edge() {
+        let dir = TempDir::new("report");
+        let mut builder = TasksBuilder::new();
+        let no_inputs: &[&str] = &[];
+        builder
+            .command(&["fails"], no_inputs, "echo broken; exit 1")
+            .unwrap()
+            .command(&["after"], &["fails"], "touch after")
+            .unwrap()
+            .command(&["made"], no_inputs, "touch made")
+            .unwrap()
+            .command(&["present"], no_inputs, "touch present")
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let workspace = Workspace::new(&*dir);
+        std::fs::write(dir.join("present"), "").unwrap();
+        let scheduler = ParallelTopoScheduler::new(1)
+            .keep_going(usize::MAX)
+            .workspace(workspace.clone());
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn).workspace(workspace.clone());
+        let report = match build_externals(&scheduler, &rebuilder, &tasks) {
+            Err(BuildError::CommandsFailed(report)) => report,
+            other => panic!("expected failures, got {:?}", other),
+        };
+        let key = |path: &str| Key::Path(path.as_bytes().to_vec().into());
+        assert_eq!(report.outcome(&key("fails")), Some(Outcome::Failed));
+        assert_eq!(report.outcome(&key("after")), Some(Outcome::Skipped));
+        assert_eq!(report.outcome(&key("made")), Some(Outcome::Succeeded));
+        assert_eq!(report.outcome(&key("present")), Some(Outcome::UpToDate));
+        assert_eq!(report.count(Outcome::Failed), 1);
+        assert_eq!(report.failure(&key("fails")).unwrap().output, "broken");
+        assert!(report.failure(&key("made")).is_none());
+
+        // Once the failed output exists, only what was skipped is left to do.
+        std::fs::write(dir.join("fails"), "").unwrap();
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn).workspace(workspace);
+        let report = build_externals(&scheduler, &rebuilder, &tasks).unwrap();
+        assert_eq!(report.count(Outcome::UpToDate), 3);
+        assert_eq!(report.outcome(&key("after")), Some(Outcome::Succeeded));
+    }
+
+    #[test]
+    fn slow_commands_time_out() {
+        let mut builder = TasksBuilder::default();
+        let no_inputs: &[&str] = &[];
+        builder
+            .command(&["ninja-test-slow"], no_inputs, "sleep 10")
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let scheduler = ParallelTopoScheduler::new(1).command_timeout(Duration::from_millis(100));
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        let started = std::time::Instant::now();
+        match build_externals(&scheduler, &rebuilder, &tasks) {
+            Err(BuildError::CommandsFailed(report)) => {
+                let failures = &report.failures().0;
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].kind, FailureKind::TimedOut);
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn cancelling_kills_running_commands() {
+        let mut builder = TasksBuilder::default();
+        let no_inputs: &[&str] = &[];
+        builder
+            .command(&["ninja-test-cancelled"], no_inputs, "sleep 10")
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let scheduler = ParallelTopoScheduler::new(1);
+        let cancel = scheduler.canceller();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            cancel.cancel();
+        });
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        let started = std::time::Instant::now();
+        match build_externals(&scheduler, &rebuilder, &tasks) {
+            Err(BuildError::Cancelled) => {}
+            other => panic!("expected the build to be cancelled, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+        // Cancelling is for good.
+        assert!(matches!(
+            build_externals(&scheduler, &rebuilder, &tasks),
+            Err(BuildError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn pools_limit_concurrency() {
+        use ninja_parse::repr::{Action, Build, Description, Pool};
+
+        let dir = TempDir::new("pool");
+        let path = |p: &str| dir.path(p);
+        // Fails if another command of the pool is running at the same time.
+        let command = |out: &str| {
+            format!(
+                "mkdir {busy} && sleep 0.1 && rmdir {busy} && touch {out}",
+                busy = path("busy"),
+                out = path(out)
+            )
+        };
+        let builds = ["a", "b", "c"]
+            .iter()
+            .map(|out| Build {
+                action: Action::Command(command(out)),
+                rule: b"serial".to_vec(),
+                rule_index: None,
+                description: None,
+                depfile: None,
+                rspfile: None,
+                deps: None,
+                pool: Some(b"one".to_vec()),
+                namespace: None,
+                inputs: vec![],
+                implicit_inputs: vec![],
+                order_inputs: vec![],
+                outputs: vec![path(out).into_bytes()],
+                implicit_outputs: vec![],
+            })
+            .collect();
+        let (tasks, _) = description_to_tasks(Description {
+            builds,
+            pools: vec![Pool {
+                name: b"one".to_vec(),
+                depth: 1,
+            }],
+            ..Default::default()
+        });
+        let scheduler = ParallelTopoScheduler::new(3);
+        let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+        build_externals(&scheduler, &rebuilder, &tasks).unwrap();
+    }
+
+    #[test]
+    fn batches_run_the_rebuilders_tasks() {
+        use ninja_parse::repr::{Action, Build, Description, RuleDescriptor};
+
+        let dir = TempDir::new("batch");
+        let builds = ["a", "b", "c"]
+            .iter()
+            .map(|out| Build {
+                action: Action::Command(format!("echo $$ >> shells && touch {}", out)),
+                rule: b"stamp".to_vec(),
+                rule_index: Some(0),
+                description: None,
+                depfile: None,
+                rspfile: None,
+                deps: None,
+                pool: None,
+                namespace: None,
+                inputs: vec![],
+                implicit_inputs: vec![],
+                order_inputs: vec![],
+                outputs: vec![out.as_bytes().to_vec()],
+                implicit_outputs: vec![],
+            })
+            .collect();
+        let (tasks, _) = description_to_tasks(Description {
+            builds,
+            rules: vec![RuleDescriptor {
+                name: b"stamp".to_vec(),
+                description: None,
+                depfile: None,
+                deps: None,
+                pool: None,
+                restat: false,
+                generator: false,
+                batch: 3,
+            }],
+            ..Default::default()
+        });
+        // Only the rebuilder knows where the commands run.
+        let rebuilder =
+            caching_mtime_rebuilder(DirectoryInputs::Warn).workspace(Workspace::new(&*dir));
+        build_externals(&ParallelTopoScheduler::new(3), &rebuilder, &tasks).unwrap();
+        let shells = std::fs::read_to_string(dir.join("shells")).unwrap();
+        assert_eq!(shells.lines().collect::<HashSet<_>>().len(), 1);
+    }
+
+    #[test]
+    fn up_to_date_without_building() {
+        let dir = TempDir::new("up-to-date");
+        let path = |p: &str| dir.path(p);
+        std::fs::write(path("in"), "").unwrap();
+        let mut builder = TasksBuilder::new();
+        builder
+            .source(path("in"))
+            .command(
+                &[path("mid")],
+                &[path("in")],
+                format!("touch {}", path("mid")),
+            )
+            .unwrap()
+            .command(
+                &[path("out")],
+                &[path("mid")],
+                format!("touch {}", path("out")),
+            )
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let out = Key::Path(path("out").into_bytes().into());
+        let up_to_date = || {
+            let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+            is_up_to_date(&rebuilder, &tasks, &out).unwrap()
+        };
+
+        assert!(!up_to_date());
+        assert!(!dir.join("mid").exists(), "nothing should have been built");
+        build(
+            &ParallelTopoScheduler::new(1),
+            &caching_mtime_rebuilder(DirectoryInputs::Warn),
+            &tasks,
+            vec![out.clone()],
+        )
+        .unwrap();
+        assert!(up_to_date());
+        // Dirtiness propagates from deep in the graph.
+        std::fs::remove_file(dir.join("mid")).unwrap();
+        assert!(!up_to_date());
+    }
+
+    /// `all` is a phony alias for a phony group, which points at two commands. Every command
+    /// appends its name to `log` so tests can see what ran.
+    struct AliasChain {
+        dir: TempDir,
+        tasks: Tasks,
+    }
+
+    impl AliasChain {
+        fn new(name: &str) -> Self {
+            let dir = TempDir::new(name);
+            let path = |p: &str| dir.path(p);
+            let touch = |p: &str| format!("echo {} >> {} && touch {}", p, path("log"), path(p));
+            let mut builder = TasksBuilder::new();
+            let no_inputs: &[&str] = &[];
+            builder
+                .command(&[path("a")], no_inputs, touch("a"))
+                .unwrap()
+                .command(&[path("b")], &[path("a")], touch("b"))
+                .unwrap()
+                .phony(&[path("group")], &[path("a"), path("b")])
+                .unwrap()
+                .phony(&[path("all")], &[path("group")])
+                .unwrap();
+            let tasks = builder.build().unwrap();
+            AliasChain { dir, tasks }
+        }
+
+        fn path(&self, p: &str) -> Vec<u8> {
+            self.dir.path(p).into_bytes()
+        }
+
+        /// Builds what the manifest would with `default all` and no targets on the command line.
+        fn build_default(&self) {
+            let defaults = Some(vec![self.path("all")].into_iter().collect());
+            self.build(requested_targets(None, defaults));
+        }
+
+        fn build_cli(&self, targets: &[&str]) {
+            let cli = targets.iter().map(|t| self.path(t)).collect();
+            self.build(requested_targets(Some(cli), None));
+        }
+
+        fn build(&self, requested: Option<Vec<KeyPath>>) {
+            let scheduler = ParallelTopoScheduler::new(1);
+            let rebuilder = caching_mtime_rebuilder(DirectoryInputs::Warn);
+            let requested = requested.map(|r| r.into_iter().map(Key::Path).collect());
+            build_requested(&scheduler, &rebuilder, &self.tasks, requested).unwrap();
+        }
+
+        /// Commands run since the last call.
+        fn ran(&self) -> Vec<String> {
+            let log = self.dir.join("log");
+            let ran = std::fs::read_to_string(&log).unwrap_or_default();
+            let _ = std::fs::remove_file(&log);
+            ran.lines().map(str::to_owned).collect()
+        }
+    }
+
+    #[test]
+    fn default_through_phony_aliases() {
+        let chain = AliasChain::new("default-phony");
+        chain.build_default();
+        assert_eq!(chain.ran(), vec!["a", "b"]);
+
+        chain.build_default();
+        assert!(chain.ran().is_empty(), "everything should be up to date");
+
+        std::fs::remove_file(chain.dir.join("b")).unwrap();
+        chain.build_default();
+        assert_eq!(chain.ran(), vec!["b"]);
+    }
+
+    #[test]
+    fn default_alias_matches_naming_targets() {
+        let by_default = AliasChain::new("default-alias");
+        let by_name = AliasChain::new("named-targets");
+        by_default.build_default();
+        by_name.build_cli(&["a", "b"]);
+        assert_eq!(by_default.ran(), by_name.ran());
+
+        by_default.build_default();
+        by_name.build_cli(&["a", "b"]);
+        assert_eq!(by_default.ran(), by_name.ran());
+    }
+
+    #[test]
+    fn concurrent_builds_in_workspaces() {
+        let builds: Vec<_> = ["first", "second"]
+            .iter()
+            .map(|name| {
+                let dir = TempDir::new(&format!("workspace-{}", name));
+                std::fs::write(dir.join("in"), "").unwrap();
+                let workspace = Workspace::new(&*dir).env("BUILD_NAME", *name);
+                let thread = std::thread::spawn(move || {
+                    let mut builder = TasksBuilder::new();
+                    builder
+                        .source("in")
+                        .command(&["sub/out"], &["in"], "echo $BUILD_NAME > sub/out")
+                        .unwrap();
+                    let tasks = builder.build().unwrap();
+                    let out = Key::Path(b"sub/out".to_vec().into());
+                    let rebuilder =
+                        caching_mtime_rebuilder(DirectoryInputs::Warn).workspace(workspace.clone());
+                    let scheduler = ParallelTopoScheduler::new(1).workspace(workspace.clone());
+                    build(&scheduler, &rebuilder, &tasks, vec![out.clone()]).unwrap();
+                    let rebuilder =
+                        caching_mtime_rebuilder(DirectoryInputs::Warn).workspace(workspace);
+                    is_up_to_date(&rebuilder, &tasks, &out).unwrap()
+                });
+                (name, dir, thread)
+            })
+            .collect();
+        for (name, dir, thread) in builds {
+            let up_to_date = thread.join().unwrap();
+            let out = std::fs::read_to_string(dir.join("sub/out")).unwrap();
+            assert_eq!(out, format!("{}\n", name));
+            assert!(up_to_date, "{} should see its own output", name);
+        }
+        assert!(!std::path::Path::new("sub/out").exists());
+    }
 }
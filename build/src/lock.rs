@@ -0,0 +1,115 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+use thiserror::Error;
+
+/// Created in the directory ninja builds in. It is never removed, since another process may be
+/// waiting on it, only locked and unlocked.
+pub const LOCK_FILE: &str = ".ninja_lock";
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("another ninja process is running in this directory (pid {0})")]
+    Held(u32),
+    #[error("another ninja process is running in this directory")]
+    HeldByUnknown,
+    #[error("locking {}: {}", LOCK_FILE, .0)]
+    Io(#[from] io::Error),
+}
+
+/// Held for the duration of a build, so that two ninja invocations in the same directory don't
+/// interleave writes to outputs and logs. Released when dropped, or when the process dies.
+#[derive(Debug)]
+pub struct BuildLock {
+    // flock() locks belong to the open file, so keeping it open keeps the lock.
+    _file: File,
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+fn holder(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+impl BuildLock {
+    /// Locks `dir`. If another process holds the lock, either fails, or with `wait`, blocks until
+    /// it is released.
+    pub fn acquire<P: AsRef<Path>>(dir: P, wait: bool) -> Result<Self, LockError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The holder's pid is only replaced once the lock is ours.
+            .truncate(false)
+            .open(dir.as_ref().join(LOCK_FILE))?;
+        if let Err(err) = flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err.into());
+            }
+            let holder = holder(&mut file);
+            if !wait {
+                return Err(holder.map_or(LockError::HeldByUnknown, LockError::Held));
+            }
+            match holder {
+                Some(pid) => eprintln!("ninja: waiting for another ninja process (pid {})", pid),
+                None => eprintln!("ninja: waiting for another ninja process"),
+            }
+            flock(&file, libc::LOCK_EX)?;
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(BuildLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn exclusive() {
+        let dir = TempDir::new("lock");
+        let lock = BuildLock::acquire(&*dir, false).expect("first lock");
+        // Each acquire opens the file anew, so this conflicts even within one process.
+        match BuildLock::acquire(&*dir, false) {
+            Err(LockError::Held(pid)) => assert_eq!(pid, std::process::id()),
+            other => panic!("expected the lock to be held, got {:?}", other),
+        }
+        drop(lock);
+        assert!(BuildLock::acquire(&*dir, false).is_ok());
+    }
+}
@@ -0,0 +1,225 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Working out which commands a build would run, and why, without running any of them.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::{
+    explain::{ExplainLog, Reason},
+    failures::json_string,
+    interface::Rebuilder,
+    task::{Key, Tasks},
+    BuildError, BuildState, CommandTaskResult, ParallelTopoScheduler,
+};
+
+/// An edge whose command would run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedEdge {
+    pub key: Key,
+    pub rule: Option<String>,
+    pub command: String,
+    /// Why the rebuilder considers the edge dirty, if it explained its decision.
+    pub reason: Option<Reason>,
+}
+
+/// The edges that building `start`, or the roots of the graph if it is None, would run, in an
+/// order they could run in. Each command is assumed to succeed, so edges depending on one that
+/// would run are dirty too.
+///
+/// Reasons are taken from `explanations`, which should be an `ExplainLog::in_memory` the rebuilder
+/// explains to.
+pub fn plan<R>(
+    rebuilder: &R,
+    tasks: &Tasks,
+    start: Option<&[Key]>,
+    explanations: &ExplainLog,
+) -> Result<Vec<PlannedEdge>, BuildError>
+where
+    R: Rebuilder<Key, CommandTaskResult>,
+{
+    let graph = ParallelTopoScheduler::build_graph(tasks, start)?;
+    let mut build_state = BuildState::for_start(&graph, start, false);
+    let mut planned = Vec::new();
+    while !build_state.done() {
+        let node = match build_state.next_ready() {
+            Some(node) => node,
+            // Only possible if what is left waits on a cycle.
+            None => break,
+        };
+        let key = graph[node];
+        if let Some(task) = tasks.task(key) {
+            if rebuilder
+                .build(key.clone(), None, task)
+                .map_err(|e| BuildError::RebuilderError(Box::new(e)))?
+                .is_some()
+            {
+                planned.push(PlannedEdge {
+                    key: key.clone(),
+                    rule: tasks
+                        .rule_for(key)
+                        .map(|rule| String::from_utf8_lossy(&rule.name).into_owned()),
+                    command: task.command().cloned().unwrap_or_default(),
+                    reason: None,
+                });
+            }
+        }
+        build_state.finish_node(&graph, node, true);
+    }
+    let reasons: HashMap<Key, Reason> = explanations
+        .take_recorded()
+        .into_iter()
+        .map(|explanation| (explanation.key, explanation.reason))
+        .collect();
+    for edge in &mut planned {
+        edge.reason = reasons.get(&edge.key).copied();
+    }
+    Ok(planned)
+}
+
+/// Writes `planned` as a JSON array, one object per edge in order, for tools that preview or split
+/// up a build.
+pub fn write_json(planned: &[PlannedEdge], out: &mut impl Write) -> io::Result<()> {
+    write!(out, "[")?;
+    for (i, edge) in planned.iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        let outputs: Vec<String> = edge
+            .key
+            .iter()
+            .map(|path| json_string(&path.name()))
+            .collect();
+        write!(out, "{{\"outputs\":[{}],\"rule\":", outputs.join(","))?;
+        match &edge.rule {
+            Some(rule) => write!(out, "{}", json_string(rule))?,
+            None => write!(out, "null")?,
+        }
+        write!(
+            out,
+            ",\"command\":{},\"reason\":",
+            json_string(&edge.command)
+        )?;
+        match edge.reason {
+            Some(reason) => write!(out, "{}", json_string(reason.as_str()))?,
+            None => write!(out, "null")?,
+        }
+        write!(out, "}}")?;
+    }
+    writeln!(out, "]")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        build_externals, caching_mtime_rebuilder, task::TasksBuilder, testing::TempDir,
+        DirectoryInputs,
+    };
+
+    #[test]
+    fn dirty_edges_in_order() {
+        let dir = TempDir::new("plan");
+        let path = |p: &str| dir.path(p);
+        std::fs::write(path("in"), "").unwrap();
+        let mut builder = TasksBuilder::new();
+        builder
+            .source(path("in"))
+            .command(
+                &[path("mid")],
+                &[path("in")],
+                format!("touch {}", path("mid")),
+            )
+            .unwrap()
+            .command(
+                &[path("out")],
+                &[path("mid")],
+                format!("touch {}", path("out")),
+            )
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let plan_now = || {
+            let explanations = ExplainLog::in_memory();
+            let rebuilder =
+                caching_mtime_rebuilder(DirectoryInputs::Warn).explain_to(explanations.clone());
+            plan(&rebuilder, &tasks, None, &explanations).unwrap()
+        };
+
+        let planned = plan_now();
+        assert!(!dir.join("mid").exists(), "nothing should have been built");
+        build_externals(
+            &ParallelTopoScheduler::new(1),
+            &caching_mtime_rebuilder(DirectoryInputs::Warn),
+            &tasks,
+        )
+        .unwrap();
+        let after_build = plan_now();
+        std::fs::remove_file(dir.join("mid")).unwrap();
+        let after_removing = plan_now();
+
+        let key = |p: &str| Key::Path(path(p).into_bytes().into());
+        let summary = |planned: Vec<PlannedEdge>| -> Vec<(Key, Option<Reason>)> {
+            planned
+                .into_iter()
+                .map(|edge| (edge.key, edge.reason))
+                .collect()
+        };
+        assert_eq!(
+            summary(planned),
+            vec![
+                (key("mid"), Some(Reason::OutputDirty)),
+                (key("out"), Some(Reason::OutputDirty)),
+            ]
+        );
+        assert_eq!(after_build, vec![]);
+        assert_eq!(
+            summary(after_removing),
+            vec![
+                (key("mid"), Some(Reason::OutputDirty)),
+                (key("out"), Some(Reason::InputDirty)),
+            ]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let planned = vec![
+            PlannedEdge {
+                key: Key::Multi(vec![b"a".to_vec().into(), b"b".to_vec().into()].into()),
+                rule: Some("cc".to_owned()),
+                command: "cc \"x\"".to_owned(),
+                reason: Some(Reason::InputNewer),
+            },
+            PlannedEdge {
+                key: Key::Path(b"c".to_vec().into()),
+                rule: None,
+                command: "touch c".to_owned(),
+                reason: None,
+            },
+        ];
+        let mut out = Vec::new();
+        write_json(&planned, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[{\"outputs\":[\"a\",\"b\"],\"rule\":\"cc\",\"command\":\"cc \\\"x\\\"\",\
+             \"reason\":\"input-newer\"},\
+             {\"outputs\":[\"c\"],\"rule\":null,\"command\":\"touch c\",\"reason\":null}]\n"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Differences between the platforms ninja runs on, as far as the build output is concerned.
+//!
+//! On Windows, tools like MSVC write their output in the console's codepage rather than UTF-8,
+//! so it is converted before being displayed. Elsewhere output is assumed to be UTF-8, and
+//! anything that isn't is displayed with replacement characters rather than aborting the build.
+
+use std::borrow::Cow;
+
+/// How ninja reads the build file, for build systems generating one that contains non-ASCII
+/// paths. Reported by `-t wincodepage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildFileEncoding {
+    Utf8,
+    /// The active Windows codepage, which is what generators should write.
+    Ansi,
+}
+
+impl std::fmt::Display for BuildFileEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildFileEncoding::Utf8 => write!(f, "UTF-8"),
+            BuildFileEncoding::Ansi => write!(f, "ANSI"),
+        }
+    }
+}
+
+/// The encoding of the build file, if this platform has a choice at all.
+pub fn build_file_encoding() -> Option<BuildFileEncoding> {
+    #[cfg(windows)]
+    {
+        Some(if windows::active_codepage_is_utf8() {
+            BuildFileEncoding::Utf8
+        } else {
+            BuildFileEncoding::Ansi
+        })
+    }
+    #[cfg(not(windows))]
+    {
+        None
+    }
+}
+
+/// Converts the output of a command to UTF-8 for display.
+pub fn command_output_to_utf8(output: &[u8]) -> Cow<'_, str> {
+    #[cfg(windows)]
+    {
+        if let Some(converted) = windows::console_to_utf8(output) {
+            return Cow::Owned(converted);
+        }
+    }
+    String::from_utf8_lossy(output)
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ptr;
+
+    const CP_UTF8: u32 = 65001;
+
+    // kernel32 is always linked by std.
+    extern "system" {
+        fn GetACP() -> u32;
+        fn GetConsoleOutputCP() -> u32;
+        fn MultiByteToWideChar(
+            code_page: u32,
+            flags: u32,
+            multi_byte: *const i8,
+            multi_byte_len: i32,
+            wide: *mut u16,
+            wide_len: i32,
+        ) -> i32;
+    }
+
+    pub(super) fn active_codepage_is_utf8() -> bool {
+        unsafe { GetACP() == CP_UTF8 }
+    }
+
+    /// None if the output is already UTF-8, or could not be converted.
+    pub(super) fn console_to_utf8(output: &[u8]) -> Option<String> {
+        // 0 means there is no console, in which case there is nothing to convert from.
+        let code_page = unsafe { GetConsoleOutputCP() };
+        if code_page == 0 || code_page == CP_UTF8 || output.is_empty() {
+            return None;
+        }
+        let len = output.len() as i32;
+        let source = output.as_ptr() as *const i8;
+        let needed = unsafe { MultiByteToWideChar(code_page, 0, source, len, ptr::null_mut(), 0) };
+        if needed <= 0 {
+            return None;
+        }
+        let mut wide = vec![0u16; needed as usize];
+        let written =
+            unsafe { MultiByteToWideChar(code_page, 0, source, len, wide.as_mut_ptr(), needed) };
+        if written <= 0 {
+            return None;
+        }
+        wide.truncate(written as usize);
+        Some(String::from_utf16_lossy(&wide))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(not(windows))]
+    #[test]
+    fn output_is_utf8_elsewhere() {
+        assert_eq!(build_file_encoding(), None);
+        assert_eq!(command_output_to_utf8("héllo".as_bytes()), "héllo");
+        assert_eq!(command_output_to_utf8(b"caf\xe9"), "caf\u{fffd}");
+    }
+}
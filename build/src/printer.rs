@@ -0,0 +1,942 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    fmt::{self, Debug},
+    io::Write,
+    process::Output,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Once,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    build_task::{CommandTaskError, CommandTaskResult},
+    platform::command_output_to_utf8,
+    status_format::{Progress, StatusFormat},
+    task::{Key, Task},
+};
+
+/// The subset of `console::Term` that the Printer relies on.
+///
+/// Going through a trait object allows tests to capture what would've been written to the
+/// terminal, and to pretend to be a TTY of a certain width.
+pub trait TerminalLike: Write + Debug {
+    /// Whether status lines can be overwritten in place. When false, each status goes on its own
+    /// line.
+    fn is_term(&self) -> bool;
+    /// Width in columns, if it can be determined.
+    fn width(&self) -> Option<usize>;
+    fn clear_line(&mut self) -> std::io::Result<()>;
+    fn move_cursor_up(&mut self, n: usize) -> std::io::Result<()>;
+    fn write_line(&mut self, s: &str) -> std::io::Result<()>;
+}
+
+impl TerminalLike for console::Term {
+    fn is_term(&self) -> bool {
+        // Like ninja, a TTY only counts if TERM says it understands cursor movement.
+        console::Term::is_term(self)
+            && matches!(std::env::var_os("TERM"), Some(term) if term != "dumb")
+    }
+
+    fn width(&self) -> Option<usize> {
+        self.size_checked().map(|(_rows, columns)| columns as usize)
+    }
+
+    fn clear_line(&mut self) -> std::io::Result<()> {
+        console::Term::clear_line(self)
+    }
+
+    fn move_cursor_up(&mut self, n: usize) -> std::io::Result<()> {
+        console::Term::move_cursor_up(self, n)
+    }
+
+    fn write_line(&mut self, s: &str) -> std::io::Result<()> {
+        console::Term::write_line(self, s)
+    }
+}
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+static INSTALL_RESIZE_HANDLER: Once = Once::new();
+
+extern "C" fn note_resize(_signal: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// The width is queried on every redraw anyway. The handler only makes sure the status line is
+/// redrawn at the new width right away, rather than whenever the throttle next allows.
+fn install_resize_handler() {
+    INSTALL_RESIZE_HANDLER.call_once(|| unsafe {
+        libc::signal(
+            libc::SIGWINCH,
+            note_resize as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    });
+}
+
+/// The first `max` characters of `s`. Slicing bytes could split a character.
+fn truncate_chars(s: &str, max: usize) -> &str {
+    match s.char_indices().nth(max) {
+        Some((end, _)) => &s[..end],
+        None => s,
+    }
+}
+
+/// Smart terminals redraw the status line at most this often. With many tiny tasks, redrawing on
+/// every start and finish would otherwise dominate the build. Command output is still printed as
+/// soon as it arrives, along with the status line it belongs to.
+const MIN_REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many of the -j slots, and of the slots of each pool, running commands hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Capacity {
+    pub(crate) jobs: usize,
+    pub(crate) parallelism: usize,
+    /// Name, slots in use and depth of each pool with commands running, by name.
+    pub(crate) pools: Vec<(String, usize, usize)>,
+}
+
+impl fmt::Display for Capacity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}/{} jobs", self.jobs, self.parallelism)?;
+        for (name, used, depth) in &self.pools {
+            write!(f, ", {} pool {}/{}", name, used, depth)?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Printer {
+    started: usize,
+    finished: usize,
+    // Commands expected to run, which shrinks as some turn out to be up to date.
+    total: usize,
+    // Whether any task started, even one without a command.
+    any_started: bool,
+    console: Box<dyn TerminalLike>,
+    min_redraw_interval: Duration,
+    last_redraw: Option<Instant>,
+    // The newest status line, if it has not been drawn yet. Elided only when drawn, so it fits
+    // the width at that time.
+    pending_status: Option<(String, String)>,
+    // Characters in the status line currently shown, to tell how many rows it wrapped onto if
+    // the terminal has since become narrower.
+    drawn_len: usize,
+    report_no_work: bool,
+    verbose: bool,
+    // Whether every command writes to the terminal itself, not just those in the console pool.
+    stream: bool,
+    status_format: StatusFormat,
+    // Shown on smart terminals while pools limit what runs, so a build that seems to use fewer
+    // jobs than it could says why.
+    capacity: Capacity,
+    start: Instant,
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        let console = console::Term::stdout();
+        if TerminalLike::is_term(&console) {
+            install_resize_handler();
+        }
+        Printer::new(Box::new(console))
+    }
+}
+
+// How this is called does need re-doing.
+// First, having NoopTask but not passing it the build task means it cannot tell whether a command
+// would actually be run or not.
+impl Printer {
+    pub(crate) fn new(console: Box<dyn TerminalLike>) -> Self {
+        Printer::with_redraw_interval(console, MIN_REDRAW_INTERVAL)
+    }
+
+    pub(crate) fn with_redraw_interval(
+        console: Box<dyn TerminalLike>,
+        min_redraw_interval: Duration,
+    ) -> Self {
+        Printer {
+            started: 0,
+            finished: 0,
+            total: 0,
+            any_started: false,
+            console,
+            min_redraw_interval,
+            last_redraw: None,
+            pending_status: None,
+            drawn_len: 0,
+            report_no_work: true,
+            verbose: false,
+            stream: false,
+            status_format: StatusFormat::default(),
+            capacity: Capacity::default(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Prefixes status lines with `format`, instead of `[finished/total] `.
+    pub(crate) fn status_format(&mut self, format: StatusFormat) {
+        self.status_format = format;
+    }
+
+    /// Up to `commands` will run, so progress is out of that many from the start, like ninja's.
+    pub(crate) fn expect(&mut self, commands: usize) {
+        self.total = commands;
+    }
+
+    /// `task` was expected to run, but is up to date, perhaps since a `restat` rule left its
+    /// inputs unchanged.
+    pub(crate) fn up_to_date(&mut self, task: &Task) {
+        if task.is_command() {
+            self.total = self.total.saturating_sub(1).max(self.started);
+        }
+    }
+
+    pub(crate) fn report_no_work(&mut self, report: bool) {
+        self.report_no_work = report;
+    }
+
+    /// Print the full command of every edge, each on its own line, instead of its description or
+    /// a status line elided to fit the terminal.
+    pub(crate) fn verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// Every command writes to the terminal as it runs, as `Workspace::stream` has them do.
+    pub(crate) fn stream(&mut self, stream: bool) {
+        self.stream = stream;
+    }
+
+    /// The slots in use as of now, for the next status line.
+    pub(crate) fn capacity(&mut self, capacity: Capacity) {
+        self.capacity = capacity;
+    }
+
+    /// Whether status lines replace each other, rather than each being printed on a new line.
+    fn smart(&self) -> bool {
+        !self.verbose && self.console.is_term() && self.console.width().is_some()
+    }
+
+    /// When the status line held back by the throttle is due to be drawn, if one is. Nothing else
+    /// may happen by then, such as while a long link runs, so the scheduler waits at most until
+    /// this before calling `redraw_if_due`.
+    pub(crate) fn redraw_due(&self) -> Option<Instant> {
+        self.pending_status.as_ref()?;
+        Some(self.last_redraw? + self.min_redraw_interval)
+    }
+
+    /// Draws the status line held back by the throttle, if it is due.
+    pub(crate) fn redraw_if_due(&mut self) {
+        if matches!(self.redraw_due(), Some(due) if due <= Instant::now()) {
+            self.redraw();
+        }
+    }
+
+    /// Draws the pending status line, if any, replacing the one currently shown.
+    fn redraw(&mut self) {
+        let (progress, command) = match self.pending_status.take() {
+            Some(status) => status,
+            None => return,
+        };
+        // Smart terminals only get here with a known width.
+        let width = self.console.width().unwrap_or(80).max(1);
+        // A line drawn when the terminal was wider has wrapped onto several rows since.
+        let rows = match self.drawn_len {
+            0 => 0,
+            len => (len - 1) / width + 1,
+        };
+        for _ in 1..rows {
+            self.console.clear_line().expect("clear");
+            self.console.move_cursor_up(1).expect("move cursor");
+        }
+        self.console.clear_line().expect("clear");
+        // Leave the last column alone, since writing to it wraps on some terminals.
+        // TODO: ninja style elision in the middle.
+        let available = width.saturating_sub(progress.chars().count() + 1);
+        // The progress is kept whole even if the terminal is too narrow for it.
+        let status = format!("{}{}", progress, truncate_chars(&command, available));
+        write!(self.console, "{}", status).expect("write");
+        self.drawn_len = status.chars().count();
+        self.last_redraw = Some(Instant::now());
+    }
+
+    fn print_status(&mut self, task: &Task) {
+        if !task.is_command() {
+            return;
+        }
+        let command = if self.verbose {
+            task.command().unwrap().as_str()
+        } else {
+            task.status_line().unwrap()
+        }
+        .trim();
+        let progress = self.status_format.render(&Progress {
+            started: self.started,
+            total: self.total,
+            finished: self.finished,
+            elapsed: self.start.elapsed(),
+        });
+
+        // Without a width, lines can't be elided or cleared reliably, so print each in full.
+        if self.smart() {
+            let progress = if self.capacity.pools.is_empty() {
+                progress
+            } else {
+                format!("{}{} ", progress, self.capacity)
+            };
+            // TODO: Handle wide characters properly.
+            self.pending_status = Some((progress, command.to_owned()));
+            let due = match self.last_redraw {
+                Some(last) => last.elapsed() >= self.min_redraw_interval,
+                None => true,
+            };
+            if due || RESIZED.swap(false, Ordering::SeqCst) {
+                self.redraw();
+            }
+        } else {
+            writeln!(self.console, "{}{}", progress, command).expect("write");
+        }
+    }
+
+    pub(crate) fn started(&mut self, task: &Task) {
+        self.any_started = true;
+        if task.is_command() {
+            self.started += 1;
+            // Without an expected total, as many as have started.
+            self.total = self.total.max(self.started);
+        }
+        self.print_status(task);
+        // Console commands write to the terminal themselves, so leave them a line of their own
+        // instead of having the next status overwrite what they print.
+        let writes_itself = task.is_console() || (self.stream && task.is_command());
+        if writes_itself && self.smart() {
+            self.redraw();
+            self.console.write_line("").expect("write");
+            self.drawn_len = 0;
+        }
+    }
+
+    /// Writes what a command printed in one go, so it can't be split up by anything else.
+    /// Local commands have both streams in `stdout` already; remote ones keep them apart.
+    fn write_output(&mut self, output: &Output) {
+        let mut text = command_output_to_utf8(&output.stdout).into_owned();
+        text.push_str(&command_output_to_utf8(&output.stderr));
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        self.console.write_all(text.as_bytes()).unwrap();
+    }
+
+    fn print_failure(&mut self, key: &Key, task: &Task, err: CommandTaskError) {
+        writeln!(
+            self.console,
+            "\nFAILED: {}\n{}",
+            key.display(),
+            task.command().unwrap()
+        )
+        .unwrap();
+        match err {
+            err @ CommandTaskError::SpawnFailed(_) => {
+                writeln!(self.console, "Failed to spawn command: {}", err).unwrap();
+            }
+            CommandTaskError::CommandFailed(out) => {
+                self.write_output(&out);
+            }
+            CommandTaskError::Signaled { output, signal } => {
+                self.write_output(&output);
+                writeln!(self.console, "interrupted by signal {}", signal).unwrap();
+            }
+            err @ CommandTaskError::TimedOut(_) => {
+                writeln!(self.console, "{}", err).unwrap();
+            }
+            CommandTaskError::OutputMissing { ref output, .. }
+            | CommandTaskError::BadDeps { ref output, .. } => {
+                self.write_output(output);
+                writeln!(self.console, "{}", err).unwrap();
+            }
+        }
+    }
+
+    pub(crate) fn finished(&mut self, key: &Key, task: &Task, result: CommandTaskResult) {
+        if task.is_command() {
+            self.finished += 1;
+        }
+        self.print_status(task);
+        let has_output = match &result {
+            Ok(output) => !output.stdout.is_empty() || !output.stderr.is_empty(),
+            Err(_) => true,
+        };
+        if has_output && self.smart() {
+            // Output should appear under the status of the command that produced it, and stay
+            // there rather than be overwritten by the next one.
+            self.redraw();
+            self.console.write_line("").expect("write");
+            self.drawn_len = 0;
+        }
+        match result {
+            Ok(output) => self.write_output(&output),
+            Err(err) => self.print_failure(key, task, err),
+        }
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        if !self.any_started {
+            if self.report_no_work {
+                self.console.write_line("ninja: no work to do.").unwrap();
+            }
+        } else if self.smart() {
+            // Show the final status, then terminate it since it was not followed by a newline.
+            self.redraw();
+            if self.drawn_len > 0 {
+                self.console.write_line("").unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{
+        cell::{Cell, RefCell},
+        os::unix::process::ExitStatusExt,
+        process::ExitStatus,
+        rc::Rc,
+    };
+
+    use super::*;
+    use crate::task::TaskVariant;
+
+    /// Records everything written to it. Clones share the same buffer, so a test can hand one
+    /// clone to the Printer and inspect the other.
+    #[derive(Debug, Clone)]
+    pub(crate) struct CapturingTerminal {
+        pub(crate) is_term: bool,
+        // Shared so a test can resize the terminal while the Printer owns it.
+        width: Rc<Cell<Option<usize>>>,
+        buffer: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl CapturingTerminal {
+        pub(crate) fn new(is_term: bool, width: Option<usize>) -> Self {
+            CapturingTerminal::with_shared_width(is_term, Rc::new(Cell::new(width)))
+        }
+
+        pub(crate) fn with_shared_width(is_term: bool, width: Rc<Cell<Option<usize>>>) -> Self {
+            CapturingTerminal {
+                is_term,
+                width,
+                buffer: Default::default(),
+            }
+        }
+
+        pub(crate) fn contents(&self) -> String {
+            String::from_utf8(self.buffer.borrow().clone()).expect("utf-8 output")
+        }
+    }
+
+    impl Write for CapturingTerminal {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TerminalLike for CapturingTerminal {
+        fn is_term(&self) -> bool {
+            self.is_term
+        }
+
+        fn width(&self) -> Option<usize> {
+            self.width.get()
+        }
+
+        fn clear_line(&mut self) -> std::io::Result<()> {
+            // Make clears visible in the captured output.
+            self.write_all(b"<clear>")
+        }
+
+        fn move_cursor_up(&mut self, n: usize) -> std::io::Result<()> {
+            write!(self, "<up {}>", n)
+        }
+
+        fn write_line(&mut self, s: &str) -> std::io::Result<()> {
+            writeln!(self, "{}", s)
+        }
+    }
+
+    fn command_task(command: &str) -> Task {
+        Task {
+            dependencies: vec![],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command(command.to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
+        }
+    }
+
+    fn out() -> Key {
+        Key::Path(b"out".to_vec().into())
+    }
+
+    fn output(code: i32, stdout: &[u8], stderr: &[u8]) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec(),
+        }
+    }
+
+    #[test]
+    fn non_tty_prints_one_line_per_status() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            let task = command_task("cc -c foo.c");
+            printer.started(&task);
+            printer.finished(&out(), &task, Ok(output(0, b"", b"")));
+        }
+        assert_eq!(term.contents(), "[0/1] cc -c foo.c\n[1/1] cc -c foo.c\n");
+    }
+
+    #[test]
+    fn description_replaces_command() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            let mut task = command_task("cc -c foo.c");
+            task.description = Some("CC foo.o".to_owned());
+            printer.started(&task);
+            printer.finished(
+                &out(),
+                &task,
+                Err(CommandTaskError::CommandFailed(output(1, b"", b""))),
+            );
+        }
+        // Failures still show the command, which is what needs debugging.
+        assert_eq!(
+            term.contents(),
+            "[0/1] CC foo.o\n[1/1] CC foo.o\n\nFAILED: out\ncc -c foo.c\n"
+        );
+    }
+
+    #[test]
+    fn verbose_prints_full_commands() {
+        let term = CapturingTerminal::new(true, Some(20));
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            printer.verbose(true);
+            let mut task = command_task("cc -c foo.c -o foo.o -Wall");
+            task.description = Some("CC foo.o".to_owned());
+            printer.started(&task);
+            printer.finished(&out(), &task, Ok(output(0, b"", b"")));
+        }
+        assert_eq!(
+            term.contents(),
+            "[0/1] cc -c foo.c -o foo.o -Wall\n[1/1] cc -c foo.c -o foo.o -Wall\n"
+        );
+    }
+
+    #[test]
+    fn status_format_replaces_progress() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            printer.status_format(StatusFormat::parse("%f of %t (%r running): ").unwrap());
+            let task = command_task("cc -c foo.c");
+            printer.started(&task);
+            printer.finished(&out(), &task, Ok(output(0, b"", b"")));
+        }
+        assert_eq!(
+            term.contents(),
+            "0 of 1 (1 running): cc -c foo.c\n1 of 1 (0 running): cc -c foo.c\n"
+        );
+    }
+
+    #[test]
+    fn total_is_known_upfront() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            printer.expect(3);
+            let task = command_task("cc -c foo.c");
+            printer.started(&task);
+            printer.finished(&out(), &task, Ok(output(0, b"", b"")));
+            // Like an edge whose restat input did not change.
+            printer.up_to_date(&task);
+            printer.started(&task);
+        }
+        assert_eq!(
+            term.contents(),
+            "[0/3] cc -c foo.c\n[1/3] cc -c foo.c\n[1/2] cc -c foo.c\n"
+        );
+    }
+
+    #[test]
+    fn tty_elides_to_width() {
+        let term = CapturingTerminal::new(true, Some(20));
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            printer.started(&command_task("0123456789abcdefghij"));
+        }
+        // The last column is left empty.
+        assert_eq!(term.contents(), "<clear>[0/1] 0123456789abc\n");
+    }
+
+    #[test]
+    fn tty_redraws_are_throttled() {
+        let term = CapturingTerminal::new(true, Some(80));
+        {
+            let mut printer =
+                Printer::with_redraw_interval(Box::new(term.clone()), Duration::from_secs(3600));
+            let tasks: Vec<Task> = ["a", "b", "c"].iter().map(|c| command_task(c)).collect();
+            for task in &tasks {
+                printer.started(task);
+            }
+            for task in &tasks {
+                printer.finished(&out(), task, Ok(output(0, b"", b"")));
+            }
+        }
+        // The first status is drawn immediately, the last one when the build ends.
+        assert_eq!(term.contents(), "<clear>[0/1] a<clear>[3/3] c\n");
+    }
+
+    #[test]
+    fn tty_held_back_status_is_drawn_when_due() {
+        let term = CapturingTerminal::new(true, Some(80));
+        let mut printer =
+            Printer::with_redraw_interval(Box::new(term.clone()), Duration::from_millis(200));
+        printer.started(&command_task("a"));
+        assert_eq!(printer.redraw_due(), None);
+        printer.started(&command_task("b"));
+        let due = printer.redraw_due().expect("b was held back");
+        printer.redraw_if_due();
+        assert_eq!(term.contents(), "<clear>[0/1] a");
+        std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        printer.redraw_if_due();
+        assert_eq!(term.contents(), "<clear>[0/1] a<clear>[0/2] b");
+        assert_eq!(printer.redraw_due(), None);
+    }
+
+    #[test]
+    fn tty_output_is_not_delayed() {
+        let term = CapturingTerminal::new(true, Some(80));
+        {
+            let mut printer =
+                Printer::with_redraw_interval(Box::new(term.clone()), Duration::from_secs(3600));
+            let (a, b) = (command_task("a"), command_task("b"));
+            printer.started(&a);
+            printer.started(&b);
+            printer.finished(&out(), &b, Ok(output(0, b"hi\n", b"")));
+        }
+        assert_eq!(term.contents(), "<clear>[0/1] a<clear>[1/2] b\nhi\n");
+    }
+
+    #[test]
+    fn tty_shows_busy_pools() {
+        let term = CapturingTerminal::new(true, Some(80));
+        {
+            let mut printer =
+                Printer::with_redraw_interval(Box::new(term.clone()), Duration::from_secs(0));
+            let task = command_task("ld app");
+            printer.started(&task);
+            printer.capacity(Capacity {
+                jobs: 1,
+                parallelism: 8,
+                pools: vec![("link".to_owned(), 1, 1)],
+            });
+            printer.started(&task);
+            printer.capacity(Capacity::default());
+            printer.finished(&out(), &task, Ok(output(0, b"", b"")));
+        }
+        assert_eq!(
+            term.contents(),
+            "<clear>[0/1] ld app<clear>[0/2] [1/8 jobs, link pool 1/1] ld app<clear>[1/2] ld app\n"
+        );
+    }
+
+    #[test]
+    fn tty_console_commands_get_their_own_line() {
+        let term = CapturingTerminal::new(true, Some(80));
+        {
+            let mut printer =
+                Printer::with_redraw_interval(Box::new(term.clone()), Duration::from_secs(3600));
+            let (a, mut b) = (command_task("a"), command_task("b"));
+            b.pool = Some(b"console".to_vec());
+            printer.started(&a);
+            printer.started(&b);
+        }
+        assert_eq!(term.contents(), "<clear>[0/1] a<clear>[0/2] b\n");
+    }
+
+    #[test]
+    fn tty_streamed_commands_get_their_own_line() {
+        let term = CapturingTerminal::new(true, Some(80));
+        {
+            let mut printer =
+                Printer::with_redraw_interval(Box::new(term.clone()), Duration::from_secs(3600));
+            printer.stream(true);
+            printer.started(&command_task("a"));
+            printer.started(&command_task("b"));
+        }
+        assert_eq!(term.contents(), "<clear>[0/1] a\n<clear>[0/2] b\n");
+    }
+
+    #[test]
+    fn output_that_is_not_utf8_is_displayed() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            let task = command_task("cl foo.c");
+            printer.started(&task);
+            printer.finished(&out(), &task, Ok(output(0, b"caf\xe9\n", b"")));
+        }
+        assert_eq!(
+            term.contents(),
+            "[0/1] cl foo.c\n[1/1] cl foo.c\ncaf\u{fffd}\n"
+        );
+    }
+
+    #[test]
+    fn output_is_printed_whole_under_its_status() {
+        let term = CapturingTerminal::new(true, Some(80));
+        {
+            let mut printer =
+                Printer::with_redraw_interval(Box::new(term.clone()), Duration::from_secs(0));
+            let (a, b) = (command_task("a"), command_task("b"));
+            printer.started(&a);
+            printer.started(&b);
+            // Remote commands report the streams apart, and warnings come with successes too.
+            printer.finished(&out(), &b, Ok(output(0, b"from b\n", b"warning: b")));
+            printer.finished(&out(), &a, Ok(output(0, b"from a\n", b"")));
+        }
+        assert_eq!(
+            term.contents(),
+            "<clear>[0/1] a<clear>[0/2] b<clear>[1/2] b\nfrom b\nwarning: b\n\
+             <clear>[2/2] a\nfrom a\n"
+        );
+    }
+
+    /// Run with `cargo test --release -p ninja-builder -- --ignored --nocapture bench_` to compare
+    /// redrawing on every update against the throttled default.
+    #[test]
+    #[ignore]
+    fn bench_many_fast_edges() {
+        const EDGES: usize = 10_000;
+        let tasks: Vec<Task> = (0..EDGES)
+            .map(|i| command_task(&format!("cc -c file_{}.c -o file_{}.o", i, i)))
+            .collect();
+        for (name, interval) in &[
+            ("every update", Duration::from_secs(0)),
+            ("throttled", MIN_REDRAW_INTERVAL),
+        ] {
+            let term = CapturingTerminal::new(true, Some(120));
+            let start = Instant::now();
+            {
+                let mut printer = Printer::with_redraw_interval(Box::new(term.clone()), *interval);
+                for task in &tasks {
+                    printer.started(task);
+                    printer.finished(&out(), task, Ok(output(0, b"", b"")));
+                }
+            }
+            eprintln!(
+                "{:>12}: {} edges in {:?}, {} bytes written",
+                name,
+                EDGES,
+                start.elapsed(),
+                term.contents().len()
+            );
+        }
+    }
+
+    #[test]
+    fn tty_narrow_terminal_does_not_panic() {
+        let term = CapturingTerminal::new(true, Some(4));
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            printer.started(&command_task("touch a"));
+        }
+        assert_eq!(term.contents(), "<clear>[0/1] \n");
+    }
+
+    #[test]
+    fn tty_elision_respects_characters() {
+        let term = CapturingTerminal::new(true, Some(10));
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            printer.started(&command_task("échoé"));
+        }
+        assert_eq!(term.contents(), "<clear>[0/1] éch\n");
+    }
+
+    #[test]
+    fn shrinking_clears_every_wrapped_row() {
+        let width = Rc::new(Cell::new(Some(40)));
+        let term = CapturingTerminal::with_shared_width(true, width.clone());
+        {
+            let mut printer =
+                Printer::with_redraw_interval(Box::new(term.clone()), Duration::from_secs(0));
+            let task = command_task("cc -c some/long/path.c");
+            printer.started(&task);
+            // 28 characters were drawn, which now take up 3 rows.
+            width.set(Some(12));
+            printer.finished(&out(), &task, Ok(output(0, b"", b"")));
+        }
+        assert_eq!(
+            term.contents(),
+            "<clear>[0/1] cc -c some/long/path.c\
+             <clear><up 1><clear><up 1><clear>[1/1] cc -c\n"
+        );
+    }
+
+    #[test]
+    fn unknown_width_prints_every_status_in_full() {
+        let term = CapturingTerminal::new(true, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            let task = command_task(
+                "a command longer than eighty characters would have been cut off, but not anymore",
+            );
+            printer.started(&task);
+            printer.finished(&out(), &task, Ok(output(0, b"", b"")));
+        }
+        assert_eq!(
+            term.contents(),
+            "[0/1] a command longer than eighty characters would have been cut off, but not anymore\n\
+             [1/1] a command longer than eighty characters would have been cut off, but not anymore\n"
+        );
+    }
+
+    #[test]
+    fn retrieve_tasks_are_not_printed() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            printer.started(&Task {
+                dependencies: vec![],
+                order_dependencies: vec![],
+                implicit_dependencies: 0,
+                variant: TaskVariant::Retrieve,
+                rule: None,
+                depfile: None,
+                rspfile: None,
+                generator: false,
+                restat: false,
+                deps: None,
+                pool: None,
+                description: None,
+            });
+        }
+        assert_eq!(term.contents(), "");
+    }
+
+    #[test]
+    fn no_work_to_do() {
+        for is_term in [true, false] {
+            let term = CapturingTerminal::new(is_term, Some(80));
+            drop(Printer::new(Box::new(term.clone())));
+            assert_eq!(term.contents(), "ninja: no work to do.\n");
+        }
+    }
+
+    #[test]
+    fn no_work_to_do_can_be_quiet() {
+        let term = CapturingTerminal::new(false, Some(80));
+        let mut printer = Printer::new(Box::new(term.clone()));
+        printer.report_no_work(false);
+        drop(printer);
+        assert_eq!(term.contents(), "");
+    }
+
+    #[test]
+    fn failure_formatting() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            let task = command_task("false");
+            printer.started(&task);
+            printer.print_failure(
+                &out(),
+                &task,
+                CommandTaskError::CommandFailed(output(1, b"out\n", b"err\n")),
+            );
+        }
+        assert_eq!(
+            term.contents(),
+            "[0/1] false\n\nFAILED: out\nfalse\nout\nerr\n"
+        );
+    }
+
+    #[test]
+    fn signal_formatting() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            let task = command_task("crash");
+            printer.started(&task);
+            printer.print_failure(
+                &out(),
+                &task,
+                CommandTaskError::Signaled {
+                    output: Output {
+                        status: ExitStatus::from_raw(libc::SIGSEGV),
+                        stdout: vec![],
+                        stderr: b"partial\n".to_vec(),
+                    },
+                    signal: libc::SIGSEGV,
+                },
+            );
+        }
+        assert_eq!(
+            term.contents(),
+            "[0/1] crash\n\nFAILED: out\ncrash\npartial\ninterrupted by signal 11\n"
+        );
+    }
+
+    #[test]
+    fn finished_with_failure_keeps_going() {
+        let term = CapturingTerminal::new(false, None);
+        {
+            let mut printer = Printer::new(Box::new(term.clone()));
+            let task = command_task("false");
+            printer.started(&task);
+            printer.finished(
+                &out(),
+                &task,
+                Err(CommandTaskError::CommandFailed(output(1, b"", b""))),
+            );
+            printer.started(&command_task("true"));
+        }
+        assert_eq!(
+            term.contents(),
+            "[0/1] false\n[1/1] false\n\nFAILED: out\nfalse\n[1/2] true\n"
+        );
+    }
+}
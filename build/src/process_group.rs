@@ -0,0 +1,439 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Runs each command in its own process group, so that cancelling a command kills everything it
+//! spawned (compilers driving assemblers and linkers, test runners, ...) and not just the shell.
+//!
+//! Once commands are out of ninja's process group, the terminal no longer delivers Ctrl-C to them.
+//! Programs embedding the builder stop builds with a `CancelHandle` instead, and their own signal
+//! handlers are left alone. While `ForwardSignals` is held, as ninja itself does, SIGINT, SIGTERM
+//! and SIGHUP are forwarded to every live group. While a build is `Interruptible` too, the first
+//! such signal only stops it: no more commands start, the ones running are waited for, and the
+//! build returns so the console can be restored. Otherwise, or on a second signal, the handler
+//! that was there before gets the signal, so by default ninja dies of it.
+//!
+//! Only POSIX process groups are implemented, since the rest of the crate does not build on
+//! Windows. There, `ProcessGroup::spawn` would assign the child to a Job Object and `Drop` would
+//! terminate the job.
+//!
+//! Commands can also be made to yield to interactive work, see `ChildScheduling`.
+
+use std::{
+    cell::UnsafeCell,
+    io,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicI32, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::process::{Child, Command};
+
+const MAX_GROUPS: usize = 256;
+const FREE: i32 = 0;
+const RESERVED: i32 = -1;
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: AtomicI32 = AtomicI32::new(FREE);
+// The signal handler may only use async-signal-safe operations, so live groups are tracked in a
+// fixed array of atomics rather than anything that allocates or locks.
+static GROUPS: [AtomicI32; MAX_GROUPS] = [EMPTY_SLOT; MAX_GROUPS];
+const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// The actions `SIGNALS` had before `forward_signal` replaced them.
+struct SavedActions(UnsafeCell<[MaybeUninit<libc::sigaction>; 3]>);
+
+// Only written while no `ForwardSignals` is alive, when `forward_signal` can't be running.
+unsafe impl Sync for SavedActions {}
+
+static SAVED_ACTIONS: SavedActions = SavedActions(UnsafeCell::new([MaybeUninit::uninit(); 3]));
+// How many `ForwardSignals` are alive. Handlers are installed and restored under the lock.
+static FORWARDING: Mutex<usize> = Mutex::new(0);
+// How many `Interruptible`s are alive, and the first signal received while any was.
+static INTERRUPTIBLE: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPTED_BY: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    for slot in GROUPS.iter() {
+        let pgid = slot.load(Ordering::SeqCst);
+        if pgid > 0 {
+            unsafe {
+                libc::kill(-pgid, signal);
+            }
+        }
+    }
+    let stops_builds = INTERRUPTIBLE.load(Ordering::SeqCst) > 0
+        && INTERRUPTED_BY
+            .compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+    if !stops_builds {
+        if let Some(index) = SIGNALS.iter().position(|s| *s == signal) {
+            unsafe {
+                let saved = (*SAVED_ACTIONS.0.get())[index].as_ptr();
+                libc::sigaction(signal, saved, ptr::null_mut());
+                libc::raise(signal);
+            }
+        }
+    }
+}
+
+/// While held, SIGINT, SIGTERM and SIGHUP are forwarded to the process groups of running
+/// commands, and then to the handlers that were installed before. Those are put back once the
+/// last one is dropped.
+#[derive(Debug)]
+pub struct ForwardSignals(());
+
+impl ForwardSignals {
+    pub fn install() -> Self {
+        let mut forwarding = FORWARDING.lock().unwrap();
+        if *forwarding == 0 {
+            let saved = unsafe { &mut *SAVED_ACTIONS.0.get() };
+            for (signal, saved) in SIGNALS.iter().zip(saved.iter_mut()) {
+                unsafe {
+                    let mut action: libc::sigaction = std::mem::zeroed();
+                    action.sa_sigaction =
+                        forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                    libc::sigemptyset(&mut action.sa_mask);
+                    action.sa_flags = libc::SA_RESTART;
+                    libc::sigaction(*signal, &action, saved.as_mut_ptr());
+                }
+            }
+        }
+        *forwarding += 1;
+        ForwardSignals(())
+    }
+}
+
+impl Drop for ForwardSignals {
+    fn drop(&mut self) {
+        let mut forwarding = FORWARDING.lock().unwrap();
+        *forwarding -= 1;
+        if *forwarding == 0 {
+            let saved = unsafe { &*SAVED_ACTIONS.0.get() };
+            for (signal, saved) in SIGNALS.iter().zip(saved.iter()) {
+                unsafe {
+                    libc::sigaction(*signal, saved.as_ptr(), ptr::null_mut());
+                }
+            }
+        }
+    }
+}
+
+/// Held for the duration of a build that checks `signal` before starting each command, so that
+/// the first interrupt lets it stop cleanly.
+#[derive(Debug)]
+pub(crate) struct Interruptible {
+    _signals: ForwardSignals,
+}
+
+impl Interruptible {
+    pub(crate) fn new() -> Self {
+        let signals = ForwardSignals::install();
+        INTERRUPTIBLE.fetch_add(1, Ordering::SeqCst);
+        Interruptible { _signals: signals }
+    }
+
+    /// The signal that interrupted the build, if any.
+    pub(crate) fn signal(&self) -> Option<i32> {
+        match INTERRUPTED_BY.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+}
+
+impl Drop for Interruptible {
+    fn drop(&mut self) {
+        // Whoever runs the next build has seen this one stop, and asked for another anyway.
+        if INTERRUPTIBLE.fetch_sub(1, Ordering::SeqCst) == 1 {
+            INTERRUPTED_BY.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// How spawned commands are scheduled relative to everything else on the machine, so a large
+/// build can run alongside interactive work.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChildScheduling {
+    /// Added to ninja's own niceness, like `nice -n`. Only root may make it negative.
+    pub nice: Option<i32>,
+    /// Bit N allows CPU N, like `taskset`. Only the first 64 CPUs can be named.
+    pub affinity: Option<u64>,
+}
+
+impl ChildScheduling {
+    /// Fails if commands can't be scheduled like this here.
+    pub fn checked(self) -> io::Result<Self> {
+        if self.affinity == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the affinity mask allows no CPUs",
+            ));
+        }
+        if self.affinity.is_some() && !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "CPU affinity is only supported on Linux",
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Everything is worked out before forking, so the child only makes system calls.
+    fn apply(self, command: &mut Command) {
+        if let Some(nice) = self.nice {
+            // -1 is also a valid priority, so errors can't be told apart. Assume it is one.
+            let current = unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) };
+            let priority = current.saturating_add(nice);
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::setpriority(libc::PRIO_PROCESS as _, 0, priority) == 0 {
+                        Ok(())
+                    } else {
+                        Err(io::Error::last_os_error())
+                    }
+                });
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(mask) = self.affinity {
+                let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+                for cpu in (0..64).filter(|cpu| mask & (1 << cpu) != 0) {
+                    unsafe { libc::CPU_SET(cpu, &mut set) };
+                }
+                unsafe {
+                    command.pre_exec(move || {
+                        let size = std::mem::size_of::<libc::cpu_set_t>();
+                        if libc::sched_setaffinity(0, size, &set) == 0 {
+                            Ok(())
+                        } else {
+                            Err(io::Error::last_os_error())
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn reserve_slot() -> Option<usize> {
+    GROUPS.iter().position(|slot| {
+        slot.compare_exchange(FREE, RESERVED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    })
+}
+
+/// The process group of a running command. Dropping it before calling `finished` kills every
+/// process in the group.
+#[derive(Debug)]
+pub(crate) struct ProcessGroup {
+    // None if the command could not be given its own group and shares ninja's.
+    slot: Option<usize>,
+    pgid: i32,
+}
+
+impl ProcessGroup {
+    /// Spawns `command` as the leader of a new process group.
+    ///
+    /// If too many groups are live already, the command stays in ninja's group, where the terminal
+    /// still delivers Ctrl-C to it, and only the immediate child is killed on drop.
+    pub(crate) fn spawn(
+        command: &mut Command,
+        scheduling: ChildScheduling,
+    ) -> io::Result<(Child, ProcessGroup)> {
+        scheduling.apply(command);
+        let slot = reserve_slot();
+        if slot.is_some() {
+            unsafe {
+                command.pre_exec(|| {
+                    if libc::setpgid(0, 0) == 0 {
+                        Ok(())
+                    } else {
+                        Err(io::Error::last_os_error())
+                    }
+                });
+            }
+        }
+        let child = match command.kill_on_drop(true).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some(slot) = slot {
+                    GROUPS[slot].store(FREE, Ordering::SeqCst);
+                }
+                return Err(e);
+            }
+        };
+        let pgid = child.id() as i32;
+        if let Some(slot) = slot {
+            GROUPS[slot].store(pgid, Ordering::SeqCst);
+        }
+        Ok((child, ProcessGroup { slot, pgid }))
+    }
+
+    /// The command exited on its own. Anything it left running in the background is left alone,
+    /// like ninja does.
+    pub(crate) fn finished(mut self) {
+        self.release();
+    }
+
+    fn release(&mut self) -> bool {
+        match self.slot.take() {
+            Some(slot) => {
+                GROUPS[slot].store(FREE, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if self.release() {
+            unsafe {
+                libc::kill(-self.pgid, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{process::Stdio, time::Duration};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(script).stdin(Stdio::null());
+        command
+    }
+
+    #[test]
+    fn runs_in_own_group() {
+        runtime().block_on(async {
+            let mut command = shell("exit 0");
+            let (child, group) =
+                ProcessGroup::spawn(&mut command, ChildScheduling::default()).expect("spawn");
+            assert_eq!(group.pgid, child.id() as i32);
+            assert!(group.slot.is_some());
+            assert!(child.await.expect("wait").success());
+            group.finished();
+        });
+    }
+
+    fn sighup_action() -> libc::sighandler_t {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGHUP, ptr::null(), &mut action);
+            action.sa_sigaction
+        }
+    }
+
+    #[test]
+    fn forwarding_restores_the_previous_handlers() {
+        let previous = unsafe { libc::signal(libc::SIGHUP, libc::SIG_IGN) };
+        let forward = ForwardSignals::install();
+        let nested = ForwardSignals::install();
+        let forwarding = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        assert_eq!(sighup_action(), forwarding);
+        drop(forward);
+        assert_eq!(sighup_action(), forwarding);
+        drop(nested);
+        assert_eq!(sighup_action(), libc::SIG_IGN);
+        unsafe { libc::signal(libc::SIGHUP, previous) };
+    }
+
+    #[test]
+    fn drop_kills_the_whole_tree() {
+        let dir = TempDir::new("pgroup");
+        let marker = dir.join("marker");
+        runtime().block_on(async {
+            // The grandchild would create the marker if it survived.
+            let mut command = shell(&format!("(sleep 0.3; touch {}) & wait", marker.display()));
+            let (_child, group) =
+                ProcessGroup::spawn(&mut command, ChildScheduling::default()).expect("spawn");
+            drop(group);
+        });
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(!marker.exists(), "grandchild outlived its process group");
+    }
+
+    fn output_of(scheduling: ChildScheduling, script: &str) -> String {
+        runtime().block_on(async {
+            let mut command = shell(script);
+            command.stdout(Stdio::piped());
+            let (child, group) = ProcessGroup::spawn(&mut command, scheduling).expect("spawn");
+            let output = child.wait_with_output().await.expect("wait");
+            group.finished();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        })
+    }
+
+    #[test]
+    fn nice() {
+        let ours = unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) };
+        let scheduling = ChildScheduling {
+            nice: Some(3),
+            affinity: None,
+        };
+        // Field 19 of /proc/self/stat would do on Linux only.
+        let theirs: i32 = output_of(scheduling, "nice").trim().parse().unwrap();
+        assert_eq!(theirs, std::cmp::min(ours + 3, 19));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn affinity() {
+        // Pick one CPU ninja may already use, so this works under any outer restriction.
+        let mut ours: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        assert_eq!(unsafe { libc::sched_getaffinity(0, size, &mut ours) }, 0);
+        let cpu = (0..64)
+            .find(|cpu| unsafe { libc::CPU_ISSET(*cpu, &ours) })
+            .expect("an allowed CPU among the first 64");
+        let scheduling = ChildScheduling {
+            nice: None,
+            affinity: Some(1 << cpu),
+        };
+        let status = output_of(scheduling, "grep Cpus_allowed_list /proc/self/status");
+        assert_eq!(status, format!("Cpus_allowed_list:\t{}\n", cpu));
+    }
+
+    #[test]
+    fn empty_affinity_is_rejected() {
+        let scheduling = ChildScheduling {
+            nice: None,
+            affinity: Some(0),
+        };
+        assert_eq!(
+            scheduling.checked().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
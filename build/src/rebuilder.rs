@@ -17,20 +17,27 @@
 use std::{
     cell::RefCell,
     collections::{hash_map::Entry, HashMap},
-    ffi::OsStr,
-    os::unix::ffi::OsStrExt,
+    io::Write,
+    path::Path,
     string::FromUtf8Error,
-    time::SystemTime,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use ninja_metrics::scoped_metric;
 use thiserror::Error;
 
 use crate::{
+    build_log::{hash_command, SharedBuildLog},
     build_task::{CommandTask, CommandTaskResult, NinjaTask},
+    depfile::{self, DepfileError},
+    deps_log::SharedDepsLog,
+    diagnostics,
+    dir_listings::SharedDirListings,
     disk_interface::DiskInterface,
+    explain::{ExplainLog, Explanation, Reason},
     interface::Rebuilder,
-    task::{Key, Task},
+    task::{namespaced_key, Key, KeyPath, Task},
+    workspace::Workspace,
 };
 
 /**
@@ -101,7 +108,52 @@ pub enum Dirtiness {
 
 pub trait DirtyCache {
     fn dirtiness(&self, key: Key) -> std::io::Result<Dirtiness>;
+    /// Like `dirtiness`, for a key that is being used as an input. Inputs may be directories, which
+    /// some caches want to treat differently.
+    fn input_dirtiness(&self, key: Key) -> std::io::Result<Dirtiness> {
+        self.dirtiness(key)
+    }
     fn mark_dirty(&self, key: Key, is_dirty: bool);
+    /// Forget what is known about `key`, so that it is looked at again, because a command may
+    /// have changed it.
+    fn forget(&self, key: &KeyPath);
+    /// The contents of a depfile, or None if it does not exist.
+    fn read_depfile(&self, _path: &KeyPath) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// What to do when an input is a directory.
+///
+/// A directory's mtime changes whenever an entry is added, removed or renamed, including
+/// temporary files that editors and compilers create and delete, but it does not change when a
+/// file inside it is modified. Neither is usually what a build edge depending on a directory
+/// wants.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DirectoryInputs {
+    /// Use the directory's own mtime, like ninja does, but warn about it.
+    Warn,
+    /// Hash the listing of everything under the directory, with the mtime of each file in it.
+    /// With the listings of earlier builds (see `dir_listings`), adding, modifying, removing or
+    /// renaming a file makes dependents dirty. Without them, the newest mtime of any file is used,
+    /// so removing one does not. Symlinks are listed, but not followed into.
+    Contents,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown directory input setting '{0}', expected 'warn' or 'contents'")]
+pub struct DirectoryInputsError(String);
+
+impl std::str::FromStr for DirectoryInputs {
+    type Err = DirectoryInputsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(DirectoryInputs::Warn),
+            "contents" => Ok(DirectoryInputs::Contents),
+            e => Err(DirectoryInputsError(e.to_owned())),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -112,6 +164,9 @@ where
     // This Key abstraction is unnatural because most places don't care about multi-keys.
     dirty: RefCell<HashMap<Key, Dirtiness>>,
     disk: Disk,
+    directory_inputs: DirectoryInputs,
+    listings: Option<SharedDirListings>,
+    workspace: Workspace,
 }
 
 impl<Disk> DiskDirtyCache<Disk>
@@ -119,11 +174,77 @@ where
     Disk: DiskInterface,
 {
     pub fn new(disk: Disk) -> Self {
+        Self::with_directory_inputs(disk, DirectoryInputs::Warn)
+    }
+
+    pub fn with_directory_inputs(disk: Disk, directory_inputs: DirectoryInputs) -> Self {
         DiskDirtyCache {
             disk,
             dirty: Default::default(),
+            directory_inputs,
+            listings: None,
+            workspace: Workspace::default(),
         }
     }
+
+    /// Resolve keys relative to `workspace` instead of the current directory.
+    pub fn in_workspace(mut self, workspace: Workspace) -> Self {
+        self.workspace = workspace;
+        self
+    }
+
+    /// Compare directory inputs with the listings in `listings`, and record theirs there.
+    pub fn with_dir_listings(mut self, listings: SharedDirListings) -> Self {
+        self.listings = Some(listings);
+        self
+    }
+
+    /// The hash of the sorted listing of everything under `dir`, with the mtime of each file, and
+    /// the newest of those mtimes, or the mtime of `dir` itself if there are no files.
+    fn list_dir(&self, dir: &Path) -> std::io::Result<(u64, SystemTime)> {
+        let mut entries = Vec::new();
+        let mut newest = None;
+        let mut pending = vec![dir.to_owned()];
+        while let Some(current) = pending.pop() {
+            for entry in self.disk.read_dir(&current)? {
+                let name = entry
+                    .path
+                    .strip_prefix(dir)
+                    .unwrap_or(&entry.path)
+                    .to_string_lossy()
+                    .into_owned();
+                if entry.is_dir {
+                    entries.push((name, None));
+                    pending.push(entry.path);
+                    continue;
+                }
+                match self.disk.modified(&entry.path) {
+                    Ok(modified) => {
+                        newest = std::cmp::max(newest, Some(modified));
+                        entries.push((name, Some(modified)));
+                    }
+                    // Deleted between listing and stat, or a symlink to nothing.
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        entries.push((name, None))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        entries.sort();
+        let mut listing = Vec::new();
+        for (name, modified) in entries {
+            let nanos = modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since_epoch| since_epoch.as_nanos());
+            writeln!(listing, "{}\0{}", name, nanos)?;
+        }
+        let newest = match newest {
+            Some(newest) => newest,
+            None => self.disk.modified(dir)?,
+        };
+        Ok((hash_command(&listing), newest))
+    }
 }
 
 impl<Disk> DirtyCache for DiskDirtyCache<Disk>
@@ -138,7 +259,7 @@ where
                     scoped_metric!("mtime_state_insert");
                     let inserted = entry.insert(
                         self.disk
-                            .modified(OsStr::from_bytes(key.as_bytes()))
+                            .modified(self.workspace.path(key.as_bytes()))
                             .map(Dirtiness::Modified)
                             .or_else(|e| {
                                 if e.kind() == std::io::ErrorKind::NotFound {
@@ -157,6 +278,59 @@ where
         }
     }
 
+    fn input_dirtiness(&self, key: Key) -> std::io::Result<Dirtiness> {
+        let (key_path, path) = match &key {
+            Key::Path(path) if !self.dirty.borrow().contains_key(&key) => {
+                (path.clone(), self.workspace.path(path.as_bytes()))
+            }
+            _ => return self.dirtiness(key),
+        };
+        let is_dir = match self.disk.is_dir(&path) {
+            Ok(is_dir) => is_dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        if !is_dir {
+            return self.dirtiness(key);
+        }
+        match self.directory_inputs {
+            DirectoryInputs::Warn => {
+                // The entry inserted below ensures this is only printed once per directory.
+                diagnostics::warn(
+                    "directory input",
+                    format_args!(
+                        "input '{}' is a directory, its mtime does not reflect changes to its contents",
+                        key_path.display()
+                    ),
+                );
+                self.dirtiness(key)
+            }
+            DirectoryInputs::Contents => {
+                scoped_metric!("directory_contents");
+                let (hash, newest) = self.list_dir(&path)?;
+                let changed = match &self.listings {
+                    Some(listings) => listings.changed(key_path.as_bytes(), hash, newest),
+                    None => newest,
+                };
+                let dirtiness = Dirtiness::Modified(changed);
+                self.dirty.borrow_mut().insert(key, dirtiness);
+                Ok(dirtiness)
+            }
+        }
+    }
+
+    fn read_depfile(&self, path: &KeyPath) -> std::io::Result<Option<Vec<u8>>> {
+        match self.disk.read(self.workspace.path(path.as_bytes())) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn forget(&self, key: &KeyPath) {
+        self.dirty.borrow_mut().remove(&Key::Path(key.clone()));
+    }
+
     fn mark_dirty(&self, key: Key, is_dirty: bool) {
         // Marking as Clean only makes sense for multi-keys. For single-keys that represent
         // filesystem resources, they are either dirty or need to be consulted in the cache in the
@@ -180,6 +354,13 @@ where
     Cache: DirtyCache,
 {
     mtime_state: Cache,
+    explain: Option<ExplainLog>,
+    build_log: Option<SharedBuildLog>,
+    deps_log: Option<SharedDepsLog>,
+    workspace: Workspace,
+    /// The newest input of each key being rebuilt because of it, to tell whether the command
+    /// updated its outputs.
+    newest_inputs: RefCell<HashMap<Key, SystemTime>>,
 }
 
 impl<Cache> CachingMTimeRebuilder<Cache>
@@ -187,7 +368,162 @@ where
     Cache: DirtyCache,
 {
     pub fn new(mtime_state: Cache) -> Self {
-        Self { mtime_state }
+        Self {
+            mtime_state,
+            explain: None,
+            build_log: None,
+            deps_log: None,
+            workspace: Workspace::default(),
+            newest_inputs: Default::default(),
+        }
+    }
+
+    /// Also record why each key was considered dirty or clean in `log`.
+    pub fn explain_to(mut self, log: ExplainLog) -> Self {
+        self.explain = Some(log);
+        self
+    }
+
+    /// Also rebuild outputs whose command changed since `log` recorded it, or that `log` has no
+    /// record of.
+    pub fn build_log(mut self, log: SharedBuildLog) -> Self {
+        self.build_log = Some(log);
+        self
+    }
+
+    /// Check the inputs recorded in `log` for edges of rules with `deps`, instead of their
+    /// depfiles.
+    pub fn deps_log(mut self, log: SharedDepsLog) -> Self {
+        self.deps_log = Some(log);
+        self
+    }
+
+    /// Why the build log makes `key` dirty, if it does. Generator rules only record their
+    /// command, since regenerating the manifest because its generator was invoked differently is
+    /// rarely wanted.
+    fn log_dirtiness(&self, key: &Key, task: &Task) -> Option<Reason> {
+        let log = self.build_log.as_ref()?;
+        let command = task.command()?;
+        if task.generator {
+            return None;
+        }
+        let hash = hash_command(command.as_bytes());
+        key.iter()
+            .find_map(|output| match log.command_hash(output.as_bytes()) {
+                None => Some(Reason::NotInLog),
+                Some(logged) if logged != hash => Some(Reason::CommandChanged),
+                Some(_) => None,
+            })
+    }
+
+    /// Why the inputs discovered by the last run of the command make `key` dirty, if they do. A
+    /// missing depfile means the command has not run to completion since the rule got one.
+    /// Missing inputs are not an error, since a header may have been removed along with its
+    /// `#include`.
+    fn depfile_dirtiness(
+        &self,
+        key: &Key,
+        depfile: &KeyPath,
+        output_mtime: SystemTime,
+    ) -> Result<Option<Reason>, RebuilderError> {
+        let contents = match self.mtime_state.read_depfile(depfile)? {
+            Some(contents) => contents,
+            None => return Ok(Some(Reason::DepfileMissing)),
+        };
+        let parsed = depfile::parse(&contents).map_err(|error| RebuilderError::BadDepfile {
+            path: depfile.display().to_string(),
+            error,
+        })?;
+        if self.discovered_inputs_dirty(key, parsed.inputs, output_mtime)? {
+            Ok(Some(Reason::DepfileInput))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Like `depfile_dirtiness`, for the inputs the deps log recorded for the first output. Deps
+    /// recorded before the output was last written are out of date, since the command that wrote
+    /// it may have discovered different inputs.
+    fn deps_log_dirtiness(
+        &self,
+        log: &SharedDepsLog,
+        key: &Key,
+        output_mtime: SystemTime,
+    ) -> Result<Option<Reason>, RebuilderError> {
+        let output = key.iter().next().expect("keys have a path");
+        let recorded = match log.deps(output.as_bytes()) {
+            Some(recorded) => recorded,
+            None => return Ok(Some(Reason::DepsMissing)),
+        };
+        let output_ns = output_mtime
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as u64);
+        if recorded.mtime < output_ns {
+            return Ok(Some(Reason::DepsOutOfDate));
+        }
+        if self.discovered_inputs_dirty(key, recorded.inputs, output_mtime)? {
+            Ok(Some(Reason::DepsInput))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// An output of `key` that is still older than the input that made it dirty, now that its
+    /// command ran, so the command will run again on every build. Dependents see all outputs as
+    /// rebuilt regardless.
+    fn output_not_updated(&self, key: &Key) -> Result<Option<KeyPath>, RebuilderError> {
+        let newest_input = match self.newest_inputs.borrow_mut().remove(key) {
+            Some(newest_input) => newest_input,
+            None => return Ok(None),
+        };
+        for output in key.iter() {
+            self.mtime_state.forget(output);
+            let dirtiness = self.mtime_state.dirtiness(Key::Path(output.clone()));
+            self.mtime_state.mark_dirty(Key::Path(output.clone()), true);
+            if matches!(dirtiness?, Dirtiness::Modified(mtime) if mtime < newest_input) {
+                return Ok(Some(output.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether any of `inputs`, as a command of `key` reported them, is newer than the oldest
+    /// output, or being rebuilt.
+    fn discovered_inputs_dirty(
+        &self,
+        key: &Key,
+        inputs: Vec<Vec<u8>>,
+        output_mtime: SystemTime,
+    ) -> Result<bool, RebuilderError> {
+        for input in inputs {
+            // Paths are relative to where the command ran, and spelled however it liked.
+            let namespace = key.namespace().filter(|_| !input.starts_with(b"/"));
+            let path = namespaced_key(namespace, &input);
+            match self.mtime_state.input_dirtiness(Key::Path(path))? {
+                Dirtiness::Modified(mtime) if mtime <= output_mtime => {}
+                Dirtiness::Clean => {}
+                _ => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<Disk> CachingMTimeRebuilder<DiskDirtyCache<Disk>>
+where
+    Disk: DiskInterface,
+{
+    /// Look up mtimes and depfiles, and run the returned tasks, in `workspace`.
+    pub fn workspace(mut self, workspace: Workspace) -> Self {
+        self.mtime_state = self.mtime_state.in_workspace(workspace.clone());
+        self.workspace = workspace;
+        self
+    }
+
+    /// Like `DiskDirtyCache::with_dir_listings`.
+    pub fn dir_listings(mut self, listings: SharedDirListings) -> Self {
+        self.mtime_state = self.mtime_state.with_dir_listings(listings);
+        self
     }
 }
 
@@ -199,6 +535,8 @@ pub enum RebuilderError {
     MissingInput { output: String, input: String },
     #[error("error looking up mtime")]
     IOError(#[from] std::io::Error),
+    #[error("loading depfile '{path}': {error}")]
+    BadDepfile { path: String, error: DepfileError },
 }
 
 impl<Cache> Rebuilder<Key, CommandTaskResult> for CachingMTimeRebuilder<Cache>
@@ -265,17 +603,14 @@ where
                 |so_far, current_dep| -> Result<Option<Dirtiness>, RebuilderError> {
                     match current_dep {
                         Key::Path(key_path) => {
-                            let dep_mtime = self.mtime_state.dirtiness(current_dep.clone())?;
+                            let dep_mtime =
+                                self.mtime_state.input_dirtiness(current_dep.clone())?;
                             if dep_mtime == Dirtiness::DoesNotExist {
-                                let output = match key.clone() {
-                                    Key::Path(key) => String::from_utf8(key.as_bytes().to_vec())?,
-                                    Key::Multi(keys) => {
-                                        String::from_utf8(keys[0].as_bytes().to_vec())?
-                                    }
-                                };
+                                // Name the first output, like ninja does.
+                                let output = key.iter().next().expect("keys have a path");
                                 Err(RebuilderError::MissingInput {
-                                    input: String::from_utf8(key_path.as_bytes().to_vec())?,
-                                    output,
+                                    input: key_path.display().to_string(),
+                                    output: output.display().to_string(),
                                 })
                             } else {
                                 Ok(match so_far {
@@ -333,22 +668,108 @@ where
             true
         };
 
+        // Only consulted when nothing else made the edge dirty, which saves reading depfiles of
+        // edges that will run anyway.
+        let log_reason = match outputs_dirty {
+            Dirtiness::Modified(_) if !dirty => self.log_dirtiness(&key, task),
+            _ => None,
+        };
+        let dirty = dirty || log_reason.is_some();
+        let depfile_reason = match (&self.deps_log, &task.deps, &task.depfile, outputs_dirty) {
+            (_, _, _, _) if dirty || !task.is_command() => None,
+            (Some(log), Some(_), _, Dirtiness::Modified(output_mtime)) => {
+                self.deps_log_dirtiness(log, &key, output_mtime)?
+            }
+            (_, _, Some(depfile), Dirtiness::Modified(output_mtime)) => {
+                self.depfile_dirtiness(&key, depfile, output_mtime)?
+            }
+            _ => None,
+        };
+        let dirty = dirty || depfile_reason.is_some();
+
         self.mtime_state.mark_dirty(key.clone(), dirty);
 
+        if let Some(explain) = &self.explain {
+            let reason =
+                log_reason
+                    .or(depfile_reason)
+                    .unwrap_or(match (outputs_dirty, inputs_dirty) {
+                        (Dirtiness::Modified(_), None) => Reason::NoInputs,
+                        (Dirtiness::Modified(_), Some(Dirtiness::Dirty)) => Reason::InputDirty,
+                        (Dirtiness::Modified(_), Some(_)) if dirty => Reason::InputNewer,
+                        (Dirtiness::Modified(_), Some(_)) => Reason::UpToDate,
+                        _ => Reason::OutputDirty,
+                    });
+            explain.record(&Explanation {
+                key: key.clone(),
+                dirty,
+                reason,
+                output_mtime: match outputs_dirty {
+                    Dirtiness::Modified(mtime) => Some(mtime),
+                    _ => None,
+                },
+                input_mtime: match inputs_dirty {
+                    Some(Dirtiness::Modified(mtime)) => Some(mtime),
+                    _ => None,
+                },
+            });
+        }
+
         if dirty && task.is_command() {
+            if let (Dirtiness::Modified(output_mtime), Some(Dirtiness::Modified(input_mtime))) =
+                (outputs_dirty, inputs_dirty)
+            {
+                if input_mtime > output_mtime && !task.restat {
+                    self.newest_inputs
+                        .borrow_mut()
+                        .insert(key.clone(), input_mtime);
+                }
+            }
             // TODO: actually need some return type that can failure to run this task if the
             // dependency is not available.
             // may want different response based on dep being source vs intermediate. for
             // intermediate, whatever should've produced it will fail and have the error message.
             // So fail with not found if not a known output.
-            Ok(Some(Box::new(CommandTask::new(
-                key,
-                task.command().unwrap().clone(),
-            ))))
+            Ok(Some(Box::new(
+                CommandTask::new(key, task.command().unwrap().clone())
+                    .console(task.is_console())
+                    .rspfile(task.rspfile.clone())
+                    .workspace(self.workspace.clone()),
+            )))
         } else {
             Ok(None)
         }
     }
+
+    /// Outputs of `restat` edges are looked at again instead of being considered dirty, so what
+    /// depends on outputs the command left alone is only rebuilt if it is older than them.
+    ///
+    /// Other commands that leave an output older than its inputs are warned about, since they
+    /// would otherwise run on every build without anyone noticing why.
+    fn finished(&self, key: Key, task: &Task) -> Result<(), Self::Error> {
+        if !task.restat {
+            if let Some(output) = self.output_not_updated(&key)? {
+                diagnostics::warn_summarized(
+                    "outputs not updated by their command",
+                    format_args!(
+                        "'{}' is still older than its inputs after its command ran, so it will \
+                         run again on every build; does its rule need `restat = 1`, or \
+                         `generator = 1`?",
+                        output.display()
+                    ),
+                );
+            }
+            return Ok(());
+        }
+        for output in key.iter() {
+            self.mtime_state.forget(output);
+        }
+        // The outputs stand on their own now, compared by mtime like any other input.
+        if key.is_multi() {
+            self.mtime_state.mark_dirty(key, false);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -361,7 +782,7 @@ mod test {
     };
 
     use super::*;
-    use crate::task::*;
+    use crate::{disk_interface::DirEntry, task::*, testing::TempDir};
 
     macro_rules! mocked_rebuilder {
         ($path:ident, $body:expr) => {{
@@ -396,7 +817,16 @@ mod test {
         let task = Task {
             dependencies: vec![Key::Path(b"foo.c".to_vec().into())],
             order_dependencies: vec![],
+            implicit_dependencies: 0,
             variant: TaskVariant::Command("cc -c foo.c".to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
         };
         let _task = rebuilder
             .build(Key::Path(b"foo.o".to_vec().into()), None, &task)
@@ -426,7 +856,16 @@ mod test {
                     b"phony_target_that_does_not_exist".to_vec().into(),
                 )],
                 order_dependencies: vec![],
+                implicit_dependencies: 0,
                 variant: TaskVariant::Retrieve,
+                rule: None,
+                depfile: None,
+                rspfile: None,
+                generator: false,
+                restat: false,
+                deps: None,
+                pool: None,
+                description: None,
             },
         );
         assert!(task.is_err());
@@ -445,7 +884,16 @@ mod test {
                     b"phony_target_that_does_not_exist".to_vec().into(),
                 )],
                 order_dependencies: vec![],
+                implicit_dependencies: 0,
                 variant: TaskVariant::Command("whatever".to_string()),
+                rule: None,
+                depfile: None,
+                rspfile: None,
+                generator: false,
+                restat: false,
+                deps: None,
+                pool: None,
+                description: None,
             },
         );
         assert!(task.is_err());
@@ -467,7 +915,16 @@ mod test {
                 b"phony_target_that_does_not_exist".to_vec().into(),
             )],
             order_dependencies: vec![],
+            implicit_dependencies: 0,
             variant: TaskVariant::Retrieve,
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
         };
         let task = rebuilder.build(
             Key::Multi(
@@ -489,6 +946,67 @@ mod test {
         }
     }
 
+    #[test]
+    fn explains_decisions() {
+        #[derive(Clone, Default)]
+        struct Shared(std::rc::Rc<RefCell<Vec<u8>>>);
+
+        impl std::io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let shared = Shared::default();
+        let rebuilder = mocked_rebuilder! {p,
+            match p.as_ref().to_str().unwrap() {
+                "new.c" | "old.o" => Ok(UNIX_EPOCH + Duration::from_secs(200)),
+                "old.c" | "new.o" => Ok(UNIX_EPOCH + Duration::from_secs(100)),
+                _ => Err(Error::new(ErrorKind::NotFound, "mock not found")),
+            }
+        }
+        .explain_to(ExplainLog::new(Box::new(shared.clone())).unwrap());
+        let compile = |input: &[u8]| Task {
+            dependencies: vec![Key::Path(input.to_vec().into())],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command("cc".to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
+        };
+        for (output, input) in &[
+            (&b"new.o"[..], &b"new.c"[..]),
+            (b"old.o", b"old.c"),
+            (b"missing.o", b"old.c"),
+            (b"uses_missing.o", b"missing.o"),
+        ] {
+            rebuilder
+                .build(Key::Path(output.to_vec().into()), None, &compile(input))
+                .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(shared.0.borrow().clone()).unwrap(),
+            format!(
+                "{}\n\
+                 new.o\tdirty\tinput-newer\t100000000000\t200000000000\n\
+                 old.o\tclean\tup-to-date\t200000000000\t100000000000\n\
+                 missing.o\tdirty\toutput-dirty-or-missing\t-\t100000000000\n\
+                 uses_missing.o\tdirty\toutput-dirty-or-missing\t-\t-\n",
+                crate::explain::HEADER
+            )
+        );
+    }
+
     #[test]
     fn test_phony_input() {
         let rebuilder = mocked_rebuilder! {
@@ -501,7 +1019,16 @@ mod test {
             &Task {
                 dependencies: vec![],
                 order_dependencies: vec![],
+                implicit_dependencies: 0,
                 variant: TaskVariant::Retrieve,
+                rule: None,
+                depfile: None,
+                rspfile: None,
+                generator: false,
+                restat: false,
+                deps: None,
+                pool: None,
+                description: None,
             },
         );
         assert!(task.is_ok());
@@ -516,7 +1043,16 @@ mod test {
                     b"phony_target_that_does_not_exist".to_vec().into(),
                 )],
                 order_dependencies: vec![],
+                implicit_dependencies: 0,
                 variant: TaskVariant::Retrieve,
+                rule: None,
+                depfile: None,
+                rspfile: None,
+                generator: false,
+                restat: false,
+                deps: None,
+                pool: None,
+                description: None,
             },
         );
         assert!(task.is_ok());
@@ -553,19 +1089,37 @@ mod test {
         let cc_task = Task {
             dependencies: vec![Key::Path(b"foo.c".to_vec().into())],
             order_dependencies: vec![],
+            implicit_dependencies: 0,
             variant: TaskVariant::Command("cc -c foo.c".to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
         };
         let link_task = Task {
             dependencies: vec![Key::Path(b"foo.o".to_vec().into())],
             order_dependencies: vec![],
+            implicit_dependencies: 0,
             variant: TaskVariant::Command("cc -o foo foo.o".to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
         };
 
         // This would previously end up marking foo.o as Clean in the cache.
-        let _task = rebuilder
+        let task = rebuilder
             .build(Key::Path(b"foo.o".to_vec().into()), None, &cc_task)
-            .expect("valid task")
-            .expect_none("foo.o newer than foo.c");
+            .expect("valid task");
+        assert!(task.is_none(), "foo.o newer than foo.c");
 
         let _task = rebuilder
             .build(Key::Path(b"foo".to_vec().into()), None, &link_task)
@@ -573,6 +1127,433 @@ mod test {
             .expect("non-None task");
     }
 
+    /// A tree of files and directories with fixed mtimes, given in seconds. Only files in
+    /// `contents` can be read.
+    struct TreeDiskInterface {
+        files: Vec<(&'static str, u64)>,
+        dirs: Vec<(&'static str, u64)>,
+        contents: Vec<(&'static str, &'static [u8])>,
+    }
+
+    impl DiskInterface for TreeDiskInterface {
+        fn modified<P: AsRef<Path>>(&self, p: P) -> Result<SystemTime> {
+            self.files
+                .iter()
+                .chain(self.dirs.iter())
+                .find(|(path, _)| p.as_ref() == Path::new(path))
+                .map(|(_, secs)| UNIX_EPOCH + Duration::from_secs(*secs))
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "mock not found"))
+        }
+
+        fn is_dir<P: AsRef<Path>>(&self, p: P) -> Result<bool> {
+            Ok(self
+                .dirs
+                .iter()
+                .any(|(path, _)| p.as_ref() == Path::new(path)))
+        }
+
+        fn read_dir<P: AsRef<Path>>(&self, p: P) -> Result<Vec<DirEntry>> {
+            let entries = |paths: &[(&str, u64)], is_dir| {
+                paths
+                    .iter()
+                    .map(|(path, _)| Path::new(path))
+                    .filter(|path| path.parent() == Some(p.as_ref()))
+                    .map(|path| DirEntry {
+                        path: path.to_path_buf(),
+                        is_dir,
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let mut listed = entries(&self.files, false);
+            listed.extend(entries(&self.dirs, true));
+            Ok(listed)
+        }
+
+        fn read<P: AsRef<Path>>(&self, p: P) -> Result<Vec<u8>> {
+            self.contents
+                .iter()
+                .find(|(path, _)| p.as_ref() == Path::new(path))
+                .map(|(_, contents)| contents.to_vec())
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "mock not found"))
+        }
+    }
+
+    fn directory_input_rebuilder(
+        directory_inputs: DirectoryInputs,
+    ) -> CachingMTimeRebuilder<DiskDirtyCache<TreeDiskInterface>> {
+        CachingMTimeRebuilder::new(DiskDirtyCache::with_directory_inputs(
+            TreeDiskInterface {
+                files: vec![("out", 500), ("src/a.c", 300), ("src/sub/b.c", 700)],
+                dirs: vec![("src", 100), ("src/sub", 100)],
+                contents: vec![],
+            },
+            directory_inputs,
+        ))
+    }
+
+    fn directory_input_task() -> Task {
+        Task {
+            dependencies: vec![Key::Path(b"src".to_vec().into())],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command("tar cf out src".to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn test_directory_input_warn_uses_directory_mtime() {
+        let rebuilder = directory_input_rebuilder(DirectoryInputs::Warn);
+        let task = rebuilder
+            .build(
+                Key::Path(b"out".to_vec().into()),
+                None,
+                &directory_input_task(),
+            )
+            .expect("valid task");
+        assert!(task.is_none(), "directory is older than out");
+    }
+
+    #[test]
+    fn test_directory_input_contents_uses_newest_file() {
+        let rebuilder = directory_input_rebuilder(DirectoryInputs::Contents);
+        rebuilder
+            .build(
+                Key::Path(b"out".to_vec().into()),
+                None,
+                &directory_input_task(),
+            )
+            .expect("valid task")
+            .expect("src/sub/b.c is newer than out");
+    }
+
+    #[test]
+    fn test_directory_input_contents_notices_removed_files() {
+        let dir = TempDir::new("rebuilder-removed-files");
+        let listings = SharedDirListings::load(dir.join("dirs")).unwrap();
+        let is_dirty = |files: Vec<(&'static str, u64)>| {
+            let cache = DiskDirtyCache::with_directory_inputs(
+                TreeDiskInterface {
+                    files,
+                    dirs: vec![("src", 100)],
+                    contents: vec![],
+                },
+                DirectoryInputs::Contents,
+            );
+            CachingMTimeRebuilder::new(cache.with_dir_listings(listings.clone()))
+                .build(
+                    Key::Path(b"out".to_vec().into()),
+                    None,
+                    &directory_input_task(),
+                )
+                .expect("valid task")
+                .is_some()
+        };
+        let before = vec![("out", 500), ("src/a.c", 300), ("src/b.c", 300)];
+        let after = vec![("out", 500), ("src/a.c", 300)];
+        assert!(!is_dirty(before), "every file is older than out");
+        assert!(is_dirty(after.clone()), "src/b.c was removed");
+        assert!(is_dirty(after), "out was not built since");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_directory_input_contents_does_not_follow_symlinks() {
+        let dir = TempDir::new("rebuilder-dir-symlinks");
+        std::fs::create_dir_all(dir.join("src/sub")).unwrap();
+        std::fs::write(dir.join("src/sub/a.c"), "").unwrap();
+        std::os::unix::fs::symlink("..", dir.join("src/sub/up")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.join("src/dangling")).unwrap();
+        let rebuilder = crate::caching_mtime_rebuilder(DirectoryInputs::Contents)
+            .workspace(Workspace::new(&*dir));
+        rebuilder
+            .build(
+                Key::Path(b"out".to_vec().into()),
+                None,
+                &directory_input_task(),
+            )
+            .expect("neither a loop nor a dangling link is an error")
+            .expect("out does not exist");
+    }
+
+    #[test]
+    fn test_directory_inputs_from_str() {
+        assert_eq!(
+            "warn".parse::<DirectoryInputs>().unwrap(),
+            DirectoryInputs::Warn
+        );
+        assert_eq!(
+            "contents".parse::<DirectoryInputs>().unwrap(),
+            DirectoryInputs::Contents
+        );
+        assert!("hash".parse::<DirectoryInputs>().is_err());
+    }
+
+    /// Builds `foo.o` from `foo.c`, where `foo.o.d` has `depfile` if it is given.
+    fn build_with_depfile(
+        depfile: Option<&'static [u8]>,
+    ) -> std::result::Result<bool, RebuilderError> {
+        let rebuilder = CachingMTimeRebuilder::new(DiskDirtyCache::new(TreeDiskInterface {
+            files: vec![
+                ("foo.o", 500),
+                ("foo.c", 300),
+                ("old.h", 400),
+                ("new.h", 600),
+            ],
+            dirs: vec![],
+            contents: depfile.map(|d| ("foo.o.d", d)).into_iter().collect(),
+        }));
+        let task = Task {
+            dependencies: vec![Key::Path(b"foo.c".to_vec().into())],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command("cc -MD -c foo.c".to_owned()),
+            rule: None,
+            depfile: Some(b"foo.o.d".to_vec().into()),
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
+        };
+        rebuilder
+            .build(Key::Path(b"foo.o".to_vec().into()), None, &task)
+            .map(|task| task.is_some())
+    }
+
+    #[test]
+    fn test_depfile_inputs() {
+        assert!(!build_with_depfile(Some(b"foo.o: foo.c old.h\n")).unwrap());
+        assert!(build_with_depfile(Some(b"foo.o: foo.c old.h \\\n new.h\n")).unwrap());
+        assert!(
+            build_with_depfile(Some(b"foo.o: foo.c gone.h\n")).unwrap(),
+            "a removed header is not an error"
+        );
+        assert!(
+            build_with_depfile(None).unwrap(),
+            "the command has not written the depfile yet"
+        );
+        let err = build_with_depfile(Some(b"foo.o foo.c\n")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "loading depfile 'foo.o.d': line 1: expected ':' after the outputs"
+        );
+    }
+
+    /// Whether `foo.o`, newer than `foo.c`, is rebuilt when the log has `logged` for it.
+    fn build_with_log(logged: Option<&str>, command: &str, generator: bool) -> bool {
+        let dir = TempDir::new("rebuilder-log");
+        let path = dir.join("log");
+        let mut contents = "# ninjars log v1\n".to_owned();
+        if let Some(logged) = logged {
+            contents.push_str(&format!(
+                "1\t2\t3\t{:x}\tfoo.o\n",
+                hash_command(logged.as_bytes())
+            ));
+        }
+        std::fs::write(&path, contents).unwrap();
+        let log = SharedBuildLog::load(&path).unwrap();
+
+        let rebuilder = CachingMTimeRebuilder::new(DiskDirtyCache::new(TreeDiskInterface {
+            files: vec![("foo.o", 500), ("foo.c", 300)],
+            dirs: vec![],
+            contents: vec![],
+        }))
+        .build_log(log);
+        let task = Task {
+            dependencies: vec![Key::Path(b"foo.c".to_vec().into())],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command(command.to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
+        };
+        rebuilder
+            .build(Key::Path(b"foo.o".to_vec().into()), None, &task)
+            .unwrap()
+            .is_some()
+    }
+
+    #[test]
+    fn test_command_changes() {
+        assert!(!build_with_log(Some("cc -c foo.c"), "cc -c foo.c", false));
+        assert!(build_with_log(
+            Some("cc -c foo.c"),
+            "cc -O2 -c foo.c",
+            false
+        ));
+        assert!(build_with_log(None, "cc -c foo.c", false));
+        assert!(
+            !build_with_log(Some("gen.py"), "gen.py --verbose", true),
+            "generator rules only rebuild for their inputs"
+        );
+    }
+
+    /// Whether `foo`, linked after `foo.o` was last written, is rebuilt after `foo.o` is rebuilt
+    /// from a newer `foo.c` by a command that leaves it alone.
+    fn link_after_untouched_compile(restat: bool) -> bool {
+        let rebuilder = CachingMTimeRebuilder::new(DiskDirtyCache::new(TreeDiskInterface {
+            files: vec![("foo.c", 600), ("foo.o", 500), ("foo", 550)],
+            dirs: vec![],
+            contents: vec![],
+        }));
+        let task = |input: &[u8], command: &str| Task {
+            dependencies: vec![Key::Path(input.to_vec().into())],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command(command.to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat,
+            deps: None,
+            pool: None,
+            description: None,
+        };
+        let compile = task(b"foo.c", "cc -c foo.c");
+        let object = Key::Path(b"foo.o".to_vec().into());
+        assert!(rebuilder
+            .build(object.clone(), None, &compile)
+            .unwrap()
+            .is_some());
+        rebuilder.finished(object, &compile).unwrap();
+        rebuilder
+            .build(
+                Key::Path(b"foo".to_vec().into()),
+                None,
+                &task(b"foo.o", "ld"),
+            )
+            .unwrap()
+            .is_some()
+    }
+
+    #[test]
+    fn restat_prunes_dependents() {
+        assert!(link_after_untouched_compile(false));
+        assert!(!link_after_untouched_compile(true));
+    }
+
+    #[test]
+    fn outputs_not_updated() {
+        let rebuilder = CachingMTimeRebuilder::new(DiskDirtyCache::new(TreeDiskInterface {
+            files: vec![("foo.c", 600), ("foo.o", 500), ("bar.c", 600)],
+            dirs: vec![],
+            contents: vec![],
+        }));
+        let compile = |input: &[u8]| Task {
+            dependencies: vec![Key::Path(input.to_vec().into())],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command("cc".to_owned()),
+            rule: None,
+            depfile: None,
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: None,
+            pool: None,
+            description: None,
+        };
+        let object = Key::Path(b"foo.o".to_vec().into());
+        assert!(rebuilder
+            .build(object.clone(), None, &compile(b"foo.c"))
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            rebuilder.output_not_updated(&object).unwrap(),
+            Some(b"foo.o".to_vec().into())
+        );
+        assert_eq!(
+            rebuilder.mtime_state.dirtiness(object.clone()).unwrap(),
+            Dirtiness::Dirty,
+            "dependents still see it rebuilt"
+        );
+        assert_eq!(rebuilder.output_not_updated(&object).unwrap(), None);
+
+        // A missing output says nothing about whether the command updates it.
+        let missing = Key::Path(b"bar.o".to_vec().into());
+        assert!(rebuilder
+            .build(missing.clone(), None, &compile(b"bar.c"))
+            .unwrap()
+            .is_some());
+        assert_eq!(rebuilder.output_not_updated(&missing).unwrap(), None);
+    }
+
+    /// Why `foo.o`, written at 500s, is rebuilt when the deps log recorded `inputs` for it at
+    /// `recorded_at`, if it is.
+    fn build_with_deps_log(recorded: Option<(u64, &[&str])>) -> Option<Reason> {
+        let dir = TempDir::new("rebuilder-deps");
+        let path = dir.join("deps");
+        let log = SharedDepsLog::load(&path).unwrap();
+        if let Some((recorded_at, inputs)) = recorded {
+            let inputs: Vec<Vec<u8>> = inputs.iter().map(|i| i.as_bytes().to_vec()).collect();
+            log.record(b"foo.o", recorded_at * 1_000_000_000, &inputs);
+        }
+
+        let rebuilder = CachingMTimeRebuilder::new(DiskDirtyCache::new(TreeDiskInterface {
+            files: vec![
+                ("foo.o", 500),
+                ("foo.c", 300),
+                ("old.h", 400),
+                ("new.h", 600),
+            ],
+            dirs: vec![],
+            contents: vec![],
+        }))
+        .deps_log(log.clone());
+        let key = Key::Path(b"foo.o".to_vec().into());
+        let task = Task {
+            dependencies: vec![Key::Path(b"foo.c".to_vec().into())],
+            order_dependencies: vec![],
+            implicit_dependencies: 0,
+            variant: TaskVariant::Command("cc -MD -c foo.c".to_owned()),
+            rule: None,
+            // Never read, since the deps log has taken over.
+            depfile: Some(b"foo.o.d".to_vec().into()),
+            rspfile: None,
+            generator: false,
+            restat: false,
+            deps: Some(ninja_parse::repr::Deps::Gcc),
+            pool: None,
+            description: None,
+        };
+        let reason = rebuilder
+            .deps_log_dirtiness(&log, &key, UNIX_EPOCH + Duration::from_secs(500))
+            .unwrap();
+        let dirty = rebuilder.build(key, None, &task).unwrap().is_some();
+        assert_eq!(dirty, reason.is_some());
+        reason
+    }
+
+    #[test]
+    fn test_deps_log_inputs() {
+        assert_eq!(build_with_deps_log(Some((500, &["foo.c", "old.h"]))), None);
+        assert_eq!(
+            build_with_deps_log(Some((500, &["foo.c", "new.h"]))),
+            Some(Reason::DepsInput)
+        );
+        assert_eq!(
+            build_with_deps_log(Some((400, &["foo.c"]))),
+            Some(Reason::DepsOutOfDate)
+        );
+        assert_eq!(build_with_deps_log(None), Some(Reason::DepsMissing));
+    }
+
     #[test]
     fn test_order_dependencies_newer() {
         // TODO: Add a test where order dependencies are newer, but target should not rebuild.
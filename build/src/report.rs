@@ -0,0 +1,261 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! What a build did with each edge it was asked to bring up to date, for embedders that need more
+//! than whether it succeeded.
+
+use std::{collections::HashMap, time::SystemTime};
+
+use thiserror::Error;
+
+use crate::{
+    disk_interface::DiskInterface,
+    failures::{CommandFailure, Failures},
+    task::{Key, KeyPath, Tasks},
+    workspace::Workspace,
+    BuildError, SchedulerGraph,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The edge ran and succeeded.
+    Succeeded,
+    /// The rebuilder found nothing to do.
+    UpToDate,
+    /// The edge ran and failed. See `BuildReport::failure`.
+    Failed,
+    /// The edge never ran, because something it depends on failed or the build stopped first.
+    Skipped,
+}
+
+/// What `BuildReport::verify` found wrong with an output of an edge whose command succeeded.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Unverified {
+    #[error("'{}' is missing after its command succeeded", .0.display())]
+    Missing(KeyPath),
+    #[error(
+        "'{}' is older than its input '{}' after its command succeeded",
+        .output.display(),
+        .input.display()
+    )]
+    OlderThanInput { output: KeyPath, input: KeyPath },
+}
+
+/// The outcome of every edge the build needed. Keys no edge produces are not included.
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    outcomes: HashMap<Key, Outcome>,
+    failures: Failures,
+    unverified: Vec<Unverified>,
+}
+
+impl BuildReport {
+    pub fn outcome(&self, key: &Key) -> Option<Outcome> {
+        self.outcomes.get(key).copied()
+    }
+
+    /// In no particular order.
+    pub fn outcomes(&self) -> impl Iterator<Item = (&Key, Outcome)> {
+        self.outcomes.iter().map(|(key, outcome)| (key, *outcome))
+    }
+
+    /// How many edges ended up with `outcome`.
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.outcomes.values().filter(|o| **o == outcome).count()
+    }
+
+    /// Every failed command, in the order they finished.
+    pub fn failures(&self) -> &Failures {
+        &self.failures
+    }
+
+    /// Why the edge producing `key` failed, with what it printed.
+    pub fn failure(&self, key: &Key) -> Option<&CommandFailure> {
+        self.failures.0.iter().find(|failure| failure.key == *key)
+    }
+
+    /// What the last `verify` found, by output.
+    pub fn unverified(&self) -> &[Unverified] {
+        &self.unverified
+    }
+
+    /// Looks at the outputs of every edge that succeeded again, to catch rules whose commands
+    /// claim success without producing them. Each must exist and be no older than the newest of
+    /// its inputs, except that `restat` edges may leave outputs alone on purpose, so theirs only
+    /// have to exist. Returns how many outputs were found wrong.
+    pub fn verify<D: DiskInterface>(
+        &mut self,
+        tasks: &Tasks,
+        disk: &D,
+        workspace: &Workspace,
+    ) -> usize {
+        let modified = |path: &KeyPath| disk.modified(workspace.path(path.as_bytes())).ok();
+        let mut succeeded: Vec<&Key> = self
+            .outcomes
+            .iter()
+            .filter(|(_, outcome)| **outcome == Outcome::Succeeded)
+            .map(|(key, _)| key)
+            .collect();
+        succeeded.sort();
+        let mut unverified = Vec::new();
+        for key in succeeded {
+            let task = match tasks.task(key) {
+                Some(task) if task.is_command() => task,
+                _ => continue,
+            };
+            // Inputs that are not files, like phony targets, have nothing to compare against.
+            let newest_input: Option<(SystemTime, &KeyPath)> = task
+                .dependencies()
+                .iter()
+                .flat_map(|dependency| dependency.iter())
+                .filter_map(|input| modified(input).map(|mtime| (mtime, input)))
+                .max_by_key(|(mtime, _)| *mtime);
+            for output in key.iter() {
+                match (modified(output), newest_input) {
+                    (None, _) => unverified.push(Unverified::Missing(output.clone())),
+                    (Some(mtime), Some((input_mtime, input)))
+                        if mtime < input_mtime && !task.restat =>
+                    {
+                        unverified.push(Unverified::OlderThanInput {
+                            output: output.clone(),
+                            input: input.clone(),
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.unverified = unverified;
+        self.unverified.len()
+    }
+
+    pub(crate) fn record(&mut self, key: &Key, outcome: Outcome) {
+        self.outcomes.insert(key.clone(), outcome);
+    }
+
+    pub(crate) fn record_failure(&mut self, failure: CommandFailure) {
+        self.outcomes.insert(failure.key.clone(), Outcome::Failed);
+        self.failures.0.push(failure);
+    }
+
+    /// Marks every edge in `graph` without an outcome as skipped.
+    fn skip_the_rest(&mut self, graph: &SchedulerGraph, tasks: &Tasks) {
+        for node in graph.node_indices() {
+            let key = graph[node];
+            if tasks.task(key).is_some() && !self.outcomes.contains_key(key) {
+                self.outcomes.insert(key.clone(), Outcome::Skipped);
+            }
+        }
+    }
+
+    /// Like `finish`, for a build that stopped at its deadline. Fails with `DeadlineReached` if
+    /// anything was left, whether or not commands failed too.
+    pub(crate) fn past_deadline(
+        mut self,
+        graph: &SchedulerGraph,
+        tasks: &Tasks,
+    ) -> Result<BuildReport, BuildError> {
+        self.skip_the_rest(graph, tasks);
+        if self.count(Outcome::Skipped) == 0 {
+            self.finish(graph, tasks)
+        } else {
+            Err(BuildError::DeadlineReached(self))
+        }
+    }
+
+    /// Marks every edge in `graph` without an outcome as skipped, and fails if any command did.
+    pub(crate) fn finish(
+        mut self,
+        graph: &SchedulerGraph,
+        tasks: &Tasks,
+    ) -> Result<BuildReport, BuildError> {
+        self.skip_the_rest(graph, tasks);
+        if self.failures.0.is_empty() {
+            Ok(self)
+        } else {
+            Err(BuildError::CommandsFailed(self))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Error, ErrorKind, Result},
+        path::Path,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::task::TasksBuilder;
+
+    /// Files with fixed mtimes, given in seconds.
+    struct Files(Vec<(&'static str, u64)>);
+
+    impl DiskInterface for Files {
+        fn modified<P: AsRef<Path>>(&self, p: P) -> Result<SystemTime> {
+            self.0
+                .iter()
+                .find(|(path, _)| p.as_ref() == Path::new(path))
+                .map(|(_, secs)| UNIX_EPOCH + Duration::from_secs(*secs))
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "mock not found"))
+        }
+    }
+
+    fn key(path: &str) -> Key {
+        Key::Path(path.as_bytes().to_vec().into())
+    }
+
+    fn path(path: &str) -> KeyPath {
+        path.as_bytes().to_vec().into()
+    }
+
+    #[test]
+    fn verify_finds_outputs_commands_did_not_produce() {
+        let mut builder = TasksBuilder::new();
+        builder
+            .source("a.c")
+            .source("b.c")
+            .command(&["a.o"], &["a.c"], "cc a.c")
+            .unwrap()
+            .command(&["b.o"], &["b.c"], "cc b.c")
+            .unwrap()
+            .command(&["c.o"], &["a.c"], "true")
+            .unwrap()
+            .command(&["d.o"], &["b.c"], "cc d.c")
+            .unwrap();
+        let tasks = builder.build().unwrap();
+        let mut report = BuildReport::default();
+        for output in &["a.o", "b.o", "c.o"] {
+            report.record(&key(output), Outcome::Succeeded);
+        }
+        // Edges that did not run are left alone.
+        report.record(&key("d.o"), Outcome::UpToDate);
+        let files = Files(vec![("a.c", 10), ("b.c", 20), ("a.o", 11), ("b.o", 19)]);
+
+        assert_eq!(report.verify(&tasks, &files, &Workspace::default()), 2);
+        assert_eq!(
+            report.unverified(),
+            &[
+                Unverified::OlderThanInput {
+                    output: path("b.o"),
+                    input: path("b.c"),
+                },
+                Unverified::Missing(path("c.o")),
+            ]
+        );
+    }
+}
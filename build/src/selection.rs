@@ -0,0 +1,194 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Strategies for choosing which targets a build starts from, other than naming them.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    process::Command,
+};
+
+use thiserror::Error;
+
+use crate::{
+    task::{Key, Tasks},
+    workspace::Workspace,
+};
+
+/// Picks the keys a build starts from by looking at the graph.
+pub trait TargetSelection {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn select(&self, tasks: &Tasks) -> Result<Vec<Key>, Self::Error>;
+}
+
+/// Every key that (transitively) depends on one of `changed`, reduced to those nothing else
+/// affected depends on. Building those builds the rest of the affected subgraph, without looking
+/// at the parts of the graph the changes can't reach from above.
+///
+/// Keys are matched by their path on disk, so a namespaced key is found by `ns/path`.
+pub fn affected_targets<P: AsRef<[u8]>>(tasks: &Tasks, changed: &[P]) -> Vec<Key> {
+    let mut dependents: HashMap<&Key, Vec<&Key>> = HashMap::new();
+    let mut by_path: HashMap<&[u8], Vec<&Key>> = HashMap::new();
+    for (key, task) in tasks.all_tasks() {
+        for dep in task.dependencies().iter().chain(task.order_dependencies()) {
+            dependents.entry(dep).or_default().push(key);
+            if let Key::Path(path) = dep {
+                by_path.entry(path.as_bytes()).or_default().push(dep);
+            }
+        }
+        if let Key::Path(path) = key {
+            by_path.entry(path.as_bytes()).or_default().push(key);
+        }
+    }
+
+    let mut affected: HashSet<&Key> = HashSet::new();
+    let mut queue: VecDeque<&Key> = changed
+        .iter()
+        .filter_map(|path| by_path.get(path.as_ref()))
+        .flatten()
+        .copied()
+        .collect();
+    while let Some(key) = queue.pop_front() {
+        if affected.insert(key) {
+            if let Some(users) = dependents.get(key) {
+                queue.extend(users);
+            }
+        }
+    }
+
+    let mut targets: Vec<Key> = affected
+        .iter()
+        .filter(|key| tasks.task(key).is_some())
+        .filter(|key| match dependents.get(*key) {
+            Some(users) => !users.iter().any(|user| affected.contains(user)),
+            None => true,
+        })
+        .map(|key| (*key).clone())
+        .collect();
+    targets.sort();
+    targets
+}
+
+#[derive(Error, Debug)]
+pub enum ChangedSinceError {
+    #[error("running git: {0}")]
+    Spawn(#[from] std::io::Error),
+    #[error("git diff failed: {0}")]
+    Git(String),
+}
+
+/// Builds what is affected by the files git reports as changed since a ref, including changes
+/// that are not committed yet. For CI runs that only verify a change.
+#[derive(Debug)]
+pub struct ChangedSince {
+    git_ref: String,
+    workspace: Workspace,
+}
+
+impl ChangedSince {
+    pub fn new(git_ref: impl Into<String>) -> Self {
+        ChangedSince {
+            git_ref: git_ref.into(),
+            workspace: Workspace::default(),
+        }
+    }
+
+    /// Ask git about `workspace` instead of the current directory.
+    pub fn workspace(mut self, workspace: Workspace) -> Self {
+        self.workspace = workspace;
+        self
+    }
+
+    /// Paths relative to the workspace, which is where the manifest's paths are relative to as
+    /// well.
+    fn changed_files(&self) -> Result<Vec<Vec<u8>>, ChangedSinceError> {
+        let mut command = Command::new("git");
+        if let Some(dir) = self.workspace.dir() {
+            command.current_dir(dir);
+        }
+        let output = command
+            .arg("diff")
+            .arg("--name-only")
+            .arg("--relative")
+            .arg("-z")
+            .arg(&self.git_ref)
+            .arg("--")
+            .output()?;
+        if !output.status.success() {
+            return Err(ChangedSinceError::Git(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            ));
+        }
+        Ok(output
+            .stdout
+            .split(|b| *b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| path.to_vec())
+            .collect())
+    }
+}
+
+impl TargetSelection for ChangedSince {
+    type Error = ChangedSinceError;
+
+    fn select(&self, tasks: &Tasks) -> Result<Vec<Key>, Self::Error> {
+        Ok(affected_targets(tasks, &self.changed_files()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::TasksBuilder;
+
+    fn key(path: &str) -> Key {
+        Key::Path(path.as_bytes().to_vec().into())
+    }
+
+    fn tasks() -> Tasks {
+        let mut builder = TasksBuilder::new();
+        builder.source("a.c").source("b.c").source("unused.c");
+        builder.command(&["a.o"], &["a.c"], "cc a.c").unwrap();
+        builder.command(&["b.o"], &["b.c"], "cc b.c").unwrap();
+        builder.command(&["app"], &["a.o", "b.o"], "ld").unwrap();
+        builder.command(&["tool"], &["b.o"], "ld b.o").unwrap();
+        builder.phony(&["all"], &["app"]).unwrap();
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn only_the_top_of_the_affected_subgraph() {
+        let tasks = tasks();
+        assert_eq!(affected_targets(&tasks, &["a.c"]), vec![key("all")]);
+        assert_eq!(
+            affected_targets(&tasks, &["b.c"]),
+            vec![key("all"), key("tool")]
+        );
+    }
+
+    #[test]
+    fn unknown_and_unused_files_affect_nothing() {
+        let tasks = tasks();
+        assert!(affected_targets(&tasks, &["README", "unused.c"]).is_empty());
+    }
+
+    #[test]
+    fn changed_outputs_count_too() {
+        let tasks = tasks();
+        assert_eq!(affected_targets(&tasks, &["tool"]), vec![key("tool")]);
+    }
+}
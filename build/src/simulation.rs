@@ -0,0 +1,368 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A deterministic, single-threaded model of `ParallelTopoScheduler`, for writing precise tests
+//! of scheduling decisions.
+//!
+//! Instead of asking a rebuilder and running commands, the simulation looks up a scripted
+//! `Answer` for each key, and commands take a scripted number of ticks on a virtual clock. It
+//! shares the graph construction and ready-queue bookkeeping with the real scheduler, and models
+//! the job slots and stopping on failure the same way. Batching is not modelled.
+
+use std::collections::{HashMap, VecDeque};
+
+use petgraph::graph::NodeIndex;
+
+use crate::{
+    task::{Key, Tasks},
+    BuildState, ParallelTopoScheduler,
+};
+
+/// What the scripted rebuilder says about a key, and how its command goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    UpToDate,
+    Succeed {
+        ticks: u64,
+    },
+    Fail {
+        ticks: u64,
+    },
+    /// Killed by Ctrl-C, which stops the build regardless of how many failures are allowed.
+    Interrupted {
+        ticks: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The key is a source, or the rebuilder said it is up to date.
+    UpToDate(Key),
+    /// The rebuilder asked for the key to be rebuilt. Its command waits for a job slot.
+    Dispatched(Key),
+    Started {
+        key: Key,
+        at: u64,
+    },
+    Finished {
+        key: Key,
+        at: u64,
+        succeeded: bool,
+    },
+    /// Dispatched, but the build stopped before the command got a slot.
+    NotRun(Key),
+}
+
+/// Runs a build of `tasks` without a rebuilder or commands, as the answers script it.
+///
+/// ```
+/// use ninja_builder::{
+///     simulation::{Answer, Event, Simulation},
+///     task::{Key, TasksBuilder},
+/// };
+///
+/// let mut builder = TasksBuilder::new();
+/// builder.command(&["a"], &[] as &[&str], "make a")?;
+/// builder.command(&["b"], &["a"], "make b")?;
+/// let tasks = builder.build()?;
+/// let a = Key::Path(b"a".to_vec().into());
+/// let b = Key::Path(b"b".to_vec().into());
+/// let events = Simulation::new(&tasks, 1)
+///     .answer(a.clone(), Answer::Fail { ticks: 2 })
+///     .run(None);
+/// assert_eq!(
+///     events,
+///     vec![
+///         Event::Dispatched(a.clone()),
+///         Event::Started { key: a.clone(), at: 0 },
+///         Event::Finished { key: a, at: 2, succeeded: false },
+///     ]
+/// );
+/// // b never ran, since what it needs failed.
+/// assert!(!events.iter().any(|event| *event == Event::Dispatched(b.clone())));
+/// # Ok::<(), ninja_builder::task::TasksBuilderError>(())
+/// ```
+#[derive(Debug)]
+pub struct Simulation<'a> {
+    tasks: &'a Tasks,
+    parallelism: usize,
+    failures_allowed: usize,
+    answers: HashMap<Key, Answer>,
+}
+
+impl<'a> Simulation<'a> {
+    /// Keys that have a task and no scripted answer succeed after 1 tick.
+    pub fn new(tasks: &'a Tasks, parallelism: usize) -> Self {
+        Simulation {
+            tasks,
+            parallelism: std::cmp::max(parallelism, 1),
+            failures_allowed: 1,
+            answers: HashMap::new(),
+        }
+    }
+
+    /// Like `ParallelTopoScheduler::keep_going`.
+    pub fn keep_going(mut self, failures_allowed: usize) -> Self {
+        self.failures_allowed = std::cmp::max(failures_allowed, 1);
+        self
+    }
+
+    pub fn answer(mut self, key: Key, answer: Answer) -> Self {
+        self.answers.insert(key, answer);
+        self
+    }
+
+    fn answer_for(&self, key: &Key) -> Answer {
+        match self.answers.get(key) {
+            Some(answer) => *answer,
+            None if self.tasks.task(key).is_some() => Answer::Succeed { ticks: 1 },
+            None => Answer::UpToDate,
+        }
+    }
+
+    /// Builds from `start`, or from the roots of the graph if it is None, and returns what
+    /// happened in order.
+    pub fn run(&self, start: Option<Vec<Key>>) -> Vec<Event> {
+        let graph = ParallelTopoScheduler::build_graph(self.tasks, start.as_deref())
+            .expect("simulated builds are small");
+        let mut build_state = BuildState::for_start(&graph, start.as_deref(), false);
+        let mut events = Vec::new();
+        let mut now = 0;
+        let mut waiting: VecDeque<NodeIndex> = VecDeque::new();
+        // (finish time, order started, node), so ties finish in the order they started.
+        let mut running: Vec<(u64, usize, NodeIndex)> = Vec::new();
+        let mut started = 0;
+        let mut failures = 0;
+        let mut stopped = false;
+
+        while !build_state.done() {
+            if stopped && waiting.is_empty() && running.is_empty() {
+                break;
+            }
+            // Like the real scheduler, decide on everything that is ready before waiting on
+            // anything.
+            let next = if stopped {
+                None
+            } else {
+                build_state.next_ready()
+            };
+            if let Some(node) = next {
+                let key = graph[node];
+                if self.answer_for(key) == Answer::UpToDate {
+                    events.push(Event::UpToDate(key.clone()));
+                    build_state.finish_node(&graph, node, true);
+                } else {
+                    events.push(Event::Dispatched(key.clone()));
+                    waiting.push_back(node);
+                }
+                continue;
+            }
+
+            while running.len() < self.parallelism {
+                let node = match waiting.pop_front() {
+                    Some(node) => node,
+                    None => break,
+                };
+                let key = graph[node];
+                if stopped {
+                    events.push(Event::NotRun(key.clone()));
+                    build_state.finish_node(&graph, node, false);
+                    continue;
+                }
+                let ticks = match self.answer_for(key) {
+                    Answer::Succeed { ticks }
+                    | Answer::Fail { ticks }
+                    | Answer::Interrupted { ticks } => ticks,
+                    Answer::UpToDate => unreachable!("up to date keys are never dispatched"),
+                };
+                events.push(Event::Started {
+                    key: key.clone(),
+                    at: now,
+                });
+                running.push((now + ticks, started, node));
+                started += 1;
+            }
+
+            let next = running
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (finish, order, _))| (*finish, *order))
+                .map(|(i, _)| i);
+            let (finish, _, node) = match next {
+                Some(i) => running.remove(i),
+                // Only possible if what is left waits on a cycle.
+                None => break,
+            };
+            now = finish;
+            let key = graph[node];
+            let answer = self.answer_for(key);
+            let succeeded = matches!(answer, Answer::Succeed { .. });
+            build_state.finish_node(&graph, node, succeeded);
+            events.push(Event::Finished {
+                key: key.clone(),
+                at: now,
+                succeeded,
+            });
+            if !succeeded {
+                failures += 1;
+                stopped = matches!(answer, Answer::Interrupted { .. })
+                    || failures >= self.failures_allowed;
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::TasksBuilder;
+
+    fn key(path: &str) -> Key {
+        Key::Path(path.as_bytes().to_vec().into())
+    }
+
+    fn started(path: &str, at: u64) -> Event {
+        Event::Started { key: key(path), at }
+    }
+
+    fn finished(path: &str, at: u64, succeeded: bool) -> Event {
+        Event::Finished {
+            key: key(path),
+            at,
+            succeeded,
+        }
+    }
+
+    /// a.o and b.o from sources, linked into app.
+    fn tasks() -> Tasks {
+        let mut builder = TasksBuilder::new();
+        builder.source("a.c").source("b.c");
+        builder.command(&["a.o"], &["a.c"], "cc a.c").unwrap();
+        builder.command(&["b.o"], &["b.c"], "cc b.c").unwrap();
+        builder.command(&["app"], &["a.o", "b.o"], "ld").unwrap();
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn serial_build_follows_dependency_order() {
+        let tasks = tasks();
+        let events: Vec<Event> = Simulation::new(&tasks, 1)
+            .answer(key("b.o"), Answer::UpToDate)
+            .run(Some(vec![key("app")]))
+            .into_iter()
+            .filter(|e| matches!(e, Event::Started { .. } | Event::Finished { .. }))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                started("a.o", 0),
+                finished("a.o", 1, true),
+                started("app", 1),
+                finished("app", 2, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn slots_are_shared_by_duration() {
+        let tasks = tasks();
+        let events = Simulation::new(&tasks, 2)
+            .answer(key("a.o"), Answer::Succeed { ticks: 5 })
+            .answer(key("b.o"), Answer::Succeed { ticks: 2 })
+            .run(Some(vec![key("app")]));
+        let finishes: Vec<&Event> = events
+            .iter()
+            .filter(|e| matches!(e, Event::Finished { .. }))
+            .collect();
+        assert_eq!(
+            finishes,
+            vec![
+                &finished("b.o", 2, true),
+                &finished("a.o", 5, true),
+                &finished("app", 6, true),
+            ]
+        );
+    }
+
+    /// Only what happens after the rebuilder has been asked.
+    fn ran(events: Vec<Event>) -> Vec<Event> {
+        events
+            .into_iter()
+            .filter(|e| !matches!(e, Event::UpToDate(_) | Event::Dispatched(_)))
+            .collect()
+    }
+
+    #[test]
+    fn failure_stops_commands_waiting_for_a_slot() {
+        let tasks = tasks();
+        let events = Simulation::new(&tasks, 1)
+            .answer(key("a.o"), Answer::Fail { ticks: 1 })
+            .run(Some(vec![key("app")]));
+        // Both objects are dispatched before anything runs, but only one gets the slot.
+        assert!(events.contains(&Event::Dispatched(key("b.o"))));
+        assert_eq!(
+            ran(events),
+            vec![
+                started("a.o", 0),
+                finished("a.o", 1, false),
+                Event::NotRun(key("b.o")),
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_going_runs_independent_commands() {
+        let tasks = tasks();
+        let events = Simulation::new(&tasks, 1)
+            .keep_going(usize::MAX)
+            .answer(key("a.o"), Answer::Fail { ticks: 1 })
+            .run(Some(vec![key("app")]));
+        // app depends on the failure, so it is never dispatched.
+        assert!(!events.contains(&Event::Dispatched(key("app"))));
+        assert_eq!(
+            ran(events),
+            vec![
+                started("a.o", 0),
+                finished("a.o", 1, false),
+                started("b.o", 1),
+                finished("b.o", 2, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn interrupt_stops_despite_keep_going() {
+        let tasks = tasks();
+        let events = Simulation::new(&tasks, 1)
+            .keep_going(usize::MAX)
+            .answer(key("a.o"), Answer::Interrupted { ticks: 1 })
+            .answer(key("b.o"), Answer::Interrupted { ticks: 1 })
+            .run(Some(vec![key("app")]));
+        let finishes = events
+            .iter()
+            .filter(|e| matches!(e, Event::Finished { .. }))
+            .count();
+        assert_eq!(finishes, 1);
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::NotRun(_)))
+                .count(),
+            1
+        );
+    }
+}
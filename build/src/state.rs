@@ -0,0 +1,192 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The state file records which format each file ninja keeps in the build directory was written
+//! in, so that switching to a version of ninja that can't read them fails up front, rather than
+//! misreading them.
+//!
+//! Each line is a file's name and the version of its format. When every version is one this
+//! ninja reads, the file is rewritten with the current versions, since the files themselves are
+//! upgraded as they are next written.
+//!
+//! The file is replaced atomically, but ninjas before that wrote it in place, so a last line
+//! without a newline may be torn. It is ignored, and the file rewritten.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{atomic::write_atomic, build_log, deps_log, dir_listings};
+
+/// Created in the directory ninja builds in, next to the files it describes.
+pub const STATE_FILE: &str = ".ninja_rs_state";
+
+/// Each file, the version of its format ninja writes, and the oldest version it still reads.
+const FORMATS: &[(&str, u32, u32)] = &[
+    (".ninja_log", build_log::VERSION, build_log::VERSION),
+    (".ninja_deps", deps_log::VERSION, deps_log::VERSION),
+    (".ninja_dirs", dir_listings::VERSION, dir_listings::VERSION),
+];
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("{}: {}", STATE_FILE, .0)]
+    Io(#[from] io::Error),
+    #[error("{} line {}: expected a file name and version", STATE_FILE, .0)]
+    Malformed(usize),
+    #[error(
+        "{file} is in format version {found}, but this ninja only reads {supported}; remove {file} \
+         and {state} to start over, or use the ninja that wrote it",
+        state = STATE_FILE
+    )]
+    Incompatible {
+        file: String,
+        found: u32,
+        /// Like `version 4` or `versions 3 to 4`.
+        supported: String,
+    },
+    #[error(
+        "{file} was written by a newer ninja that this one doesn't know about; remove it and {state} \
+         to start over, or use the ninja that wrote it",
+        state = STATE_FILE
+    )]
+    Unknown { file: String },
+}
+
+fn contents() -> String {
+    FORMATS
+        .iter()
+        .map(|(file, current, _)| format!("{} {}\n", file, current))
+        .collect()
+}
+
+fn parse(contents: &str) -> Result<HashMap<&str, u32>, StateError> {
+    let complete = contents.rfind('\n').map_or("", |i| &contents[..=i]);
+    complete
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next().map(str::parse), words.next()) {
+                (Some(file), Some(Ok(version)), None) => Ok((file, version)),
+                _ => Err(StateError::Malformed(i + 1)),
+            }
+        })
+        .collect()
+}
+
+/// Fails if the state file in `dir` names a file this ninja doesn't know, or a format version it
+/// can't read. Otherwise writes the current versions, creating the file if it is missing.
+pub fn check_state<P: AsRef<Path>>(dir: P) -> Result<(), StateError> {
+    let path: PathBuf = dir.as_ref().join(STATE_FILE);
+    let existing = match std::fs::read_to_string(&path) {
+        Ok(existing) => existing,
+        // A new build directory, or one from before the state file existed. Those used the
+        // formats that are current now.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    for (file, found) in parse(&existing)? {
+        match FORMATS.iter().find(|(known, _, _)| *known == file) {
+            Some(&(_, current, oldest)) if found < oldest || found > current => {
+                return Err(StateError::Incompatible {
+                    file: file.to_owned(),
+                    found,
+                    supported: if oldest == current {
+                        format!("version {}", current)
+                    } else {
+                        format!("versions {} to {}", oldest, current)
+                    },
+                });
+            }
+            Some(_) => {}
+            None => {
+                return Err(StateError::Unknown {
+                    file: file.to_owned(),
+                })
+            }
+        }
+    }
+    let current = contents();
+    if existing != current {
+        write_atomic(&path, false, |file| file.write_all(current.as_bytes()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn created_when_missing() {
+        let dir = TempDir::new("state-missing");
+        check_state(&*dir).unwrap();
+        let written = std::fs::read_to_string(dir.join(STATE_FILE)).unwrap();
+        check_state(&*dir).unwrap();
+        assert_eq!(written, contents());
+    }
+
+    #[test]
+    fn ignores_torn_last_line() {
+        let dir = TempDir::new("state-torn");
+        std::fs::write(dir.join(STATE_FILE), ".ninja_log 1\n.ninja_deps 4").unwrap();
+        let result = check_state(&*dir);
+        let written = std::fs::read_to_string(dir.join(STATE_FILE)).unwrap();
+        std::fs::write(dir.join(STATE_FILE), ".ninja_log 1\n.ninja_de").unwrap();
+        let torn_name = check_state(&*dir);
+        assert!(result.is_ok());
+        assert!(torn_name.is_ok());
+        assert_eq!(written, contents());
+    }
+
+    #[test]
+    fn incompatible_versions() {
+        let dir = TempDir::new("state-incompatible");
+        let check = |contents: &str| {
+            std::fs::write(dir.join(STATE_FILE), contents).unwrap();
+            check_state(&*dir)
+        };
+        let newer = format!(".ninja_deps {}\n", deps_log::VERSION + 1);
+        let results = [
+            check(&newer),
+            check(".ninja_deps 3\n"),
+            check(".ninja_hashes 1\n"),
+            check(".ninja_log\n"),
+        ];
+        // Nothing was overwritten.
+        let left = std::fs::read_to_string(dir.join(STATE_FILE)).unwrap();
+        assert_eq!(left, ".ninja_log\n");
+        assert!(
+            matches!(&results[0], Err(StateError::Incompatible { found, .. })
+            if *found == deps_log::VERSION + 1)
+        );
+        assert!(matches!(
+            &results[1],
+            Err(StateError::Incompatible { found: 3, .. })
+        ));
+        assert!(
+            matches!(&results[2], Err(StateError::Unknown { file }) if file == ".ninja_hashes")
+        );
+        assert!(matches!(&results[3], Err(StateError::Malformed(1))));
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The progress prefix of status lines, configured like ninja's `NINJA_STATUS`.
+//!
+//! Placeholders are replaced as ninja does: `%s` started edges, `%t` total edges, `%r` running
+//! edges, `%u` edges yet to start, `%f` finished edges, `%o` edges finished per second, `%p` the
+//! percentage finished, `%e` seconds elapsed, `%w` time elapsed as `[h:]mm:ss`, and `%%` a `%`.
+//! The total is every command that may run, and shrinks as some turn out to be up to date.
+
+use std::{fmt::Write, time::Duration};
+
+use thiserror::Error;
+
+/// The environment variable the format is read from.
+pub const STATUS_ENV: &str = "NINJA_STATUS";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum StatusFormatError {
+    #[error("unknown placeholder '%{0}' in status format")]
+    UnknownPlaceholder(char),
+    #[error("status format ends with a lone '%'")]
+    TrailingPercent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Started,
+    Total,
+    Running,
+    Unstarted,
+    Finished,
+    Rate,
+    Percentage,
+    ElapsedSeconds,
+    ElapsedTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Placeholder(Part),
+}
+
+/// Where the build is, for filling in a status format.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Progress {
+    pub(crate) started: usize,
+    pub(crate) total: usize,
+    pub(crate) finished: usize,
+    pub(crate) elapsed: Duration,
+}
+
+/// A parsed status format. The default is ninja's, `[%f/%t] `.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusFormat(Vec<Piece>);
+
+impl Default for StatusFormat {
+    fn default() -> Self {
+        StatusFormat::parse("[%f/%t] ").expect("valid default")
+    }
+}
+
+impl StatusFormat {
+    pub fn parse(format: &str) -> Result<Self, StatusFormatError> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                literal.push(c);
+                continue;
+            }
+            let part = match chars.next() {
+                None => return Err(StatusFormatError::TrailingPercent),
+                Some('%') => {
+                    literal.push('%');
+                    continue;
+                }
+                Some('s') => Part::Started,
+                Some('t') => Part::Total,
+                Some('r') => Part::Running,
+                Some('u') => Part::Unstarted,
+                Some('f') => Part::Finished,
+                Some('o') => Part::Rate,
+                Some('p') => Part::Percentage,
+                Some('e') => Part::ElapsedSeconds,
+                Some('w') => Part::ElapsedTime,
+                Some(other) => return Err(StatusFormatError::UnknownPlaceholder(other)),
+            };
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+            }
+            pieces.push(Piece::Placeholder(part));
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(StatusFormat(pieces))
+    }
+
+    /// The format read from `NINJA_STATUS`, or the default if it is unset.
+    pub fn from_env() -> Result<Self, StatusFormatError> {
+        match std::env::var(STATUS_ENV) {
+            Ok(format) => StatusFormat::parse(&format),
+            Err(_) => Ok(StatusFormat::default()),
+        }
+    }
+
+    pub(crate) fn render(&self, progress: &Progress) -> String {
+        let mut out = String::new();
+        for piece in &self.0 {
+            let part = match piece {
+                Piece::Literal(literal) => {
+                    out.push_str(literal);
+                    continue;
+                }
+                Piece::Placeholder(part) => part,
+            };
+            let seconds = progress.elapsed.as_secs_f64();
+            let _ = match part {
+                Part::Started => write!(out, "{}", progress.started),
+                Part::Total => write!(out, "{}", progress.total),
+                Part::Running => write!(out, "{}", progress.started - progress.finished),
+                Part::Unstarted => write!(out, "{}", progress.total - progress.started),
+                Part::Finished => write!(out, "{}", progress.finished),
+                Part::Rate if seconds > 0.0 => {
+                    write!(out, "{:.1}", progress.finished as f64 / seconds)
+                }
+                Part::Rate => write!(out, "?"),
+                Part::Percentage => write!(
+                    out,
+                    "{:3}%",
+                    (100 * progress.finished)
+                        .checked_div(progress.total)
+                        .unwrap_or(0)
+                ),
+                Part::ElapsedSeconds => write!(out, "{:.3}", seconds),
+                Part::ElapsedTime => {
+                    let secs = progress.elapsed.as_secs();
+                    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+                    if hours > 0 {
+                        write!(out, "{}:{:02}:{:02}", hours, minutes, secs)
+                    } else {
+                        write!(out, "{:02}:{:02}", minutes, secs)
+                    }
+                }
+            };
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn render(format: &str, started: usize, finished: usize, elapsed_ms: u64) -> String {
+        StatusFormat::parse(format).unwrap().render(&Progress {
+            started,
+            total: started + 2,
+            finished,
+            elapsed: Duration::from_millis(elapsed_ms),
+        })
+    }
+
+    #[test]
+    fn default_is_ninjas() {
+        let progress = Progress {
+            started: 2,
+            total: 3,
+            finished: 1,
+            elapsed: Duration::default(),
+        };
+        assert_eq!(StatusFormat::default().render(&progress), "[1/3] ");
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(render("%s %t %r %u %f", 5, 2, 0), "5 7 3 2 2");
+        assert_eq!(render("%p|%%|%o", 2, 1, 2000), " 25%|%|0.5");
+        assert_eq!(render("%p %o", 0, 0, 0), "  0% ?");
+        assert_eq!(render("%e", 0, 0, 1500), "1.500");
+        assert_eq!(render("%w", 0, 0, 65_000), "01:05");
+        assert_eq!(render("%w", 0, 0, 3_725_000), "1:02:05");
+    }
+
+    #[test]
+    fn bad_formats() {
+        assert_eq!(
+            StatusFormat::parse("[%x] "),
+            Err(StatusFormatError::UnknownPlaceholder('x'))
+        );
+        assert_eq!(
+            StatusFormat::parse("50%"),
+            Err(StatusFormatError::TrailingPercent)
+        );
+    }
+}
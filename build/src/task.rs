@@ -14,32 +14,130 @@
  * limitations under the License.
  */
 
-use std::{collections::HashMap, fmt::Display, ops::Deref};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::Arc,
+};
 
-use ninja_parse::repr::*;
+use ninja_parse::{
+    canon::{canonicalize_path, PathStyle},
+    repr::*,
+};
+use thiserror::Error;
 
-#[derive(Debug, PartialOrd, Ord, Hash, Eq, PartialEq, Clone)]
-pub struct KeyPath(Vec<u8>);
+use crate::check::{analyze_graph, GraphAnalysis};
+
+/// Separates the namespace from the path when naming a namespaced key, as in `host:foo.o`.
+const NAMESPACE_SEPARATOR: u8 = b':';
+
+/// Keys are cloned wherever the graph is walked, so the bytes are shared rather than copied.
+/// Keys made while turning a manifest into tasks share one allocation per path, through a
+/// `PathCache`, so comparing equal keys is usually just comparing pointers.
+#[derive(Debug, PartialOrd, Ord, Eq, Clone)]
+pub struct KeyPath {
+    namespace: Option<Arc<[u8]>>,
+    // Includes the namespace directory, so this is always the path on disk.
+    path: Arc<[u8]>,
+}
+
+impl PartialEq for KeyPath {
+    fn eq(&self, other: &Self) -> bool {
+        (Arc::ptr_eq(&self.path, &other.path) || self.path == other.path)
+            && self.namespace == other.namespace
+    }
+}
+
+impl Hash for KeyPath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.namespace.hash(state);
+        self.path.hash(state);
+    }
+}
 
 impl From<Vec<u8>> for KeyPath {
     fn from(v: Vec<u8>) -> Self {
-        KeyPath(v)
+        KeyPath {
+            namespace: None,
+            path: v.into(),
+        }
     }
 }
 
+fn join_namespace(namespace: &[u8], path: &[u8]) -> Vec<u8> {
+    let mut full = Vec::with_capacity(namespace.len() + 1 + path.len());
+    full.extend_from_slice(namespace);
+    full.push(b'/');
+    full.extend_from_slice(path);
+    full
+}
+
 impl KeyPath {
+    /// A path relative to the directory of `namespace`.
+    pub fn namespaced(namespace: Vec<u8>, path: &[u8]) -> Self {
+        KeyPath {
+            path: join_namespace(&namespace, path).into(),
+            namespace: Some(namespace.into()),
+        }
+    }
+
+    /// The path on disk, relative to the directory ninja runs in.
     pub fn as_bytes(&self) -> &[u8] {
-        self.0.as_slice()
+        &self.path
+    }
+
+    pub fn namespace(&self) -> Option<&[u8]> {
+        self.namespace.as_deref()
+    }
+
+    /// The path as written in the manifest, relative to the namespace directory.
+    pub fn relative(&self) -> &[u8] {
+        match &self.namespace {
+            Some(namespace) => &self.path[namespace.len() + 1..],
+            None => &self.path,
+        }
+    }
+
+    /// How the key is shown to users and named on the command line: `namespace:path`, or just the
+    /// path outside of a namespace.
+    pub fn name(&self) -> Cow<'_, str> {
+        match &self.namespace {
+            Some(namespace) => {
+                let mut name = namespace.to_vec();
+                name.push(NAMESPACE_SEPARATOR);
+                name.extend_from_slice(self.relative());
+                Cow::Owned(String::from_utf8_lossy(&name).into_owned())
+            }
+            None => String::from_utf8_lossy(&self.path),
+        }
+    }
+
+    /// Shows the path to users. See `KeyDisplay`.
+    pub fn display(&self) -> KeyDisplay<'_> {
+        KeyDisplay::new(std::slice::from_ref(self))
     }
 }
 
 impl Display for KeyPath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Path({})",
-            std::str::from_utf8(&self.0).map_err(|_| std::fmt::Error {})?
-        )
+        if let Some(namespace) = &self.namespace {
+            write!(
+                f,
+                "Path({}:{})",
+                std::str::from_utf8(namespace).map_err(|_| std::fmt::Error {})?,
+                std::str::from_utf8(self.relative()).map_err(|_| std::fmt::Error {})?
+            )
+        } else {
+            write!(
+                f,
+                "Path({})",
+                std::str::from_utf8(&self.path).map_err(|_| std::fmt::Error {})?
+            )
+        }
     }
 }
 
@@ -85,11 +183,27 @@ impl Key {
         matches!(self, Key::Multi(_))
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &KeyPath> {
+    /// The namespace of the edge producing this key. All outputs of an edge share one.
+    pub fn namespace(&self) -> Option<&[u8]> {
         match self {
-            Key::Path(p) => std::iter::once(p),
-            Key::Multi(_) => panic!(),
-            //Key::Multi(vs) => { Box::new( vs.iter().map(|v| v.iter()).flatten() )},
+            Key::Path(path) => path.namespace(),
+            Key::Multi(paths) => paths[0].namespace(),
+        }
+    }
+
+    /// Shows the key to users. See `KeyDisplay`.
+    pub fn display(&self) -> KeyDisplay<'_> {
+        match self {
+            Key::Path(p) => p.display(),
+            Key::Multi(ps) => KeyDisplay::new(ps),
+        }
+    }
+
+    /// Every path the key stands for: the one path of a `Path`, or each output of a `Multi`.
+    pub fn iter(&self) -> std::slice::Iter<'_, KeyPath> {
+        match self {
+            Key::Path(p) => std::slice::from_ref(p).iter(),
+            Key::Multi(ps) => ps.iter(),
         }
     }
 }
@@ -103,6 +217,71 @@ impl Display for Key {
     }
 }
 
+/// How many outputs of a `Multi` key are named before the rest are only counted.
+const MAX_DISPLAYED_PATHS: usize = 5;
+
+/// Shows keys to users, unlike `Display`, which spells out the key's structure for debugging.
+///
+/// Paths are named as on the command line, absolute paths inside the directory ninja runs in are
+/// shown relative to it, and keys with many outputs are cut short, so that messages stay readable.
+/// Use `exact` where the names must be usable as targets again.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyDisplay<'a> {
+    paths: &'a [KeyPath],
+    exact: bool,
+}
+
+impl<'a> KeyDisplay<'a> {
+    fn new(paths: &'a [KeyPath]) -> Self {
+        KeyDisplay {
+            paths,
+            exact: false,
+        }
+    }
+
+    /// Names every path exactly as the key does.
+    pub fn exact(mut self) -> Self {
+        self.exact = true;
+        self
+    }
+}
+
+impl Display for KeyDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let shown = if self.exact {
+            self.paths.len()
+        } else {
+            self.paths.len().min(MAX_DISPLAYED_PATHS)
+        };
+        // Only looked up when needed, since most manifests only use relative paths.
+        let mut dir = None;
+        for (i, path) in self.paths[..shown].iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            let absolute = path.namespace.is_none() && path.path.starts_with(b"/");
+            if self.exact || !absolute {
+                write!(f, "{}", path.name())?;
+                continue;
+            }
+            let dir = dir.get_or_insert_with(|| std::env::current_dir().ok());
+            let name = path.name();
+            let relative = dir
+                .as_ref()
+                .and_then(|dir| std::path::Path::new(name.as_ref()).strip_prefix(dir).ok())
+                .filter(|relative| !relative.as_os_str().is_empty());
+            match relative {
+                Some(relative) => write!(f, "{}", relative.display())?,
+                None => write!(f, "{}", name)?,
+            }
+        }
+        if shown < self.paths.len() {
+            write!(f, " (and {} more)", self.paths.len() - shown)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum TaskVariant {
     Source,
@@ -114,11 +293,45 @@ pub enum TaskVariant {
 
 pub type Dependencies = Vec<Key>;
 
+/// Refers to a rule's descriptor in `Tasks`. Tasks share one descriptor per rule, rather than
+/// each carrying a copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RuleId(usize);
+
+/// A file a command reads its arguments from, written with `content` before it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseFile {
+    pub path: KeyPath,
+    pub content: Vec<u8>,
+}
+
 #[derive(Debug)]
 pub struct Task {
     pub dependencies: Dependencies,
     pub order_dependencies: Dependencies,
+    /// How many of the last `dependencies` were implicit inputs. They are only told apart for
+    /// tools like `-t query`.
+    pub implicit_dependencies: usize,
     pub variant: TaskVariant,
+    /// The rule the task was created from. None for phony edges and tasks not from a manifest.
+    pub rule: Option<RuleId>,
+    /// Where the command writes the extra inputs it discovered, like the headers a C file
+    /// included.
+    pub depfile: Option<KeyPath>,
+    /// Written before the command runs, and removed once it succeeds.
+    pub rspfile: Option<ResponseFile>,
+    /// Whether the rule regenerates the manifest, so changes to its command alone don't cause a
+    /// rebuild.
+    pub generator: bool,
+    /// Whether the rule's commands may leave their outputs untouched, in which case what depends
+    /// on them only rebuilds if they changed.
+    pub restat: bool,
+    /// How the command reports the inputs it discovered, if they go in the deps log.
+    pub deps: Option<Deps>,
+    /// The pool limiting how many commands like this one run at once, if any.
+    pub pool: Option<Vec<u8>>,
+    /// What to show instead of the command while it runs, like `CC foo.o`.
+    pub description: Option<String>,
 }
 
 impl Task {
@@ -130,6 +343,16 @@ impl Task {
         &self.order_dependencies
     }
 
+    /// The inputs listed before `|`.
+    pub fn explicit_dependencies(&self) -> &[Key] {
+        &self.dependencies[..self.dependencies.len() - self.implicit_dependencies]
+    }
+
+    /// The inputs listed after `|`.
+    pub fn implicit_dependencies(&self) -> &[Key] {
+        &self.dependencies[self.dependencies.len() - self.implicit_dependencies..]
+    }
+
     pub fn is_retrieve(&self) -> bool {
         std::matches!(self.variant, TaskVariant::Retrieve)
     }
@@ -144,6 +367,18 @@ impl Task {
             _ => None,
         }
     }
+
+    /// What to show while the command runs: its description, or the command itself without one.
+    pub fn status_line(&self) -> Option<&str> {
+        self.description
+            .as_deref()
+            .or_else(|| self.command().map(String::as_str))
+    }
+
+    /// Whether the command is in the `console` pool, and so writes to the terminal directly.
+    pub fn is_console(&self) -> bool {
+        matches!(&self.pool, Some(pool) if pool == CONSOLE_POOL)
+    }
 }
 
 pub type TasksMap = HashMap<Key, Task>;
@@ -151,6 +386,9 @@ pub type TasksMap = HashMap<Key, Task>;
 #[derive(Debug)]
 pub struct Tasks {
     map: TasksMap,
+    rules: Vec<RuleDescriptor>,
+    // Depth of every pool, including `console`.
+    pools: HashMap<Vec<u8>, usize>,
 }
 
 impl Tasks {
@@ -158,9 +396,37 @@ impl Tasks {
         self.map.get(key)
     }
 
+    pub fn rule(&self, id: RuleId) -> &RuleDescriptor {
+        &self.rules[id.0]
+    }
+
+    /// The descriptor of the rule `key`'s task was created from, if any.
+    pub fn rule_for(&self, key: &Key) -> Option<&RuleDescriptor> {
+        self.task(key)?.rule.map(|id| self.rule(id))
+    }
+
     pub fn all_tasks(&self) -> &TasksMap {
         &self.map
     }
+
+    /// Every rule declared in the manifest, including those no edge uses.
+    pub fn rules(&self) -> &[RuleDescriptor] {
+        &self.rules
+    }
+
+    /// Every pool and how many of its commands may run at once, 0 meaning any number. Includes
+    /// `console`.
+    pub fn pools(&self) -> impl Iterator<Item = (&[u8], usize)> {
+        self.pools
+            .iter()
+            .map(|(name, depth)| (name.as_slice(), *depth))
+    }
+}
+
+fn pool_depths(pools: Vec<Pool>) -> HashMap<Vec<u8>, usize> {
+    std::iter::once((CONSOLE_POOL.to_vec(), 1))
+        .chain(pools.into_iter().map(|pool| (pool.name, pool.depth)))
+        .collect()
 }
 
 impl Display for Tasks {
@@ -182,83 +448,377 @@ impl Display for Tasks {
 }
 
 fn path_to_key(path: Vec<u8>) -> KeyPath {
-    KeyPath(path)
+    KeyPath::from(path)
+}
+
+/// The key for a path as written in `namespace`, canonicalized so that `foo/../bar.o` and
+/// `bar.o` are one file.
+pub(crate) fn namespaced_key(namespace: Option<&[u8]>, path: &[u8]) -> KeyPath {
+    let path = canonicalize_path(path, PathStyle::native());
+    match namespace {
+        Some(namespace) => KeyPath::namespaced(namespace.to_vec(), &path),
+        None => path_to_key(path),
+    }
+}
+
+/// Hands out one shared copy of each path, for the keys of a whole manifest.
+#[derive(Debug, Default)]
+struct PathCache(RefCell<HashSet<Arc<[u8]>>>);
+
+impl PathCache {
+    fn intern(&self, path: &[u8]) -> Arc<[u8]> {
+        let mut paths = self.0.borrow_mut();
+        match paths.get(path) {
+            Some(interned) => interned.clone(),
+            None => {
+                let interned: Arc<[u8]> = path.into();
+                paths.insert(interned.clone());
+                interned
+            }
+        }
+    }
+
+    /// Like `namespaced_key`, sharing the path with every other key for it.
+    fn key(&self, namespace: Option<&[u8]>, path: &[u8]) -> KeyPath {
+        let path = canonicalize_path(path, PathStyle::native());
+        match namespace {
+            Some(namespace) => KeyPath {
+                namespace: Some(self.intern(namespace)),
+                path: self.intern(&join_namespace(namespace, &path)),
+            },
+            None => KeyPath {
+                namespace: None,
+                path: self.intern(&path),
+            },
+        }
+    }
+
+    /// Resolves a path written in `namespace`. A `name:` prefix naming a known namespace
+    /// overrides it, and an empty prefix refers to the top level.
+    fn resolve(
+        &self,
+        namespaces: &HashSet<Vec<u8>>,
+        namespace: Option<&Vec<u8>>,
+        path: &[u8],
+    ) -> KeyPath {
+        if let Some(split) = path.iter().position(|c| *c == NAMESPACE_SEPARATOR) {
+            let (prefix, rest) = (&path[..split], &path[split + 1..]);
+            if prefix.is_empty() {
+                return self.key(None, rest);
+            }
+            if namespaces.contains(prefix) {
+                return self.key(Some(prefix), rest);
+            }
+        }
+        self.key(namespace.map(Vec::as_slice), path)
+    }
 }
 
-fn paths_to_multi_key(mut outputs: Vec<Vec<u8>>) -> KeyMulti {
+fn paths_to_multi_key(mut outputs: Vec<KeyPath>) -> KeyMulti {
     assert!(outputs.len() > 1);
     // TODO: This isn't perfect because we want to show any errors to the user in the order in
     // which they originally wrote the build rule, and not what we determine to be the order.
     outputs.sort();
-    KeyMulti(outputs.iter().map(|o| path_to_key(o.clone())).collect())
+    KeyMulti(outputs)
+}
+
+/// Decides which keys a build starts from.
+///
+/// Targets named on the command line take precedence over `default` statements in the manifest.
+/// `None` means neither was specified, in which case every root of the graph should be built.
+/// Defaults are sorted since the manifest does not impose an order on them.
+pub fn requested_targets(
+    cli_targets: Option<Vec<Vec<u8>>>,
+    defaults: Option<HashSet<Vec<u8>>>,
+) -> Option<Vec<KeyPath>> {
+    match cli_targets {
+        Some(targets) if !targets.is_empty() => {
+            Some(targets.into_iter().map(path_to_key).collect())
+        }
+        _ => defaults.map(|defaults| {
+            let mut defaults: Vec<KeyPath> = defaults.into_iter().map(path_to_key).collect();
+            defaults.sort();
+            defaults
+        }),
+    }
+}
+
+/// Adds the tasks for a single build edge, returning the key the edge's task was stored under.
+fn insert_edge(map: &mut TasksMap, outputs: Vec<KeyPath>, task: Task) -> Key {
+    // Since no two build edges can produce any single output, they also cannot produce any
+    // multi-outputs. This means every build's outputs are guaranteed to be unique and we may as
+    // well create a new key for each.
+    let key = if outputs.len() == 1 {
+        Key::Path(outputs.into_iter().next().unwrap())
+    } else {
+        let main_key = paths_to_multi_key(outputs);
+        for key in main_key.deref() {
+            map.insert(
+                Key::Path(key.clone()),
+                Task {
+                    dependencies: vec![Key::Multi(main_key.clone())],
+                    order_dependencies: vec![],
+                    implicit_dependencies: 0,
+                    variant: TaskVariant::Retrieve,
+                    rule: None,
+                    depfile: None,
+                    rspfile: None,
+                    generator: false,
+                    restat: false,
+                    deps: None,
+                    pool: None,
+                    description: None,
+                },
+            );
+        }
+        Key::Multi(main_key)
+    };
+    map.insert(key.clone(), task);
+    key
 }
 
 pub fn description_to_tasks_with_start(
     desc: Description,
     start: Option<Vec<Vec<u8>>>,
 ) -> (Tasks, Option<Vec<KeyPath>>) {
-    let requested = if let Some(specified) = start {
-        Some(specified.into_iter().map(path_to_key).collect())
-    } else {
-        desc.defaults
-            .map(|v| v.into_iter().map(path_to_key).collect())
-    };
+    let namespaces: HashSet<Vec<u8>> = desc
+        .builds
+        .iter()
+        .filter_map(|build| build.namespace.clone())
+        .collect();
+    let paths = PathCache::default();
+    // Targets are resolved like the inputs of an edge outside of any namespace.
+    let requested = requested_targets(start, desc.defaults).map(|targets| {
+        targets
+            .into_iter()
+            .map(|target| paths.resolve(&namespaces, None, target.as_bytes()))
+            .collect()
+    });
+    let rules = &desc.rules;
     let mut map: TasksMap = HashMap::new();
-    // Since no two build edges can produce any single output, they also cannot produce any
-    // multi-outputs. This means every build's outputs are guaranteed to be unique and we may as
-    // well create a new key for each.
     for build in desc.builds {
-        let key = if build.outputs.len() == 1 {
-            Key::Path(path_to_key((&build.outputs[0]).clone()))
-        } else {
-            let main_key = paths_to_multi_key(build.outputs);
-            for key in main_key.deref() {
-                map.insert(
-                    Key::Path(key.clone()),
-                    Task {
-                        dependencies: vec![Key::Multi(main_key.clone())],
-                        order_dependencies: vec![],
-                        variant: TaskVariant::Retrieve,
-                    },
-                );
+        let namespace = build.namespace.as_ref();
+        let to_key = |path: Vec<u8>| paths.resolve(&namespaces, namespace, &path);
+        let implicit_dependencies = build.implicit_inputs.len();
+        let dependencies = build
+            .inputs
+            .into_iter()
+            .chain(build.implicit_inputs)
+            .map(to_key)
+            .map(Key::Path)
+            .collect();
+        let order_dependencies = build
+            .order_inputs
+            .into_iter()
+            .map(to_key)
+            .map(Key::Path)
+            .collect();
+        let in_namespace = |path: Vec<u8>| paths.key(namespace.map(Vec::as_slice), &path);
+        let outputs = build
+            .outputs
+            .into_iter()
+            .chain(build.implicit_outputs)
+            .map(in_namespace)
+            .collect();
+        let depfile = build.depfile.map(in_namespace);
+        let rspfile = build.rspfile.map(|rspfile| ResponseFile {
+            path: in_namespace(rspfile.path),
+            content: rspfile.content,
+        });
+        let variant = match build.action {
+            Action::Phony => TaskVariant::Retrieve,
+            Action::Command(s) => TaskVariant::Command(s),
+        };
+        let rule = build.rule_index.map(RuleId);
+        let task = Task {
+            dependencies,
+            order_dependencies,
+            implicit_dependencies,
+            variant,
+            rule,
+            depfile,
+            rspfile,
+            generator: matches!(rule, Some(RuleId(id)) if rules[id].generator),
+            restat: matches!(rule, Some(RuleId(id)) if rules[id].restat),
+            deps: build.deps,
+            pool: build.pool,
+            description: build
+                .description
+                .filter(|description| !description.is_empty()),
+        };
+        insert_edge(&mut map, outputs, task);
+    }
+
+    (
+        Tasks {
+            map,
+            rules: desc.rules,
+            pools: pool_depths(desc.pools),
+        },
+        requested,
+    )
+}
+
+pub fn description_to_tasks(desc: Description) -> (Tasks, Option<Vec<KeyPath>>) {
+    description_to_tasks_with_start(desc, None)
+}
+
+/// Like `description_to_tasks_with_start`, also reporting inputs no edge produces and edges the
+/// requested targets don't need. This walks the whole graph again, so builds don't use it.
+pub fn description_to_tasks_with_analysis(
+    desc: Description,
+    start: Option<Vec<Vec<u8>>>,
+) -> (Tasks, Option<Vec<KeyPath>>, GraphAnalysis) {
+    let (tasks, requested) = description_to_tasks_with_start(desc, start);
+    let analysis = analyze_graph(&tasks, requested.as_deref());
+    (tasks, requested, analysis)
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TasksBuilderError {
+    #[error("build edge has no outputs")]
+    NoOutputs,
+    #[error("multiple rules generate {0}")]
+    DuplicateOutput(String),
+    #[error("'{input}', needed by '{output}', is neither a source nor the output of any edge")]
+    DanglingDependency { output: String, input: String },
+}
+
+/// Constructs Tasks directly, for using the scheduler and rebuilders as a generic build engine
+/// without generating a ninja manifest first.
+///
+/// Manifests can depend on any file that exists on disk. A builder is stricter: every dependency
+/// must be the output of some edge, or be declared with `source`.
+///
+/// ```
+/// use ninja_builder::task::TasksBuilder;
+///
+/// let mut builder = TasksBuilder::new();
+/// builder.source("foo.c");
+/// builder.command(&["foo.o"], &["foo.c"], "cc -c foo.c -o foo.o")?;
+/// builder.command(&["foo"], &["foo.o"], "cc foo.o -o foo")?;
+/// builder.phony(&["all"], &["foo"])?;
+/// let tasks = builder.build()?;
+/// # Ok::<(), ninja_builder::task::TasksBuilderError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct TasksBuilder {
+    map: TasksMap,
+    sources: HashSet<KeyPath>,
+    outputs: HashSet<KeyPath>,
+}
+
+fn to_paths<P: AsRef<[u8]>>(paths: &[P]) -> Vec<Vec<u8>> {
+    paths.iter().map(|p| p.as_ref().to_vec()).collect()
+}
+
+impl TasksBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Declares a file that is expected to exist, rather than be produced by the build.
+    pub fn source<P: AsRef<[u8]>>(&mut self, path: P) -> &mut Self {
+        self.sources.insert(path_to_key(path.as_ref().to_vec()));
+        self
+    }
+
+    /// Adds an edge producing `outputs`. Fails if any output is already produced by another edge.
+    pub fn edge<O: AsRef<[u8]>, I: AsRef<[u8]>, OI: AsRef<[u8]>>(
+        &mut self,
+        outputs: &[O],
+        inputs: &[I],
+        order_inputs: &[OI],
+        variant: TaskVariant,
+    ) -> Result<&mut Self, TasksBuilderError> {
+        if outputs.is_empty() {
+            return Err(TasksBuilderError::NoOutputs);
+        }
+        let outputs = to_paths(outputs);
+        let mut seen = HashSet::new();
+        for output in &outputs {
+            let key = path_to_key(output.clone());
+            if self.outputs.contains(&key) || !seen.insert(key) {
+                return Err(TasksBuilderError::DuplicateOutput(
+                    String::from_utf8_lossy(output).into_owned(),
+                ));
             }
-            Key::Multi(main_key)
+        }
+        self.outputs.extend(seen);
+        let to_keys = |paths: Vec<Vec<u8>>| -> Dependencies {
+            paths.into_iter().map(path_to_key).map(Key::Path).collect()
         };
-        map.insert(
-            key.clone(),
+        insert_edge(
+            &mut self.map,
+            outputs.into_iter().map(path_to_key).collect(),
             Task {
-                dependencies: build
-                    .inputs
-                    .into_iter()
-                    .map(path_to_key)
-                    .map(Key::Path)
-                    .chain(
-                        build
-                            .implicit_inputs
-                            .into_iter()
-                            .map(path_to_key)
-                            .map(Key::Path),
-                    )
-                    .collect(),
-                order_dependencies: build
-                    .order_inputs
-                    .into_iter()
-                    .map(path_to_key)
-                    .map(Key::Path)
-                    .collect(),
-                variant: match build.action {
-                    Action::Phony => TaskVariant::Retrieve,
-                    Action::Command(s) => TaskVariant::Command(s),
-                },
+                dependencies: to_keys(to_paths(inputs)),
+                order_dependencies: to_keys(to_paths(order_inputs)),
+                implicit_dependencies: 0,
+                variant,
+                rule: None,
+                depfile: None,
+                rspfile: None,
+                generator: false,
+                restat: false,
+                deps: None,
+                pool: None,
+                description: None,
             },
         );
+        Ok(self)
     }
 
-    (Tasks { map }, requested)
-}
+    pub fn command<O: AsRef<[u8]>, I: AsRef<[u8]>>(
+        &mut self,
+        outputs: &[O],
+        inputs: &[I],
+        command: impl Into<String>,
+    ) -> Result<&mut Self, TasksBuilderError> {
+        let no_order_inputs: &[&[u8]] = &[];
+        self.edge(
+            outputs,
+            inputs,
+            no_order_inputs,
+            TaskVariant::Command(command.into()),
+        )
+    }
 
-pub fn description_to_tasks(desc: Description) -> (Tasks, Option<Vec<KeyPath>>) {
-    description_to_tasks_with_start(desc, None)
+    pub fn phony<O: AsRef<[u8]>, I: AsRef<[u8]>>(
+        &mut self,
+        outputs: &[O],
+        inputs: &[I],
+    ) -> Result<&mut Self, TasksBuilderError> {
+        let no_order_inputs: &[&[u8]] = &[];
+        self.edge(outputs, inputs, no_order_inputs, TaskVariant::Retrieve)
+    }
+
+    /// Validates that every dependency is known and returns the Tasks.
+    pub fn build(self) -> Result<Tasks, TasksBuilderError> {
+        let mut dangling: Vec<(&Key, &KeyPath)> = Vec::new();
+        for (key, task) in &self.map {
+            for dep in task.dependencies().iter().chain(task.order_dependencies()) {
+                if let Key::Path(path) = dep {
+                    if !self.outputs.contains(path) && !self.sources.contains(path) {
+                        dangling.push((key, path));
+                    }
+                }
+            }
+        }
+        // Report the same error regardless of HashMap ordering.
+        if let Some((key, input)) = dangling.into_iter().min() {
+            let output = key.iter().next().expect("keys have a path");
+            return Err(TasksBuilderError::DanglingDependency {
+                output: output.display().to_string(),
+                input: input.display().to_string(),
+            });
+        }
+        Ok(Tasks {
+            map: self.map,
+            rules: vec![],
+            pools: pool_depths(vec![]),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -268,20 +828,20 @@ mod test {
     #[test]
     #[should_panic]
     fn test_paths_to_multi_key_1() {
-        paths_to_multi_key(vec![b"a".to_vec()]);
+        paths_to_multi_key(vec![b"a".to_vec().into()]);
     }
 
     #[test]
     fn test_paths_to_multi_key_at_least_2() {
-        paths_to_multi_key(vec![b"a".to_vec(), b"b".to_vec()]);
+        paths_to_multi_key(vec![b"a".to_vec().into(), b"b".to_vec().into()]);
     }
 
     #[test]
     fn test_sort() {
         let key = paths_to_multi_key(vec![
-            b"hello".to_vec(),
-            b"grungy".to_vec(),
-            b"aaaaaaaaaaaaaaaa.txt".to_vec(),
+            b"hello".to_vec().into(),
+            b"grungy".to_vec().into(),
+            b"aaaaaaaaaaaaaaaa.txt".to_vec().into(),
         ]);
 
         let mut iter = key.iter().peekable();
@@ -297,12 +857,29 @@ mod test {
         let desc = Description {
             builds: vec![Build {
                 action: Action::Command("compiler".to_owned()),
+                rule: b"compiler".to_vec(),
+                rule_index: None,
+                description: None,
+                depfile: None,
+                rspfile: None,
+                deps: None,
+                pool: None,
+                namespace: None,
                 inputs: vec![],
                 implicit_inputs: vec![],
                 order_inputs: vec![],
                 outputs: vec![b"output9.txt".to_vec(), b"output2.txt".to_vec()],
+                implicit_outputs: vec![],
             }],
             defaults: None,
+            rules: vec![],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
         };
 
         let (tasks, _) = description_to_tasks(desc);
@@ -317,8 +894,8 @@ mod test {
                 assert_eq!(
                     keys.0,
                     vec![
-                        KeyPath(b"output2.txt".to_vec()),
-                        KeyPath(b"output9.txt".to_vec())
+                        KeyPath::from(b"output2.txt".to_vec()),
+                        KeyPath::from(b"output9.txt".to_vec())
                     ]
                 );
                 let task = tasks.task(key).expect("valid task");
@@ -339,26 +916,167 @@ mod test {
         assert_eq!(single_count, 2);
     }
 
+    #[test]
+    fn implicit_outputs() {
+        let edge = |outputs: &[&[u8]], implicit_outputs: &[&[u8]], inputs: &[&[u8]]| Build {
+            action: Action::Command("cc".to_owned()),
+            rule: b"cc".to_vec(),
+            rule_index: None,
+            description: None,
+            depfile: None,
+            rspfile: None,
+            deps: None,
+            pool: None,
+            namespace: None,
+            inputs: inputs.iter().map(|i| i.to_vec()).collect(),
+            implicit_inputs: vec![],
+            order_inputs: vec![],
+            outputs: outputs.iter().map(|o| o.to_vec()).collect(),
+            implicit_outputs: implicit_outputs.iter().map(|o| o.to_vec()).collect(),
+        };
+        let desc = Description {
+            builds: vec![
+                edge(&[b"a.o"], &[b"a.d"], &[b"a.c"]),
+                edge(&[b"report"], &[], &[b"a.d"]),
+            ],
+            defaults: None,
+            rules: vec![],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
+        };
+        let (tasks, _) = description_to_tasks(desc);
+        let path = |p: &[u8]| KeyPath::from(p.to_vec());
+        let key = Key::Multi(vec![path(b"a.d"), path(b"a.o")].into());
+        assert!(tasks.task(&key).unwrap().is_command());
+        // Depending on the implicit output waits for the edge like depending on `a.o` would.
+        for output in &[b"a.o", b"a.d"] {
+            let task = tasks.task(&Key::Path(path(*output))).unwrap();
+            assert!(task.is_retrieve());
+            assert_eq!(task.dependencies(), std::slice::from_ref(&key));
+        }
+        assert_eq!(
+            tasks
+                .task(&Key::Path(path(b"report")))
+                .unwrap()
+                .dependencies(),
+            &[Key::Path(path(b"a.d"))]
+        );
+    }
+
+    #[test]
+    fn spellings_of_a_path_are_one_key() {
+        let edge = |outputs: &[&[u8]], inputs: &[&[u8]]| Build {
+            action: Action::Command("cc".to_owned()),
+            rule: b"cc".to_vec(),
+            rule_index: None,
+            description: None,
+            depfile: None,
+            rspfile: None,
+            deps: None,
+            pool: None,
+            namespace: None,
+            inputs: inputs.iter().map(|i| i.to_vec()).collect(),
+            implicit_inputs: vec![],
+            order_inputs: vec![],
+            outputs: outputs.iter().map(|o| o.to_vec()).collect(),
+            implicit_outputs: vec![],
+        };
+        let desc = Description {
+            builds: vec![
+                edge(&[b"out/../bar.o"], &[b"./bar.c"]),
+                edge(&[b"app"], &[b"bar.o", b"out//.//../bar.o"]),
+            ],
+            defaults: None,
+            rules: vec![],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
+        };
+        let (tasks, requested) =
+            description_to_tasks_with_start(desc, Some(vec![b"./app".to_vec()]));
+        let key = |path: &[u8]| Key::Path(KeyPath::from(path.to_vec()));
+        assert_eq!(requested, Some(vec![KeyPath::from(b"app".to_vec())]));
+        assert_eq!(
+            tasks.task(&key(b"bar.o")).unwrap().dependencies(),
+            &[key(b"bar.c")]
+        );
+        assert_eq!(
+            tasks.task(&key(b"app")).unwrap().dependencies(),
+            &[key(b"bar.o"), key(b"bar.o")]
+        );
+    }
+
+    #[test]
+    fn keys_for_a_path_share_it() {
+        let paths = PathCache::default();
+        let a = paths.key(None, b"out/a.o");
+        let b = paths.key(None, b"out/./a.o");
+        assert!(Arc::ptr_eq(&a.path, &b.path));
+        assert_eq!(a, KeyPath::from(b"out/a.o".to_vec()));
+        let namespaced = paths.key(Some(b"host"), b"a.o");
+        assert_eq!(namespaced, KeyPath::namespaced(b"host".to_vec(), b"a.o"));
+        // Same path on disk, but a different key.
+        assert_ne!(
+            paths.key(None, b"host/a.o"),
+            KeyPath::namespaced(b"host".to_vec(), b"a.o")
+        );
+    }
+
     #[test]
     fn implicit_dependencies() {
         let desc = Description {
             builds: vec![Build {
                 action: Action::Command("compiler".to_owned()),
+                rule: b"compiler".to_vec(),
+                rule_index: None,
+                description: None,
+                depfile: None,
+                rspfile: None,
+                deps: None,
+                pool: None,
+                namespace: None,
                 inputs: vec![b"a.txt".to_vec(), b"b.txt".to_vec()],
                 implicit_inputs: vec![b"c.txt".to_vec(), b"d.txt".to_vec()],
                 order_inputs: vec![],
                 outputs: vec![b"z.txt".to_vec()],
+                implicit_outputs: vec![],
             }],
             defaults: None,
+            rules: vec![],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
         };
 
         let (tasks, _) = description_to_tasks(desc);
         assert_eq!(tasks.all_tasks().len(), 1);
         let task = tasks
-            .task(&Key::Path(KeyPath(b"z.txt".to_vec())))
+            .task(&Key::Path(KeyPath::from(b"z.txt".to_vec())))
             .expect("valid task");
         assert!(task.is_command());
         assert_eq!(task.dependencies().len(), 4);
+        let key = |path: &[u8]| Key::Path(KeyPath::from(path.to_vec()));
+        assert_eq!(
+            task.explicit_dependencies(),
+            &[key(b"a.txt"), key(b"b.txt")]
+        );
+        assert_eq!(
+            task.implicit_dependencies(),
+            &[key(b"c.txt"), key(b"d.txt")]
+        );
     }
 
     #[test]
@@ -366,21 +1084,317 @@ mod test {
         let desc = Description {
             builds: vec![Build {
                 action: Action::Command("compiler".to_owned()),
+                rule: b"compiler".to_vec(),
+                rule_index: None,
+                description: None,
+                depfile: None,
+                rspfile: None,
+                deps: None,
+                pool: None,
+                namespace: None,
                 inputs: vec![b"a.txt".to_vec(), b"b.txt".to_vec()],
                 implicit_inputs: vec![],
                 order_inputs: vec![b"c.txt".to_vec(), b"d.txt".to_vec()],
                 outputs: vec![b"z.txt".to_vec()],
+                implicit_outputs: vec![],
             }],
             defaults: None,
+            rules: vec![],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
         };
 
         let (tasks, _) = description_to_tasks(desc);
         assert_eq!(tasks.all_tasks().len(), 1);
         let task = tasks
-            .task(&Key::Path(KeyPath(b"z.txt".to_vec())))
+            .task(&Key::Path(KeyPath::from(b"z.txt".to_vec())))
             .expect("valid task");
         assert!(task.is_command());
         assert_eq!(task.dependencies().len(), 2);
         assert_eq!(task.order_dependencies().len(), 2);
     }
+
+    #[test]
+    fn requested_targets_precedence() {
+        let defaults: Option<HashSet<Vec<u8>>> =
+            Some(vec![b"d2".to_vec(), b"d1".to_vec()].into_iter().collect());
+
+        // Command line wins.
+        assert_eq!(
+            requested_targets(Some(vec![b"cli".to_vec()]), defaults.clone()),
+            Some(vec![KeyPath::from(b"cli".to_vec())])
+        );
+        // Then defaults, in a stable order. An empty command line is the same as none.
+        for cli in [None, Some(vec![])] {
+            assert_eq!(
+                requested_targets(cli, defaults.clone()),
+                Some(vec![
+                    KeyPath::from(b"d1".to_vec()),
+                    KeyPath::from(b"d2".to_vec())
+                ])
+            );
+        }
+        // Then everything.
+        assert_eq!(requested_targets(None, None), None);
+        assert_eq!(requested_targets(Some(vec![]), None), None);
+    }
+
+    #[test]
+    fn builder() {
+        let mut builder = TasksBuilder::new();
+        builder
+            .source("foo.c")
+            .command(&["foo.o"], &["foo.c"], "cc -c foo.c")
+            .unwrap()
+            .command(&["foo", "foo.map"], &["foo.o"], "ld foo.o")
+            .unwrap()
+            .phony(&["all"], &["foo"])
+            .unwrap();
+        let tasks = builder.build().expect("valid tasks");
+        // foo.o, the multi key, its two retrieves and all.
+        assert_eq!(tasks.all_tasks().len(), 5);
+        assert!(tasks
+            .task(&Key::Path(KeyPath::from(b"all".to_vec())))
+            .expect("all")
+            .is_retrieve());
+        assert!(tasks
+            .task(&Key::Path(KeyPath::from(b"foo.o".to_vec())))
+            .expect("foo.o")
+            .is_command());
+    }
+
+    #[test]
+    fn builder_duplicate_output() {
+        let mut builder = TasksBuilder::new();
+        builder.command(&["a"], &[] as &[&str], "one").unwrap();
+        assert_eq!(
+            builder
+                .command(&["b", "a"], &[] as &[&str], "two")
+                .unwrap_err(),
+            TasksBuilderError::DuplicateOutput("a".to_owned())
+        );
+        assert_eq!(
+            builder
+                .command(&["c", "c"], &[] as &[&str], "three")
+                .unwrap_err(),
+            TasksBuilderError::DuplicateOutput("c".to_owned())
+        );
+        assert_eq!(
+            builder.phony(&[] as &[&str], &["a"]).unwrap_err(),
+            TasksBuilderError::NoOutputs
+        );
+    }
+
+    #[test]
+    fn builder_dangling_dependency() {
+        let mut builder = TasksBuilder::new();
+        builder.command(&["b"], &["a"], "cp a b").unwrap();
+        let err = builder.build().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'a', needed by 'b', is neither a source nor the output of any edge"
+        );
+    }
+
+    #[test]
+    fn key_iter() {
+        let path: KeyPath = b"a".to_vec().into();
+        assert_eq!(
+            Key::Path(path.clone()).iter().collect::<Vec<_>>(),
+            vec![&path]
+        );
+        let paths: Vec<KeyPath> = vec![b"b".to_vec().into(), b"a".to_vec().into()];
+        let multi = Key::Multi(paths.clone().into());
+        assert_eq!(multi.iter().cloned().collect::<Vec<_>>(), paths);
+    }
+
+    #[test]
+    fn namespaces() {
+        let edge = |namespace: Option<&[u8]>, output: &[u8], inputs: &[&[u8]]| Build {
+            action: Action::Command("cc".to_owned()),
+            rule: b"cc".to_vec(),
+            rule_index: None,
+            description: None,
+            depfile: None,
+            rspfile: None,
+            deps: None,
+            pool: None,
+            namespace: namespace.map(|n| n.to_vec()),
+            inputs: inputs.iter().map(|i| i.to_vec()).collect(),
+            implicit_inputs: vec![],
+            order_inputs: vec![],
+            outputs: vec![output.to_vec()],
+            implicit_outputs: vec![],
+        };
+        let desc = Description {
+            builds: vec![
+                edge(Some(b"host"), b"foo.o", &[b"foo.c"]),
+                edge(Some(b"target"), b"foo.o", &[b"foo.c"]),
+                edge(
+                    Some(b"target"),
+                    b"app",
+                    &[b"foo.o", b"host:foo.o", b":tool", b"nope:x"],
+                ),
+            ],
+            defaults: None,
+            rules: vec![],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
+        };
+        let (tasks, requested) = description_to_tasks_with_start(
+            desc,
+            Some(vec![b"target:app".to_vec(), b"foo.o".to_vec()]),
+        );
+        let host = KeyPath::namespaced(b"host".to_vec(), b"foo.o");
+        let target = KeyPath::namespaced(b"target".to_vec(), b"foo.o");
+        assert_eq!(host.as_bytes(), b"host/foo.o");
+        assert_eq!(host.relative(), b"foo.o");
+        assert_eq!(host.name(), "host:foo.o");
+        assert!(tasks.task(&Key::Path(host.clone())).is_some());
+        assert!(tasks.task(&Key::Path(target.clone())).is_some());
+        assert!(tasks
+            .task(&Key::Path(KeyPath::from(b"foo.o".to_vec())))
+            .is_none());
+
+        let app = KeyPath::namespaced(b"target".to_vec(), b"app");
+        assert_eq!(
+            tasks.task(&Key::Path(app.clone())).unwrap().dependencies(),
+            &[
+                Key::Path(target),
+                Key::Path(host),
+                Key::Path(KeyPath::from(b"tool".to_vec())),
+                // Not a namespace, so just a path with a colon in it.
+                Key::Path(KeyPath::namespaced(b"target".to_vec(), b"nope:x")),
+            ]
+        );
+        assert_eq!(requested, Some(vec![app, KeyPath::from(b"foo.o".to_vec())]));
+    }
+
+    #[test]
+    fn key_display() {
+        let path = |p: &str| KeyPath::from(p.as_bytes().to_vec());
+        let multi = |n: usize| -> Key {
+            Key::Multi(
+                (0..n)
+                    .map(|i| path(&format!("{}.o", i)))
+                    .collect::<Vec<_>>()
+                    .into(),
+            )
+        };
+        assert_eq!(
+            Key::Path(KeyPath::namespaced(b"host".to_vec(), b"foo.o"))
+                .display()
+                .to_string(),
+            "host:foo.o"
+        );
+        assert_eq!(multi(2).display().to_string(), "0.o 1.o");
+        assert_eq!(multi(5).display().to_string(), "0.o 1.o 2.o 3.o 4.o");
+        assert_eq!(
+            multi(8).display().to_string(),
+            "0.o 1.o 2.o 3.o 4.o (and 3 more)"
+        );
+        assert_eq!(
+            multi(8).display().exact().to_string(),
+            "0.o 1.o 2.o 3.o 4.o 5.o 6.o 7.o"
+        );
+
+        let dir = std::env::current_dir().unwrap();
+        let inside = path(dir.join("out/a.o").to_str().unwrap());
+        assert_eq!(inside.display().to_string(), "out/a.o");
+        assert_eq!(inside.display().exact().to_string(), inside.name());
+        // The directory itself is not shown as an empty name.
+        let here = path(dir.to_str().unwrap());
+        assert_eq!(here.display().to_string(), here.name());
+        assert_eq!(
+            path("/nonexistent-elsewhere/a.o").display().to_string(),
+            "/nonexistent-elsewhere/a.o"
+        );
+    }
+
+    #[test]
+    fn tasks_share_rule_descriptors() {
+        let edge = |rule: &[u8], output: &[u8]| Build {
+            action: if rule == b"phony" {
+                Action::Phony
+            } else {
+                Action::Command("cc".to_owned())
+            },
+            rule: rule.to_vec(),
+            rule_index: if rule == b"cc" { Some(0) } else { None },
+            description: None,
+            depfile: None,
+            rspfile: None,
+            deps: None,
+            pool: None,
+            namespace: None,
+            inputs: vec![],
+            implicit_inputs: vec![],
+            order_inputs: vec![],
+            outputs: vec![output.to_vec()],
+            implicit_outputs: vec![],
+        };
+        let cc = RuleDescriptor {
+            name: b"cc".to_vec(),
+            depfile: Some(b"${out}.d".to_vec()),
+            restat: true,
+            generator: true,
+            ..Default::default()
+        };
+        let desc = Description {
+            builds: vec![
+                edge(b"cc", b"a.o"),
+                edge(b"cc", b"b.o"),
+                edge(b"phony", b"all"),
+            ],
+            defaults: None,
+            rules: vec![cc.clone()],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
+        };
+        let (tasks, _) = description_to_tasks(desc);
+        let key = |path: &[u8]| Key::Path(KeyPath::from(path.to_vec()));
+        assert_eq!(tasks.rule_for(&key(b"a.o")), Some(&cc));
+        assert_eq!(
+            tasks.task(&key(b"a.o")).unwrap().rule,
+            tasks.task(&key(b"b.o")).unwrap().rule
+        );
+        assert_eq!(tasks.rule_for(&key(b"all")), None);
+        // What the rebuilder needs to know about the rule is on the task.
+        let task = tasks.task(&key(b"a.o")).unwrap();
+        assert!(task.generator && task.restat);
+        assert!(!tasks.task(&key(b"all")).unwrap().generator);
+    }
+
+    #[test]
+    fn defaults_flow_through_description() {
+        let desc = Description {
+            builds: vec![],
+            defaults: Some(vec![b"all".to_vec()].into_iter().collect()),
+            rules: vec![],
+            missing_include: None,
+            redefinitions: vec![],
+            unquoted_expansions: vec![],
+            self_dependencies: vec![],
+            pools: vec![],
+            files: vec![],
+            builddir: None,
+        };
+        let (_, requested) = description_to_tasks(desc);
+        assert_eq!(requested, Some(vec![KeyPath::from(b"all".to_vec())]));
+    }
 }
@@ -0,0 +1,47 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Helpers shared by the unit tests.
+
+use std::{ops::Deref, path::Path};
+
+/// A directory of its own for a test, removed when dropped, even if the test fails. Derefs to
+/// its path.
+pub(crate) struct TempDir(tempfile::TempDir);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        TempDir(
+            tempfile::Builder::new()
+                .prefix(&format!("ninja-{}-", name))
+                .tempdir()
+                .unwrap(),
+        )
+    }
+
+    /// `name` in the directory, as a string for commands and keys.
+    pub(crate) fn path(&self, name: &str) -> String {
+        self.join(name).to_str().unwrap().to_owned()
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        self.0.path()
+    }
+}
@@ -1,10 +1,14 @@
 use crate::{
+    build_log::SharedBuildLog,
     build_task::CommandTaskResult,
     caching_mtime_rebuilder,
+    deps_log::SharedDepsLog,
+    dir_listings::SharedDirListings,
     disk_interface::SystemDiskInterface,
+    explain::ExplainLog,
     interface::Rebuilder,
     task::{Key, Task},
-    CachingMTimeRebuilder, DiskDirtyCache,
+    CachingMTimeRebuilder, DirectoryInputs, DiskDirtyCache, Workspace,
 };
 use std::cell::Cell;
 
@@ -13,17 +17,55 @@ pub struct TrackingRebuilder {
     inner: InnerRebuilder,
     key_to_track: Key,
     required_rebuild: Cell<bool>,
+    on_rebuild: Option<Box<dyn Fn()>>,
 }
 
 impl TrackingRebuilder {
-    pub fn with_caching_rebuilder(key: Key) -> Self {
+    pub fn with_caching_rebuilder(key: Key, directory_inputs: DirectoryInputs) -> Self {
         TrackingRebuilder {
-            inner: caching_mtime_rebuilder(),
+            inner: caching_mtime_rebuilder(directory_inputs),
             key_to_track: key,
             required_rebuild: Cell::new(false),
+            on_rebuild: None,
         }
     }
 
+    /// Calls `hook` once the tracked key is found to need rebuilding, before it is rebuilt.
+    pub fn on_rebuild<F: Fn() + 'static>(mut self, hook: F) -> Self {
+        self.on_rebuild = Some(Box::new(hook));
+        self
+    }
+
+    /// Also record why each key was considered dirty or clean in `log`.
+    pub fn explain_to(mut self, log: ExplainLog) -> Self {
+        self.inner = self.inner.explain_to(log);
+        self
+    }
+
+    /// Like `CachingMTimeRebuilder::build_log`.
+    pub fn build_log(mut self, log: SharedBuildLog) -> Self {
+        self.inner = self.inner.build_log(log);
+        self
+    }
+
+    /// Like `CachingMTimeRebuilder::deps_log`.
+    pub fn deps_log(mut self, log: SharedDepsLog) -> Self {
+        self.inner = self.inner.deps_log(log);
+        self
+    }
+
+    /// Like `CachingMTimeRebuilder::dir_listings`.
+    pub fn dir_listings(mut self, listings: SharedDirListings) -> Self {
+        self.inner = self.inner.dir_listings(listings);
+        self
+    }
+
+    /// Like `CachingMTimeRebuilder::workspace`.
+    pub fn workspace(mut self, workspace: Workspace) -> Self {
+        self.inner = self.inner.workspace(workspace);
+        self
+    }
+
     pub fn required_rebuild(&self) -> bool {
         self.required_rebuild.get()
     }
@@ -41,9 +83,14 @@ impl Rebuilder<Key, CommandTaskResult> for TrackingRebuilder {
     ) -> Result<Option<Box<Self::Task>>, Self::Error> {
         let matches = key == self.key_to_track;
         let build_task = self.inner.build(key, _unused, task)?;
-        if matches && build_task.is_some() {
-            self.required_rebuild.set(true);
+        if matches && build_task.is_some() && !self.required_rebuild.replace(true) {
+            if let Some(hook) = &self.on_rebuild {
+                hook();
+            }
         }
         Ok(build_task)
     }
+    fn finished(&self, key: Key, task: &Task) -> Result<(), Self::Error> {
+        self.inner.finished(key, task)
+    }
 }
@@ -0,0 +1,165 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Time-weighted samples of how busy the scheduler was.
+///
+/// Each sample records the state of the scheduler from that instant until the next sample, so the
+/// histogram is "how long were N jobs running" rather than "how many times did we see N jobs".
+/// This is what helps pick a `-j` value. If all slots are rarely busy, a lower value will do. If
+/// they are always busy and the ready queue is deep, a higher value may help.
+#[derive(Debug)]
+pub(crate) struct Utilization {
+    parallelism: usize,
+    last: Option<(Instant, usize, usize)>,
+    // Index is the number of running jobs.
+    running: Vec<Duration>,
+    ready_weighted: f64,
+    peak_ready: usize,
+}
+
+impl Utilization {
+    pub(crate) fn new(parallelism: usize) -> Self {
+        Utilization {
+            parallelism,
+            last: None,
+            running: vec![Duration::default(); parallelism + 1],
+            ready_weighted: 0.0,
+            peak_ready: 0,
+        }
+    }
+
+    /// `ready` is the number of tasks that could run but are waiting for a slot.
+    pub(crate) fn sample(&mut self, now: Instant, ready: usize, running: usize) {
+        let running = std::cmp::min(running, self.parallelism);
+        if let Some((then, last_ready, last_running)) = self.last {
+            let elapsed = now.saturating_duration_since(then);
+            self.running[last_running] += elapsed;
+            self.ready_weighted += last_ready as f64 * elapsed.as_secs_f64();
+        }
+        self.peak_ready = std::cmp::max(self.peak_ready, ready);
+        self.last = Some((now, ready, running));
+    }
+
+    fn total(&self) -> Duration {
+        self.running.iter().sum()
+    }
+
+    fn percent(&self, part: Duration) -> f64 {
+        let total = self.total().as_secs_f64();
+        if total == 0.0 {
+            0.0
+        } else {
+            100.0 * part.as_secs_f64() / total
+        }
+    }
+
+    /// Percentage of the build during which every job slot was in use.
+    pub(crate) fn saturated_percent(&self) -> f64 {
+        self.percent(self.running[self.parallelism])
+    }
+
+    pub(crate) fn average_running(&self) -> f64 {
+        let total = self.total().as_secs_f64();
+        if total == 0.0 {
+            return 0.0;
+        }
+        self.running
+            .iter()
+            .enumerate()
+            .map(|(count, duration)| count as f64 * duration.as_secs_f64())
+            .sum::<f64>()
+            / total
+    }
+
+    pub(crate) fn average_ready(&self) -> f64 {
+        let total = self.total().as_secs_f64();
+        if total == 0.0 {
+            0.0
+        } else {
+            self.ready_weighted / total
+        }
+    }
+}
+
+impl fmt::Display for Utilization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>7} {:>11} {:>7}", "running", "time (ms)", "%")?;
+        writeln!(f, "{:-^7} {:-^11} {:-^7}", "", "", "")?;
+        for (count, duration) in self.running.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>7} {:>11} {:>7.1}",
+                count,
+                duration.as_millis(),
+                self.percent(*duration)
+            )?;
+        }
+        writeln!(
+            f,
+            "all {} job slots busy {:.1}% of the time, {:.2} jobs running on average",
+            self.parallelism,
+            self.saturated_percent(),
+            self.average_running()
+        )?;
+        writeln!(
+            f,
+            "ready queue depth: {:.2} on average, {} at peak",
+            self.average_ready(),
+            self.peak_ready
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let u = Utilization::new(2);
+        assert_eq!(u.saturated_percent(), 0.0);
+        assert_eq!(u.average_running(), 0.0);
+        assert_eq!(u.average_ready(), 0.0);
+    }
+
+    #[test]
+    fn time_weighted() {
+        let start = Instant::now();
+        let mut u = Utilization::new(2);
+        // 1 job for 100ms, then 2 jobs with 4 waiting for 300ms, then done.
+        u.sample(start, 0, 1);
+        u.sample(start + Duration::from_millis(100), 4, 2);
+        u.sample(start + Duration::from_millis(400), 0, 0);
+        assert!((u.saturated_percent() - 75.0).abs() < 1e-9);
+        assert!((u.average_running() - 1.75).abs() < 1e-9);
+        assert!((u.average_ready() - 3.0).abs() < 1e-9);
+        assert_eq!(u.peak_ready, 4);
+    }
+
+    #[test]
+    fn running_is_clamped_to_parallelism() {
+        let start = Instant::now();
+        let mut u = Utilization::new(1);
+        u.sample(start, 0, 5);
+        u.sample(start + Duration::from_millis(10), 0, 0);
+        assert!((u.saturated_percent() - 100.0).abs() < 1e-9);
+    }
+}
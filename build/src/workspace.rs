@@ -0,0 +1,179 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Where a build runs, for what would otherwise be process-wide state.
+
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::process::Command;
+
+use crate::process_group::ChildScheduling;
+
+#[derive(Debug, Clone, Default)]
+struct Settings {
+    dir: Option<PathBuf>,
+    env: Vec<(String, String)>,
+    scheduling: ChildScheduling,
+    shell: Option<PathBuf>,
+    stream: bool,
+    keep_rspfiles: bool,
+}
+
+/// The directory a build's paths are relative to, and what its commands add to their environment
+/// and how they are scheduled.
+///
+/// The default is the current directory, adding nothing. A process running several builds at once
+/// gives each its own instead of changing the current directory or environment, which would
+/// change them for every build. Clones are cheap, and changing one leaves the others as they were.
+#[derive(Debug, Clone, Default)]
+pub struct Workspace(Arc<Settings>);
+
+impl Workspace {
+    /// Paths of the build are relative to `dir`, and its commands run there.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Workspace(Arc::new(Settings {
+            dir: Some(dir.into()),
+            ..Default::default()
+        }))
+    }
+
+    /// Sets `name` to `value` in the environment of every command.
+    pub fn env<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        Arc::make_mut(&mut self.0)
+            .env
+            .push((name.into(), value.into()));
+        self
+    }
+
+    /// Schedules commands as `scheduling` asks, failing if that is not possible here.
+    pub fn child_scheduling(mut self, scheduling: ChildScheduling) -> io::Result<Self> {
+        Arc::make_mut(&mut self.0).scheduling = scheduling.checked()?;
+        Ok(self)
+    }
+
+    /// Runs commands with `shell -c` instead of `/bin/sh -c`. Without a slash, `shell` is looked
+    /// up in `PATH`.
+    pub fn shell<P: Into<PathBuf>>(mut self, shell: P) -> Self {
+        Arc::make_mut(&mut self.0).shell = Some(shell.into());
+        self
+    }
+
+    /// Lets every command write to the terminal as it runs, like those in the `console` pool,
+    /// instead of capturing what it prints to show once it finishes. Commands running at the same
+    /// time share the terminal, so their output can mix.
+    pub fn stream(mut self, stream: bool) -> Self {
+        Arc::make_mut(&mut self.0).stream = stream;
+        self
+    }
+
+    /// Leaves response files in place after their commands succeed, for debugging.
+    pub fn keep_rspfiles(mut self, keep: bool) -> Self {
+        Arc::make_mut(&mut self.0).keep_rspfiles = keep;
+        self
+    }
+
+    /// None for the current directory.
+    pub fn dir(&self) -> Option<&Path> {
+        self.0.dir.as_deref()
+    }
+
+    /// Where to find `path`, a path of the build, from the current directory.
+    pub fn path<'a>(&self, path: &'a [u8]) -> Cow<'a, Path> {
+        let path = Path::new(OsStr::from_bytes(path));
+        match &self.0.dir {
+            Some(dir) => Cow::Owned(dir.join(path)),
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    pub(crate) fn scheduling(&self) -> ChildScheduling {
+        self.0.scheduling
+    }
+
+    pub(crate) fn streams(&self) -> bool {
+        self.0.stream
+    }
+
+    pub(crate) fn keeps_rspfiles(&self) -> bool {
+        self.0.keep_rspfiles
+    }
+
+    pub(crate) fn shell_path(&self) -> &Path {
+        self.0
+            .shell
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/bin/sh"))
+    }
+
+    /// Runs `command` in the directory of `namespace`, or the workspace's own outside of one, with
+    /// the workspace's environment.
+    pub(crate) fn configure(&self, command: &mut Command, namespace: Option<&[u8]>) {
+        match namespace {
+            Some(namespace) => {
+                command.current_dir(self.path(namespace));
+            }
+            None => {
+                if let Some(dir) = &self.0.dir {
+                    command.current_dir(dir);
+                }
+            }
+        }
+        command.envs(self.0.env.iter().map(|(name, value)| (name, value)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let current = Workspace::default();
+        assert_eq!(current.dir(), None);
+        assert_eq!(current.path(b"a/b.o"), Path::new("a/b.o"));
+
+        let workspace = Workspace::new("/src/out");
+        assert_eq!(workspace.path(b"a/b.o"), Path::new("/src/out/a/b.o"));
+        assert_eq!(workspace.path(b"/tmp/x"), Path::new("/tmp/x"));
+    }
+
+    #[test]
+    fn changing_a_clone() {
+        let workspace = Workspace::new("out").env("A", "1");
+        let other = workspace.clone().env("B", "2");
+        assert_eq!(workspace.0.env.len(), 1);
+        assert_eq!(other.0.env.len(), 2);
+        assert_eq!(other.dir(), Some(Path::new("out")));
+    }
+
+    #[test]
+    fn shell() {
+        assert_eq!(Workspace::default().shell_path(), Path::new("/bin/sh"));
+        let workspace = Workspace::new("out").shell("bash");
+        assert_eq!(workspace.shell_path(), Path::new("bash"));
+        assert_eq!(
+            workspace.clone().env("A", "1").shell_path(),
+            Path::new("bash")
+        );
+    }
+}
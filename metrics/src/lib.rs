@@ -15,30 +15,48 @@
  */
 
 use std::{
-    cell::RefCell,
-    fmt,
-    sync::atomic::{AtomicBool, Ordering},
-    thread_local,
+    cell::{Cell, RefCell},
+    fmt, thread_local,
     time::{Duration, Instant},
 };
 
+#[cfg(feature = "count-allocations")]
+mod alloc;
+#[cfg(feature = "count-allocations")]
+pub use alloc::CountingAllocator;
+
+#[cfg(feature = "count-allocations")]
+fn allocations() -> (usize, usize) {
+    alloc::allocations()
+}
+
+#[cfg(not(feature = "count-allocations"))]
+fn allocations() -> (usize, usize) {
+    (0, 0)
+}
+
 #[derive(Debug, Default)]
 struct Metric {
     name: &'static str,
     count: usize,
     sum: u128,
+    allocations: usize,
+    allocated_bytes: usize,
 }
 
 impl Metric {
-    pub fn record(&mut self, elapsed: Duration) {
+    pub fn record(&mut self, elapsed: Duration, allocations: (usize, usize)) {
         self.count += 1;
         self.sum += elapsed.as_micros();
+        self.allocations += allocations.0;
+        self.allocated_bytes += allocations.1;
     }
 }
 
 pub struct ScopedMetric {
     metric_index: usize,
     start: Instant,
+    start_allocations: (usize, usize),
 }
 
 impl ScopedMetric {
@@ -46,22 +64,41 @@ impl ScopedMetric {
         ScopedMetric {
             metric_index,
             start: Instant::now(),
+            start_allocations: allocations(),
         }
     }
 }
 
 impl Drop for ScopedMetric {
     fn drop(&mut self) {
+        let (allocs, bytes) = allocations();
+        let delta = (
+            allocs.wrapping_sub(self.start_allocations.0),
+            bytes.wrapping_sub(self.start_allocations.1),
+        );
         METRICS.with(|m| {
             m.borrow_mut()
-                .record(self.metric_index, self.start.elapsed())
+                .record(self.metric_index, self.start.elapsed(), delta)
         });
     }
 }
 
+/// Parsing one manifest file, which may be loaded more than once.
+#[derive(Debug)]
+struct FileMetric {
+    name: String,
+    count: usize,
+    edges: usize,
+    /// Not counting the files it loads.
+    own: u128,
+    /// Counting the files it loads.
+    total: u128,
+}
+
 #[derive(Debug)]
 struct Metrics {
     metrics: Vec<Metric>,
+    files: Vec<FileMetric>,
 }
 
 impl Metrics {
@@ -74,8 +111,72 @@ impl Metrics {
         len
     }
 
-    fn record(&mut self, i: usize, elapsed: Duration) {
-        self.metrics[i].record(elapsed);
+    fn record(&mut self, i: usize, elapsed: Duration, allocations: (usize, usize)) {
+        self.metrics[i].record(elapsed, allocations);
+    }
+
+    fn record_file(&mut self, name: &str, own: Duration, total: Duration, edges: usize) {
+        let file = match self.files.iter().position(|file| file.name == name) {
+            Some(i) => &mut self.files[i],
+            None => {
+                self.files.push(FileMetric {
+                    name: name.to_owned(),
+                    count: 0,
+                    edges: 0,
+                    own: 0,
+                    total: 0,
+                });
+                self.files.last_mut().unwrap()
+            }
+        };
+        file.count += 1;
+        file.edges += edges;
+        file.own += own.as_micros();
+        file.total += total.as_micros();
+    }
+
+    /// The files that took longest to parse first, since those are the ones worth generating
+    /// differently.
+    fn fmt_files(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut files: Vec<&FileMetric> = self.files.iter().collect();
+        files.sort_by(|a, b| b.own.cmp(&a.own).then_with(|| a.name.cmp(&b.name)));
+        let name_width = files
+            .iter()
+            .map(|file| file.name.len())
+            .fold(5, std::cmp::max); // To fit "file ".
+        writeln!(
+            f,
+            "{:name_width$} {:>6} {:>8} {:>10} {:>11}",
+            "file ",
+            "count",
+            "edges",
+            "self (us)",
+            "total (us)",
+            name_width = name_width
+        )?;
+        writeln!(
+            f,
+            "{:-<name_width$} {:-^6} {:-^8} {:-^10} {:-^11}",
+            "",
+            "",
+            "",
+            "",
+            "",
+            name_width = name_width
+        )?;
+        for file in files {
+            writeln!(
+                f,
+                "{:name_width$} {:>6} {:>8} {:>10} {:>11}",
+                file.name,
+                file.count,
+                file.edges,
+                file.own,
+                file.total,
+                name_width = name_width
+            )?;
+        }
+        Ok(())
     }
 }
 
@@ -87,7 +188,8 @@ impl fmt::Display for Metrics {
         for metric in metrics {
             name_width = std::cmp::max(name_width, metric.name.len());
         }
-        writeln!(
+        let count_allocations = cfg!(feature = "count-allocations");
+        write!(
             f,
             "{:name_width$} {:>6} {:>9} {:>11}",
             "metric ",
@@ -96,7 +198,11 @@ impl fmt::Display for Metrics {
             "total (us)",
             name_width = name_width
         )?;
-        writeln!(
+        if count_allocations {
+            write!(f, " {:>9} {:>12}", "allocs", "alloc bytes")?;
+        }
+        writeln!(f)?;
+        write!(
             f,
             "{:-<name_width$} {:-^6} {:-^9} {:-^11}",
             "",
@@ -105,8 +211,12 @@ impl fmt::Display for Metrics {
             "",
             name_width = name_width
         )?;
+        if count_allocations {
+            write!(f, " {:-^9} {:-^12}", "", "")?;
+        }
+        writeln!(f)?;
         for metric in metrics {
-            writeln!(
+            write!(
                 f,
                 "{:name_width$} {: >6} {:>9.3} {:>11}",
                 metric.name,
@@ -115,6 +225,18 @@ impl fmt::Display for Metrics {
                 metric.sum,
                 name_width = name_width
             )?;
+            if count_allocations {
+                write!(
+                    f,
+                    " {:>9} {:>12}",
+                    metric.allocations, metric.allocated_bytes
+                )?;
+            }
+            writeln!(f)?;
+        }
+        if !self.files.is_empty() {
+            writeln!(f)?;
+            self.fmt_files(f)?;
         }
         Ok(())
     }
@@ -135,17 +257,24 @@ macro_rules! scoped_metric {
     };
 }
 
+// Like the metrics themselves, whether they are collected is per thread, so that builds running on
+// different threads of one process don't affect each other.
 thread_local! {
-    static METRICS: RefCell<Metrics> = RefCell::new(Metrics { metrics: vec![] });
+    static METRICS: RefCell<Metrics> = const {
+        RefCell::new(Metrics {
+            metrics: vec![],
+            files: vec![],
+        })
+    };
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
 }
-static ENABLED: AtomicBool = AtomicBool::new(false);
 
 pub fn enable() {
-    ENABLED.store(true, Ordering::Relaxed);
+    ENABLED.with(|enabled| enabled.set(true));
 }
 
 pub fn is_enabled() -> bool {
-    ENABLED.load(Ordering::Relaxed)
+    ENABLED.with(|enabled| enabled.get())
 }
 
 pub fn dump() {
@@ -157,3 +286,9 @@ pub fn dump() {
 pub fn new_metric(name: &'static str) -> usize {
     METRICS.with(|m| m.borrow_mut().new_metric(name))
 }
+
+/// Records that parsing the manifest file `name` took `own`, or `total` with the files it loads,
+/// and described `edges` edges. `dump` lists each file, slowest first.
+pub fn record_file(name: &str, own: Duration, total: Duration, edges: usize) {
+    METRICS.with(|m| m.borrow_mut().record_file(name, own, total, edges))
+}
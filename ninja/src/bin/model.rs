@@ -14,9 +14,13 @@
  * limitations under the License.
  */
 
-use std::{collections::HashSet, fs::metadata, process::Command};
+use std::{
+    collections::HashSet, ffi::OsStr, fs::metadata, os::unix::ffi::OsStrExt, path::Path,
+    process::Command,
+};
 
 extern crate petgraph;
+use ninja_builder::task::{Key, KeyPath};
 use petgraph::{
     graph::NodeIndex,
     visit::{depth_first_search, Control, DfsEvent},
@@ -37,22 +41,15 @@ trait Scheduler<K, V> {
 
 // --------- impl
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
-enum Key {
-    Path(String),
-    Multi(Vec<String>),
-}
-
-impl From<String> for Key {
-    fn from(s: String) -> Key {
-        Key::Path(s)
-    }
+fn path(p: &str) -> KeyPath {
+    KeyPath::from(p.as_bytes().to_vec())
 }
 
-impl From<&str> for Key {
-    fn from(s: &str) -> Key {
-        Key::Path(s.to_string())
-    }
+fn mtime(p: &KeyPath) -> std::time::SystemTime {
+    metadata(Path::new(OsStr::from_bytes(p.as_bytes())))
+        .expect("metadata")
+        .modified()
+        .expect("mtime")
 }
 
 // actually needs a buffer result or something.
@@ -104,24 +101,17 @@ struct MTimeRebuilder<'a> {
 impl<'a> Rebuilder<NodeIndex, TaskResult> for MTimeRebuilder<'a> {
     fn build(&self, node: NodeIndex, _: TaskResult, task: &dyn Task<TaskResult>) -> TaskResult {
         let key = &self.graph[node];
-        let outputs: Vec<String> = match key {
+        let outputs: Vec<KeyPath> = match key {
             Key::Path(p) => vec![p.clone()],
-            Key::Multi(ps) => ps.clone(),
+            Key::Multi(ps) => ps.to_vec(),
         };
         // If the oldest output is older than any input, rebuild.
-        let mtime = outputs
-            .iter()
-            .map(|path| metadata(path).expect("metadata").modified().expect("mtime"))
-            .min()
-            .expect("at least one");
+        let oldest_output = outputs.iter().map(mtime).min().expect("at least one");
         let mut dependencies = self.graph.neighbors_directed(node, Direction::Outgoing);
         let dirty = dependencies.any(|dep| {
             let dep = &self.graph[dep];
             match dep {
-                Key::Path(p) => {
-                    let dep_mtime = metadata(p).expect("metadata").modified().expect("mtime");
-                    dep_mtime > mtime
-                }
+                Key::Path(p) => mtime(p) > oldest_output,
                 Key::Multi(_) => {
                     // TODO: assert task is phony.
                     true
@@ -175,29 +165,29 @@ fn main() {
     // Graph is flipped to accomodate dfs topo sort
     let mut graph: Graph<Key, ()> = petgraph::Graph::new();
 
-    let source_key = Key::Path("foo.c".into());
+    let source_key = Key::Path(path("foo.c"));
     let source_node = graph.add_node(source_key);
 
     let cc_task = CommandTask::new("gcc -c foo.c");
-    let cc_key = Key::Path("foo.o".into());
+    let cc_key = Key::Path(path("foo.o"));
     let cc_node = graph.add_node(cc_key);
     graph.add_edge(cc_node, source_node, ());
 
     let link_task = CommandTask::new("gcc -o foo foo.o");
-    let link_key = Key::Path("foo".into());
+    let link_key = Key::Path(path("foo"));
     let link_node = graph.add_node(link_key);
     graph.add_edge(link_node, cc_node, ());
 
-    let a_key = Key::Path("a".into());
+    let a_key = Key::Path(path("a"));
     let a_node = graph.add_node(a_key);
     let touch_task = CommandTask::new("touch b c");
     let phony_b = PhonyTask::new();
     let phony_c = PhonyTask::new();
-    let multi_key = Key::Multi(vec!["b".into(), "c".into()]);
+    let multi_key = Key::Multi(vec![path("b"), path("c")].into());
     let multi_node = graph.add_node(multi_key);
-    let phony_b_key = Key::Path("b".into());
+    let phony_b_key = Key::Path(path("b"));
     let phony_b_node = graph.add_node(phony_b_key);
-    let phony_c_key = Key::Path("c".into());
+    let phony_c_key = Key::Path(path("c"));
     let phony_c_node = graph.add_node(phony_c_key);
     graph.add_edge(multi_node, a_node, ());
     graph.add_edge(phony_b_node, multi_node, ());
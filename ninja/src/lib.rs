@@ -18,14 +18,77 @@ use anyhow::{self, Context};
 use thiserror::Error;
 
 use ninja_builder::{
-    build, build_externals, caching_mtime_rebuilder,
-    task::{description_to_tasks, description_to_tasks_with_start, Key},
+    build,
+    build_id::{BuildId, BUILD_ID_ENV},
+    build_log::SharedBuildLog,
+    build_requested, caching_mtime_rebuilder,
+    check::{analyze_graph, check_tasks},
+    deps_log::SharedDepsLog,
+    diagnostics,
+    dir_listings::SharedDirListings,
+    disk_interface::SystemDiskInterface,
+    distributed::{self, DistributedScheduler},
+    explain::ExplainLog,
+    frontend::{Frontend, ManifestEvent},
+    lock::BuildLock,
+    plan,
+    selection::{ChangedSince, TargetSelection},
+    state::check_state,
+    status_format::StatusFormat,
+    task::{description_to_tasks_with_start, Key},
     tracking_rebuilder::TrackingRebuilder,
-    ParallelTopoScheduler,
+    BuildError, CancelHandle, ChildScheduling, DirectoryInputs, Failures, ForwardSignals,
+    ParallelTopoScheduler, Workspace,
 };
 use ninja_metrics::scoped_metric;
-use ninja_parse::{build_representation, Loader};
-use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
+use ninja_parse::{build_representation_with_limits, repr::Description, Limits, Loader};
+use std::{
+    cell::RefCell,
+    ffi::OsStr,
+    io::BufWriter,
+    net::TcpListener,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Where the command that produced each output is recorded, relative to the state directory.
+const BUILD_LOG_PATH: &str = ".ninja_log";
+/// Where the inputs discovered by commands of rules with `deps` are recorded.
+const DEPS_LOG_PATH: &str = ".ninja_deps";
+/// Where the listing of each directory input is recorded, under `--directory-inputs=contents`.
+const DIR_LISTINGS_PATH: &str = ".ninja_dirs";
+
+/// The lock on the state directory, and the files in it, all None when nothing is written.
+type State = (
+    Option<BuildLock>,
+    Option<SharedBuildLog>,
+    Option<SharedDepsLog>,
+    Option<SharedDirListings>,
+);
+
+/// The secret a coordinator has to send workers before they run its commands. Both read it from
+/// the environment, so it doesn't show up in process listings.
+pub const WORKER_TOKEN_ENV: &str = "NINJA_WORKER_TOKEN";
+
+fn worker_token(flag: &str) -> anyhow::Result<Vec<u8>> {
+    match std::env::var_os(WORKER_TOKEN_ENV) {
+        Some(token) if !token.is_empty() => Ok(token.into_vec()),
+        _ => anyhow::bail!(
+            "{} needs a secret shared by the coordinator and its workers in {}",
+            flag,
+            WORKER_TOKEN_ENV
+        ),
+    }
+}
+
+mod message_format;
+mod notify;
+mod tools;
+pub use message_format::{MessageFormat, MessageFormatError};
+pub use notify::Notify;
+pub use tools::{Tool, ToolError};
 
 /// Nothing to do with rustc debug vs. release.
 /// This is just ninja terminology.
@@ -33,6 +96,35 @@ use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
 pub enum DebugMode {
     List,
     Stats,
+    Redefinitions,
+    /// Write every dirtiness decision to a file, rather than explaining on the console.
+    ExplainFile(String),
+    /// Leave response files in place after their commands succeed.
+    KeepRsp,
+}
+
+/// Optional lints, enabled with `-w`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum WarningFlag {
+    List,
+    /// Warn about `$in` and `$out` in quotes expanding to paths that ninja quotes itself.
+    ShellQuote,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown warning flag '{0}'")]
+pub struct WarningFlagError(String);
+
+impl std::str::FromStr for WarningFlag {
+    type Err = WarningFlagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "list" => Ok(WarningFlag::List),
+            "shellquote" => Ok(WarningFlag::ShellQuote),
+            e => Err(WarningFlagError(e.to_owned())),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -46,53 +138,450 @@ impl std::str::FromStr for DebugMode {
         match s {
             "stats" => Ok(DebugMode::Stats),
             "list" => Ok(DebugMode::List),
+            "redefinitions" => Ok(DebugMode::Redefinitions),
+            "keeprsp" => Ok(DebugMode::KeepRsp),
+            s if s.starts_with("explain-file=") => Ok(DebugMode::ExplainFile(
+                s["explain-file=".len()..].to_owned(),
+            )),
             e @ _ => Err(DebugModeError(e.to_owned())),
         }
     }
 }
 
+/// What `--profile` measures.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// Loading the manifest and turning it into tasks, reported per stage, without building.
+    Startup,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown profile '{0}'")]
+pub struct ProfileError(String);
+
+impl std::str::FromStr for Profile {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "startup" => Ok(Profile::Startup),
+            e => Err(ProfileError(e.to_owned())),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub execution_dir: Option<String>,
     pub parallelism: usize,
+    /// Number of failed commands after which no new commands are started. 0 means no limit.
+    pub failures_allowed: usize,
+    /// Print the full command of every edge, instead of its description.
+    pub verbose: bool,
+    /// Let every command write to the terminal as it runs, instead of printing its output when it
+    /// finishes.
+    pub stream: bool,
+    /// The progress prefix of status lines, from `NINJA_STATUS`.
+    pub status_format: StatusFormat,
     pub build_file: String,
     pub debug_modes: Vec<DebugMode>,
+    pub warning_flags: Vec<WarningFlag>,
+    pub profile: Option<Profile>,
+    /// Only parse and validate the manifest, without running any commands.
+    pub check_manifest: bool,
+    /// Skip bringing the manifest itself up to date before building, for when the generator is run
+    /// separately.
+    pub no_rebuild_manifest: bool,
+    /// How much manifest to read before giving up.
+    pub manifest_limits: Limits,
+    /// How to treat inputs that are directories.
+    pub directory_inputs: DirectoryInputs,
+    /// Socket or named pipe to stream build progress to, in ninja's frontend protocol.
+    pub frontend: Option<String>,
+    /// Run a subtool instead of building.
+    pub tool: Option<Tool>,
+    /// Only build what is affected by files changed since this git ref, instead of the targets.
+    pub changed_since: Option<String>,
+    /// When another ninja is building in the same directory, wait for it rather than failing.
+    pub wait_for_lock: bool,
+    /// Niceness and CPU affinity for commands.
+    pub child_scheduling: ChildScheduling,
+    /// Runs commands with this shell instead of `/bin/sh`.
+    pub shell: Option<String>,
+    /// Kill and fail commands that run for longer than this.
+    pub command_timeout: Option<Duration>,
+    /// Start no commands once this long has passed since the build started.
+    pub deadline: Option<Duration>,
+    /// After building, check that every command that succeeded left its outputs up to date.
+    pub verify: bool,
+    /// Where to write the failed commands, and why they failed, as JSON. Written even when
+    /// nothing failed, so a stale report is never mistaken for the current one.
+    pub failures_json: Option<String>,
+    /// Print the commands that would run, and why, as JSON instead of running them. The manifest
+    /// is not brought up to date first, since that would run commands.
+    pub dry_run_json: bool,
+    /// Run commands on these `host:port` workers instead of locally. Experimental.
+    pub workers: Vec<String>,
+    /// Run commands for a coordinator connecting to this address, instead of building.
+    pub serve_worker: Option<String>,
+    /// Let `serve_worker` listen on an address other machines can reach.
+    pub allow_remote_coordinators: bool,
+    /// Tell the user when the build finishes, and how it went.
+    pub notify: Option<Notify>,
+    /// How to report errors in the manifest, besides on stderr.
+    pub message_format: MessageFormat,
     pub targets: Vec<String>,
 }
 
-struct FileLoader {}
+/// Where ninja keeps its logs and other files between builds: the manifest's `builddir`, relative
+/// to `dir`, the directory it builds in, or `dir` itself.
+pub(crate) fn state_dir(dir: &Path, repr: &Description) -> PathBuf {
+    match &repr.builddir {
+        Some(builddir) => dir.join(OsStr::from_bytes(builddir)),
+        None => dir.to_owned(),
+    }
+}
+
+struct FileLoader {
+    workspace: Workspace,
+}
 impl Loader for FileLoader {
     fn load(&mut self, from: Option<&[u8]>, request: &[u8]) -> std::io::Result<Vec<u8>> {
         let path = if let Some(from) = from {
             let src_path = Path::new(OsStr::from_bytes(from));
             let req_path = Path::new(OsStr::from_bytes(request));
             if req_path.components().count() > 1 {
-                todo!("handle relative paths");
+                // Like ninja, relative to where it runs, not to the including file.
+                req_path.to_owned()
             } else {
                 src_path.with_file_name(req_path)
             }
         } else {
             Path::new(OsStr::from_bytes(request)).to_owned()
         };
-        std::fs::read(path)
+        scoped_metric!("load_manifest");
+        std::fs::read(self.workspace.path(path.as_os_str().as_bytes()))
     }
 }
 
+/// Parses the manifest, also reporting errors in it as `config.message_format` asks, for the build
+/// `build_id` if there is one.
+fn parse_manifest(
+    loader: &mut FileLoader,
+    config: &Config,
+    build_id: Option<&BuildId>,
+) -> anyhow::Result<Description> {
+    build_representation_with_limits(
+        loader,
+        config.build_file.clone().into_bytes(),
+        config.manifest_limits,
+    )
+    .map_err(|e| {
+        if config.message_format == MessageFormat::Json {
+            // Failing to write the copy is no reason to lose the error itself.
+            let _ =
+                message_format::write_manifest_error(&e, build_id, &mut std::io::stdout().lock());
+        }
+        e.into()
+    })
+}
+
+/// Parses the manifest as it is, without bringing it up to date first. For tools, which only look
+/// at the graph, from the current directory.
+fn load_description(config: &Config) -> anyhow::Result<Description> {
+    let mut loader = FileLoader {
+        workspace: Workspace::default(),
+    };
+    let repr = parse_manifest(&mut loader, config, None)?;
+    if let Some(include) = &repr.missing_include {
+        anyhow::bail!(
+            "'{}' does not exist yet; build it first",
+            String::from_utf8_lossy(include)
+        );
+    }
+    Ok(repr)
+}
+
+fn target_bytes(config: &Config) -> Vec<Vec<u8>> {
+    config
+        .targets
+        .iter()
+        .map(|v| v.clone().into_bytes())
+        .collect()
+}
+
+/// What `ninja` does with `config`. Tools and workers change to `execution_dir` first, which
+/// affects the whole process; builds use a `BuildSession` and leave the current directory alone.
 pub fn run(config: Config) -> anyhow::Result<()> {
-    if let Some(dir) = &config.execution_dir {
-        std::env::set_current_dir(&dir).with_context(|| format!("changing to {} for -C", &dir))?;
+    if config.tool.is_some() || config.serve_worker.is_some() {
+        if let Some(dir) = &config.execution_dir {
+            std::env::set_current_dir(dir)
+                .with_context(|| format!("changing to {} for -C", dir))?;
+        }
+    }
+
+    if let Some(tool) = &config.tool {
+        return tools::run_tool(tool, &config);
     }
 
-    let metrics_enabled = config.debug_modes.iter().any(|v| v == &DebugMode::Stats);
+    if let Some(address) = &config.serve_worker {
+        let mut workspace = Workspace::default()
+            .child_scheduling(config.child_scheduling)
+            .context("configuring --nice/--affinity")?;
+        if let Some(shell) = &config.shell {
+            workspace = workspace.shell(shell);
+        }
+        let token = worker_token("--serve-worker")?;
+        // So commands, which run in their own process groups, don't outlive the worker.
+        let _signals = ForwardSignals::install();
+        let listener =
+            TcpListener::bind(address).with_context(|| format!("listening on {}", address))?;
+        let local = listener.local_addr()?;
+        if !local.ip().is_loopback() && !config.allow_remote_coordinators {
+            anyhow::bail!(
+                "not listening on {}, which other machines can reach, without \
+                 --allow-remote-coordinators",
+                local
+            );
+        }
+        eprintln!(
+            "ninja: running commands for coordinators connecting to {}",
+            listener.local_addr()?
+        );
+        distributed::serve(listener, config.parallelism, &workspace, &token)?;
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let session = BuildSession::new(config).handle_signals();
+    let result = session.run();
+    let config = session.config();
+    // Checking the manifest or profiling is quick enough to watch.
+    if let Some(how) = &config.notify {
+        if !config.check_manifest && config.profile.is_none() {
+            notify::notify(how, &result, started.elapsed());
+        }
+    }
+    result
+}
+
+/// A build of the manifest in `execution_dir`, or the current directory.
+///
+/// Sessions leave the current directory alone, so several can run at once on different threads, as
+/// long as they build in different directories. Metrics and warnings are collected per thread, but
+/// the rest is shared by the process:
+///
+/// - signal handlers are process-wide, so with `handle_signals`, an interrupt stops every session
+///   that is running;
+/// - only one session notices that the terminal was resized, and the others redraw their status
+///   line at the new width whenever they next would;
+/// - all of them print their status and command output to the same terminal.
+///
+/// `tool` and `serve_worker` are ignored, since they change the current directory; see `run`.
+#[derive(Debug)]
+pub struct BuildSession {
+    config: Config,
+    cancel: CancelHandle,
+    handle_signals: bool,
+}
+
+impl BuildSession {
+    pub fn new(config: Config) -> Self {
+        BuildSession {
+            config,
+            cancel: CancelHandle::new(),
+            handle_signals: false,
+        }
+    }
+
+    /// Stop at the first SIGINT, SIGTERM or SIGHUP like ninja does, passing it on to running
+    /// commands. Otherwise the program's own handlers see them, and it can stop builds with
+    /// `canceller`.
+    pub fn handle_signals(mut self) -> Self {
+        self.handle_signals = true;
+        self
+    }
+
+    /// Stops this session's builds from another thread, killing the commands that are running.
+    /// `run` then fails with `BuildError::Cancelled`.
+    pub fn canceller(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Builds the requested targets, bringing the manifest up to date first. Each run gets a new
+    /// build id.
+    pub fn run(&self) -> anyhow::Result<()> {
+        run_build(&self.config, &self.cancel, self.handle_signals)
+    }
+}
+
+fn run_build(config: &Config, cancel: &CancelHandle, handle_signals: bool) -> anyhow::Result<()> {
+    if !config.workers.is_empty()
+        && (config.frontend.is_some()
+            || config.command_timeout.is_some()
+            || config.deadline.is_some())
+    {
+        anyhow::bail!(
+            "--frontend, --command-timeout and --deadline are not supported with --workers yet"
+        );
+    }
+    let worker_token = if config.workers.is_empty() {
+        None
+    } else {
+        Some(worker_token("--workers")?)
+    };
+    let deadline = config.deadline.map(|deadline| Instant::now() + deadline);
+
+    if config.changed_since.is_some() && !config.targets.is_empty() {
+        anyhow::bail!("--changed-since selects the targets itself; don't name any");
+    }
+
+    let profile_startup = config.profile == Some(Profile::Startup);
+    let metrics_enabled =
+        profile_startup || config.debug_modes.iter().any(|v| v == &DebugMode::Stats);
     if metrics_enabled {
         ninja_metrics::enable();
     }
 
-    let mut loader = FileLoader {};
+    // Commands get the id in their environment. The build log, the frontend, the failure summary
+    // and the JSON outputs record it, to tell which build they came from.
+    let build_id = BuildId::generate();
+    let mut workspace = match &config.execution_dir {
+        Some(dir) => Workspace::new(dir),
+        None => Workspace::default(),
+    }
+    .env(BUILD_ID_ENV, build_id.as_str())
+    .child_scheduling(config.child_scheduling)
+    .context("configuring --nice/--affinity")?
+    .stream(config.stream)
+    .keep_rspfiles(config.debug_modes.contains(&DebugMode::KeepRsp));
+    if let Some(shell) = &config.shell {
+        workspace = workspace.shell(shell);
+    }
+    let dir = workspace.dir().unwrap_or_else(|| Path::new("."));
+    // Opened once the manifest says where they are, by `open_state`. Nothing is written when only
+    // looking at the manifest, so then there is no need to keep other invocations out.
+    let writes_state = !(config.check_manifest || profile_startup);
+    let mut state: Option<State> = None;
+    let open_state = |repr: &Description| -> anyhow::Result<State> {
+        if !writes_state {
+            return Ok((None, None, None, None));
+        }
+        let state_dir = state_dir(dir, repr);
+        std::fs::create_dir_all(&state_dir)
+            .with_context(|| format!("creating builddir {}", state_dir.display()))?;
+        let lock = BuildLock::acquire(&state_dir, config.wait_for_lock)?;
+        // Read under the lock, since another build may be appending to them.
+        check_state(&state_dir)?;
+        let build_log = SharedBuildLog::load(state_dir.join(BUILD_LOG_PATH))
+            .with_context(|| format!("loading {}", BUILD_LOG_PATH))?
+            .for_build(build_id.clone());
+        let deps_log = SharedDepsLog::load(state_dir.join(DEPS_LOG_PATH))
+            .with_context(|| format!("loading {}", DEPS_LOG_PATH))?;
+        let dir_listings = SharedDirListings::load(state_dir.join(DIR_LISTINGS_PATH))
+            .with_context(|| format!("loading {}", DIR_LISTINGS_PATH))?;
+        Ok((
+            Some(lock),
+            Some(build_log),
+            Some(deps_log),
+            Some(dir_listings),
+        ))
+    };
+
+    let explain = config
+        .debug_modes
+        .iter()
+        .find_map(|mode| match mode {
+            DebugMode::ExplainFile(path) => Some(path),
+            _ => None,
+        })
+        .map(|path| -> anyhow::Result<ExplainLog> {
+            let file = std::fs::File::create(workspace.path(path.as_bytes()))
+                .with_context(|| format!("creating explain file {}", path))?;
+            Ok(ExplainLog::new(Box::new(BufWriter::new(file)))?)
+        })
+        .transpose()?;
+
+    // Connect before doing any work, so a bad path fails fast. The frontend only sees the build
+    // that was asked for, and what happened to the manifest, not the edges bringing it up to date.
+    // It is shared with the hook that says the manifest is being regenerated.
+    let frontend = Rc::new(RefCell::new(
+        config
+            .frontend
+            .as_ref()
+            .map(|path| {
+                Frontend::connect(workspace.path(path.as_bytes()))
+                    .map(|frontend| frontend.build_id(build_id.clone()))
+                    .with_context(|| format!("connecting to frontend {}", path))
+            })
+            .transpose()?,
+    ));
+    let tell_frontend = |event: ManifestEvent| {
+        if let Some(frontend) = frontend.borrow_mut().as_mut() {
+            frontend.manifest(event, &config.build_file);
+        }
+    };
+    let mut regenerated = false;
+
+    let mut loader = FileLoader {
+        workspace: workspace.clone(),
+    };
 
     for _ in 1..=100 {
         let build_key = Key::Path(config.build_file.clone().into_bytes().into());
-        let repr = build_representation(&mut loader, config.build_file.clone().into_bytes())?;
+        let mut repr = parse_manifest(&mut loader, config, Some(&build_id))?;
+        // From the first parse. A regenerated manifest that moves `builddir` takes effect on the
+        // next invocation.
+        if state.is_none() {
+            state = Some(open_state(&repr)?);
+        }
+        let (_, build_log, deps_log, dir_listings) = state.as_ref().expect("opened above");
+        let missing_include = repr.missing_include.take();
+        if regenerated {
+            tell_frontend(ManifestEvent::Reloaded);
+            regenerated = false;
+        }
+        if config.debug_modes.contains(&DebugMode::Redefinitions) {
+            for redefinition in &repr.redefinitions {
+                diagnostics::warn(
+                    "redefinition",
+                    format_args!(
+                        "{}: '{}' reassigned after {} edge(s) that may have used its previous value",
+                        redefinition.location,
+                        String::from_utf8_lossy(&redefinition.name),
+                        redefinition.edges_before
+                    ),
+                );
+            }
+        }
+        if config.warning_flags.contains(&WarningFlag::ShellQuote) {
+            for expansion in &repr.unquoted_expansions {
+                diagnostics::warn(
+                    "shellquote",
+                    format_args!(
+                        "edge for '{}': ${} expands to '{}' inside quotes, where the quotes ninja adds \
+                         to it would be taken literally",
+                        String::from_utf8_lossy(&expansion.output),
+                        expansion.variable,
+                        String::from_utf8_lossy(&expansion.path)
+                    ),
+                );
+            }
+        }
+        for dependency in &repr.self_dependencies {
+            diagnostics::warn(
+                "self-dependency",
+                format_args!(
+                    "{}: '{}' is both an input and an output of its edge; ignoring it as an input",
+                    dependency.location,
+                    String::from_utf8_lossy(&dependency.path)
+                ),
+            );
+        }
         // // at this point we should basically have a structure where all commands are fully expanded and
         // // ready to go.
         // Unlike a suspending/restarting + monadic tasks combination, and also because our tasks are
@@ -103,28 +592,157 @@ pub fn run(config: Config) -> anyhow::Result<()> {
         // don't spit out executable tasks, instead just having an enum.
         let (tasks, requested) = {
             scoped_metric!("to_tasks");
-            let targets_clone = config.targets.clone();
-            if targets_clone.is_empty() {
-                description_to_tasks(repr)
-            } else {
-                description_to_tasks_with_start(
-                    repr,
-                    Some(targets_clone.into_iter().map(|v| v.into_bytes()).collect()),
-                )
-            }
+            description_to_tasks_with_start(repr, Some(target_bytes(config)))
         };
 
-        let scheduler = ParallelTopoScheduler::new(config.parallelism);
+        if profile_startup {
+            // Only loading is being measured, so there is no point regenerating the manifest.
+            break;
+        }
+
+        if config.check_manifest {
+            if let Some(include) = &missing_include {
+                anyhow::bail!(
+                    "'{}' does not exist yet; build it before checking the manifest",
+                    String::from_utf8_lossy(include)
+                );
+            }
+            let problems = check_tasks(&tasks, requested.as_deref());
+            for problem in &problems {
+                eprintln!("ninja: error: {}", problem);
+            }
+            // Not errors, since ninja accepts them, but worth a look.
+            let analysis = analyze_graph(&tasks, requested.as_deref());
+            for input in &analysis.orphan_inputs {
+                if !workspace.path(input.as_bytes()).exists() {
+                    eprintln!(
+                        "ninja: warning: '{}' is missing and no edge produces it",
+                        input.display()
+                    );
+                }
+            }
+            for edge in &analysis.unreachable_edges {
+                eprintln!(
+                    "ninja: warning: nothing requested depends on the edge for '{}'",
+                    edge.display()
+                );
+            }
+            if !problems.is_empty() {
+                anyhow::bail!(
+                    "{} problem(s) found in '{}'",
+                    problems.len(),
+                    config.build_file
+                );
+            }
+            println!("ninja: '{}' is valid", config.build_file);
+            break;
+        }
+
+        let failures_allowed = if config.failures_allowed == 0 {
+            usize::MAX
+        } else {
+            config.failures_allowed
+        };
+        // The manifest and generated includes are built before what the user asked for, and only
+        // the latter should say whether there was anything to do.
+        let mut prepass_scheduler = ParallelTopoScheduler::new(config.parallelism)
+            .keep_going(failures_allowed)
+            .quiet_when_up_to_date()
+            .workspace(workspace.clone());
+        if config.verbose {
+            prepass_scheduler = prepass_scheduler.verbose();
+        }
+        prepass_scheduler = prepass_scheduler.status_format(config.status_format.clone());
+        if let Some(timeout) = config.command_timeout {
+            prepass_scheduler = prepass_scheduler.command_timeout(timeout);
+        }
+        if let Some(deadline) = deadline {
+            prepass_scheduler = prepass_scheduler.deadline(deadline);
+        }
+        if let Some(log) = &build_log {
+            prepass_scheduler = prepass_scheduler.build_log(log.clone());
+        }
+        prepass_scheduler = prepass_scheduler.cancel_handle(cancel.clone());
+        if handle_signals {
+            prepass_scheduler = prepass_scheduler.handle_signals();
+        }
+        if let Some(log) = &deps_log {
+            prepass_scheduler = prepass_scheduler.deps_log(log.clone());
+        }
 
-        if tasks.task(&build_key).is_some() {
-            let rebuilder = TrackingRebuilder::with_caching_rebuilder(build_key.clone());
+        if !config.no_rebuild_manifest && !config.dry_run_json && tasks.task(&build_key).is_some() {
+            let mut rebuilder = TrackingRebuilder::with_caching_rebuilder(
+                build_key.clone(),
+                config.directory_inputs,
+            )
+            .workspace(workspace.clone());
+            if frontend.borrow().is_some() {
+                let frontend = frontend.clone();
+                let manifest = config.build_file.clone();
+                rebuilder = rebuilder.on_rebuild(move || {
+                    if let Some(frontend) = frontend.borrow_mut().as_mut() {
+                        frontend.manifest(ManifestEvent::Dirty, &manifest);
+                    }
+                });
+            }
+            if let Some(explain) = &explain {
+                rebuilder = rebuilder.explain_to(explain.clone());
+            }
+            if let Some(log) = &build_log {
+                rebuilder = rebuilder.build_log(log.clone());
+            }
+            if let Some(log) = &deps_log {
+                rebuilder = rebuilder.deps_log(log.clone());
+            }
+            if let Some(listings) = &dir_listings {
+                rebuilder = rebuilder.dir_listings(listings.clone());
+            }
             // let build_task = rebuilder.build(build_key, None, task)?;
-            build(&scheduler, &rebuilder, &tasks, vec![build_key])?;
+            build(&prepass_scheduler, &rebuilder, &tasks, vec![build_key])?;
             // TODO: How do we determine if it was already up to date!
             if rebuilder.required_rebuild() {
                 // Re-parse and try again.
+                regenerated = true;
                 continue;
             }
+            tell_frontend(ManifestEvent::Unchanged);
+        }
+
+        // The manifest includes a file that an earlier edge generates. Build it and parse again.
+        if let Some(include) = missing_include {
+            let name = String::from_utf8_lossy(&include).into_owned();
+            if config.dry_run_json {
+                anyhow::bail!(
+                    "'{}' does not exist yet; build it before planning the build",
+                    name
+                );
+            }
+            let include_key = Key::Path(include.clone().into());
+            if tasks.task(&include_key).is_none() {
+                anyhow::bail!(
+                    "loading '{}': file not found and no preceding edge produces it",
+                    name
+                );
+            }
+            let mut rebuilder =
+                caching_mtime_rebuilder(config.directory_inputs).workspace(workspace.clone());
+            if let Some(explain) = &explain {
+                rebuilder = rebuilder.explain_to(explain.clone());
+            }
+            if let Some(log) = &build_log {
+                rebuilder = rebuilder.build_log(log.clone());
+            }
+            if let Some(log) = &deps_log {
+                rebuilder = rebuilder.deps_log(log.clone());
+            }
+            if let Some(listings) = &dir_listings {
+                rebuilder = rebuilder.dir_listings(listings.clone());
+            }
+            build(&prepass_scheduler, &rebuilder, &tasks, vec![include_key])?;
+            if !workspace.path(&include).exists() {
+                anyhow::bail!("building '{}' did not create it", name);
+            }
+            continue;
         }
 
         // BTW, one way to model cheap string/byte references by index without having to pass lifetimes
@@ -140,23 +758,130 @@ pub fn run(config: Config) -> anyhow::Result<()> {
         // We may want to pass an mtime oracle here instead of making mtimerebuilder aware of the
         // filesystem.
         {
-            let rebuilder = caching_mtime_rebuilder();
+            let mut scheduler = ParallelTopoScheduler::new(config.parallelism)
+                .keep_going(failures_allowed)
+                .workspace(workspace.clone());
+            if let Some(frontend) = frontend.borrow_mut().take() {
+                scheduler = scheduler.frontend(frontend);
+            }
+            if config.verbose {
+                scheduler = scheduler.verbose();
+            }
+            scheduler = scheduler.status_format(config.status_format.clone());
+            // Errors in what the user asked for matter most to them.
+            if !config.targets.is_empty() {
+                scheduler = scheduler.prioritize_requested();
+            }
+            if let Some(timeout) = config.command_timeout {
+                scheduler = scheduler.command_timeout(timeout);
+            }
+            if let Some(deadline) = deadline {
+                scheduler = scheduler.deadline(deadline);
+            }
+            if let Some(log) = &build_log {
+                scheduler = scheduler.build_log(log.clone());
+            }
+            if let Some(log) = &deps_log {
+                scheduler = scheduler.deps_log(log.clone());
+            }
+            scheduler = scheduler.cancel_handle(cancel.clone());
+            if handle_signals {
+                scheduler = scheduler.handle_signals();
+            }
+            let start = match &config.changed_since {
+                Some(git_ref) => Some(
+                    ChangedSince::new(git_ref.as_str())
+                        .workspace(workspace.clone())
+                        .select(&tasks)?,
+                ),
+                None => requested.map(|requested| requested.into_iter().map(Key::Path).collect()),
+            };
+            let mut rebuilder =
+                caching_mtime_rebuilder(config.directory_inputs).workspace(workspace.clone());
+            if let Some(explain) = &explain {
+                rebuilder = rebuilder.explain_to(explain.clone());
+            }
+            if let Some(log) = &build_log {
+                rebuilder = rebuilder.build_log(log.clone());
+            }
+            if let Some(log) = &deps_log {
+                rebuilder = rebuilder.deps_log(log.clone());
+            }
+            if let Some(listings) = &dir_listings {
+                rebuilder = rebuilder.dir_listings(listings.clone());
+            }
+            if config.dry_run_json {
+                // The reasons come from explanations, so they replace any explain file.
+                let explanations = ExplainLog::in_memory();
+                let rebuilder = rebuilder.explain_to(explanations.clone());
+                let planned = plan::plan(&rebuilder, &tasks, start.as_deref(), &explanations)?;
+                plan::write_json(&planned, &mut std::io::stdout().lock())
+                    .context("writing the plan")?;
+                break;
+            }
             scoped_metric!("build");
-            if let Some(requested) = requested {
-                build(
-                    &scheduler,
-                    &rebuilder,
-                    &tasks,
-                    requested.into_iter().map(Key::Path).collect(),
-                )?;
+            // Bringing the manifest up to date above always happens locally.
+            let mut result = if config.workers.is_empty() {
+                build_requested(&scheduler, &rebuilder, &tasks, start)
             } else {
-                build_externals(&scheduler, &rebuilder, &tasks)?;
+                let mut scheduler = DistributedScheduler::new(
+                    config.workers.clone(),
+                    worker_token.clone().unwrap_or_default(),
+                )
+                .keep_going(failures_allowed)
+                .workspace(workspace.clone());
+                if config.verbose {
+                    scheduler = scheduler.verbose();
+                }
+                scheduler = scheduler.status_format(config.status_format.clone());
+                if let Some(log) = &build_log {
+                    scheduler = scheduler.build_log(log.clone());
+                }
+                if let Some(log) = &deps_log {
+                    scheduler = scheduler.deps_log(log.clone());
+                }
+                build_requested(&scheduler, &rebuilder, &tasks, start)
+            };
+            let mut unverified = 0;
+            if config.verify {
+                if let Ok(report) | Err(BuildError::CommandsFailed(report)) = &mut result {
+                    unverified = report.verify(&tasks, &SystemDiskInterface, &workspace);
+                    for problem in report.unverified() {
+                        eprintln!("ninja: error: {}", problem);
+                    }
+                }
+            }
+            if let Some(path) = &config.failures_json {
+                let no_failures = Failures(vec![]);
+                let failures = match &result {
+                    Err(BuildError::CommandsFailed(report))
+                    | Err(BuildError::DeadlineReached(report)) => report.failures(),
+                    _ => &no_failures,
+                };
+                std::fs::File::create(workspace.path(path.as_bytes()))
+                    .and_then(|mut file| failures.write_json(&build_id, &mut file))
+                    .with_context(|| format!("writing {}", path))?;
+            }
+            if let Err(BuildError::CommandsFailed(report))
+            | Err(BuildError::DeadlineReached(report)) = &result
+            {
+                if !report.failures().is_empty() {
+                    report.failures().print_summary(&build_id);
+                }
+            }
+            result?;
+            if unverified > 0 {
+                anyhow::bail!("{} output(s) failed verification", unverified);
             }
         }
         break;
     }
+    if let Some(explain) = &explain {
+        explain.flush().context("writing explain file")?;
+    }
     // build log loading later
     if metrics_enabled {
+        eprintln!("build id: {}", build_id);
         ninja_metrics::dump();
     }
     Ok(())
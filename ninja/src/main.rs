@@ -14,16 +14,87 @@
  * limitations under the License.
  */
 
-use ninjars::{run, Config, DebugMode};
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
 
-fn read_debug_modes(args: &mut pico_args::Arguments) -> anyhow::Result<Vec<DebugMode>> {
+use anyhow::Context;
+use ninja_builder::{
+    diagnostics, status_format::StatusFormat, BuildError, ChildScheduling, DirectoryInputs, Outcome,
+};
+use ninja_parse::Limits;
+use ninjars::{run, Config, DebugMode, MessageFormat, Notify, Tool, WarningFlag, WORKER_TOKEN_ENV};
+
+#[cfg(feature = "count-allocations")]
+#[global_allocator]
+static ALLOCATOR: ninja_metrics::CountingAllocator = ninja_metrics::CountingAllocator;
+
+/// Memory to budget per job when picking `-j` adaptively. Large C++ translation units and links
+/// easily use this much.
+const MEMORY_PER_JOB: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Parses the `MemAvailable` line of /proc/meminfo, which is reported in kB.
+fn meminfo_available(meminfo: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+fn read_bytes(path: &str) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Memory left under the container's limit, for cgroup v2 and v1. None if there is no limit.
+fn cgroup_available() -> Option<u64> {
+    let (limit, usage) = match read_bytes("/sys/fs/cgroup/memory.max") {
+        Some(limit) => (limit, read_bytes("/sys/fs/cgroup/memory.current")?),
+        // v1 reports an absurdly large number when unlimited, which the min below takes care of.
+        None => (
+            read_bytes("/sys/fs/cgroup/memory/memory.limit_in_bytes")?,
+            read_bytes("/sys/fs/cgroup/memory/memory.usage_in_bytes")?,
+        ),
+    };
+    Some(limit.saturating_sub(usage))
+}
+
+fn available_memory() -> Option<u64> {
+    let system = std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| meminfo_available(&meminfo));
+    match (system, cgroup_available()) {
+        (Some(system), Some(cgroup)) => Some(std::cmp::min(system, cgroup)),
+        (system, cgroup) => system.or(cgroup),
+    }
+}
+
+/// The `-j` default. CPU count overstates capacity on laptops and in containers, where running
+/// out of memory is worse than running fewer jobs, so `adaptive` also caps it at one job per
+/// `MEMORY_PER_JOB` of available memory.
+fn default_parallelism(adaptive: bool) -> usize {
+    let cpus = num_cpus::get() + 1;
+    if !adaptive {
+        return cpus;
+    }
+    match available_memory() {
+        Some(bytes) => std::cmp::max(1, std::cmp::min(cpus, (bytes / MEMORY_PER_JOB) as usize)),
+        None => cpus,
+    }
+}
+
+fn read_debug_modes(args: &mut Args) -> anyhow::Result<Vec<DebugMode>> {
     let mut debug_modes: Vec<DebugMode> = Vec::new();
     while let Some(debug_mode) = args.opt_value_from_str("-d")? {
         if debug_mode == DebugMode::List {
             eprintln!(
                 r#" debugging modes:
-  stats        print operation counts/timing info
+  stats        print operation counts/timing info, and parse time per manifest file
+  redefinitions  warn about top-level variables reassigned after edges used them
   explain      explain what caused a command to execute
+  explain-file=PATH  write why every target was considered dirty or clean to PATH
   keepdepfile  don't delete depfiles after they're read by ninja
   keeprsp      don't delete @response files on success
 multiple modes can be enabled via -d FOO -d BAR"#
@@ -35,6 +106,174 @@ multiple modes can be enabled via -d FOO -d BAR"#
     Ok(debug_modes)
 }
 
+fn read_warning_flags(args: &mut Args) -> anyhow::Result<Vec<WarningFlag>> {
+    let mut warning_flags: Vec<WarningFlag> = Vec::new();
+    while let Some(warning_flag) = args.opt_value_from_str("-w")? {
+        if warning_flag == WarningFlag::List {
+            eprintln!(
+                r#"warning flags:
+  shellquote  warn about $in/$out in quotes expanding to paths that ninja quotes itself"#
+            );
+            std::process::exit(1);
+        }
+        warning_flags.push(warning_flag);
+    }
+    Ok(warning_flags)
+}
+
+fn parse_cpu_mask(mask: &str) -> Result<u64, std::num::ParseIntError> {
+    let digits = mask
+        .strip_prefix("0x")
+        .or_else(|| mask.strip_prefix("0X"))
+        .unwrap_or(mask);
+    u64::from_str_radix(digits, 16)
+}
+
+fn parse_workers(workers: &str) -> Result<Vec<String>, std::convert::Infallible> {
+    Ok(workers
+        .split(',')
+        .filter(|worker| !worker.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+fn read_manifest_limits(args: &mut Args) -> anyhow::Result<Limits> {
+    let defaults = Limits::default();
+    Ok(Limits {
+        max_include_depth: args
+            .opt_value_from_str("--max-include-depth")?
+            .unwrap_or(defaults.max_include_depth),
+        max_bytes: args
+            .opt_value_from_str("--max-manifest-bytes")?
+            .unwrap_or(defaults.max_bytes),
+        max_edges: args
+            .opt_value_from_str("--max-edges")?
+            .unwrap_or(defaults.max_edges),
+    })
+}
+
+/// Environment variable holding default flags, applied as if they came before the command line.
+const FLAGS_ENV: &str = "NINJARS_FLAGS";
+
+/// The command line, as pico-args reads it, remembering the flags read with a value. Those may be
+/// followed by their value as a separate argument, which `default_flags` has to know.
+struct Args {
+    args: pico_args::Arguments,
+    value_flags: Vec<&'static str>,
+}
+
+impl Args {
+    fn new(args: Vec<OsString>) -> Self {
+        Args {
+            args: pico_args::Arguments::from_vec(args),
+            value_flags: Vec::new(),
+        }
+    }
+
+    fn contains<A: Into<pico_args::Keys>>(&mut self, keys: A) -> bool {
+        self.args.contains(keys)
+    }
+
+    fn opt_value_from_str<T>(&mut self, flag: &'static str) -> Result<Option<T>, pico_args::Error>
+    where
+        T: FromStr,
+        <T as FromStr>::Err: Display,
+    {
+        self.value_flags.push(flag);
+        self.args.opt_value_from_str(flag)
+    }
+
+    fn opt_value_from_fn<T, E: Display>(
+        &mut self,
+        flag: &'static str,
+        f: fn(&str) -> Result<T, E>,
+    ) -> Result<Option<T>, pico_args::Error> {
+        self.value_flags.push(flag);
+        self.args.opt_value_from_fn(flag, f)
+    }
+
+    fn free(&mut self) -> Result<Vec<String>, pico_args::Error> {
+        std::mem::replace(&mut self.args, pico_args::Arguments::from_vec(Vec::new())).free()
+    }
+}
+
+/// Flags that take a value, found by parsing an empty command line, which reads every flag.
+fn value_flags() -> Vec<&'static str> {
+    let mut args = Args::new(Vec::new());
+    // Nothing is required, so this can only fail after all the flags have been read.
+    let _ = parse_config(&mut args);
+    args.value_flags
+}
+
+/// Flags that may be given more than once, so defaults add to the command line instead of
+/// being replaced by it.
+const REPEATABLE_FLAGS: &[&str] = &["-d", "-w"];
+
+fn flag_name(arg: &str) -> &str {
+    arg.split('=').next().unwrap_or(arg)
+}
+
+/// Splits the defaults into flags, each with its value if it takes a separate one. Anything that
+/// isn't a flag is dropped with a warning, since targets don't belong in the defaults.
+fn default_flags(defaults: &str) -> Vec<Vec<String>> {
+    let value_flags = value_flags();
+    let mut flags = Vec::new();
+    let mut words = defaults.split_whitespace();
+    while let Some(word) = words.next() {
+        if !word.starts_with('-') {
+            diagnostics::warn(
+                "flags",
+                format!("ignoring '{}' in {}, which is not a flag", word, FLAGS_ENV),
+            );
+            continue;
+        }
+        let mut flag = vec![word.to_owned()];
+        if value_flags.contains(&word) {
+            flag.extend(words.next().map(str::to_owned));
+        }
+        flags.push(flag);
+    }
+    flags
+}
+
+/// The command line, preceded by the defaults from `FLAGS_ENV` that it doesn't override.
+fn args_with_defaults() -> Vec<OsString> {
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let defaults = match std::env::var(FLAGS_ENV) {
+        Ok(defaults) => defaults,
+        Err(std::env::VarError::NotPresent) => return args,
+        Err(std::env::VarError::NotUnicode(_)) => {
+            diagnostics::warn(
+                "flags",
+                format!("ignoring {}, which is not UTF-8", FLAGS_ENV),
+            );
+            return args;
+        }
+    };
+    merge_defaults(&defaults, args)
+}
+
+fn merge_defaults(defaults: &str, args: Vec<OsString>) -> Vec<OsString> {
+    let given: Vec<&str> = args
+        .iter()
+        .filter_map(|arg| arg.to_str())
+        .take_while(|arg| *arg != "--")
+        .filter(|arg| arg.starts_with('-'))
+        .map(flag_name)
+        .collect();
+    let mut merged: Vec<OsString> = default_flags(defaults)
+        .into_iter()
+        .filter(|flag| {
+            let name = flag_name(&flag[0]);
+            REPEATABLE_FLAGS.contains(&name) || !given.contains(&name)
+        })
+        .flatten()
+        .map(OsString::from)
+        .collect();
+    merged.extend(args);
+    merged
+}
+
 fn print_usage() {
     let called_as = std::env::args().next();
     eprintln!(
@@ -49,17 +288,158 @@ options:
   -f FILE  specify input build file [default=build.ninja]
 
   -j N     run N jobs in parallel [default={}, derived from CPUs available]
+  --adaptive-jobs  without -j, also cap jobs at one per 2GB of available memory (here: {})
+  -k N     keep going until N jobs fail (0 means infinity) [default=1]
+  -v, --verbose  show all command lines while building
+  --stream  let commands print to the terminal as they run, like those in the console pool,
+            instead of showing their output when they finish
 
   -d MODE  enable debugging (use -d list to list modes)
+  -w FLAG  enable an optional warning (use -w list to list warnings)
+  -t TOOL  run a subtool (use -t list to list subtools)
+  -t query --dirty TARGET...  say whether each target is up to date, exiting with 1 if any
+                              is not, without building anything
+  -t targets [depth N | all | rule [RULE]]  list targets with their rules, to depth N (default 1,
+                                            0 for all) from the roots, every one, or those of RULE
+  -t compdb [RULE...]  print a JSON compilation database of the edges of RULEs, or of all
+  -t rerun OUTPUT  run the command that last built OUTPUT again, as recorded in the build log
+  -t clean [-g] [-r] [--allow-outside] [TARGET...]  remove built files, all but generator
+                                  outputs unless -g, or those TARGET needs, or with -r those of
+                                  the named rules; files outside the build directory are only
+                                  removed with --allow-outside
+  --profile=startup  time loading the manifest and converting it to tasks, without building
+
+  --check-manifest  parse and validate the build file, then exit without building
+  --no-rebuild-manifest  don't rebuild the build file before building the targets
+  --max-include-depth=N  fail if includes nest more than N deep [default={}]
+  --max-manifest-bytes=N  fail if the build file and its includes exceed N bytes [default={}]
+  --max-edges=N  fail if the build file describes more than N edges [default={}]
+  --directory-inputs MODE  how to treat directory inputs, 'warn' about their mtime [default]
+                           or compare a hash of their 'contents' with the last build's
+  --changed-since=REF  build only what depends on files git reports as changed since REF
+  --wait-for-lock  wait for another ninja building in the same directory, instead of failing
+  --nice=N  run commands N levels nicer than ninja itself, like nice -n
+  --affinity=MASK  only let commands run on the CPUs set in the hex MASK, like taskset (Linux)
+  --shell=PATH  run commands with PATH -c instead of /bin/sh -c
+  --command-timeout=SECS  kill and fail commands that run for longer than SECS
+  --deadline=SECS  start no commands after SECS, let running ones finish, and exit with 124 if
+                   anything was left unbuilt
+  --verify  after building, fail if a command that succeeded left an output missing or older
+            than its inputs
+  --failures-json=PATH  write the failed commands, and how each failed, to PATH as JSON
+  --message-format=FORMAT  also print errors in the build file and its includes as JSON on
+                           stdout, one object per line, if FORMAT is 'json' [default=human]
+  --dry-run-json  print the commands that would run, in order and with why, as JSON on stdout,
+                  without running them or rebuilding the build file
+  --frontend=PATH  stream build progress to the socket or named pipe at PATH, using ninja's
+                   frontend protocol
+  --notify  show a desktop notification with the result and duration when the build finishes
+  --notify-command=CMD  instead run CMD with sh when the build finishes, with NINJA_BUILD_RESULT
+                        (success or failure), NINJA_BUILD_SECONDS and NINJA_BUILD_MESSAGE set
+
+default flags can be set in the {} environment variable, separated by spaces. flags given
+on the command line take precedence, except -d and -w, which add to the defaults.
+
+the progress status is printed as NINJA_STATUS says, with placeholders %f finished, %t total,
+%s started, %r running, %u unstarted edges, %o finished per second, %p percentage finished,
+%e seconds elapsed, %w elapsed time and %% a '%' [default="[%f/%t] "].
+
+experimental distributed builds, over a filesystem shared by every machine:
+  --serve-worker=ADDR  run up to -j commands at once for a coordinator connecting to ADDR
+                       (host:port), instead of building. Start it in the build directory.
+                       a worker runs any command it is sent, as the user running it
+  --allow-remote-coordinators  let --serve-worker listen on addresses other than loopback
+  --workers=ADDR,...   run commands on the workers listening at each ADDR, instead of locally
+
+workers only take commands from coordinators that send the secret in the {} environment
+variable, which both need to have. it and the commands are sent unencrypted, so anyone who
+can reach a worker, or see its traffic, may be able to run commands on it. only let workers
+listen on networks where everyone is trusted.
     "#,
         called_as.as_deref().unwrap_or("ninjars"),
         env!("CARGO_PKG_VERSION"),
-        num_cpus::get() + 1,
+        default_parallelism(false),
+        default_parallelism(true),
+        Limits::default().max_include_depth,
+        Limits::default().max_bytes,
+        Limits::default().max_edges,
+        FLAGS_ENV,
+        WORKER_TOKEN_ENV,
     );
 }
 
+/// Everything but `--help` and `--version`, which are handled first.
+fn parse_config(args: &mut Args) -> anyhow::Result<Config> {
+    let adaptive_jobs = args.contains("--adaptive-jobs");
+    Ok(Config {
+        execution_dir: args.opt_value_from_str("-C")?,
+        parallelism: args
+            .opt_value_from_str("-j")?
+            .unwrap_or_else(|| default_parallelism(adaptive_jobs)),
+        failures_allowed: args.opt_value_from_str("-k")?.unwrap_or(1),
+        verbose: args.contains(["-v", "--verbose"]),
+        stream: args.contains("--stream"),
+        verify: args.contains("--verify"),
+        build_file: args
+            .opt_value_from_str("-f")?
+            .unwrap_or("build.ninja".to_owned()),
+        debug_modes: read_debug_modes(args)?,
+        warning_flags: read_warning_flags(args)?,
+        profile: args.opt_value_from_str("--profile")?,
+        check_manifest: args.contains("--check-manifest"),
+        no_rebuild_manifest: args.contains("--no-rebuild-manifest"),
+        manifest_limits: read_manifest_limits(args)?,
+        directory_inputs: args
+            .opt_value_from_str("--directory-inputs")?
+            .unwrap_or(DirectoryInputs::Warn),
+        frontend: args.opt_value_from_str("--frontend")?,
+        tool: match args.opt_value_from_str("-t")? {
+            Some(Tool::Query { .. }) => Some(Tool::Query {
+                dirty: args.contains("--dirty"),
+            }),
+            Some(Tool::Clean { .. }) => Some(Tool::Clean {
+                generator: args.contains("-g"),
+                rules: args.contains("-r"),
+                outside: args.contains("--allow-outside"),
+            }),
+            tool => tool,
+        },
+        changed_since: args.opt_value_from_str("--changed-since")?,
+        wait_for_lock: args.contains("--wait-for-lock"),
+        child_scheduling: ChildScheduling {
+            nice: args.opt_value_from_str("--nice")?,
+            affinity: args.opt_value_from_fn("--affinity", parse_cpu_mask)?,
+        },
+        shell: args.opt_value_from_str("--shell")?,
+        command_timeout: args
+            .opt_value_from_str("--command-timeout")?
+            .map(Duration::from_secs),
+        deadline: args
+            .opt_value_from_str("--deadline")?
+            .map(Duration::from_secs),
+        failures_json: args.opt_value_from_str("--failures-json")?,
+        dry_run_json: args.contains("--dry-run-json"),
+        workers: args
+            .opt_value_from_fn("--workers", parse_workers)?
+            .unwrap_or_default(),
+        serve_worker: args.opt_value_from_str("--serve-worker")?,
+        allow_remote_coordinators: args.contains("--allow-remote-coordinators"),
+        notify: match args.opt_value_from_str("--notify-command")? {
+            Some(command) => Some(Notify::Command(command)),
+            None if args.contains("--notify") => Some(Notify::Desktop),
+            None => None,
+        },
+        message_format: args
+            .opt_value_from_str("--message-format")?
+            .unwrap_or(MessageFormat::Human),
+        targets: args.free()?,
+        // Last, so that `value_flags` sees every flag even if this fails.
+        status_format: StatusFormat::from_env().context("reading NINJA_STATUS")?,
+    })
+}
+
 fn main() -> anyhow::Result<()> {
-    let mut args = pico_args::Arguments::from_env();
+    let mut args = Args::new(args_with_defaults());
     if args.contains(["-h", "--help"]) {
         print_usage();
         std::process::exit(1);
@@ -68,17 +448,105 @@ fn main() -> anyhow::Result<()> {
         println!("{}", env!("CARGO_PKG_VERSION"));
         std::process::exit(0);
     }
-    let config = Config {
-        execution_dir: args.opt_value_from_str("-C")?,
-        parallelism: args
-            .opt_value_from_str("-j")?
-            .unwrap_or_else(|| num_cpus::get() + 1),
-        build_file: args
-            .opt_value_from_str("-f")?
-            .unwrap_or("build.ninja".to_owned()),
-        debug_modes: read_debug_modes(&mut args)?,
-        targets: args.free()?,
-    };
+    let config = parse_config(&mut args)?;
 
-    run(config)
+    let result = run(config);
+    diagnostics::flush();
+    if let Some(BuildError::Interrupted(signal)) =
+        result.as_ref().err().and_then(|e| e.downcast_ref())
+    {
+        eprintln!("ninja: build stopped: interrupted by user.");
+        // As a shell reports a command killed by the signal.
+        std::process::exit(128 + signal);
+    }
+    if let Some(BuildError::DeadlineReached(report)) =
+        result.as_ref().err().and_then(|e| e.downcast_ref())
+    {
+        eprintln!(
+            "ninja: build stopped: deadline reached, {} edge(s) left to build.",
+            report.count(Outcome::Skipped)
+        );
+        // Like timeout(1), so CI can tell a time-boxed build from a failed one.
+        std::process::exit(124);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn merged(defaults: &str, args: &[&str]) -> Vec<String> {
+        let args = args.iter().map(OsString::from).collect();
+        merge_defaults(defaults, args)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn keep_going_takes_a_count() {
+        let failures_allowed = |args: &[&str]| {
+            let args = args.iter().map(OsString::from).collect();
+            parse_config(&mut Args::new(args)).unwrap().failures_allowed
+        };
+        assert_eq!(failures_allowed(&[]), 1);
+        assert_eq!(failures_allowed(&["-k", "3"]), 3);
+        // No limit, which `run` passes on to the schedulers as `usize::MAX`.
+        assert_eq!(failures_allowed(&["-k", "0"]), 0);
+        assert!(parse_config(&mut Args::new(vec!["-k".into(), "some".into()])).is_err());
+    }
+
+    #[test]
+    fn value_flags_are_what_the_parser_reads() {
+        let value_flags = value_flags();
+        for flag in &[
+            "-C",
+            "-j",
+            "-d",
+            "-t",
+            "--workers",
+            "--max-edges",
+            "--notify-command",
+        ] {
+            assert!(value_flags.contains(flag), "{} takes a value", flag);
+        }
+        for flag in &["-v", "--stream", "--notify", "--dirty"] {
+            assert!(!value_flags.contains(flag), "{} takes no value", flag);
+        }
+    }
+
+    #[test]
+    fn defaults_come_first() {
+        assert_eq!(merged("-j 4 -v", &["all"]), vec!["-j", "4", "-v", "all"]);
+        // A value flag's value goes along with it, and joined values are one word.
+        assert_eq!(
+            merged("-k 0 --shell=/bin/bash --stream", &[]),
+            vec!["-k", "0", "--shell=/bin/bash", "--stream"]
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_defaults() {
+        assert_eq!(
+            merged(
+                "-j 4 --shell=/bin/bash -v",
+                &["-j", "2", "--shell", "/bin/zsh"]
+            ),
+            vec!["-v", "-j", "2", "--shell", "/bin/zsh"]
+        );
+        assert_eq!(merged("-j 4", &["-j=2"]), vec!["-j=2"]);
+        // Except for flags that add up.
+        assert_eq!(
+            merged("-d stats -w shellquote", &["-d", "explain"]),
+            vec!["-d", "stats", "-w", "shellquote", "-d", "explain"]
+        );
+        // Nothing after `--` is a flag.
+        assert_eq!(merged("-j 4", &["--", "-j"]), vec!["-j", "4", "--", "-j"]);
+    }
+
+    #[test]
+    fn targets_in_defaults_are_ignored() {
+        assert_eq!(merged("-v all", &[]), vec!["-v"]);
+    }
 }
@@ -0,0 +1,99 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reporting errors in a form tools can read, for `--message-format`.
+
+use std::io::{self, Write};
+
+use ninja_builder::{build_id::BuildId, json_string};
+use ninja_parse::{Position, ProcessingError};
+use thiserror::Error;
+
+/// How errors in the manifest are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Only as text on stderr.
+    Human,
+    /// Also as a JSON object on its own line on stdout, like cargo's `--message-format=json`.
+    Json,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown message format '{0}', expected 'human' or 'json'")]
+pub struct MessageFormatError(String);
+
+impl std::str::FromStr for MessageFormat {
+    type Err = MessageFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            e => Err(MessageFormatError(e.to_owned())),
+        }
+    }
+}
+
+fn write_position(out: &mut impl Write, position: &Position) -> io::Result<()> {
+    let file = position
+        .source_name
+        .as_deref()
+        .map(|name| json_string(&String::from_utf8_lossy(name)));
+    write!(
+        out,
+        "\"file\":{},\"line\":{},\"column\":{}",
+        file.as_deref().unwrap_or("null"),
+        position.line,
+        position.column
+    )
+}
+
+/// Writes `error` as a `manifest-error` message: where it is, if known, and the `include` and
+/// `subninja` statements that led to that file, innermost first, so that generator authors can
+/// trace a bad fragment back to whatever included it. `build_id` is null when the manifest was
+/// only loaded for a tool.
+pub fn write_manifest_error(
+    error: &ProcessingError,
+    build_id: Option<&BuildId>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let build_id = build_id.map(|id| json_string(id.as_str()));
+    write!(
+        out,
+        "{{\"reason\":\"manifest-error\",\"build_id\":{},\"message\":{},",
+        build_id.as_deref().unwrap_or("null"),
+        json_string(&error.message())
+    )?;
+    match error.position() {
+        Some(position) => write_position(out, position)?,
+        None => write!(out, "\"file\":null,\"line\":null,\"column\":null")?,
+    }
+    write!(out, ",\"included_from\":[")?;
+    for (i, inclusion) in error.included_from().iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        let kind = if inclusion.subninja {
+            "subninja"
+        } else {
+            "include"
+        };
+        write!(out, "{{\"kind\":\"{}\",", kind)?;
+        write_position(out, &inclusion.position)?;
+        write!(out, "}}")?;
+    }
+    writeln!(out, "],\"rendered\":{}}}", json_string(&error.to_string()))
+}
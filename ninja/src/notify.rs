@@ -0,0 +1,111 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Telling the user a build finished, for builds long enough that they went off to do something
+//! else.
+
+use std::{process::Command, time::Duration};
+
+use ninja_builder::BuildError;
+
+/// How `--notify` tells the user.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Notify {
+    /// A desktop notification, with `notify-send` or, on macOS, `osascript`.
+    Desktop,
+    /// Run this with `sh -c`. `NINJA_BUILD_RESULT` is `success` or `failure`,
+    /// `NINJA_BUILD_SECONDS` the whole seconds the build took and `NINJA_BUILD_MESSAGE` the same
+    /// summary a desktop notification would show.
+    Command(String),
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+fn summary(result: &anyhow::Result<()>, duration: Duration) -> String {
+    let took = format_duration(duration);
+    match result {
+        Ok(()) => format!("build finished in {}", took),
+        Err(e) => match e.downcast_ref::<BuildError>() {
+            Some(BuildError::CommandsFailed(report)) => format!(
+                "build failed in {}, {} command(s) failed",
+                took,
+                report.failures().len()
+            ),
+            Some(BuildError::Interrupted(_)) => format!("build interrupted after {}", took),
+            Some(BuildError::DeadlineReached(_)) => {
+                format!("build stopped at its deadline after {}", took)
+            }
+            _ => format!("build failed in {}: {}", took, e),
+        },
+    }
+}
+
+const DESKTOP_NOTIFIER: &str = if cfg!(target_os = "macos") {
+    "osascript"
+} else {
+    "notify-send"
+};
+
+fn desktop_command(message: &str) -> Command {
+    if cfg!(target_os = "macos") {
+        let mut command = Command::new(DESKTOP_NOTIFIER);
+        command.arg("-e").arg(format!(
+            "display notification {:?} with title \"ninja\"",
+            message
+        ));
+        command
+    } else {
+        let mut command = Command::new(DESKTOP_NOTIFIER);
+        command.arg("ninja").arg(message);
+        command
+    }
+}
+
+/// Reports how the build went. Failing to notify is only a warning, since the build itself is
+/// what the exit status should reflect.
+pub fn notify(how: &Notify, result: &anyhow::Result<()>, duration: Duration) {
+    let message = summary(result, duration);
+    let (program, mut command) = match how {
+        Notify::Desktop => (DESKTOP_NOTIFIER, desktop_command(&message)),
+        Notify::Command(script) => {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(script)
+                .env(
+                    "NINJA_BUILD_RESULT",
+                    if result.is_ok() { "success" } else { "failure" },
+                )
+                .env("NINJA_BUILD_SECONDS", duration.as_secs().to_string())
+                .env("NINJA_BUILD_MESSAGE", &message);
+            ("--notify-command", command)
+        }
+    };
+    match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("ninja: warning: notifying with {}: {}", program, status),
+        Err(e) => eprintln!("ninja: warning: notifying with {}: {}", program, e),
+    }
+}
@@ -0,0 +1,604 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Subtools, run with `-t TOOL` instead of building.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use thiserror::Error;
+
+use anyhow::Context;
+use ninja_builder::{
+    build_id::BUILD_ID_ENV,
+    build_log::{hash_command, SharedBuildLog},
+    caching_mtime_rebuilder,
+    compdb::write_compdb,
+    deps_log::SharedDepsLog,
+    diagnostics, is_up_to_date,
+    platform::build_file_encoding,
+    task::{description_to_tasks_with_analysis, description_to_tasks_with_start, Key, Task, Tasks},
+};
+
+use ninja_parse::repr::ManifestFile;
+
+use crate::{load_description, state_dir, target_bytes, Config, BUILD_LOG_PATH, DEPS_LOG_PATH};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Tool {
+    /// With `generator`, also remove the outputs of generator rules. With `rules`, the targets
+    /// name rules instead. Files outside the build directory are only removed with `outside`.
+    Clean {
+        generator: bool,
+        rules: bool,
+        outside: bool,
+    },
+    /// A compilation database of the edges of the rules named as targets, or of every rule.
+    Compdb,
+    Includes,
+    List,
+    /// With `dirty`, only say whether each target is up to date.
+    Query {
+        dirty: bool,
+    },
+    /// Runs the command of the edge producing the target again, whether or not it is dirty, once
+    /// the build log confirms it is the command that last built it.
+    Rerun,
+    StatsGraph,
+    Targets,
+    Wincodepage,
+}
+
+/// Names and descriptions, in the order `-t list` shows them.
+const TOOLS: &[(&str, &str)] = &[
+    ("clean", "clean built files"),
+    ("compdb", "dump JSON compilation database to stdout"),
+    (
+        "includes",
+        "show the files the manifest includes, with their rules and edges",
+    ),
+    ("list", "list available tools"),
+    (
+        "query",
+        "show inputs/outputs for a path, or with --dirty whether it is up to date",
+    ),
+    (
+        "rerun",
+        "run the command that last built an output again, even if it is up to date",
+    ),
+    (
+        "stats-graph",
+        "list inputs no edge produces and edges the targets don't need",
+    ),
+    ("targets", "list targets by their rule or depth in the DAG"),
+    ("wincodepage", "print the Windows code page used by ninja"),
+];
+
+#[derive(Error, Debug)]
+#[error("unknown tool '{0}', use '-t list' to list subtools")]
+pub struct ToolError(String);
+
+impl std::str::FromStr for Tool {
+    type Err = ToolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clean" => Ok(Tool::Clean {
+                generator: false,
+                rules: false,
+                outside: false,
+            }),
+            "compdb" => Ok(Tool::Compdb),
+            "includes" => Ok(Tool::Includes),
+            "list" => Ok(Tool::List),
+            "query" => Ok(Tool::Query { dirty: false }),
+            "rerun" => Ok(Tool::Rerun),
+            "stats-graph" => Ok(Tool::StatsGraph),
+            "targets" => Ok(Tool::Targets),
+            "wincodepage" => Ok(Tool::Wincodepage),
+            e => Err(ToolError(e.to_owned())),
+        }
+    }
+}
+
+/// The edge producing `key` and its task. The outputs of a multi-output edge each retrieve the
+/// edge's key.
+fn edge_for<'a>(tasks: &'a Tasks, key: &'a Key) -> Option<(&'a Key, &'a Task)> {
+    let task = tasks.task(key)?;
+    Some(match task.dependencies() {
+        [multi @ Key::Multi(_)] => (multi, tasks.task(multi).unwrap_or(task)),
+        _ => (key, task),
+    })
+}
+
+/// The name of the rule `edge` was created from, with edges without one being phony.
+fn rule_name(tasks: &Tasks, edge: &Key) -> String {
+    tasks.rule_for(edge).map_or("phony".into(), |rule| {
+        String::from_utf8_lossy(&rule.name).into_owned()
+    })
+}
+
+/// Prints the edge producing `key` and the edges using it, like ninja's `-t query`. Fails if
+/// neither exists, since the manifest doesn't mention `key` then.
+fn print_query(tasks: &Tasks, key: &Key) -> anyhow::Result<()> {
+    let edge = edge_for(tasks, key);
+    let mut users: Vec<&Key> = tasks
+        .all_tasks()
+        .iter()
+        .filter(|(user, task)| {
+            // Skip the outputs of multi-output edges, which only stand for the edge.
+            !matches!(task.dependencies(), [Key::Multi(_)] if user.is_path())
+                && task
+                    .dependencies()
+                    .iter()
+                    .chain(task.order_dependencies())
+                    .any(|dependency| dependency == key)
+        })
+        .map(|(user, _)| user)
+        .collect();
+    if edge.is_none() && users.is_empty() {
+        anyhow::bail!("unknown target '{}'", key.display().exact());
+    }
+    println!("{}:", key.display().exact());
+    if let Some((edge, task)) = edge {
+        println!("  input: {}", rule_name(tasks, edge));
+        for input in task.explicit_dependencies() {
+            println!("    {}", input.display().exact());
+        }
+        for input in task.implicit_dependencies() {
+            println!("    | {}", input.display().exact());
+        }
+        for input in task.order_dependencies() {
+            println!("    || {}", input.display().exact());
+        }
+    }
+    users.sort();
+    println!("  outputs:");
+    for user in users {
+        println!("    {}", user.display().exact());
+    }
+    Ok(())
+}
+
+/// Prints `keys` and what they are built from, down to `depth` levels with 0 meaning all of them,
+/// like ninja's `-t targets depth`.
+fn print_targets(tasks: &Tasks, keys: &[&Key], depth: usize, indent: usize) {
+    for key in keys {
+        print!("{}", "  ".repeat(indent));
+        match edge_for(tasks, key) {
+            Some((edge, task)) => {
+                println!("{}: {}", key.display().exact(), rule_name(tasks, edge));
+                if depth != 1 {
+                    let inputs: Vec<&Key> = task
+                        .dependencies()
+                        .iter()
+                        .chain(task.order_dependencies())
+                        .collect();
+                    print_targets(tasks, &inputs, depth.saturating_sub(1), indent + 1);
+                }
+            }
+            None => println!("{}", key.display().exact()),
+        }
+    }
+}
+
+/// Targets no edge uses, sorted.
+fn root_targets(tasks: &Tasks) -> Vec<&Key> {
+    let used: HashSet<&Key> = tasks
+        .all_tasks()
+        .values()
+        .flat_map(|task| task.dependencies().iter().chain(task.order_dependencies()))
+        .collect();
+    let mut roots: Vec<&Key> = tasks
+        .all_tasks()
+        .keys()
+        .filter(|key| key.is_path() && !used.contains(key))
+        .collect();
+    roots.sort();
+    roots
+}
+
+/// Adds the files a command task writes: its outputs and depfile.
+/// Each file indented under the one that loaded it.
+fn print_includes(files: &[ManifestFile]) {
+    let mut depths: Vec<usize> = Vec::with_capacity(files.len());
+    for file in files {
+        // Files come after the file that loaded them.
+        let depth = file.parent.map_or(0, |parent| depths[parent] + 1);
+        depths.push(depth);
+        let kind = match (file.parent, file.subninja) {
+            (None, _) => "",
+            (Some(_), false) => "include ",
+            (Some(_), true) => "subninja ",
+        };
+        println!(
+            "{:indent$}{}{} ({} rule(s), {} edge(s))",
+            "",
+            kind,
+            String::from_utf8_lossy(&file.path),
+            file.rules,
+            file.edges,
+            indent = 2 * depth
+        );
+    }
+}
+
+fn add_built_files(files: &mut BTreeSet<Vec<u8>>, key: &Key, task: &Task) {
+    if task.is_command() {
+        files.extend(key.iter().map(|path| path.as_bytes().to_vec()));
+        files.extend(task.depfile.iter().map(|path| path.as_bytes().to_vec()));
+        files.extend(task.rspfile.iter().map(|rsp| rsp.path.as_bytes().to_vec()));
+    }
+}
+
+/// Adds the files built for `key` and everything it depends on, like ninja's `-t clean TARGET`.
+fn add_target_files(
+    tasks: &Tasks,
+    key: &Key,
+    visited: &mut HashSet<Key>,
+    files: &mut BTreeSet<Vec<u8>>,
+) {
+    if !visited.insert(key.clone()) {
+        return;
+    }
+    if let Some(task) = tasks.task(key) {
+        add_built_files(files, key, task);
+        for dependency in task.dependencies().iter().chain(task.order_dependencies()) {
+            add_target_files(tasks, dependency, visited, files);
+        }
+    }
+}
+
+/// Whether removing `path` would remove something outside `dir`, which must be canonical, or `dir`
+/// itself. Symlinks leading to the file are followed, but not the file itself, since removing a
+/// symlink leaves its target alone. Paths that don't resolve have nothing to remove.
+fn is_outside(dir: &Path, path: &Path) -> bool {
+    let resolved = match path.file_name() {
+        Some(name) => {
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            std::fs::canonicalize(parent).map(|parent| parent.join(name))
+        }
+        None => std::fs::canonicalize(path),
+    };
+    match resolved {
+        Ok(resolved) => resolved == dir || !resolved.starts_with(dir),
+        Err(_) => false,
+    }
+}
+
+/// Removes the files that exist and prints how many there were, like ninja's cleaner. Failing to
+/// remove one file doesn't stop the others from being removed. Unless `outside` allows it, nothing
+/// is removed if any file resolves to somewhere outside the current directory, since a manifest
+/// naming such outputs is more likely mistaken than meant to be cleaned.
+fn remove_files(files: &BTreeSet<Vec<u8>>, outside: bool) -> anyhow::Result<()> {
+    if !outside {
+        let dir = std::env::current_dir()
+            .and_then(std::fs::canonicalize)
+            .context("finding the current directory")?;
+        let mut refused = 0;
+        for file in files {
+            let path = Path::new(OsStr::from_bytes(file));
+            if is_outside(&dir, path) {
+                eprintln!(
+                    "ninja: error: refusing to remove '{}', which is outside the build directory",
+                    path.display()
+                );
+                refused += 1;
+            }
+        }
+        if refused > 0 {
+            anyhow::bail!(
+                "refused to clean {} file(s) outside the build directory; \
+                 pass --allow-outside to remove them",
+                refused
+            );
+        }
+    }
+    println!("Cleaning...");
+    let mut removed = 0;
+    let mut failed = 0;
+    for file in files {
+        let path = Path::new(OsStr::from_bytes(file));
+        let result = match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => std::fs::remove_dir(path),
+            Ok(_) => std::fs::remove_file(path),
+            Err(_) => continue,
+        };
+        match result {
+            Ok(()) => removed += 1,
+            Err(e) => {
+                eprintln!("ninja: error: remove({}): {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} files.", removed);
+    if failed > 0 {
+        anyhow::bail!("failed to remove {} file(s)", failed);
+    }
+    Ok(())
+}
+
+/// Runs the command of `task`, for `edge`, as a build would, saying what it relies on and how it
+/// exited. Exits with the command's status if it fails.
+fn rerun(edge: &Key, task: &Task, config: &Config) -> anyhow::Result<()> {
+    let command = task.command().unwrap();
+    let shell = config.shell.as_deref().unwrap_or("/bin/sh");
+    let dir = edge.namespace().map_or(Path::new("."), |namespace| {
+        Path::new(OsStr::from_bytes(namespace))
+    });
+    println!("command: {}", command);
+    println!("directory: {}", dir.display());
+    println!("shell: {} -c", shell);
+    println!(
+        "environment: this process's, without the {} a build sets",
+        BUILD_ID_ENV
+    );
+    // Left in place afterwards, so the command can be run again by hand.
+    if let Some(rspfile) = &task.rspfile {
+        let path = Path::new(OsStr::from_bytes(rspfile.path.as_bytes()));
+        std::fs::write(path, &rspfile.content)
+            .with_context(|| format!("writing {}", path.display()))?;
+        println!("response file: {}", path.display());
+    }
+    let status = std::process::Command::new(shell)
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .stdin(std::process::Stdio::null())
+        .status()
+        .with_context(|| format!("running {}", shell))?;
+    println!("{}", status);
+    if !status.success() {
+        diagnostics::flush();
+        // As a shell reports a command killed by a signal.
+        std::process::exit(status.code().unwrap_or_else(|| {
+            128 + std::os::unix::process::ExitStatusExt::signal(&status).unwrap_or(0)
+        }));
+    }
+    Ok(())
+}
+
+pub(crate) fn run_tool(tool: &Tool, config: &Config) -> anyhow::Result<()> {
+    match tool {
+        Tool::Clean {
+            generator,
+            rules,
+            outside,
+        } => {
+            let (tasks, requested) = description_to_tasks_with_start(
+                load_description(config)?,
+                Some(target_bytes(config)),
+            );
+            let mut files = BTreeSet::new();
+            if *rules {
+                if config.targets.is_empty() {
+                    anyhow::bail!("-t clean -r expects at least one rule");
+                }
+                for name in &config.targets {
+                    let name = name.as_bytes();
+                    if !tasks.rules().iter().any(|rule| rule.name == name) {
+                        anyhow::bail!("unknown rule '{}'", String::from_utf8_lossy(name));
+                    }
+                    for (key, task) in tasks.all_tasks() {
+                        if matches!(tasks.rule_for(key), Some(rule) if rule.name == name) {
+                            add_built_files(&mut files, key, task);
+                        }
+                    }
+                }
+            } else if !config.targets.is_empty() {
+                let mut visited = HashSet::new();
+                for target in requested.expect("targets were named") {
+                    let key = Key::Path(target);
+                    if tasks.task(&key).is_none() {
+                        anyhow::bail!("unknown target '{}'", key.display());
+                    }
+                    add_target_files(&tasks, &key, &mut visited, &mut files);
+                }
+            } else {
+                for (key, task) in tasks.all_tasks() {
+                    if *generator || !task.generator {
+                        add_built_files(&mut files, key, task);
+                    }
+                }
+            }
+            remove_files(&files, *outside)?;
+        }
+        Tool::Compdb => {
+            let (tasks, _) = description_to_tasks_with_start(load_description(config)?, None);
+            let rules: Vec<&[u8]> = config.targets.iter().map(|rule| rule.as_bytes()).collect();
+            let base = std::env::current_dir().context("finding the current directory")?;
+            write_compdb(&tasks, &rules, &base, &mut std::io::stdout().lock())?;
+        }
+        Tool::Includes => print_includes(&load_description(config)?.files),
+        Tool::List => {
+            println!("ninja subtools:");
+            for (name, description) in TOOLS {
+                println!("{:>11}  {}", name, description);
+            }
+        }
+        Tool::Query { dirty } => {
+            if config.targets.is_empty() {
+                anyhow::bail!("-t query expects at least one target");
+            }
+            let repr = load_description(config)?;
+            let state_dir = state_dir(Path::new("."), &repr);
+            let (tasks, requested) =
+                description_to_tasks_with_start(repr, Some(target_bytes(config)));
+            let requested = requested.expect("targets were named");
+            if !dirty {
+                for target in requested {
+                    print_query(&tasks, &Key::Path(target))?;
+                }
+                return Ok(());
+            }
+            let build_log = SharedBuildLog::load(state_dir.join(BUILD_LOG_PATH))
+                .with_context(|| format!("loading {}", BUILD_LOG_PATH))?;
+            let deps_log = SharedDepsLog::load(state_dir.join(DEPS_LOG_PATH))
+                .with_context(|| format!("loading {}", DEPS_LOG_PATH))?;
+            let rebuilder = caching_mtime_rebuilder(config.directory_inputs)
+                .build_log(build_log)
+                .deps_log(deps_log);
+            let mut dirty_targets = 0;
+            for target in requested {
+                let key = Key::Path(target);
+                let up_to_date = is_up_to_date(&rebuilder, &tasks, &key)?;
+                if !up_to_date {
+                    dirty_targets += 1;
+                }
+                println!(
+                    "{}: {}",
+                    key.display().exact(),
+                    if up_to_date { "up to date" } else { "dirty" }
+                );
+            }
+            // Scripts only need the exit status.
+            if dirty_targets > 0 {
+                diagnostics::flush();
+                std::process::exit(1);
+            }
+        }
+        Tool::Rerun => {
+            if config.targets.len() != 1 {
+                anyhow::bail!("-t rerun expects exactly one output");
+            }
+            let repr = load_description(config)?;
+            let state_dir = state_dir(Path::new("."), &repr);
+            let (tasks, requested) =
+                description_to_tasks_with_start(repr, Some(target_bytes(config)));
+            let target = requested.expect("targets were named").remove(0);
+            let key = Key::Path(target.clone());
+            let (edge, task) = match edge_for(&tasks, &key) {
+                Some(edge) => edge,
+                None => anyhow::bail!("unknown target '{}'", key.display()),
+            };
+            let command = match task.command() {
+                Some(command) => command,
+                None => anyhow::bail!("'{}' is not built by a command", key.display()),
+            };
+            // The log only has a hash of the command, so the manifest's has to be the same one.
+            let build_log = SharedBuildLog::load(state_dir.join(BUILD_LOG_PATH))
+                .with_context(|| format!("loading {}", BUILD_LOG_PATH))?;
+            match build_log.command_hash(target.as_bytes()) {
+                None => anyhow::bail!(
+                    "'{}' is not in {}, so no command has been recorded for it",
+                    key.display(),
+                    BUILD_LOG_PATH
+                ),
+                Some(hash) if hash != hash_command(command.as_bytes()) => anyhow::bail!(
+                    "the command for '{}' is not the one {} recorded, which only records \
+                     commands that succeeded; build it to record this one",
+                    key.display(),
+                    BUILD_LOG_PATH
+                ),
+                Some(_) => {}
+            }
+            rerun(edge, task, config)?;
+        }
+        Tool::StatsGraph => {
+            let (_, requested, analysis) = description_to_tasks_with_analysis(
+                load_description(config)?,
+                Some(target_bytes(config)),
+            );
+            println!("edges: {}", analysis.edges);
+            println!("inputs no edge produces: {}", analysis.orphan_inputs.len());
+            for input in &analysis.orphan_inputs {
+                println!("  {}", input.display().exact());
+            }
+            // Without defaults or named targets, everything is requested.
+            if requested.is_some() {
+                println!(
+                    "edges the requested targets don't need: {}",
+                    analysis.unreachable_edges.len()
+                );
+                for edge in &analysis.unreachable_edges {
+                    println!("  {}", edge.display().exact());
+                }
+            }
+        }
+        Tool::Targets => {
+            let (tasks, _) = description_to_tasks_with_start(load_description(config)?, None);
+            let args: Vec<&str> = config.targets.iter().map(String::as_str).collect();
+            match args.as_slice() {
+                [] | ["depth"] => print_targets(&tasks, &root_targets(&tasks), 1, 0),
+                ["depth", depth] => {
+                    let depth = depth
+                        .parse()
+                        .with_context(|| format!("invalid depth '{}'", depth))?;
+                    print_targets(&tasks, &root_targets(&tasks), depth, 0);
+                }
+                ["all"] => {
+                    let mut outputs: Vec<&Key> = tasks
+                        .all_tasks()
+                        .keys()
+                        .filter(|key| key.is_path())
+                        .collect();
+                    outputs.sort();
+                    for output in outputs {
+                        if let Some((edge, _)) = edge_for(&tasks, output) {
+                            println!("{}: {}", output.display().exact(), rule_name(&tasks, edge));
+                        }
+                    }
+                }
+                ["rule"] => {
+                    // Without a rule, the inputs no edge produces.
+                    let sources: BTreeSet<String> = tasks
+                        .all_tasks()
+                        .values()
+                        .flat_map(|task| {
+                            task.dependencies().iter().chain(task.order_dependencies())
+                        })
+                        .filter(|input| tasks.task(input).is_none())
+                        .map(|key| key.display().exact().to_string())
+                        .collect();
+                    for source in sources {
+                        println!("{}", source);
+                    }
+                }
+                ["rule", rule] => {
+                    let outputs: BTreeSet<String> = tasks
+                        .all_tasks()
+                        .keys()
+                        .filter(|key| key.is_path())
+                        .filter(|key| {
+                            matches!(edge_for(&tasks, key), Some((edge, _)) if rule_name(&tasks, edge) == *rule)
+                        })
+                        .map(|key| key.display().exact().to_string())
+                        .collect();
+                    for output in outputs {
+                        println!("{}", output);
+                    }
+                }
+                _ => anyhow::bail!(
+                    "unknown target tool mode '{}', use depth N, all or rule RULE",
+                    args.join(" ")
+                ),
+            }
+        }
+        Tool::Wincodepage => match build_file_encoding() {
+            Some(encoding) => println!("Build file encoding: {}", encoding),
+            None => anyhow::bail!("-t wincodepage is only available on Windows"),
+        },
+    }
+    Ok(())
+}
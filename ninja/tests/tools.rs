@@ -0,0 +1,185 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Runs the subtools against a small manifest.
+
+use std::{path::Path, process::Command};
+
+use tempfile::TempDir;
+
+/// `foo.c` and `foo.h` compile to `foo.o`, which links into `app`, the default through `all`.
+const MANIFEST: &str = "\
+rule cc
+  command = cat $in > $out
+rule link
+  command = cat $in > $out
+build foo.o: cc foo.c | foo.h
+build app: link foo.o
+build all: phony app
+default all
+";
+
+/// A directory with `MANIFEST` and its sources in it, removed when dropped.
+fn project() -> TempDir {
+    let dir = tempfile::Builder::new()
+        .prefix("ninja-tools-")
+        .tempdir()
+        .unwrap();
+    std::fs::write(dir.path().join("build.ninja"), MANIFEST).unwrap();
+    std::fs::write(dir.path().join("foo.c"), "int main;\n").unwrap();
+    std::fs::write(dir.path().join("foo.h"), "").unwrap();
+    dir
+}
+
+/// Runs ninja in `dir` with `args`, returning whether it succeeded and what it printed to stdout.
+fn ninja(dir: &Path, args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_ninja"))
+        .args(args)
+        .current_dir(dir)
+        .env_remove("NINJARS_FLAGS")
+        .env_remove("NINJA_STATUS")
+        .output()
+        .unwrap();
+    // Shown if the test fails.
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn query() {
+    let dir = project();
+    assert_eq!(
+        ninja(dir.path(), &["-t", "query", "foo.o"]),
+        (
+            true,
+            "foo.o:\n  input: cc\n    foo.c\n    | foo.h\n  outputs:\n    app\n".to_owned()
+        )
+    );
+    assert!(!ninja(dir.path(), &["-t", "query", "missing"]).0);
+}
+
+#[test]
+fn query_dirty() {
+    let dir = project();
+    assert_eq!(
+        ninja(dir.path(), &["-t", "query", "--dirty", "app"]),
+        (false, "app: dirty\n".to_owned())
+    );
+    assert!(ninja(dir.path(), &[]).0);
+    assert_eq!(
+        ninja(dir.path(), &["-t", "query", "--dirty", "app"]),
+        (true, "app: up to date\n".to_owned())
+    );
+}
+
+#[test]
+fn clean() {
+    let dir = project();
+    assert!(ninja(dir.path(), &[]).0);
+    assert_eq!(
+        ninja(dir.path(), &["-t", "clean", "foo.o"]),
+        (true, "Cleaning...\n1 files.\n".to_owned())
+    );
+    assert!(!dir.path().join("foo.o").exists());
+    assert!(dir.path().join("app").exists());
+    assert_eq!(
+        ninja(dir.path(), &["-t", "clean"]),
+        (true, "Cleaning...\n1 files.\n".to_owned())
+    );
+    assert!(!dir.path().join("app").exists());
+    assert!(dir.path().join("foo.c").exists());
+}
+
+#[test]
+fn targets() {
+    let dir = project();
+    let targets = |args: &[&str]| ninja(dir.path(), &[&["-t", "targets"], args].concat());
+    assert_eq!(targets(&[]), (true, "all: phony\n".to_owned()));
+    assert_eq!(
+        targets(&["depth", "0"]),
+        (
+            true,
+            "all: phony\n  app: link\n    foo.o: cc\n      foo.c\n      foo.h\n".to_owned()
+        )
+    );
+    assert_eq!(
+        targets(&["all"]),
+        (true, "all: phony\napp: link\nfoo.o: cc\n".to_owned())
+    );
+    assert_eq!(targets(&["rule"]), (true, "foo.c\nfoo.h\n".to_owned()));
+    assert_eq!(targets(&["rule", "cc"]), (true, "foo.o\n".to_owned()));
+    assert!(!targets(&["sideways"]).0);
+}
+
+#[test]
+fn compdb() {
+    let dir = project();
+    let directory = std::fs::canonicalize(dir.path()).unwrap();
+    assert_eq!(
+        ninja(dir.path(), &["-t", "compdb", "cc"]),
+        (
+            true,
+            format!(
+                "[\n  {{\n    \"directory\": \"{}\",\n    \"command\": \"cat foo.c > foo.o\",\n    \
+                 \"file\": \"foo.c\",\n    \"output\": \"foo.o\"\n  }}\n]\n",
+                directory.display()
+            )
+        )
+    );
+}
+
+#[test]
+fn includes() {
+    let dir = project();
+    let write = |name: &str, contents: &str| std::fs::write(dir.path().join(name), contents);
+    write(
+        "build.ninja",
+        "include rules.ninja\nsubninja sub.ninja\nbuild foo.o: cc foo.c\n",
+    )
+    .unwrap();
+    write("rules.ninja", "rule cc\n  command = cat $in > $out\n").unwrap();
+    write("sub.ninja", "build bar.o: cc foo.c\n").unwrap();
+    assert_eq!(
+        ninja(dir.path(), &["-t", "includes"]),
+        (
+            true,
+            "build.ninja (0 rule(s), 1 edge(s))\n  include rules.ninja (1 rule(s), 0 edge(s))\n  \
+             subninja sub.ninja (0 rule(s), 1 edge(s))\n"
+                .to_owned()
+        )
+    );
+}
+
+#[test]
+fn rerun() {
+    let dir = project();
+    // Nothing has recorded the command yet.
+    assert!(!ninja(dir.path(), &["-t", "rerun", "foo.o"]).0);
+    assert!(ninja(dir.path(), &[]).0);
+    std::fs::write(dir.path().join("foo.o"), "stale").unwrap();
+    let (succeeded, printed) = ninja(dir.path(), &["-t", "rerun", "foo.o"]);
+    assert!(succeeded);
+    assert!(printed.starts_with("command: cat foo.c > foo.o\ndirectory: .\n"));
+    assert!(printed.ends_with("exit status: 0\n"));
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("foo.o")).unwrap(),
+        "int main;\n"
+    );
+    assert!(!ninja(dir.path(), &["-t", "rerun", "all"]).0);
+}
@@ -15,25 +15,71 @@
  */
 
 use super::env::Env;
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap};
 
+/// Terms borrow from the manifest being parsed, so that the bulk of a manifest, its build edges,
+/// can be evaluated without copying every token. Anything that outlives the parse of a single
+/// file, like rule bindings, is converted to an owned `Expr<'static>`.
 #[derive(Debug)]
-pub enum Term {
-    Literal(Vec<u8>),
-    Reference(Vec<u8>),
+pub enum Term<'a> {
+    Literal(Cow<'a, [u8]>),
+    Reference(Cow<'a, [u8]>),
+}
+
+impl<'a> Term<'a> {
+    pub fn into_owned(self) -> Term<'static> {
+        match self {
+            Term::Literal(v) => Term::Literal(Cow::Owned(v.into_owned())),
+            Term::Reference(v) => Term::Reference(Cow::Owned(v.into_owned())),
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct Expr(pub Vec<Term>);
+pub struct Expr<'a>(pub Vec<Term<'a>>);
+
+impl<'a> Expr<'a> {
+    pub fn into_owned(self) -> Expr<'static> {
+        Expr(self.0.into_iter().map(Term::into_owned).collect())
+    }
 
-impl Expr {
     pub fn eval(&self, env: &Env) -> Vec<u8> {
         let mut result = Vec::new();
         for term in &self.0 {
             match term {
                 Term::Literal(bytes) => result.extend_from_slice(bytes),
                 Term::Reference(name) => {
-                    result.extend(env.lookup(name.as_slice()).unwrap_or_default());
+                    result.extend(env.lookup(name.as_ref()).unwrap_or_default());
+                }
+            }
+        }
+        result
+    }
+
+    /// Whether the expression refers to the variable `name`.
+    pub fn references(&self, name: &[u8]) -> bool {
+        self.0
+            .iter()
+            .any(|term| matches!(term, Term::Reference(r) if r.as_ref() == name))
+    }
+
+    /// The expression as it could be written in a manifest, with references in `${name}` form.
+    pub fn to_template(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        for term in &self.0 {
+            match term {
+                Term::Literal(bytes) => {
+                    for &c in bytes.iter() {
+                        if c == b'$' {
+                            result.push(b'$');
+                        }
+                        result.push(c);
+                    }
+                }
+                Term::Reference(name) => {
+                    result.extend_from_slice(b"${");
+                    result.extend_from_slice(name);
+                    result.push(b'}');
                 }
             }
         }
@@ -47,7 +93,7 @@ impl Expr {
                 Term::Literal(bytes) => result.extend_from_slice(bytes),
                 Term::Reference(name) => {
                     result.extend(
-                        env.lookup_for_build(rule, name.as_slice())
+                        env.lookup_for_build(rule, name.as_ref())
                             .unwrap_or_default(),
                     );
                 }
@@ -57,20 +103,84 @@ impl Expr {
     }
 }
 
+/// An expression prepared once per rule, with its literals joined and each reference sorted by
+/// where an edge finds its value, so that evaluating it for an edge only fills in the variables.
+#[derive(Debug)]
+pub struct Template {
+    spans: Vec<Span>,
+    // The bytes of all literals, which every evaluation needs at least.
+    literal_len: usize,
+}
+
+#[derive(Debug)]
+enum Span {
+    Literal(Vec<u8>),
+    /// Bound by the rule, and evaluated again for each edge.
+    Rule(Vec<u8>),
+    /// Bound by the edge or the scopes around it.
+    Scope(Vec<u8>),
+}
+
+impl Template {
+    pub fn new(expr: &Expr, rule: &Rule) -> Self {
+        let mut spans = Vec::with_capacity(expr.0.len());
+        let mut literal_len = 0;
+        for term in &expr.0 {
+            match (term, spans.last_mut()) {
+                (Term::Literal(bytes), Some(Span::Literal(joined))) => {
+                    joined.extend_from_slice(bytes)
+                }
+                (Term::Literal(bytes), _) => spans.push(Span::Literal(bytes.to_vec())),
+                (Term::Reference(name), _) if rule.bindings.contains_key(name.as_ref()) => {
+                    spans.push(Span::Rule(name.to_vec()))
+                }
+                (Term::Reference(name), _) => spans.push(Span::Scope(name.to_vec())),
+            }
+            if let Term::Literal(bytes) = term {
+                literal_len += bytes.len();
+            }
+        }
+        Template { spans, literal_len }
+    }
+
+    /// The same as `Expr::eval_for_build` on the expression the template was made from.
+    pub fn eval_for_build(&self, env: &Env, rule: &Rule) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.literal_len);
+        for span in &self.spans {
+            match span {
+                Span::Literal(bytes) => result.extend_from_slice(bytes),
+                Span::Rule(name) => result.extend(
+                    env.lookup_for_build(rule, name.as_slice())
+                        .unwrap_or_default(),
+                ),
+                Span::Scope(name) => env.lookup_into(name, &mut result),
+            }
+        }
+        result
+    }
+}
+
 #[derive(Debug)]
 pub struct Rule {
     pub name: Vec<u8>,
-    pub bindings: HashMap<Vec<u8>, Expr>,
+    pub bindings: HashMap<Vec<u8>, Expr<'static>>,
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    pub name: Vec<u8>,
+    pub bindings: HashMap<Vec<u8>, Expr<'static>>,
 }
 
 #[derive(Debug, Default)]
-pub struct Build {
+pub struct Build<'a> {
     pub rule: Vec<u8>,
     // These will become structs once we discriminate inputs and outputs.
-    pub inputs: Vec<Expr>,
-    pub implicit_inputs: Vec<Expr>,
-    pub order_inputs: Vec<Expr>,
-    pub outputs: Vec<Expr>,
+    pub inputs: Vec<Expr<'a>>,
+    pub implicit_inputs: Vec<Expr<'a>>,
+    pub order_inputs: Vec<Expr<'a>>,
+    pub outputs: Vec<Expr<'a>>,
+    pub implicit_outputs: Vec<Expr<'a>>,
     pub bindings: Env,
     // ...
 }
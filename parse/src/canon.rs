@@ -0,0 +1,288 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Path canonicalization, so that spellings of one path name one file.
+//!
+//! The parser keeps paths as written, and commands see them that way. Keys for the build graph
+//! are canonicalized when the description is turned into tasks. It is purely lexical: `..`
+//! removes the component before it without checking whether that was a symlink, as ninja does.
+
+/// How the platform that builds the manifest spells paths.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PathStyle {
+    /// Only `/` separates components.
+    Unix,
+    /// `\` separates components too, and paths may start with a drive (`C:`) or be UNC paths
+    /// (`\\server\share`). With `fold_case`, paths that differ only in ASCII case are the same
+    /// path, as on NTFS by default. Off, they stay distinct, for case-sensitive directories.
+    Windows { fold_case: bool },
+}
+
+impl PathStyle {
+    /// The style of the platform ninja was built for, without folding case.
+    pub fn native() -> Self {
+        if cfg!(windows) {
+            PathStyle::Windows { fold_case: false }
+        } else {
+            PathStyle::Unix
+        }
+    }
+
+    fn is_separator(self, c: u8) -> bool {
+        c == b'/' || (c == b'\\' && self != PathStyle::Unix)
+    }
+}
+
+/// Returns `path` without `.` components, repeated separators, or `..` components that follow a
+/// named one. Separators become `/`. Windows drive letters become uppercase. `..` at the start of
+/// an absolute path is dropped, since the root is its own parent. A path that names the current
+/// directory becomes `.`.
+pub fn canonicalize_path(path: &[u8], style: PathStyle) -> Vec<u8> {
+    let (prefix, rest) = split_prefix(path, style);
+    let rooted = prefix.ends_with(b"/");
+    let mut components: Vec<&[u8]> = Vec::new();
+    for component in rest.split(|c| style.is_separator(*c)) {
+        match component {
+            b"" | b"." => {}
+            b".." => match components.last() {
+                Some(last) if *last != b".." => {
+                    components.pop();
+                }
+                _ if rooted => {}
+                _ => components.push(component),
+            },
+            _ => components.push(component),
+        }
+    }
+
+    let mut canonical = prefix;
+    for (i, component) in components.iter().enumerate() {
+        if i > 0 {
+            canonical.push(b'/');
+        }
+        canonical.extend_from_slice(component);
+    }
+    if canonical.is_empty() {
+        canonical.push(b'.');
+    }
+    if let PathStyle::Windows { fold_case: true } = style {
+        canonical.make_ascii_lowercase();
+        // Keep the drive letter as it is everywhere else.
+        if has_drive(&canonical) {
+            canonical[0].make_ascii_uppercase();
+        }
+    }
+    canonical
+}
+
+fn has_drive(path: &[u8]) -> bool {
+    path.len() >= 2 && path[0].is_ascii_alphabetic() && path[1] == b':'
+}
+
+/// Splits off what comes before the first component, spelled canonically: `/`, `C:`, `C:/`,
+/// `//server/share/`, or nothing for a relative path.
+fn split_prefix(path: &[u8], style: PathStyle) -> (Vec<u8>, &[u8]) {
+    let is_separator = |c: Option<&u8>| matches!(c, Some(c) if style.is_separator(*c));
+    if style != PathStyle::Unix {
+        if has_drive(path) {
+            let mut prefix = vec![path[0].to_ascii_uppercase(), b':'];
+            let mut rest = &path[2..];
+            if is_separator(rest.first()) {
+                prefix.push(b'/');
+                rest = &rest[1..];
+            }
+            return (prefix, rest);
+        }
+        if is_separator(path.first()) && is_separator(path.get(1)) && !is_separator(path.get(2)) {
+            // The server and share are part of the root; `..` can't leave them.
+            let rest = &path[2..];
+            let mut separators = rest
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| style.is_separator(**c))
+                .map(|(i, _)| i);
+            let end = match (separators.next(), separators.next()) {
+                (Some(_), Some(share_end)) => share_end + 1,
+                _ => rest.len(),
+            };
+            let mut prefix = b"//".to_vec();
+            prefix.extend(
+                rest[..end]
+                    .iter()
+                    .map(|c| if style.is_separator(*c) { b'/' } else { *c }),
+            );
+            if !prefix.ends_with(b"/") {
+                prefix.push(b'/');
+            }
+            return (prefix, &rest[end..]);
+        }
+    }
+    if is_separator(path.first()) {
+        (b"/".to_vec(), &path[1..])
+    } else {
+        (Vec::new(), path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WINDOWS: PathStyle = PathStyle::Windows { fold_case: false };
+    const FOLDED: PathStyle = PathStyle::Windows { fold_case: true };
+
+    fn canonical(path: &str, style: PathStyle) -> String {
+        String::from_utf8(canonicalize_path(path.as_bytes(), style)).unwrap()
+    }
+
+    #[test]
+    fn relative_paths() {
+        for style in &[PathStyle::Unix, WINDOWS, FOLDED] {
+            for (path, expected) in &[
+                ("foo.c", "foo.c"),
+                ("./foo.c", "foo.c"),
+                ("a//b/./c", "a/b/c"),
+                ("a/b/", "a/b"),
+                ("a/../b", "b"),
+                ("a/b/../../c", "c"),
+                ("../a", "../a"),
+                ("a/../../b", "../b"),
+                ("../../a/..", "../.."),
+                (".", "."),
+                ("./", "."),
+                ("a/..", "."),
+                ("", "."),
+            ] {
+                assert_eq!(
+                    &canonical(path, *style),
+                    expected,
+                    "{} in {:?}",
+                    path,
+                    style
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn absolute_paths() {
+        for style in &[PathStyle::Unix, WINDOWS] {
+            for (path, expected) in &[
+                ("/", "/"),
+                ("/usr//lib/", "/usr/lib"),
+                ("/usr/../lib", "/lib"),
+                ("/../lib", "/lib"),
+                ("/..", "/"),
+            ] {
+                assert_eq!(
+                    &canonical(path, *style),
+                    expected,
+                    "{} in {:?}",
+                    path,
+                    style
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn backslashes() {
+        assert_eq!(canonical("a\\b\\c", WINDOWS), "a/b/c");
+        assert_eq!(canonical("a\\.\\b/../c\\", WINDOWS), "a/c");
+        assert_eq!(canonical("\\foo", WINDOWS), "/foo");
+        // A file name on Unix.
+        assert_eq!(canonical("a\\b", PathStyle::Unix), "a\\b");
+        assert_eq!(canonical("a\\..\\b", PathStyle::Unix), "a\\..\\b");
+    }
+
+    #[test]
+    fn drives() {
+        for (path, expected) in &[
+            ("C:\\foo\\bar", "C:/foo/bar"),
+            ("c:/foo", "C:/foo"),
+            ("C:\\", "C:/"),
+            ("C:/..", "C:/"),
+            ("C:\\a\\..\\..\\b", "C:/b"),
+            // Relative to the current directory of drive C.
+            ("C:foo\\bar", "C:foo/bar"),
+            ("C:", "C:"),
+            ("C:..\\foo", "C:../foo"),
+            ("C:a\\..", "C:"),
+        ] {
+            assert_eq!(&canonical(path, WINDOWS), expected, "{}", path);
+        }
+        // Not a drive elsewhere.
+        assert_eq!(canonical("C:\\foo", PathStyle::Unix), "C:\\foo");
+        assert_eq!(canonical("C:/foo/../bar", PathStyle::Unix), "C:/bar");
+    }
+
+    #[test]
+    fn unc_paths() {
+        for (path, expected) in &[
+            ("\\\\server\\share\\a\\b", "//server/share/a/b"),
+            ("//server/share/../a", "//server/share/a"),
+            ("\\\\server\\share", "//server/share/"),
+            ("\\\\server", "//server/"),
+        ] {
+            assert_eq!(&canonical(path, WINDOWS), expected, "{}", path);
+        }
+        // Only two leading separators start a UNC path.
+        assert_eq!(canonical("\\\\\\a", WINDOWS), "/a");
+        assert_eq!(
+            canonical("//server/share", PathStyle::Unix),
+            "/server/share"
+        );
+    }
+
+    #[test]
+    fn case() {
+        assert_eq!(canonical("Src\\Foo.C", WINDOWS), "Src/Foo.C");
+        assert_eq!(canonical("Src\\Foo.C", FOLDED), "src/foo.c");
+        assert_eq!(canonical("c:\\Src\\..\\Foo.C", FOLDED), "C:/foo.c");
+        assert_eq!(
+            canonical("\\\\Server\\Share\\A", FOLDED),
+            "//server/share/a"
+        );
+        assert_eq!(canonical("Src/Foo.C", PathStyle::Unix), "Src/Foo.C");
+        // Bytes beyond ASCII are left alone.
+        assert_eq!(canonical("\u{c9}t\u{c9}", FOLDED), "\u{c9}t\u{c9}");
+    }
+
+    #[test]
+    fn spellings_agree() {
+        let spellings = [
+            "C:\\src\\lib\\foo.c",
+            "c:/src/./lib//foo.c",
+            "C:\\src\\bin\\..\\lib\\foo.c",
+        ];
+        for style in &[WINDOWS, FOLDED] {
+            let canonical: Vec<_> = spellings
+                .iter()
+                .map(|path| canonical(path, *style))
+                .collect();
+            assert!(
+                canonical.iter().all(|path| path == "C:/src/lib/foo.c"),
+                "{:?}",
+                canonical
+            );
+        }
+        assert_eq!(canonical("C:\\SRC\\lib\\foo.c", FOLDED), "C:/src/lib/foo.c");
+        assert_ne!(
+            canonical("C:\\SRC\\lib\\foo.c", WINDOWS),
+            "C:/src/lib/foo.c"
+        );
+    }
+}
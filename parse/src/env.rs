@@ -58,6 +58,15 @@ impl Env {
             .or_else(|| self.parent.as_ref().and_then(|p| p.borrow().lookup(x)))
     }
 
+    /// Appends the value of `name`, if any, to `result`, without copying it first like `lookup`.
+    pub fn lookup_into(&self, name: &[u8], result: &mut Vec<u8>) {
+        match (self.bindings.get(name), &self.parent) {
+            (Some(value), _) => result.extend_from_slice(value),
+            (None, Some(parent)) => parent.borrow().lookup_into(name, result),
+            (None, None) => {}
+        }
+    }
+
     // While this function works, it requires the caller to be aware of when to use it.
     // It would be nicer to have a BuildEnv binding on build edges that always took a rule binding
     // for evaluation (and did not have just `lookup`). That would also need the AST eval thing to
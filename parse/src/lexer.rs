@@ -22,7 +22,8 @@ use thiserror::Error;
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Pos(usize); // This way, it is only possible to obtain a Pos from a token/error.
 
-#[derive(Debug, PartialEq, Eq)]
+/// A line and column in a manifest, both counting from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Position {
     pub source_name: Option<Vec<u8>>,
     pub line: usize,
@@ -158,7 +159,7 @@ pub enum LexerError {
     UnexpectedEof(Pos),
     #[error("Illegal character")]
     IllegalCharacter(Pos, u8),
-    #[error("Expected identifier ([a-zA-Z_-])")]
+    #[error("Expected identifier ([a-zA-Z0-9_.-])")]
     NotAnIdentifier(Pos, u8),
     #[error("Missing closing paren '}}'")]
     MissingBrace(Pos),
@@ -222,19 +223,29 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Rule names, variable names in assignments and `${name}` references.
     fn is_permitted_identifier_char(ch: u8) -> bool {
+        Lexer::is_simple_varname_char(ch) || ch == b'.'
+    }
+
+    /// Unbraced `$name` references stop at a '.', so that `$out.d` means `${out}.d`, like ninja.
+    fn is_simple_varname_char(ch: u8) -> bool {
         ch.is_ascii_alphanumeric() || ch == b'_' || ch == b'-'
     }
 
-    fn read_identifier(&mut self) -> Lexeme<'a> {
+    fn read_while(&mut self, permitted: fn(u8) -> bool) -> Lexeme<'a> {
         assert!(!self.done());
         let span_start = self.offset;
-        while !self.done() && Lexer::is_permitted_identifier_char(self.ch.unwrap()) {
+        while !self.done() && permitted(self.ch.unwrap()) {
             self.advance();
         }
         Lexeme::Identifier(&self.data[span_start..self.offset])
     }
 
+    fn read_identifier(&mut self) -> Lexeme<'a> {
+        self.read_while(Lexer::is_permitted_identifier_char)
+    }
+
     fn lookup_keyword(&mut self, ident: Lexeme<'a>) -> Lexeme<'a> {
         match ident {
             Lexeme::Identifier(slice) => match slice {
@@ -440,13 +451,10 @@ impl<'a> Lexer<'a> {
                         self.lexer_mode = LexerMode::Default;
                         break;
                     }
-                    b' ' => {
-                        // Done with this path.
+                    // Done with this path. A '|' is lexed on the next call, like ':'.
+                    b' ' | b'|' => {
                         break;
                     }
-                    b'|' => {
-                        todo!("Implicit outs/deps not supported!");
-                    }
                     // Only expect to encounter this in `build` declarations.
                     // The parser will take care if that does not happen.
                     b':' => {
@@ -586,8 +594,8 @@ impl<'a> Lexer<'a> {
                     Err(LexerError::UnexpectedEof(Pos(pos)))
                 }
             }
-            _ if Lexer::is_permitted_identifier_char(ch) => {
-                let ident = self.read_identifier();
+            _ if Lexer::is_simple_varname_char(ch) => {
+                let ident = self.read_while(Lexer::is_simple_varname_char);
                 Ok(Lexeme::VarRef(VarRefType::WithoutParens, ident.value()))
             }
             _ => {
@@ -694,7 +702,9 @@ impl<'a> Iterator for Lexer<'a> {
                     self.lexer_mode = LexerMode::Default;
                     Some(Ok((Lexeme::Newline, pos)))
                 }
-                b'=' => {
+                // Only an assignment at the start of a line. Elsewhere, as in paths like
+                // `out/config=release/foo.o` or in values, it is just a character.
+                b'=' if self.lexer_mode == LexerMode::Default => {
                     self.advance();
                     self.skip_horizontal_whitespace();
                     self.lexer_mode = LexerMode::ValueMode;
@@ -1100,6 +1110,90 @@ rule"#,
         }
     }
 
+    #[test]
+    fn test_identifiers_with_periods() {
+        assert_eq!(
+            parse_and_slice_no_error("rule cxx.o_rule"),
+            &[Lexeme::Rule, Lexeme::Identifier(b"cxx.o_rule")]
+        );
+        assert_eq!(
+            parse_and_slice_no_error("foo.bar = ${foo.bar}$out.d"),
+            &[
+                Lexeme::Identifier(b"foo.bar"),
+                Lexeme::Equals,
+                Lexeme::Expr(vec![
+                    Lexeme::VarRef(VarRefType::WithParens, b"foo.bar"),
+                    Lexeme::VarRef(VarRefType::WithoutParens, b"out"),
+                    Lexeme::Literal(b".d"),
+                ]),
+            ]
+        );
+        assert_eq!(
+            parse_and_slice_no_error("build a: cxx.o_rule b"),
+            &[
+                Lexeme::Build,
+                Lexeme::Expr(vec![Lexeme::Literal(b"a")]),
+                Lexeme::Colon,
+                Lexeme::Identifier(b"cxx.o_rule"),
+                Lexeme::Expr(vec![Lexeme::Literal(b"b")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_equals_in_paths() {
+        let res = parse_and_slice_no_error("build out/config=release/a.o =b.o: cc =c.c | d=");
+        assert_eq!(
+            res,
+            &[
+                Lexeme::Build,
+                Lexeme::Expr(vec![Lexeme::Literal(b"out/config=release/a.o")]),
+                Lexeme::Expr(vec![Lexeme::Literal(b"=b.o")]),
+                Lexeme::Colon,
+                Lexeme::Identifier(b"cc"),
+                Lexeme::Expr(vec![Lexeme::Literal(b"=c.c")]),
+                Lexeme::Pipe,
+                Lexeme::Expr(vec![Lexeme::Literal(b"d=")]),
+            ]
+        );
+        for test in &["default =all", "include =a.ninja", "subninja a=b.ninja"] {
+            let res = parse_and_slice_no_error(test);
+            assert_eq!(res.len(), 2);
+            let path = test.split(' ').nth(1).unwrap().as_bytes();
+            assert_eq!(res[1], Lexeme::Expr(vec![Lexeme::Literal(path)]));
+        }
+    }
+
+    #[test]
+    fn test_equals_in_values() {
+        let res = parse_and_slice_no_error("flags = =a b==c");
+        assert_eq!(
+            res,
+            &[
+                Lexeme::Identifier(b"flags"),
+                Lexeme::Equals,
+                Lexeme::Expr(vec![Lexeme::Literal(b"=a b==c")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exotic_path_characters() {
+        let path = "a+b,c@d%e~f!(g)[h]{i}'j'\"k\"\u{e9}.o";
+        let input = format!("build {}: cc {}", path, path);
+        let res = parse_and_slice_no_error(&input);
+        assert_eq!(
+            res,
+            &[
+                Lexeme::Build,
+                Lexeme::Expr(vec![Lexeme::Literal(path.as_bytes())]),
+                Lexeme::Colon,
+                Lexeme::Identifier(b"cc"),
+                Lexeme::Expr(vec![Lexeme::Literal(path.as_bytes())]),
+            ]
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_escape_and_lex_modes() {
@@ -24,6 +24,7 @@ use std::{
     rc::Rc,
     str::Utf8Error,
     string::FromUtf8Error,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
@@ -32,12 +33,15 @@ pub trait Loader {
 }
 
 mod ast;
+pub mod canon;
 mod env;
 mod lexer;
 mod parser;
 pub mod repr;
+mod shell;
 
 use env::Env;
+pub use lexer::Position;
 use parser::{ParseError, Parser};
 pub use repr::*;
 
@@ -48,6 +52,49 @@ pub struct ProcessingErrorWithPosition {
     position: lexer::Position,
 }
 
+/// An `include` or `subninja` statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inclusion {
+    pub position: Position,
+    pub subninja: bool,
+}
+
+impl std::fmt::Display for Inclusion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let statement = if self.subninja { "subninja" } else { "include" };
+        write!(f, "in {} at {}", statement, self.position)
+    }
+}
+
+/// How many inclusions an error message lists, since a file that includes itself leads to a
+/// very long chain of them.
+const MAX_DISPLAYED_INCLUSIONS: usize = 8;
+
+/// An error in a file that was included, with the statements that led to that file.
+#[derive(Error, Debug)]
+pub struct ProcessingErrorInInclude {
+    inner: ProcessingError,
+    /// Innermost first.
+    chain: Vec<Inclusion>,
+}
+
+impl std::fmt::Display for ProcessingErrorInInclude {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.inner)?;
+        for inclusion in self.chain.iter().take(MAX_DISPLAYED_INCLUSIONS) {
+            write!(f, "\n  {}", inclusion)?;
+        }
+        if self.chain.len() > MAX_DISPLAYED_INCLUSIONS {
+            write!(
+                f,
+                "\n  ... (and {} more)",
+                self.chain.len() - MAX_DISPLAYED_INCLUSIONS
+            )?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ProcessingError {
     #[error("utf-8 error")]
@@ -62,12 +109,38 @@ pub enum ProcessingError {
     UnknownRule(String),
     #[error("missing 'command' for rule: {0}")]
     MissingCommand(String),
+    #[error("'batch' for rule {0} must be a number, not '{1}'")]
+    InvalidBatch(String, String),
+    #[error("duplicate pool name: {0}")]
+    DuplicatePool(String),
+    #[error("missing 'depth' for pool: {0}")]
+    MissingPoolDepth(String),
+    #[error("'depth' for pool {0} must be a number, not '{1}'")]
+    InvalidPoolDepth(String, String),
+    #[error("unknown pool name: {0}")]
+    UnknownPool(String),
+    #[error("unknown deps type '{0}'")]
+    UnknownDepsType(String),
+    #[error("'{0}' is both an input and an output of the same edge; only restat rules may list it twice")]
+    SelfDependency(String),
+    #[error(
+        "including '{path}' nests includes more than {limit} deep; does a file include itself?"
+    )]
+    IncludeTooDeep { path: String, limit: usize },
+    #[error("the manifest and its includes are larger than {0} bytes")]
+    TooManyBytes(usize),
+    #[error("the manifest describes more than {0} edges")]
+    TooManyEdges(usize),
+    #[error("'ninjars_manifest_version' must be a number, not '{0}'")]
+    InvalidManifestVersion(String),
     #[error(transparent)]
     ParseFailed(#[from] ParseError),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error(transparent)]
     WithPosition(#[from] Box<ProcessingErrorWithPosition>),
+    #[error(transparent)]
+    InInclude(#[from] Box<ProcessingErrorInInclude>),
 }
 
 impl ProcessingError {
@@ -81,26 +154,124 @@ impl ProcessingError {
     fn with_position_boxed(self, position: lexer::Position) -> Box<ProcessingErrorWithPosition> {
         Box::new(self.with_position(position))
     }
+
+    /// Records that the file with the error was loaded by `inclusion`.
+    fn included_by(self, inclusion: Inclusion) -> ProcessingError {
+        match self {
+            ProcessingError::InInclude(mut e) => {
+                e.chain.push(inclusion);
+                ProcessingError::InInclude(e)
+            }
+            inner => ProcessingError::InInclude(Box::new(ProcessingErrorInInclude {
+                inner,
+                chain: vec![inclusion],
+            })),
+        }
+    }
+
+    /// The error in the file it was found in, without the statements that included that file.
+    pub fn without_includes(&self) -> &ProcessingError {
+        match self {
+            ProcessingError::InInclude(e) => &e.inner,
+            e => e,
+        }
+    }
+
+    /// The `include` and `subninja` statements that led to the file with the error, innermost
+    /// first. Empty if the error is in the manifest itself.
+    pub fn included_from(&self) -> &[Inclusion] {
+        match self {
+            ProcessingError::InInclude(e) => &e.chain,
+            _ => &[],
+        }
+    }
+
+    /// Where the error is, if it is at a particular place in a file.
+    pub fn position(&self) -> Option<&Position> {
+        match self.without_includes() {
+            ProcessingError::WithPosition(e) => Some(&e.position),
+            ProcessingError::ParseFailed(e) => Some(e.position()),
+            _ => None,
+        }
+    }
+
+    /// What went wrong, without where.
+    pub fn message(&self) -> String {
+        match self.without_includes() {
+            ProcessingError::WithPosition(e) => e.inner.message(),
+            ProcessingError::ParseFailed(e) => e.message().to_owned(),
+            e => e.to_string(),
+        }
+    }
 }
 
 const PHONY: &[u8] = &[112, 104, 111, 110, 121];
+/// Binding that places build edges in a namespace. See `Build::namespace`.
+const NAMESPACE: &[u8] = b"ninja_namespace";
+/// Top-level binding that opts into extensions which make a manifest mean something different to
+/// ninja. Manifests without it are read the way ninja reads them.
+const MANIFEST_VERSION: &[u8] = b"ninjars_manifest_version";
+/// Top-level binding naming the directory for ninja's own files. See `Description::builddir`.
+const BUILDDIR: &[u8] = b"builddir";
+/// From this manifest version, `subninja dir/build.ninja` works as if it changed to `dir/` first:
+/// paths in that file, including those of further subninjas, are relative to `dir/` and its
+/// commands run there. Its edges are placed in the `dir` namespace, so a path outside of `dir/`
+/// is written `$:path`.
+const CHDIR_SUBNINJA_VERSION: u32 = 2;
 
-fn space_seperated_paths(paths: &Vec<Vec<u8>>) -> Vec<u8> {
-    let mut vec = Vec::new();
-    for (i, el) in paths.iter().enumerate() {
-        vec.extend(el);
-        if i != paths.len() - 1 {
-            vec.push(b' ');
+/// Bounds on how much manifest to read, so that a generator gone wrong fails quickly instead of
+/// exhausting the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How deeply `include`s may nest, counting the top-level manifest.
+    pub max_include_depth: usize,
+    /// Total size of the manifest and every file it includes. A file included twice counts twice.
+    pub max_bytes: usize,
+    pub max_edges: usize,
+}
+
+impl Default for Limits {
+    /// Far beyond any real build, but well short of what would run a machine out of memory.
+    fn default() -> Self {
+        Limits {
+            max_include_depth: 64,
+            max_bytes: std::cmp::min(4u64 << 30, usize::MAX as u64) as usize,
+            max_edges: 50_000_000,
         }
     }
-    vec
+}
+
+struct KnownRule {
+    rule: past::Rule,
+    // The index in `Description::rules`. Only phony has none.
+    index: Option<usize>,
+    // Every edge of the rule evaluates its command, so it is prepared when the rule is declared.
+    command: Option<past::Template>,
 }
 
 struct ParseState {
-    known_rules: HashMap<Vec<u8>, past::Rule>,
-    outputs_seen: HashSet<Vec<u8>>,
+    // Each rule in scope.
+    known_rules: HashMap<Vec<u8>, Rc<KnownRule>>,
+    // The rules defined by the subninja being parsed, or by the top-level manifest. Rules of outer
+    // files may be shadowed, but not these.
+    scope_rules: HashSet<Vec<u8>>,
+    known_pools: HashSet<Vec<u8>>,
+    // Outputs only collide within a namespace.
+    outputs_seen: HashSet<(Option<Vec<u8>>, Vec<u8>)>,
     description: Description,
     bindings: Rc<RefCell<Env>>,
+    // How many edges had been described when each top-level variable was last assigned.
+    assigned_at: HashMap<Vec<u8>, usize>,
+    limits: Limits,
+    // How many files are being parsed, counting the one being parsed now and its includers.
+    depth: usize,
+    bytes_read: usize,
+    // The directory of the subninja being parsed, when subninjas change directory.
+    chdir: Option<Vec<u8>>,
+    // The index in `Description::files` of the file being parsed.
+    file: usize,
+    // How long the files loaded by the file being parsed took so far, for -d stats.
+    loaded_time: Duration,
 }
 
 impl Default for ParseState {
@@ -109,55 +280,185 @@ impl Default for ParseState {
         // Insert built-in rules.
         rules.insert(
             PHONY.to_vec(),
-            past::Rule {
-                name: PHONY.to_vec(),
-                bindings: HashMap::default(),
-            },
+            Rc::new(KnownRule {
+                rule: past::Rule {
+                    name: PHONY.to_vec(),
+                    bindings: HashMap::default(),
+                },
+                index: None,
+                command: None,
+            }),
         );
         Self {
             known_rules: rules,
+            scope_rules: HashSet::default(),
+            known_pools: std::iter::once(CONSOLE_POOL.to_vec()).collect(),
             outputs_seen: HashSet::default(),
             description: Description::default(),
             bindings: Rc::new(RefCell::new(Env::default())),
+            assigned_at: HashMap::default(),
+            limits: Limits::default(),
+            depth: 0,
+            bytes_read: 0,
+            chdir: None,
+            file: 0,
+            loaded_time: Duration::default(),
         }
     }
 }
 
 impl ParseState {
+    /// Top-level variables take effect from the point they are assigned, since everything that
+    /// refers to them is evaluated as it is parsed. Edges before a reassignment keep the old
+    /// value, which is worth pointing out when debugging a manifest.
+    fn add_top_level_binding(
+        &mut self,
+        name: Vec<u8>,
+        value: Vec<u8>,
+        extends: bool,
+        location: impl FnOnce() -> String,
+    ) {
+        let edges = self.description.builds.len();
+        if let Some(previous) = self.assigned_at.insert(name.clone(), edges) {
+            if edges > previous && !extends {
+                self.description.redefinitions.push(Redefinition {
+                    name: name.clone(),
+                    location: location(),
+                    edges_before: edges - previous,
+                });
+            }
+        }
+        self.bindings.borrow_mut().add_binding(name, value);
+    }
+
+    fn count_in_file(&mut self, count: impl FnOnce(&mut ManifestFile)) {
+        // Tests may add to a state that isn't parsing any file.
+        if let Some(file) = self.description.files.get_mut(self.file) {
+            count(file);
+        }
+    }
+
     fn add_rule(&mut self, rule: past::Rule) -> Result<(), ProcessingError> {
-        if self.known_rules.get(&rule.name).is_some() {
+        if rule.name == PHONY || self.scope_rules.contains(&rule.name) {
             // TODO: Also add line/col information from token position, which isn't being preserved
             // right now!
             Err(ProcessingError::DuplicateRule(
                 std::str::from_utf8(&rule.name)?.to_owned(),
             ))
         } else {
-            self.known_rules.insert(rule.name.clone(), rule);
+            let template = |name: &[u8]| rule.bindings.get(name).map(|e| e.to_template());
+            // Edges are batched before their bindings are evaluated, so only a literal will do.
+            let batch = match template(b"batch") {
+                Some(value) => std::str::from_utf8(&value)?
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| {
+                        ProcessingError::InvalidBatch(
+                            String::from_utf8_lossy(&rule.name).into_owned(),
+                            String::from_utf8_lossy(&value).into_owned(),
+                        )
+                    })?,
+                None => 0,
+            };
+            let descriptor = RuleDescriptor {
+                name: rule.name.clone(),
+                description: template(b"description"),
+                depfile: template(b"depfile"),
+                deps: template(b"deps"),
+                pool: template(b"pool"),
+                restat: matches!(template(b"restat"), Some(v) if !v.is_empty()),
+                generator: matches!(template(b"generator"), Some(v) if !v.is_empty()),
+                batch,
+            };
+            self.description.rules.push(descriptor);
+            self.count_in_file(|file| file.rules += 1);
+            self.scope_rules.insert(rule.name.clone());
+            self.known_rules.insert(
+                rule.name.clone(),
+                Rc::new(KnownRule {
+                    command: rule
+                        .bindings
+                        .get(b"command".as_ref())
+                        .map(|command| past::Template::new(command, &rule)),
+                    rule,
+                    index: Some(self.description.rules.len() - 1),
+                }),
+            );
             Ok(())
         }
     }
 
+    /// Pools are global, and their depth is evaluated when they are declared.
+    fn add_pool(&mut self, pool: past::Pool) -> Result<(), ProcessingError> {
+        let name = || String::from_utf8_lossy(&pool.name).into_owned();
+        if self.known_pools.contains(&pool.name) {
+            return Err(ProcessingError::DuplicatePool(name()));
+        }
+        let depth = match pool.bindings.get(b"depth".as_ref()) {
+            Some(depth) => depth.eval(&self.bindings.borrow()),
+            None => return Err(ProcessingError::MissingPoolDepth(name())),
+        };
+        let depth = std::str::from_utf8(&depth)
+            .ok()
+            .and_then(|depth| depth.trim().parse::<usize>().ok())
+            .ok_or_else(|| {
+                ProcessingError::InvalidPoolDepth(
+                    name(),
+                    String::from_utf8_lossy(&depth).into_owned(),
+                )
+            })?;
+        self.known_pools.insert(pool.name.clone());
+        self.description.pools.push(Pool {
+            name: pool.name,
+            depth,
+        });
+        Ok(())
+    }
+
+    /// Evaluates the outputs of an edge, which no other edge in the namespace may also produce.
+    fn add_outputs(
+        &mut self,
+        outputs: &[past::Expr],
+        env: &Env,
+        namespace: Option<&Vec<u8>>,
+    ) -> Result<Vec<Vec<u8>>, ProcessingError> {
+        let mut evaluated = Vec::with_capacity(outputs.len());
+        for output in outputs {
+            let output = output.eval(env);
+            let seen = (namespace.cloned(), output);
+            if self.outputs_seen.contains(&seen) {
+                // TODO: Also add line/col information from token position, which isn't being preserved
+                // right now!
+                return Err(ProcessingError::DuplicateOutput(
+                    String::from_utf8(seen.1)?.to_owned(),
+                ));
+            }
+            evaluated.push(seen.1.clone());
+            self.outputs_seen.insert(seen);
+        }
+        Ok(evaluated)
+    }
+
     fn add_build_edge(
         &mut self,
         build: past::Build,
         _top: Rc<RefCell<Env>>,
+        location: impl FnOnce() -> String,
     ) -> Result<(), ProcessingError> {
-        let mut evaluated_outputs = Vec::with_capacity(build.outputs.len());
+        if self.description.builds.len() >= self.limits.max_edges {
+            return Err(ProcessingError::TooManyEdges(self.limits.max_edges));
+        }
         // TODO: Use the environment in scope + the rule environment.
         // TODO: Are the build bindings available to the input and output path evaluation?
+        let namespace = build
+            .bindings
+            .lookup(NAMESPACE)
+            .filter(|namespace| !namespace.is_empty());
 
-        for output in &build.outputs {
-            let output = output.eval(&build.bindings);
-            if self.outputs_seen.contains(&output) {
-                // TODO: Also add line/col information from token position, which isn't being preserved
-                // right now!
-                return Err(ProcessingError::DuplicateOutput(
-                    String::from_utf8(output)?.to_owned(),
-                ));
-            }
-            self.outputs_seen.insert(output.clone());
-            evaluated_outputs.push(output);
-        }
+        let evaluated_outputs =
+            self.add_outputs(&build.outputs, &build.bindings, namespace.as_ref())?;
+        let evaluated_implicit_outputs =
+            self.add_outputs(&build.implicit_outputs, &build.bindings, namespace.as_ref())?;
 
         let evaluated_inputs: Vec<Vec<u8>> = build
             .inputs
@@ -177,56 +478,208 @@ impl ParseState {
             .map(|i| i.eval(&build.bindings))
             .collect();
 
+        // Every edge, including phony ones, gets an environment with $in and $out, so that
+        // bindings like `description` evaluate the same way regardless of the rule.
         // TODO: Note that any rule/build level binding can refer to these variables, so the entire
-        // build statement evaluation must have this environment available. In addition, these are
-        // "shell quoted" when expanding within a command.
-        // TODO: Get environment from rule!
+        // build statement evaluation must have this environment available.
         let mut env = Env::with_parent(Rc::new(RefCell::new(build.bindings)));
-        env.add_binding(b"out".to_vec(), space_seperated_paths(&evaluated_outputs));
-        env.add_binding(b"in".to_vec(), space_seperated_paths(&evaluated_inputs));
+        // Only the command gets paths quoted for the shell, since nothing else is run by one.
+        let bind_paths = |env: &mut Env, quoted: bool| {
+            let join = |paths: &[Vec<u8>], separator: u8| {
+                if quoted {
+                    shell::quoted_paths(paths, separator)
+                } else {
+                    paths.join(&separator)
+                }
+            };
+            env.add_binding(b"out".to_vec(), join(&evaluated_outputs, b' '));
+            env.add_binding(b"in".to_vec(), join(&evaluated_inputs, b' '));
+            // Mostly for `rspfile_content`, for tools that read one path per line.
+            env.add_binding(b"in_newline".to_vec(), join(&evaluated_inputs, b'\n'));
+        };
+        bind_paths(&mut env, false);
 
-        let action = {
-            match build.rule.as_slice() {
-                [112, 104, 111, 110, 121] => Action::Phony,
-                other => {
-                    let rule = self.known_rules.get(other);
-                    if rule.is_none() {
-                        return Err(ProcessingError::UnknownRule(
-                            std::str::from_utf8(&other)?.to_owned(),
-                        ));
-                    }
+        let known = match self.known_rules.get(build.rule.as_slice()) {
+            Some(known) => known,
+            None => {
+                return Err(ProcessingError::UnknownRule(
+                    std::str::from_utf8(&build.rule)?.to_owned(),
+                ));
+            }
+        };
+        let (rule, rule_index) = (&known.rule, known.index);
 
-                    let rule = rule.unwrap();
-                    let command = rule.bindings.get("command".as_bytes());
-                    if command.is_none() {
+        let action = if rule.name == PHONY {
+            Action::Phony
+        } else {
+            let (command, template) =
+                match (rule.bindings.get("command".as_bytes()), &known.command) {
+                    (Some(command), Some(template)) => (command, template),
+                    _ => {
                         return Err(ProcessingError::MissingCommand(
                             std::str::from_utf8(&rule.name)?.to_owned(),
-                        ));
+                        ))
                     }
+                };
+            bind_paths(&mut env, true);
+            for (variable, path) in shell::unquoted_expansions(
+                command,
+                &env,
+                rule,
+                &evaluated_inputs,
+                &evaluated_outputs,
+            ) {
+                self.description
+                    .unquoted_expansions
+                    .push(UnquotedExpansion {
+                        output: evaluated_outputs[0].clone(),
+                        variable,
+                        path,
+                    });
+            }
+            let command = String::from_utf8(template.eval_for_build(&env, rule));
+            bind_paths(&mut env, false);
+            Action::Command(command?)
+        };
 
-                    Action::Command(String::from_utf8(
-                        command.unwrap().eval_for_build(&env, &rule),
-                    )?)
+        let description = env
+            .lookup_for_build(rule, "description".as_bytes())
+            .map(String::from_utf8)
+            .transpose()?;
+        let depfile = match action {
+            Action::Command(_) => env
+                .lookup_for_build(rule, "depfile".as_bytes())
+                .filter(|depfile| !depfile.is_empty()),
+            Action::Phony => None,
+        };
+        let deps = match (&action, env.lookup_for_build(rule, "deps".as_bytes())) {
+            (Action::Phony, _) | (_, None) => None,
+            (_, Some(deps)) => match deps.as_slice() {
+                b"" => None,
+                b"gcc" => Some(Deps::Gcc),
+                b"msvc" => Some(Deps::Msvc {
+                    prefix: env
+                        .lookup_for_build(rule, "msvc_deps_prefix".as_bytes())
+                        .filter(|prefix| !prefix.is_empty())
+                        .unwrap_or_else(|| DEFAULT_MSVC_DEPS_PREFIX.to_vec()),
+                }),
+                other => {
+                    return Err(ProcessingError::UnknownDepsType(
+                        String::from_utf8_lossy(other).into_owned(),
+                    ))
                 }
+            },
+        };
+
+        let rspfile = match action {
+            Action::Command(_) => env
+                .lookup_for_build(rule, "rspfile".as_bytes())
+                .filter(|path| !path.is_empty())
+                .map(|path| Rspfile {
+                    path,
+                    content: env
+                        .lookup_for_build(rule, "rspfile_content".as_bytes())
+                        .unwrap_or_default(),
+                }),
+            Action::Phony => None,
+        };
+
+        let pool = match action {
+            Action::Command(_) => env
+                .lookup_for_build(rule, "pool".as_bytes())
+                .filter(|pool| !pool.is_empty()),
+            Action::Phony => None,
+        };
+        if let Some(pool) = &pool {
+            if !self.known_pools.contains(pool) {
+                return Err(ProcessingError::UnknownPool(
+                    String::from_utf8_lossy(pool).into_owned(),
+                ));
             }
+        }
+
+        // An edge can't wait for itself. Rules that touch a file in place and restat it are the one
+        // legitimate reason to list it twice, so those just lose the self-reference.
+        let mut inputs = [
+            evaluated_inputs,
+            evaluated_implicit_inputs,
+            evaluated_order_inputs,
+        ];
+        let is_output = |path: &Vec<u8>| {
+            evaluated_outputs.contains(path) || evaluated_implicit_outputs.contains(path)
         };
+        if let Some(path) = inputs.iter().flatten().find(|path| is_output(path)) {
+            let restat = matches!(
+                env.lookup_for_build(rule, "restat".as_bytes()),
+                Some(restat) if !restat.is_empty()
+            );
+            if !restat {
+                return Err(ProcessingError::SelfDependency(
+                    String::from_utf8_lossy(path).into_owned(),
+                ));
+            }
+            let location = location();
+            for list in inputs.iter_mut() {
+                let self_dependencies = &mut self.description.self_dependencies;
+                list.retain(|path| {
+                    if !is_output(path) {
+                        return true;
+                    }
+                    self_dependencies.push(SelfDependency {
+                        path: path.clone(),
+                        location: location.clone(),
+                    });
+                    false
+                });
+            }
+        }
+        let [evaluated_inputs, evaluated_implicit_inputs, evaluated_order_inputs] = inputs;
+
+        self.count_in_file(|file| file.edges += 1);
         self.description.builds.push(Build {
             action,
+            rule: build.rule.clone(),
+            rule_index,
+            description,
+            depfile,
+            deps,
+            rspfile,
+            pool,
+            namespace,
             inputs: evaluated_inputs,
             implicit_inputs: evaluated_implicit_inputs,
             order_inputs: evaluated_order_inputs,
             outputs: evaluated_outputs,
+            implicit_outputs: evaluated_implicit_outputs,
         });
         Ok(())
     }
 
-    fn add_default(&mut self, entries: Vec<u8>) {
+    fn add_default(&mut self, mut entries: Vec<u8>) {
+        // Named the way the command line would name a path in that namespace.
+        if let Some(dir) = &self.chdir {
+            let mut namespaced = dir.clone();
+            namespaced.push(b':');
+            namespaced.extend_from_slice(&entries);
+            entries = namespaced;
+        }
         if self.description.defaults.is_none() {
             self.description.defaults = Some(HashSet::new());
         }
         self.description.defaults.as_mut().unwrap().insert(entries);
     }
 
+    fn manifest_version(&self) -> Result<u32, ProcessingError> {
+        match self.bindings.borrow().lookup(MANIFEST_VERSION) {
+            Some(value) => std::str::from_utf8(&value)?.trim().parse().map_err(|_| {
+                ProcessingError::InvalidManifestVersion(
+                    String::from_utf8_lossy(&value).into_owned(),
+                )
+            }),
+            None => Ok(1),
+        }
+    }
+
     fn into_description(self) -> Description {
         self.description
     }
@@ -235,41 +688,174 @@ impl ParseState {
 fn parse_single(
     contents: &[u8],
     name: Option<Vec<u8>>,
+    subninja: bool,
     state: &mut ParseState,
     loader: &mut dyn Loader,
 ) -> Result<(), ProcessingError> {
-    Parser::new(&contents, name).parse(state, loader)?;
-    Ok(())
+    if state.depth >= state.limits.max_include_depth {
+        return Err(ProcessingError::IncludeTooDeep {
+            path: String::from_utf8_lossy(name.as_deref().unwrap_or_default()).into_owned(),
+            limit: state.limits.max_include_depth,
+        });
+    }
+    state.bytes_read += contents.len();
+    if state.bytes_read > state.limits.max_bytes {
+        return Err(ProcessingError::TooManyBytes(state.limits.max_bytes));
+    }
+    let parent = if state.description.files.is_empty() {
+        None
+    } else {
+        Some(state.file)
+    };
+    state.description.files.push(ManifestFile {
+        path: name.clone().unwrap_or_default(),
+        parent,
+        subninja,
+        rules: 0,
+        edges: 0,
+    });
+    let outer_file = std::mem::replace(&mut state.file, state.description.files.len() - 1);
+    let outer_loaded_time = std::mem::take(&mut state.loaded_time);
+    let start = ninja_metrics::is_enabled().then(Instant::now);
+    state.depth += 1;
+    let result = Parser::new(contents, name).parse(state, loader);
+    state.depth -= 1;
+    if let Some(start) = start {
+        let total = start.elapsed();
+        let file = &state.description.files[state.file];
+        ninja_metrics::record_file(
+            &String::from_utf8_lossy(&file.path),
+            total.checked_sub(state.loaded_time).unwrap_or_default(),
+            total,
+            file.edges,
+        );
+        state.loaded_time = outer_loaded_time + total;
+    } else {
+        state.loaded_time = outer_loaded_time;
+    }
+    state.file = outer_file;
+    result
+}
+
+fn join_path(dir: Option<&[u8]>, path: &[u8]) -> Vec<u8> {
+    match dir {
+        Some(dir) => {
+            let mut joined = dir.to_vec();
+            joined.push(b'/');
+            joined.extend_from_slice(path);
+            joined
+        }
+        None => path.to_vec(),
+    }
+}
+
+/// Unlike an `include`, the variables and rules a subninja defines are gone once it ends. It may
+/// shadow rules of the files that include it, like variables. `location` is that of the `subninja`
+/// statement, for errors in the file.
+fn parse_subninja(
+    path: Vec<u8>,
+    from: Option<&[u8]>,
+    state: &mut ParseState,
+    loader: &mut dyn Loader,
+    location: impl FnOnce() -> Position,
+) -> Result<(), ProcessingError> {
+    let changes_dir = state.manifest_version()? >= CHDIR_SUBNINJA_VERSION;
+    let (request, from, chdir) = if changes_dir {
+        // Loaded by its path from the top, since `from` may be in another directory.
+        let request = join_path(state.chdir.as_deref(), &path);
+        let chdir = request
+            .iter()
+            .rposition(|c| *c == b'/')
+            .map(|slash| request[..slash].to_vec());
+        (request, None, chdir)
+    } else {
+        (path, from, state.chdir.clone())
+    };
+    let contents = match loader.load(from, &request) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            state.description.missing_include = Some(request);
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let outer_bindings = state.bindings.clone();
+    let mut scope = Env::with_parent(outer_bindings.clone());
+    if changes_dir && chdir != state.chdir {
+        if let Some(dir) = &chdir {
+            scope.add_binding(NAMESPACE.to_vec(), dir.clone());
+        }
+    }
+    state.bindings = Rc::new(RefCell::new(scope));
+    let outer_chdir = std::mem::replace(&mut state.chdir, chdir);
+    let outer_rules = state.known_rules.clone();
+    let outer_scope_rules = std::mem::take(&mut state.scope_rules);
+    let outer_assigned_at = state.assigned_at.clone();
+
+    let result = parse_single(&contents, Some(request), true, state, loader).map_err(|e| {
+        e.included_by(Inclusion {
+            position: location(),
+            subninja: true,
+        })
+    });
+
+    state.bindings = outer_bindings;
+    state.chdir = outer_chdir;
+    state.known_rules = outer_rules;
+    state.scope_rules = outer_scope_rules;
+    state.assigned_at = outer_assigned_at;
+    result
 }
 
 pub fn build_representation(
     loader: &mut dyn Loader,
     start: Vec<u8>,
+) -> Result<Description, ProcessingError> {
+    build_representation_with_limits(loader, start, Limits::default())
+}
+
+pub fn build_representation_with_limits(
+    loader: &mut dyn Loader,
+    start: Vec<u8>,
+    limits: Limits,
 ) -> Result<Description, ProcessingError> {
     scoped_metric!("parse");
-    let mut state = ParseState::default();
+    let mut state = ParseState {
+        limits,
+        ..ParseState::default()
+    };
     let contents = loader.load(None, &start)?;
-    parse_single(&contents, Some(start), &mut state, loader)?;
+    parse_single(&contents, Some(start), false, &mut state, loader)?;
+    // Only the top level counts; a subninja's scope is gone by now.
+    state.description.builddir = state
+        .bindings
+        .borrow()
+        .lookup(BUILDDIR)
+        .filter(|builddir| !builddir.is_empty());
     Ok(state.into_description())
 }
 
 #[cfg(test)]
 mod test {
 
-    use super::{ast as past, ParseState, ProcessingError};
+    use super::{
+        ast as past, build_representation_with_limits, Deps, Limits, Loader, ParseState, Pool,
+        ProcessingError, Rspfile, SelfDependency, DEFAULT_MSVC_DEPS_PREFIX,
+    };
     use crate::env::Env;
     use insta::assert_debug_snapshot;
     use std::{cell::RefCell, rc::Rc};
 
     macro_rules! lit {
         ($name:expr) => {
-            past::Term::Literal($name.to_vec())
+            past::Term::Literal($name.to_vec().into())
         };
     }
 
     macro_rules! aref {
         ($name:literal) => {
-            past::Term::Reference($name.to_vec())
+            past::Term::Reference($name.to_vec().into())
         };
     }
 
@@ -295,6 +881,606 @@ mod test {
         };
     }
 
+    /// Serves manifests from memory.
+    struct MapLoader(std::collections::HashMap<&'static [u8], &'static [u8]>);
+
+    impl Loader for MapLoader {
+        fn load(&mut self, _from: Option<&[u8]>, request: &[u8]) -> std::io::Result<Vec<u8>> {
+            self.0
+                .get(request)
+                .map(|contents| contents.to_vec())
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        }
+    }
+
+    fn parse_with_limits(
+        files: &[(&'static [u8], &'static [u8])],
+        limits: Limits,
+    ) -> Result<crate::Description, ProcessingError> {
+        let mut loader = MapLoader(files.iter().copied().collect());
+        build_representation_with_limits(&mut loader, files[0].0.to_vec(), limits)
+    }
+
+    #[test]
+    fn include_depth_limit() {
+        let files: &[(&[u8], &[u8])] = &[(b"build.ninja", b"include build.ninja\n")];
+        let err = parse_with_limits(files, Limits::default()).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "including 'build.ninja' nests includes more than 64 deep; does a file include itself?"
+        );
+        assert_eq!(err.included_from().len(), 64);
+        assert!(err
+            .to_string()
+            .ends_with("\n  in include at build.ninja:1:1\n  ... (and 56 more)"));
+
+        let files: &[(&[u8], &[u8])] = &[
+            (b"build.ninja", b"include a.ninja\n"),
+            (b"a.ninja", b"include b.ninja\n"),
+            (b"b.ninja", b"x = 1\n"),
+        ];
+        let limits = Limits {
+            max_include_depth: 3,
+            ..Limits::default()
+        };
+        assert!(parse_with_limits(files, limits).is_ok());
+        let limits = Limits {
+            max_include_depth: 2,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            parse_with_limits(files, limits).unwrap_err().without_includes(),
+            ProcessingError::IncludeTooDeep { path, limit: 2 } if path == "b.ninja"
+        ));
+    }
+
+    #[test]
+    fn byte_limit() {
+        // Siblings count too, not just nested includes.
+        let files: &[(&[u8], &[u8])] = &[
+            (b"build.ninja", b"include a.ninja\ninclude a.ninja\n"),
+            (b"a.ninja", b"x = 0123456789\n"),
+        ];
+        let limits = Limits {
+            max_bytes: 62,
+            ..Limits::default()
+        };
+        assert!(parse_with_limits(files, limits).is_ok());
+        let limits = Limits {
+            max_bytes: 61,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            parse_with_limits(files, limits)
+                .unwrap_err()
+                .without_includes(),
+            ProcessingError::TooManyBytes(61)
+        ));
+    }
+
+    #[test]
+    fn edge_limit() {
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"build a: phony\nbuild b: phony\nbuild c: phony\n",
+        )];
+        let limits = Limits {
+            max_edges: 3,
+            ..Limits::default()
+        };
+        assert_eq!(parse_with_limits(files, limits).unwrap().builds.len(), 3);
+        let limits = Limits {
+            max_edges: 2,
+            ..Limits::default()
+        };
+        assert_eq!(
+            parse_with_limits(files, limits).unwrap_err().to_string(),
+            "build.ninja:3:1: the manifest describes more than 2 edges"
+        );
+    }
+
+    #[test]
+    fn commands_are_evaluated_per_edge() {
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"flags = -top\nrule cc\n  depfile = $out.d\n  command = cc $flags -MF $depfile $$HOME $in -o $out\n\
+              build a.o: cc a.c\nbuild b.o: cc b.c\n  flags = -edge\n",
+        )];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        let commands: Vec<_> = repr
+            .builds
+            .iter()
+            .map(|build| match &build.action {
+                crate::Action::Command(command) => command.as_str(),
+                crate::Action::Phony => "",
+            })
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "cc -top -MF a.o.d $HOME a.c -o a.o",
+                "cc -edge -MF b.o.d $HOME b.c -o b.o"
+            ]
+        );
+    }
+
+    #[test]
+    fn subninja_scope() {
+        let files: &[(&[u8], &[u8])] = &[
+            (
+                b"build.ninja",
+                b"x = outer\nrule cc\n  command = cc $x\nsubninja sub.ninja\n\
+                  rule link\n  command = link $x\nbuild c: link b\n",
+            ),
+            (
+                b"sub.ninja",
+                b"x = inner\nrule link\n  command = ln $x\nbuild a: cc\nbuild b: link a\n",
+            ),
+        ];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        let commands: Vec<_> = repr
+            .builds
+            .iter()
+            .map(|build| match &build.action {
+                crate::Action::Command(command) => command.as_str(),
+                crate::Action::Phony => "",
+            })
+            .collect();
+        assert_eq!(commands, vec!["cc inner", "ln inner", "link outer"]);
+        assert!(repr.builds.iter().all(|build| build.namespace.is_none()));
+        assert!(repr.redefinitions.is_empty());
+    }
+
+    #[test]
+    fn files_record_what_they_declare() {
+        let files: &[(&[u8], &[u8])] = &[
+            (
+                b"build.ninja",
+                b"include rules.ninja
+subninja sub.ninja
+build c: cc b
+",
+            ),
+            (
+                b"rules.ninja",
+                b"rule cc
+  command = cc
+",
+            ),
+            (
+                b"sub.ninja",
+                b"include rules.ninja
+build a: cc
+build b: cc a
+",
+            ),
+        ];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        let file = |path: &[u8], parent, subninja, rules, edges| crate::ManifestFile {
+            path: path.to_vec(),
+            parent,
+            subninja,
+            rules,
+            edges,
+        };
+        assert_eq!(
+            repr.files,
+            vec![
+                file(b"build.ninja", None, false, 0, 1),
+                file(b"rules.ninja", Some(0), false, 1, 0),
+                file(b"sub.ninja", Some(0), true, 0, 2),
+                file(b"rules.ninja", Some(2), false, 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn builddir_is_top_level() {
+        let files: &[(&[u8], &[u8])] = &[
+            (
+                b"build.ninja",
+                b"out = out\nbuilddir = $out/meta\ninclude more.ninja\nsubninja sub.ninja\n",
+            ),
+            (b"more.ninja", b"builddir = $builddir/more\n"),
+            (b"sub.ninja", b"builddir = elsewhere\n"),
+        ];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        assert_eq!(repr.builddir, Some(b"out/meta/more".to_vec()));
+
+        let files: &[(&[u8], &[u8])] = &[(b"build.ninja", b"builddir = $unset\n")];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        assert_eq!(repr.builddir, None);
+    }
+
+    #[test]
+    fn subninja_rules_shadow_and_stay_apart() {
+        let files: &[(&[u8], &[u8])] = &[
+            (
+                b"build.ninja",
+                b"rule cc\n  command = cc\nsubninja a.ninja\nsubninja b.ninja\nbuild top: cc\n",
+            ),
+            (
+                b"a.ninja",
+                b"rule cc\n  command = cc-a\n  restat = 1\nbuild a: cc\n",
+            ),
+            (b"b.ninja", b"rule cc\n  command = cc-b\nbuild b: cc\n"),
+        ];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        let rules: Vec<(String, bool)> = repr
+            .builds
+            .iter()
+            .map(|build| {
+                let rule = &repr.rules[build.rule_index.unwrap()];
+                assert_eq!(rule.name, b"cc");
+                match &build.action {
+                    crate::Action::Command(command) => (command.clone(), rule.restat),
+                    crate::Action::Phony => unreachable!(),
+                }
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                ("cc-a".to_owned(), true),
+                ("cc-b".to_owned(), false),
+                ("cc".to_owned(), false),
+            ]
+        );
+
+        // Within a scope, including through `include`, rules are still defined once.
+        let files: &[(&[u8], &[u8])] = &[
+            (b"build.ninja", b"subninja a.ninja\n"),
+            (b"a.ninja", b"rule cc\n  command = cc\ninclude b.ninja\n"),
+            (b"b.ninja", b"rule cc\n  command = cc\n"),
+        ];
+        assert_eq!(
+            parse_with_limits(files, Limits::default())
+                .unwrap_err()
+                .to_string(),
+            "b.ninja:1:1: duplicate rule name: cc\n  in include at a.ninja:3:1\n  \
+             in subninja at build.ninja:1:1"
+        );
+    }
+
+    #[test]
+    fn errors_in_includes() {
+        let files: &[(&[u8], &[u8])] = &[
+            (
+                b"build.ninja",
+                b"x = 1
+subninja sub/build.ninja
+",
+            ),
+            (
+                b"sub/build.ninja",
+                b"\ninclude sub/rules.ninja
+",
+            ),
+            (
+                b"sub/rules.ninja",
+                b"rule cc
+  command = cc
+  bogus = 1
+",
+            ),
+        ];
+        let err = parse_with_limits(files, Limits::default()).unwrap_err();
+        let position = err.position().unwrap();
+        assert_eq!(
+            position.source_name.as_deref(),
+            Some(&b"sub/rules.ninja"[..])
+        );
+        assert_eq!(position.line, 3);
+        assert!(err.message().starts_with("unexpected variable"));
+        let chain: Vec<String> = err
+            .included_from()
+            .iter()
+            .map(|inclusion| inclusion.to_string())
+            .collect();
+        assert_eq!(
+            chain,
+            vec![
+                "in include at sub/build.ninja:2:1",
+                "in subninja at build.ninja:2:1"
+            ]
+        );
+
+        // Errors in the manifest itself aren't wrapped.
+        let files: &[(&[u8], &[u8])] = &[(b"build.ninja", b"build a: nope\n")];
+        let err = parse_with_limits(files, Limits::default()).unwrap_err();
+        assert!(err.included_from().is_empty());
+        assert_eq!(err.message(), "build edge refers to unknown rule: nope");
+        assert_eq!(err.position().unwrap().line, 1);
+    }
+
+    #[test]
+    fn subninja_chdir() {
+        let files: &[(&[u8], &[u8])] = &[
+            (
+                b"build.ninja",
+                b"ninjars_manifest_version = 2\nrule cp\n  command = cp $in $out\n\
+                  subninja lib/build.ninja\nsubninja top.ninja\n",
+            ),
+            (
+                b"lib/build.ninja",
+                b"build a: cp a.in\ndefault a\nsubninja inner/build.ninja\n",
+            ),
+            (b"lib/inner/build.ninja", b"build b: cp $:x\n"),
+            (b"top.ninja", b"build c: cp lib$:a\n"),
+        ];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        let namespaces: Vec<_> = repr
+            .builds
+            .iter()
+            .map(|build| build.namespace.as_deref())
+            .collect();
+        assert_eq!(
+            namespaces,
+            vec![Some(&b"lib"[..]), Some(&b"lib/inner"[..]), None]
+        );
+        assert_eq!(repr.builds[1].inputs, vec![b":x".to_vec()]);
+        assert_eq!(
+            repr.defaults.unwrap().into_iter().collect::<Vec<_>>(),
+            vec![b"lib:a".to_vec()]
+        );
+    }
+
+    #[test]
+    fn subninja_without_version_keeps_paths() {
+        let files: &[(&[u8], &[u8])] = &[
+            (b"build.ninja", b"subninja lib/build.ninja\n"),
+            (b"lib/build.ninja", b"build a: phony\ndefault a\n"),
+        ];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        assert_eq!(repr.builds[0].namespace, None);
+        assert_eq!(
+            repr.defaults.unwrap().into_iter().collect::<Vec<_>>(),
+            vec![b"a".to_vec()]
+        );
+
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"ninjars_manifest_version = two\nsubninja lib/build.ninja\n",
+        )];
+        assert!(matches!(
+            parse_with_limits(files, Limits::default()),
+            Err(ProcessingError::InvalidManifestVersion(v)) if v == "two"
+        ));
+    }
+
+    #[test]
+    fn rule_templates_evaluate_per_edge() {
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"rule cc\n  command = cc $in -o $out\n  depfile = $dir$out.d\n  description = CC $out\n\
+build a.o: cc a.c\n\
+build b.o: cc b.c\n  dir = deps/\n\
+build c.o: cc c.c\n  dir = $in-\n\
+build all: phony a.o\n",
+        )];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        // The rule keeps its templates, which only mean something for a given edge.
+        assert_eq!(
+            repr.rules[0].depfile.as_deref(),
+            Some(&b"${dir}${out}.d"[..])
+        );
+        let evaluated: Vec<_> = repr
+            .builds
+            .iter()
+            .map(|build| {
+                (
+                    build
+                        .depfile
+                        .as_ref()
+                        .map(|d| String::from_utf8_lossy(d).into_owned()),
+                    build.description.clone(),
+                )
+            })
+            .collect();
+        let some = |s: &str| Some(s.to_owned());
+        assert_eq!(
+            evaluated,
+            vec![
+                (some("a.o.d"), some("CC a.o")),
+                (some("deps/b.o.d"), some("CC b.o")),
+                // Like in ninja, edge bindings are evaluated as written, before $in exists.
+                (some("c.o.d"), some("CC c.o")),
+                (None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn rspfile() {
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"rule ld\n  command = ld\n  rspfile = $out.rsp\n  rspfile_content = $in_newline\n\
+rule cc\n  command = cc $in\n  rspfile = $rsp\n\
+build app: ld a.o b.o\n\
+build c.o: cc c.c\n\
+build d.o: cc d.c\n  rsp = d.rsp\n\
+build all: phony app\n  rspfile = all.rsp\n",
+        )];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        let rspfiles: Vec<_> = repr.builds.into_iter().map(|build| build.rspfile).collect();
+        assert_eq!(
+            rspfiles,
+            vec![
+                Some(Rspfile {
+                    path: b"app.rsp".to_vec(),
+                    content: b"a.o\nb.o".to_vec(),
+                }),
+                // An empty path means no response file.
+                None,
+                Some(Rspfile {
+                    path: b"d.rsp".to_vec(),
+                    content: vec![],
+                }),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn deps() {
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"rule cc\n  command = cc\n  deps = gcc\n  depfile = $out.d\n\
+rule cl\n  command = cl\n  deps = $style\n\
+build a.o: cc a.c\n\
+build b.obj: cl b.c\n  style = msvc\n\
+build c.obj: cl c.c\n  style = msvc\n  msvc_deps_prefix = Remarque :\n\
+build d.obj: cl d.c\n",
+        )];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        let deps: Vec<_> = repr.builds.into_iter().map(|build| build.deps).collect();
+        assert_eq!(
+            deps,
+            vec![
+                Some(Deps::Gcc),
+                Some(Deps::Msvc {
+                    prefix: DEFAULT_MSVC_DEPS_PREFIX.to_vec()
+                }),
+                Some(Deps::Msvc {
+                    prefix: b"Remarque :".to_vec()
+                }),
+                None,
+            ]
+        );
+
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"rule cc\n  command = cc\n  deps = clang\nbuild a.o: cc a.c\n",
+        )];
+        assert_eq!(
+            parse_with_limits(files, Limits::default())
+                .unwrap_err()
+                .to_string(),
+            "build.ninja:4:1: unknown deps type 'clang'"
+        );
+    }
+
+    #[test]
+    fn self_dependency() {
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"rule stamp
+  command = touch $out
+  restat = 1
+build a b: stamp a | c || b
+",
+        )];
+        let repr = parse_with_limits(files, Limits::default()).unwrap();
+        assert_eq!(repr.builds[0].inputs, Vec::<Vec<u8>>::new());
+        assert_eq!(repr.builds[0].implicit_inputs, vec![b"c".to_vec()]);
+        assert_eq!(repr.builds[0].order_inputs, Vec::<Vec<u8>>::new());
+        assert_eq!(
+            repr.self_dependencies,
+            vec![
+                SelfDependency {
+                    path: b"a".to_vec(),
+                    location: "build.ninja:4:1".to_owned(),
+                },
+                SelfDependency {
+                    path: b"b".to_vec(),
+                    location: "build.ninja:4:1".to_owned(),
+                },
+            ]
+        );
+
+        let files: &[(&[u8], &[u8])] = &[(
+            b"build.ninja",
+            b"rule cc
+  command = cc
+build a.o: cc a.c | a.o
+",
+        )];
+        assert_eq!(
+            parse_with_limits(files, Limits::default())
+                .unwrap_err()
+                .to_string(),
+            "build.ninja:3:1: 'a.o' is both an input and an output of the same edge; only restat \
+rules may list it twice"
+        );
+    }
+
+    #[test]
+    fn implicit_outputs() {
+        let parse = |manifest: &'static [u8]| {
+            let files: &[(&[u8], &[u8])] = &[(b"build.ninja", manifest)];
+            parse_with_limits(files, Limits::default())
+        };
+        let repr = parse(b"rule cc\n  command = cc -o $out\nbuild a.o | a.d: cc a.c | a.d\n");
+        assert_eq!(
+            repr.unwrap_err().to_string(),
+            "build.ninja:3:1: 'a.d' is both an input and an output of the same edge; only restat \
+rules may list it twice"
+        );
+
+        let repr = parse(b"rule cc\n  command = cc -o $out\nbuild a.o|a.d: cc a.c\n").unwrap();
+        assert_eq!(repr.builds[0].outputs, vec![b"a.o".to_vec()]);
+        assert_eq!(repr.builds[0].implicit_outputs, vec![b"a.d".to_vec()]);
+        assert!(matches!(
+            &repr.builds[0].action,
+            crate::Action::Command(command) if command == "cc -o a.o"
+        ));
+
+        assert_eq!(
+            parse(b"rule cc\n  command = cc\nbuild a.o | a.d: cc\nbuild a.d: cc\n")
+                .unwrap_err()
+                .to_string(),
+            "build.ninja:4:1: duplicate output: a.d"
+        );
+        assert!(parse(b"rule cc\n  command = cc\nbuild | a.d: cc\n").is_err());
+        assert!(parse(b"rule cc\n  command = cc\nbuild a.o | a.d || b: cc\n").is_err());
+    }
+
+    #[test]
+    fn pools() {
+        let parse = |manifest: &'static [u8]| {
+            let files: &[(&[u8], &[u8])] = &[(b"build.ninja", manifest)];
+            parse_with_limits(files, Limits::default())
+        };
+        let repr = parse(
+            b"pool link\n  depth = 4\nrule ld\n  command = ld\n  pool = link\n\
+rule cc\n  command = cc\nbuild a: ld\nbuild b: cc\n  pool = console\nbuild c: phony\n  pool = link\n",
+        )
+        .unwrap();
+        assert_eq!(
+            repr.pools,
+            vec![Pool {
+                name: b"link".to_vec(),
+                depth: 4
+            }]
+        );
+        let pools: Vec<_> = repr.builds.into_iter().map(|build| build.pool).collect();
+        assert_eq!(
+            pools,
+            vec![Some(b"link".to_vec()), Some(b"console".to_vec()), None]
+        );
+
+        for (manifest, error) in &[
+            (
+                &b"pool link\n  depth = 1\npool link\n  depth = 2\n"[..],
+                "build.ninja:3:1: duplicate pool name: link",
+            ),
+            (
+                b"pool console\n  depth = 2\n",
+                "build.ninja:1:1: duplicate pool name: console",
+            ),
+            (
+                b"pool link\n  depth = many\n",
+                "build.ninja:1:1: 'depth' for pool link must be a number, not 'many'",
+            ),
+            (
+                b"rule ld\n  command = ld\nbuild a: ld\n  pool = link\n",
+                "build.ninja:3:1: unknown pool name: link",
+            ),
+        ] {
+            assert_eq!(&parse(manifest).unwrap_err().to_string(), error);
+        }
+    }
+
     #[test]
     fn no_rule_named_phony() {
         let mut parse_state = ParseState::default();
@@ -323,6 +1509,7 @@ mod test {
                     ..Default::default()
                 },
                 env.clone(),
+                String::new,
             )
             .unwrap();
         let err = parse_state
@@ -333,6 +1520,7 @@ mod test {
                     ..Default::default()
                 },
                 env.clone(),
+                String::new,
             )
             .expect_err("duplicate output");
         assert!(matches!(err, ProcessingError::DuplicateOutput(_)));
@@ -353,6 +1541,7 @@ mod test {
                     ..Default::default()
                 },
                 env.clone(),
+                String::new,
             )
             .unwrap();
         let err = parse_state
@@ -366,6 +1555,7 @@ mod test {
                     ..Default::default()
                 },
                 env.clone(),
+                String::new,
             )
             .expect_err("duplicate output");
         assert!(matches!(err, ProcessingError::DuplicateOutput(_)));
@@ -382,6 +1572,7 @@ mod test {
                     ..Default::default()
                 },
                 env,
+                String::new,
             )
             .expect_err("unknown rule");
         assert!(matches!(err, ProcessingError::UnknownRule(_)));
@@ -426,7 +1617,9 @@ mod test {
                 ..Default::default()
             },
         ] {
-            parse_state.add_build_edge(build, env.clone()).unwrap();
+            parse_state
+                .add_build_edge(build, env.clone(), String::new)
+                .unwrap();
         }
         let repr = parse_state.into_description();
         assert_debug_snapshot!(repr);
@@ -464,9 +1657,89 @@ mod test {
             ],
             ..Default::default()
         }] {
-            let _ = parse_state.add_build_edge(build, env.clone()).unwrap();
+            parse_state
+                .add_build_edge(build, env.clone(), String::new)
+                .unwrap();
         }
         let repr = parse_state.into_description();
         assert_debug_snapshot!(repr);
     }
+
+    #[test]
+    fn description_sees_in_and_out() {
+        let mut parse_state = ParseState::default();
+        let env = Rc::new(RefCell::new(Env::default()));
+        parse_state
+            .add_rule(past::Rule {
+                name: b"cc".to_vec(),
+                bindings: vec![
+                    (b"command".to_vec(), past::Expr(vec![lit!(b"clang")])),
+                    (
+                        b"description".to_vec(),
+                        past::Expr(vec![
+                            lit!(b"CC "),
+                            aref!(b"in"),
+                            lit!(b" -> "),
+                            aref!(b"out"),
+                        ]),
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+            })
+            .unwrap();
+        parse_state
+            .add_build_edge(
+                past::Build {
+                    rule: b"cc".to_vec(),
+                    inputs: vec![past::Expr(vec![lit!(b"a.c")])],
+                    outputs: vec![past::Expr(vec![lit!(b"a.o")])],
+                    ..Default::default()
+                },
+                env.clone(),
+                String::new,
+            )
+            .unwrap();
+        let repr = parse_state.into_description();
+        assert_eq!(repr.builds[0].description.as_deref(), Some("CC a.c -> a.o"));
+    }
+
+    #[test]
+    fn phony_description() {
+        let mut parse_state = ParseState::default();
+        let env = Rc::new(RefCell::new(Env::default()));
+        let mut bindings = Env::default();
+        bindings.add_binding("description", "all the things");
+        parse_state
+            .add_build_edge(
+                past::Build {
+                    rule: b"phony".to_vec(),
+                    inputs: vec![past::Expr(vec![lit!(b"a.o")])],
+                    outputs: vec![past::Expr(vec![lit!(b"all")])],
+                    bindings,
+                    ..Default::default()
+                },
+                env.clone(),
+                String::new,
+            )
+            .unwrap();
+        parse_state
+            .add_build_edge(
+                past::Build {
+                    rule: b"phony".to_vec(),
+                    outputs: vec![past::Expr(vec![lit!(b"nothing")])],
+                    ..Default::default()
+                },
+                env,
+                String::new,
+            )
+            .unwrap();
+        let repr = parse_state.into_description();
+        assert!(matches!(repr.builds[0].action, crate::Action::Phony));
+        assert_eq!(
+            repr.builds[0].description.as_deref(),
+            Some("all the things")
+        );
+        assert_eq!(repr.builds[1].description, None);
+    }
 }
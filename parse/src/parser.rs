@@ -15,6 +15,7 @@
  */
 
 use std::{
+    borrow::Cow,
     cell::RefCell,
     collections::HashMap,
     fmt::{Display, Formatter},
@@ -28,7 +29,7 @@ use super::{
     env::Env,
     lexer,
     lexer::{Lexeme, Lexer, LexerError, LexerItem, Position},
-    Loader, ParseState, ProcessingError,
+    Inclusion, Loader, ParseState, ProcessingError,
 };
 
 #[derive(Debug, Error)]
@@ -51,6 +52,14 @@ impl ParseError {
         }
     }
 
+    pub(crate) fn position(&self) -> &Position {
+        &self.position
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        &self.message
+    }
+
     fn eof<S: Into<String>>(msg: S, lexer: &Lexer) -> ParseError {
         let pos = lexer.last_pos();
         ParseError::new(msg, pos, lexer)
@@ -147,15 +156,15 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn expr_to_expr(lexeme: Lexeme<'a>) -> Expr {
+    fn expr_to_expr(lexeme: Lexeme<'a>) -> Expr<'a> {
         lexeme.check();
         if let Lexeme::Expr(items) = lexeme {
             Expr(
                 items
-                    .iter()
+                    .into_iter()
                     .map(|item| match item {
-                        Lexeme::Literal(v) | Lexeme::Escape(v) => Term::Literal(v.clone().to_vec()),
-                        Lexeme::VarRef(_, v) => Term::Reference(v.clone().to_vec()),
+                        Lexeme::Literal(v) | Lexeme::Escape(v) => Term::Literal(Cow::Borrowed(v)),
+                        Lexeme::VarRef(_, v) => Term::Reference(Cow::Borrowed(v)),
                         _ => unreachable!(),
                     })
                     .collect(),
@@ -206,7 +215,7 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn expect_value(&mut self) -> Result<Expr, ParseError> {
+    fn expect_value(&mut self) -> Result<Expr<'a>, ParseError> {
         self.handle_eof_and_comments("value").and_then(|res| {
             res.map_err(|lex_err| ParseError::from_lexer_error(lex_err, &self.lexer))
                 .and_then(|(token, pos)| match token {
@@ -262,7 +271,7 @@ impl<'a> Parser<'a> {
         })
     }
 
-    fn read_assignment(&mut self) -> Result<(&'a [u8], Expr), ParseError> {
+    fn read_assignment(&mut self) -> Result<(&'a [u8], Expr<'a>), ParseError> {
         let var = self.expect_identifier_eating_indent()?;
         self.discard_assignment()?;
         let value = self.expect_value()?;
@@ -271,10 +280,12 @@ impl<'a> Parser<'a> {
 
     // really need a peekable overlay while allowing us to access the lexer whenever we want
     // (mostly for errors).
-    fn parse_rule(&mut self) -> Result<Rule, ParseError> {
-        let identifier = self.expect_identifier()?;
-        self.discard_newline()?;
-
+    /// Reads the indented bindings of a `rule` or `pool` declaration, which must have at least
+    /// one, allowing only the variables in `allowed`.
+    fn parse_declaration_bindings(
+        &mut self,
+        allowed: &[&[u8]],
+    ) -> Result<HashMap<Vec<u8>, Expr<'static>>, ParseError> {
         let mut bindings = HashMap::new();
         let mut at_least_one = false;
         loop {
@@ -303,7 +314,7 @@ impl<'a> Parser<'a> {
                         self.discard_indent()?;
                         let (var, value) = self.read_assignment()?;
                         // TODO: Move this to a semantic pass.
-                        if !allowed_rule_variable(var) {
+                        if !allowed.contains(&var) {
                             return Err(ParseError::new(
                                 format!(
                                     "unexpected variable '{}'",
@@ -313,37 +324,55 @@ impl<'a> Parser<'a> {
                                 &self.lexer,
                             ));
                         }
-                        bindings.insert(var.to_vec(), value);
+                        bindings.insert(var.to_vec(), value.into_owned());
                     }
                     _ => {
-                        // Done with this rule since we encountered a non-indent.
+                        // Done with this declaration since we encountered a non-indent.
                         break;
                     }
                 }
             }
         }
+        Ok(bindings)
+    }
 
+    fn parse_rule(&mut self) -> Result<Rule, ParseError> {
+        let identifier = self.expect_identifier()?;
+        self.discard_newline()?;
+        let bindings = self.parse_declaration_bindings(ALLOWED_RULE_VARIABLES)?;
         Ok(Rule {
             name: identifier.value().to_vec(),
             bindings,
         })
     }
 
-    fn parse_build(&mut self, top_env: Rc<RefCell<Env>>) -> Result<Build, ParseError> {
+    fn parse_pool(&mut self) -> Result<Pool, ParseError> {
+        let identifier = self.expect_identifier()?;
+        self.discard_newline()?;
+        let bindings = self.parse_declaration_bindings(ALLOWED_POOL_VARIABLES)?;
+        Ok(Pool {
+            name: identifier.value().to_vec(),
+            bindings,
+        })
+    }
+
+    fn parse_build(&mut self, top_env: Rc<RefCell<Env>>) -> Result<Build<'a>, ParseError> {
         // TODO: Support all kinds of optional outputs and dependencies.
         #[derive(Debug, PartialEq, Eq)]
         enum Read {
             Outputs,
+            ImplicitOutputs,
             Rule,
             Inputs,
             ImplicitInputs,
             OrderInputs,
         };
 
-        let mut outputs: Vec<Expr> = Vec::new();
-        let mut inputs: Vec<Expr> = Vec::new();
-        let mut implicit_inputs: Vec<Expr> = Vec::new();
-        let mut order_inputs: Vec<Expr> = Vec::new();
+        let mut outputs: Vec<Expr<'a>> = Vec::new();
+        let mut implicit_outputs: Vec<Expr<'a>> = Vec::new();
+        let mut inputs: Vec<Expr<'a>> = Vec::new();
+        let mut implicit_inputs: Vec<Expr<'a>> = Vec::new();
+        let mut order_inputs: Vec<Expr<'a>> = Vec::new();
         let mut rule = None;
         let mut state = Read::Outputs;
         let mut first_line_pos = None;
@@ -358,20 +387,43 @@ impl<'a> Parser<'a> {
                     Lexeme::Expr(_) => {
                         outputs.push(Parser::expr_to_expr(token));
                     }
+                    Lexeme::Colon | Lexeme::Pipe if outputs.is_empty() => {
+                        return Err(ParseError::new(
+                            "Expected at least one output for build",
+                            pos,
+                            &self.lexer,
+                        ));
+                    }
+                    Lexeme::Colon => {
+                        state = Read::Rule;
+                    }
+                    Lexeme::Pipe => {
+                        state = Read::ImplicitOutputs;
+                    }
+                    _ => {
+                        return Err(ParseError::new(
+                            format!(
+                                "Expected another output or one of ({}, {}), got {}",
+                                Lexeme::Pipe,
+                                Lexeme::Colon,
+                                token
+                            ),
+                            pos,
+                            &self.lexer,
+                        ));
+                    }
+                },
+                Read::ImplicitOutputs => match token {
+                    Lexeme::Expr(_) => {
+                        implicit_outputs.push(Parser::expr_to_expr(token));
+                    }
                     Lexeme::Colon => {
-                        if outputs.is_empty() {
-                            return Err(ParseError::new(
-                                "Expected at least one output for build",
-                                pos,
-                                &self.lexer,
-                            ));
-                        }
                         state = Read::Rule;
                     }
                     _ => {
                         return Err(ParseError::new(
                             format!(
-                                "Expected another output or {}, got {}",
+                                "Expected an implicit output or {}, got {}",
                                 Lexeme::Colon,
                                 token
                             ),
@@ -482,6 +534,7 @@ impl<'a> Parser<'a> {
             implicit_inputs,
             order_inputs,
             outputs,
+            implicit_outputs,
             bindings: Env::with_parent(top_env.clone()),
         };
 
@@ -533,24 +586,37 @@ impl<'a> Parser<'a> {
                 Lexeme::Identifier(ident) => {
                     self.discard_assignment()?;
                     let value = self.expect_value()?;
+                    // Building a value up from itself, like `cflags = $cflags -g`, is the usual
+                    // reason to reassign a variable, so that alone is no cause for a warning.
+                    let extends = value.references(ident);
                     // Top-level bindings are evaluated immediately.
                     let value = {
                         let b = state.bindings.borrow();
                         value.eval(&b)
                     };
-                    state.bindings.borrow_mut().add_binding(ident, value);
+                    let lexer = &self.lexer;
+                    state.add_top_level_binding(ident.to_vec(), value, extends, || {
+                        lexer.to_position(pos).to_string()
+                    });
                 }
                 Lexeme::Rule => {
                     state
                         .add_rule(self.parse_rule()?)
                         .map_err(|e| e.with_position_boxed(self.lexer.to_position(pos)))?;
                 }
+                Lexeme::Pool => {
+                    let pool = self.parse_pool()?;
+                    state
+                        .add_pool(pool)
+                        .map_err(|e| e.with_position_boxed(self.lexer.to_position(pos)))?;
+                }
                 Lexeme::Build => {
+                    let build = self.parse_build(state.bindings.clone())?;
+                    let lexer = &self.lexer;
                     state
-                        .add_build_edge(
-                            self.parse_build(state.bindings.clone())?,
-                            state.bindings.clone(),
-                        )
+                        .add_build_edge(build, state.bindings.clone(), || {
+                            lexer.to_position(pos).to_string()
+                        })
                         .map_err(|e| e.with_position_boxed(self.lexer.to_position(pos)))?;
                 }
                 Lexeme::Include => {
@@ -560,9 +626,46 @@ impl<'a> Parser<'a> {
                         let env = state.bindings.borrow();
                         path.eval(&env)
                     };
-                    let contents = loader.load(self.source_name.as_deref(), &path)?;
-                    // TODO: Error should be from the included path.
-                    super::parse_single(&contents, Some(path), state, loader)?;
+                    let contents = match loader.load(self.source_name.as_deref(), &path) {
+                        Ok(contents) => contents,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                            // May be generated by an edge seen so far. Stop here so nothing
+                            // after the include is evaluated without it.
+                            state.description.missing_include = Some(path);
+                            return Ok(());
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+                    super::parse_single(&contents, Some(path), false, state, loader).map_err(
+                        |e| {
+                            e.included_by(Inclusion {
+                                position: self.lexer.to_position(pos),
+                                subninja: false,
+                            })
+                        },
+                    )?;
+                    if state.description.missing_include.is_some() {
+                        return Ok(());
+                    }
+                }
+                Lexeme::Subninja => {
+                    let path = self.expect_value()?;
+                    self.discard_newline()?;
+                    let path = {
+                        let env = state.bindings.borrow();
+                        path.eval(&env)
+                    };
+                    let lexer = &self.lexer;
+                    super::parse_subninja(
+                        path,
+                        self.source_name.as_deref(),
+                        state,
+                        loader,
+                        || lexer.to_position(pos),
+                    )?;
+                    if state.description.missing_include.is_some() {
+                        return Ok(());
+                    }
                 }
                 Lexeme::Default => {
                     // Consume until we eat a newline assuming paths.
@@ -602,18 +705,20 @@ impl<'a> Parser<'a> {
 }
 
 const ALLOWED_RULE_VARIABLES: &[&[u8]] = &[
+    b"batch",
     b"command",
     b"depfile",
     b"deps",
     b"description",
     b"generator",
+    b"msvc_deps_prefix",
     b"pool",
     b"restat",
+    b"rspfile",
+    b"rspfile_content",
 ];
 
-fn allowed_rule_variable(name: &[u8]) -> bool {
-    ALLOWED_RULE_VARIABLES.contains(&name)
-}
+const ALLOWED_POOL_VARIABLES: &[&[u8]] = &[b"depth"];
 
 #[cfg(test)]
 mod test {
@@ -631,7 +736,7 @@ mod test {
     fn simple_parser(input: &[u8]) -> Result<Description, ProcessingError> {
         let mut parse_state = ParseState::default();
         let mut loader = DummyLoader {};
-        let _ = parse_single(input, None, &mut parse_state, &mut loader)?;
+        parse_single(input, None, false, &mut parse_state, &mut loader)?;
         Ok(parse_state.into_description())
     }
 
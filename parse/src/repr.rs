@@ -24,6 +24,103 @@ pub struct Description {
     // will have things like pools and minimum ninja version and defaults and so on.
     pub builds: Vec<Build>,
     pub defaults: Option<HashSet<Vec<u8>>>,
+    /// Every rule in the manifest except the built-in `phony`, in the order they were declared.
+    pub rules: Vec<RuleDescriptor>,
+    /// An `include` whose file does not exist. Parsing stops there, so only what came before it is
+    /// described. If one of those edges produces the file, build it and parse again.
+    pub missing_include: Option<Vec<u8>>,
+    /// Top-level variables assigned again after edges were evaluated with their previous value.
+    pub redefinitions: Vec<Redefinition>,
+    /// `$in` or `$out` expansions that the shell would split or interpret.
+    pub unquoted_expansions: Vec<UnquotedExpansion>,
+    /// Inputs of `restat` edges that are also outputs of the same edge. They are left out of the
+    /// edge's inputs, since they would make it wait on itself.
+    pub self_dependencies: Vec<SelfDependency>,
+    /// Pools declared in the manifest, in the order they were declared. The built-in `console`
+    /// pool is not included.
+    pub pools: Vec<Pool>,
+    /// The files the manifest was read from, starting with the manifest itself, each followed by
+    /// the files it loaded.
+    pub files: Vec<ManifestFile>,
+    /// The top-level `builddir` variable, where ninja keeps its logs and other files between
+    /// builds. Relative to the directory ninja builds in.
+    pub builddir: Option<Vec<u8>>,
+}
+
+/// The manifest, or a file it loaded with `include` or `subninja`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestFile {
+    /// As the loader was asked for it.
+    pub path: Vec<u8>,
+    /// The index in `Description::files` of the file that loaded this one. None for the manifest.
+    pub parent: Option<usize>,
+    /// Whether this file was loaded with `subninja` rather than `include`.
+    pub subninja: bool,
+    /// How many rules this file declares, not counting the files it loads.
+    pub rules: usize,
+    /// How many edges this file describes, not counting the files it loads.
+    pub edges: usize,
+}
+
+/// A top-level variable reassigned after some edges had already used its old value. This is
+/// how ninja behaves, but it is usually unintended when the value is not built up from itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redefinition {
+    pub name: Vec<u8>,
+    /// Where the reassignment is, as `file:line:column`.
+    pub location: String,
+    /// How many edges were described between the previous assignment and this one.
+    pub edges_before: usize,
+}
+
+/// A path with spaces or shell metacharacters that `$in` or `$out` pastes into quotes the command
+/// added. Such paths get quotes of their own, which the command's would make part of the path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnquotedExpansion {
+    /// The first output of the edge.
+    pub output: Vec<u8>,
+    /// `in` or `out`.
+    pub variable: &'static str,
+    pub path: Vec<u8>,
+}
+
+/// A path listed as both an input and an output of one edge, usually a rule that updates a file
+/// in place and relies on `restat` to tell whether it changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfDependency {
+    pub path: Vec<u8>,
+    /// Where the build statement is, as `file:line:column`.
+    pub location: String,
+}
+
+/// Limits how many edges assigned to it run at once, regardless of `-j`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pool {
+    pub name: Vec<u8>,
+    /// 0 means no limit.
+    pub depth: usize,
+}
+
+/// The built-in pool of depth 1 whose commands write directly to the terminal, for long-running
+/// commands with their own progress output.
+pub const CONSOLE_POOL: &[u8] = b"console";
+
+/// What is known about a rule beyond the command, for pools, restat, depfiles and tooling.
+///
+/// Bindings are kept as written, with references in `${name}` form, since they are evaluated
+/// separately for each edge.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleDescriptor {
+    pub name: Vec<u8>,
+    pub description: Option<Vec<u8>>,
+    pub depfile: Option<Vec<u8>>,
+    pub deps: Option<Vec<u8>>,
+    pub pool: Option<Vec<u8>>,
+    pub restat: bool,
+    pub generator: bool,
+    /// How many ready edges of this rule may be run together in a single shell invocation. 0 and
+    /// 1 both mean every edge runs on its own.
+    pub batch: usize,
 }
 
 #[derive(Debug)]
@@ -32,11 +129,64 @@ pub enum Action {
     Command(String),
 }
 
+/// How a command reports the inputs it discovered, set by the `deps` binding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Deps {
+    /// Listed in the depfile, which is removed once ninja has recorded them.
+    Gcc,
+    /// Lines of the command's output starting with `prefix`, the way `cl /showIncludes` prints
+    /// them. They are taken out of the output shown to the user.
+    Msvc { prefix: Vec<u8> },
+}
+
+/// A file written with the evaluated `rspfile_content` binding before the command runs, for
+/// commands that would be too long to pass the inputs on the command line, like a linker reading
+/// them from `@$out.rsp`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rspfile {
+    /// The evaluated `rspfile` binding. Relative to the namespace directory, like the outputs.
+    pub path: Vec<u8>,
+    pub content: Vec<u8>,
+}
+
+/// What `cl /showIncludes` starts its lines with in English, for when `msvc_deps_prefix` is not
+/// set.
+pub const DEFAULT_MSVC_DEPS_PREFIX: &[u8] = b"Note: including file: ";
+
 #[derive(Debug)]
 pub struct Build {
     pub action: Action,
+    /// Name of the rule, matching one of `Description::rules` unless it is `phony`.
+    pub rule: Vec<u8>,
+    /// Index of the rule in `Description::rules`, since subninjas may define rules with the same
+    /// name. None for phony edges.
+    pub rule_index: Option<usize>,
+    /// The evaluated `description` binding, if the rule or edge has one.
+    pub description: Option<String>,
+    /// The evaluated `depfile` binding of a command edge, if it is not empty. Relative to the
+    /// namespace directory, like the outputs.
+    pub depfile: Option<Vec<u8>>,
+    /// Set when ninja should record the inputs the command discovered in its deps log, instead of
+    /// reading the depfile on every run.
+    pub deps: Option<Deps>,
+    /// Set when a command edge has a non-empty `rspfile` binding.
+    pub rspfile: Option<Rspfile>,
+    /// The evaluated `pool` binding of a command edge, if it is not empty. Either `console` or one
+    /// of `Description::pools`.
+    pub pool: Option<Vec<u8>>,
+    /// Set by a `ninja_namespace` binding, at the top level or on the edge.
+    ///
+    /// Paths of a namespaced edge are relative to the directory named after the namespace, and its
+    /// command runs there. This lets several toolchains build the same relative paths without
+    /// colliding. An input written as `other$:path` refers to `path` in the `other` namespace, and
+    /// `$:path` to `path` outside of any namespace. `$in` keeps such references as written, so they
+    /// are best listed as implicit inputs.
+    pub namespace: Option<Vec<u8>>,
     pub inputs: Vec<Vec<u8>>,
     pub implicit_inputs: Vec<Vec<u8>>,
     pub order_inputs: Vec<Vec<u8>>,
     pub outputs: Vec<Vec<u8>>,
+    /// Outputs listed after a `|`. They are built and checked like the other outputs, but left out
+    /// of `$out`.
+    pub implicit_outputs: Vec<Vec<u8>>,
 }
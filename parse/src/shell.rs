@@ -0,0 +1,181 @@
+/*
+ * Copyright 2020 Nikhil Marathe <nsm.nikhil@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Quoting of the paths `$in` and `$out` paste into commands.
+//!
+//! Like ninja, a path the shell would split or interpret, like one with a space, is put in single
+//! quotes as it expands in `command`. Other paths expand as they are. A command that quotes the
+//! expansion itself then ends up with two sets of quotes around such a path, which the lint here
+//! finds. Only references written directly in `command` are checked, not ones reached through
+//! another variable.
+
+use std::borrow::Cow;
+
+use crate::{
+    ast::{Expr, Rule, Term},
+    env::Env,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Quoting {
+    Unquoted,
+    Single,
+    Double,
+}
+
+/// The quoting in effect at the end of `command`, following POSIX quoting rules and nothing else.
+fn quoting_at_end(command: &[u8]) -> Quoting {
+    let mut quoting = Quoting::Unquoted;
+    let mut bytes = command.iter();
+    while let Some(&c) = bytes.next() {
+        quoting = match (quoting, c) {
+            (Quoting::Unquoted, b'\\') | (Quoting::Double, b'\\') => {
+                bytes.next();
+                quoting
+            }
+            (Quoting::Unquoted, b'\'') => Quoting::Single,
+            (Quoting::Unquoted, b'"') => Quoting::Double,
+            (Quoting::Single, b'\'') | (Quoting::Double, b'"') => Quoting::Unquoted,
+            (quoting, _) => quoting,
+        };
+    }
+    quoting
+}
+
+/// Whether the shell would do anything but take `path` literally if it were not quoted.
+fn needs_quoting(path: &[u8]) -> bool {
+    let special: &[u8] = b" \t\n|&;<>()$`\\\"'*?[";
+    matches!(path.first(), Some(b'#' | b'~')) || path.iter().any(|c| special.contains(c))
+}
+
+/// `path` as the shell takes it literally, in single quotes if it needs any. Single quotes hold
+/// anything but another single quote, which is ended, escaped and started again.
+pub(crate) fn quote(path: &[u8]) -> Cow<'_, [u8]> {
+    if !needs_quoting(path) {
+        return Cow::Borrowed(path);
+    }
+    let mut quoted = Vec::with_capacity(path.len() + 2);
+    quoted.push(b'\'');
+    for &c in path {
+        if c == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted.push(b'\'');
+    Cow::Owned(quoted)
+}
+
+/// `paths` quoted for a command, each followed by `separator` but the last.
+pub(crate) fn quoted_paths(paths: &[Vec<u8>], separator: u8) -> Vec<u8> {
+    let mut joined = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            joined.push(separator);
+        }
+        joined.extend_from_slice(&quote(path));
+    }
+    joined
+}
+
+/// Returns the variable and path for every path that needs quoting and that `$in` or `$out` in
+/// `command` would expand to inside quotes the command added, where the quotes ninja adds would
+/// be taken literally. `env` must have the quoted expansions.
+pub(crate) fn unquoted_expansions(
+    command: &Expr,
+    env: &Env,
+    rule: &Rule,
+    inputs: &[Vec<u8>],
+    outputs: &[Vec<u8>],
+) -> Vec<(&'static str, Vec<u8>)> {
+    // Nearly every path is plain, so most edges never evaluate the command a second time.
+    if !inputs.iter().chain(outputs).any(|path| needs_quoting(path)) {
+        return vec![];
+    }
+    let mut found = Vec::new();
+    let mut evaluated = Vec::new();
+    for term in &command.0 {
+        match term {
+            Term::Literal(bytes) => evaluated.extend_from_slice(bytes),
+            Term::Reference(name) => {
+                let (variable, paths) = match name.as_ref() {
+                    b"in" => ("in", inputs),
+                    b"out" => ("out", outputs),
+                    _ => {
+                        evaluated.extend(
+                            env.lookup_for_build(rule, name.as_ref())
+                                .unwrap_or_default(),
+                        );
+                        continue;
+                    }
+                };
+                if quoting_at_end(&evaluated) != Quoting::Unquoted {
+                    found.extend(
+                        paths
+                            .iter()
+                            .filter(|path| needs_quoting(path))
+                            .map(|path| (variable, path.clone())),
+                    );
+                }
+                evaluated.extend(
+                    env.lookup_for_build(rule, name.as_ref())
+                        .unwrap_or_default(),
+                );
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quoting_at_end(b"cc "), Quoting::Unquoted);
+        assert_eq!(quoting_at_end(b"cc '"), Quoting::Single);
+        assert_eq!(quoting_at_end(b"cc \""), Quoting::Double);
+        assert_eq!(quoting_at_end(b"cc 'a\"b' "), Quoting::Unquoted);
+        assert_eq!(quoting_at_end(b"cc \\\""), Quoting::Unquoted);
+        assert_eq!(quoting_at_end(b"cc \"a\\\"b"), Quoting::Double);
+        // Backslashes are literal in single quotes.
+        assert_eq!(quoting_at_end(b"cc 'a\\'"), Quoting::Unquoted);
+    }
+
+    #[test]
+    fn paths_needing_quotes() {
+        assert!(!needs_quoting(b"src/foo-1.2_x+y.c"));
+        assert!(needs_quoting(b"my file.c"));
+        assert!(needs_quoting(b"a;b"));
+        assert!(needs_quoting(b"$HOME"));
+        assert!(needs_quoting(b"~home"));
+        assert!(!needs_quoting(b"a~b#c"));
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(&*quote(b"plain.c"), b"plain.c");
+        assert_eq!(&*quote(b"my file.c"), b"'my file.c'");
+        assert_eq!(&*quote(b"$HOME"), b"'$HOME'");
+        assert_eq!(&*quote(b"it's"), b"'it'\\''s'");
+        assert_eq!(
+            quoted_paths(&[b"a.c".to_vec(), b"b c.c".to_vec()], b' '),
+            b"a.c 'b c.c'"
+        );
+    }
+}